@@ -23,6 +23,43 @@ RH_RF95 rf95(RFM95_CS, RFM95_INT);
 // Blinky on receipt
 #define LED 13
 
+// Everything a gauge sends starts with the magic 125, 8, the kind, then a length, then the device id.
+#define MAGIC_0 125
+#define MAGIC_1 8
+#define KIND_OFFSET 2
+#define CONFIG_MAGIC 145
+#define CONFIG_REPORT_MAGIC 146
+#define ACK_MAGIC 149
+#define DEVICE_ID_OFFSET 4
+#define DEVICE_ID_LEN 16
+
+// A gauge listens for 1.5s after it transmits.  A full frame takes it about 400ms, so once nothing has come from it
+// for half a second it has finished, leaving time for a config and an ack.
+#define QUIET_MS 500
+
+// Frames for gauges from the downlink-processor, one of each kind per gauge.  Settings are held until their gauge
+// answers with a config report.  An ack is only sent once, the gauge gets another for whatever it sends next.
+#define MAX_PENDING 8
+
+struct Pending {
+  bool used;
+  uint8_t len;
+  uint8_t frame[RH_RF95_MAX_MESSAGE_LEN];
+};
+
+Pending pending[MAX_PENDING];
+
+// The gauge heard from last, and whether it has been sent what is held for it.
+uint8_t last_device_id[DEVICE_ID_LEN];
+unsigned long last_heard_at = 0;
+bool answered = true;
+
+// A frame coming in from the downlink-processor, framed the same way as ours going out: XXXX, the length, the frame.
+uint8_t serial_xs = 0;
+int serial_len = -1;
+uint8_t serial_read = 0;
+uint8_t serial_frame[RH_RF95_MAX_MESSAGE_LEN];
+
 void setup()
 {
   digitalWrite(LED, HIGH);
@@ -66,8 +103,92 @@ void setup()
   digitalWrite(LED, LOW);
 }
 
+bool fromGauge(uint8_t *frame, uint8_t len)
+{
+  return len >= DEVICE_ID_OFFSET + DEVICE_ID_LEN && frame[0] == MAGIC_0 && frame[1] == MAGIC_1;
+}
+
+bool isFor(Pending *held, uint8_t *device_id)
+{
+  return held->used && memcmp(held->frame + DEVICE_ID_OFFSET, device_id, DEVICE_ID_LEN) == 0;
+}
+
+Pending *pendingFor(uint8_t *device_id, uint8_t kind)
+{
+  for (int i = 0; i < MAX_PENDING; i++) {
+    if (isFor(&pending[i], device_id) && pending[i].frame[KIND_OFFSET] == kind) {
+      return &pending[i];
+    }
+  }
+  return NULL;
+}
+
+// Hold a frame for its gauge, replacing any of the same kind already held for it.  When every slot is taken the
+// first goes.
+void hold(uint8_t *frame, uint8_t len)
+{
+  if (!fromGauge(frame, len)) {
+    return;
+  }
+
+  Pending *slot = pendingFor(frame + DEVICE_ID_OFFSET, frame[KIND_OFFSET]);
+  for (int i = 0; slot == NULL && i < MAX_PENDING; i++) {
+    if (!pending[i].used) {
+      slot = &pending[i];
+    }
+  }
+  if (slot == NULL) {
+    slot = &pending[0];
+  }
+
+  slot->used = true;
+  slot->len = len;
+  memcpy(slot->frame, frame, len);
+}
+
+void readSerial()
+{
+  while (Serial.available() > 0) {
+    uint8_t byte = Serial.read();
+
+    if (serial_xs < 4) {
+      serial_xs = byte == 'X' ? serial_xs + 1 : 0;
+    } else if (serial_len < 0) {
+      if (byte == 0 || byte > RH_RF95_MAX_MESSAGE_LEN) {
+        serial_xs = 0;
+      } else {
+        serial_len = byte;
+        serial_read = 0;
+      }
+    } else {
+      serial_frame[serial_read++] = byte;
+      if (serial_read == serial_len) {
+        hold(serial_frame, serial_len);
+        serial_xs = 0;
+        serial_len = -1;
+      }
+    }
+  }
+}
+
 void loop()
 {
+  readSerial();
+
+  // Settings are sent every time the gauge listens, until it reports it has them.
+  if (!answered && millis() - last_heard_at >= QUIET_MS) {
+    answered = true;
+    for (int i = 0; i < MAX_PENDING; i++) {
+      if (isFor(&pending[i], last_device_id)) {
+        rf95.send(pending[i].frame, pending[i].len);
+        rf95.waitPacketSent();
+        if (pending[i].frame[KIND_OFFSET] == ACK_MAGIC) {
+          pending[i].used = false;
+        }
+      }
+    }
+  }
+
   if (rf95.available())
   {
     // Should be a message for us now
@@ -82,6 +203,18 @@ void loop()
     if (rf95.recv(buf, &len))
     {
       digitalWrite(LED, HIGH);
+
+      if (fromGauge(buf, len)) {
+        memcpy(last_device_id, buf + DEVICE_ID_OFFSET, DEVICE_ID_LEN);
+        last_heard_at = millis();
+        answered = false;
+
+        Pending *held = pendingFor(last_device_id, CONFIG_MAGIC);
+        if (held != NULL && buf[KIND_OFFSET] == CONFIG_REPORT_MAGIC) {
+          held->used = false;
+        }
+      }
+
       Serial.write(88);
       Serial.write(88);
       Serial.write(88);
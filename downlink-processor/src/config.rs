@@ -0,0 +1,75 @@
+use std::path::Path;
+
+use dotenv::var;
+use rainguage_config::Kind;
+
+// Read when --config is not given, it is fine for it not to exist.
+const DEFAULT_FILE:&str = "downlink-processor.toml";
+
+// See config.example.toml for what these do.
+const SETTINGS:[(&str, Kind); 6] = [
+    ("SERIAL_PORT", Kind::Required),
+    ("HTTP_UPLINK_URL", Kind::Url),
+    ("HTTP_UPLINK_TIPS_URL", Kind::OptionalUrl),
+    ("HTTP_UPLINK_POWER_URL", Kind::OptionalUrl),
+    ("HTTP_UPLINK_API_KEY", Kind::Secret),
+    ("DEAD_LETTER_FILE", Kind::Text)
+];
+
+/// Every problem with the settings, looking each one up with `lookup`.
+fn check(lookup:&dyn Fn(&str) -> Option<String>) -> Vec<String> {
+    rainguage_config::check(&SETTINGS, lookup)
+}
+
+/// Load the config file into the environment, apply the `name=value` overrides from the command line and check the
+/// result.  The environment wins over the file and the overrides win over both.  Returns every problem found.
+pub fn load(path:Option<&Path>, overrides:&[String]) -> Vec<String> {
+    let mut problems = rainguage_config::load(&SETTINGS, DEFAULT_FILE, path, overrides);
+    problems.extend(check(&|name| var(name).ok()));
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn lookup(settings:&[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let map:HashMap<String, String> = settings.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| map.get(name).cloned()
+    }
+
+    const REQUIRED:[(&str, &str); 2] = [("SERIAL_PORT", "/dev/ttyACM0"), ("HTTP_UPLINK_URL", "http://rain/telemetry")];
+
+    #[test]
+    fn urls_must_be_http() {
+        assert_eq!(vec![
+            "SERIAL_PORT is not set".to_string(),
+            "HTTP_UPLINK_URL is not set".to_string()
+        ], check(&lookup(&[])));
+        assert!(check(&lookup(&REQUIRED)).is_empty());
+
+        let mut settings = REQUIRED.to_vec();
+        settings[1] = ("HTTP_UPLINK_URL", "rain/telemetry");
+        settings.push(("HTTP_UPLINK_TIPS_URL", "ftp://rain/tips"));
+        settings.push(("HTTP_UPLINK_POWER_URL", "https://rain/power"));
+        assert_eq!(vec![
+            "HTTP_UPLINK_URL must be an http or https url, not \"rain/telemetry\"".to_string(),
+            "HTTP_UPLINK_TIPS_URL must be an http or https url, not \"ftp://rain/tips\"".to_string()
+        ], check(&lookup(&settings)));
+    }
+
+    #[test]
+    fn api_keys_cannot_be_empty() {
+        let mut settings = REQUIRED.to_vec();
+        settings.push(("HTTP_UPLINK_API_KEY", ""));
+        assert_eq!(vec!["HTTP_UPLINK_API_KEY is empty, leave it out instead".to_string()],
+            check(&lookup(&settings)));
+
+        settings[2] = ("HTTP_UPLINK_API_KEY", "rgk_0123");
+        assert!(check(&lookup(&settings)).is_empty());
+    }
+}
@@ -1,30 +1,105 @@
 use dotenv::dotenv;
 use dotenv::var;
 
-use reqwest::blocking::Client;
+use chrono::Utc;
+use rainguage_messages::AckPacket;
+use rainguage_messages::ConfigPacket;
+use rainguage_messages::DeserializeError;
+use rainguage_messages::Message;
+use rainguage_uplink::Uplink;
+use rainguage_uplink::Urls;
 use std::fs::File;
+use std::fs::OpenOptions;
+use std::path::PathBuf;
 
 use std::io::Read;
+use std::io::Write;
+
+use structopt::StructOpt;
 
 #[macro_use]
 extern crate log;
 
+mod config;
+
+#[derive(StructOpt)]
+#[structopt(about = "Reads telemetry from the downlink's serial port and posts it to the telemetry service")]
+struct Options {
+    /// A toml config file, by default downlink-processor.toml when there is one
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Change a setting, as name=value with the name from the config file or environment (serial.port=/dev/ttyACM0
+    /// or SERIAL_PORT=/dev/ttyACM0).  Can be given more than once and wins over both
+    #[structopt(long = "set", number_of_values = 1)]
+    overrides: Vec<String>,
+    /// Report every problem with the settings and exit
+    #[structopt(long)]
+    check_config: bool,
+    /// Send settings to the gauge with this device id, as 32 hex digits, and exit.  The downlink holds them until the
+    /// gauge next transmits
+    #[structopt(long)]
+    configure: Option<String>,
+    /// With --configure, how often the gauge sends telemetry, in seconds
+    #[structopt(long)]
+    interval: Option<u32>,
+    /// With --configure, the gauge's frequency in MHz.  The downlink stays on its own, so change it to match
+    #[structopt(long)]
+    frequency: Option<u32>,
+    /// With --configure, the gauge's transmit power in dBm
+    #[structopt(long)]
+    tx_power: Option<u8>,
+    /// With --configure, added to the gauge's temperature readings, in °C
+    #[structopt(long, allow_hyphen_values = true)]
+    temperature_offset: Option<f32>,
+    /// With --configure, added to the gauge's relative humidity readings, in percent
+    #[structopt(long, allow_hyphen_values = true)]
+    humidity_offset: Option<f32>
+}
+
 fn main() {
+    let options = Options::from_args();
+
     simplelog::SimpleLogger::init(simplelog::LevelFilter::Info, simplelog::Config::default()).unwrap();
     dotenv().ok();
-    
+
+    let problems = config::load(options.config.as_deref(), &options.overrides);
+    rainguage_config::exit_on_problems(&problems, options.check_config);
+
+    // config::load has made sure these are there.
     let file_name = &var("SERIAL_PORT").unwrap();
-    let url =  &var("HTTP_UPLINK_URL").unwrap();
+
+    if let Some(device_id) = &options.configure {
+        match configure(file_name, device_id, &options) {
+            Ok(_) => info!("Sent to the downlink, look for the gauge's config report once it next transmits"),
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut urls = Urls::new(&var("HTTP_UPLINK_URL").unwrap());
+    if let Ok(tips_url) = var("HTTP_UPLINK_TIPS_URL") {
+        urls.tips = tips_url;
+    }
+    if let Ok(power_url) = var("HTTP_UPLINK_POWER_URL") {
+        urls.power = power_url;
+    }
+    let api_key = var("HTTP_UPLINK_API_KEY").ok().filter(|key| !key.is_empty());
+    let dead_letter_file = var("DEAD_LETTER_FILE").unwrap_or_else(|_| "dead-letter.ndjson".to_string());
+
+    // Kept through reopening the port, so the gauges are not sent readings they already have acks for again.
+    let mut uplink = Uplink::new(urls, api_key, &dead_letter_file);
 
     loop {
         info!("Opening {}.  Hopefully you remembered to put it into raw mode.", file_name);
-        let file = File::open(file_name).unwrap();
+        // Written to for the acks.
+        let file = OpenOptions::new().read(true).write(true).open(file_name).unwrap();
 
         info!("starting loop");
 
-        let client = reqwest::blocking::Client::new();
-
-        match process(&client, url, &file) {
+        match process(&mut uplink, &file) {
             Err(err) => {
                 error!("Handled error, resetting:{:?}", err);
             },
@@ -37,7 +112,8 @@ fn main() {
 }
 #[derive(Debug)]
 enum ProcessError {
-    CorruptTelemetry(rainguage_messages::DeserializeError),
+    // Boxed as it holds a whole frame.
+    CorruptTelemetry(Box<rainguage_messages::DeserializeError>),
     IOError,
     HttpError(reqwest::Error)
 }
@@ -57,25 +133,35 @@ impl From<reqwest::Error> for ProcessError {
 
 impl From<rainguage_messages::DeserializeError> for ProcessError {
     fn from(err: rainguage_messages::DeserializeError) -> Self {
-        ProcessError::CorruptTelemetry(err)
+        ProcessError::CorruptTelemetry(Box::new(err))
     }
 }
-fn process(client: &Client, url:&str, file:&File) -> Result<(),ProcessError> {    
+
+fn process(uplink:&mut Uplink, file:&File) -> Result<(),ProcessError> {
     let bytes_iter = file.bytes()
         .map(|r| r.unwrap());
-    let packet_iter = rainguage_messages::PacketIterator::new(bytes_iter);
-
-    for packet in packet_iter {
-        info!("received:{:?}, posting to {}", packet, url);
-        match packet {
-            Ok(packet) => {
-
-                let res = client.post(url)
-                    .json(&packet)
-                    .send()?;
-                
-                    info!("response: {}", res.status());
-                
+    let message_iter = rainguage_messages::MessageIterator::new(bytes_iter);
+
+    for message in message_iter {
+        // The service dates packets by when we heard them, not when they reach it.
+        let received_at = Utc::now();
+
+        match message {
+            // The gauge took the settings from --configure.
+            Ok(Message::ConfigReport(config)) => {
+                info!("{} has settings {:?}", hex(&config.device_id), config);
+            },
+            Ok(message) => {
+                uplink.send(received_at, &message)?;
+                match message {
+                    Message::Telemetry(packet) => acknowledge(file, packet.device_id, uplink)?,
+                    Message::History(history) => acknowledge(file, history.device_id, uplink)?,
+                    _ => {}
+                }
+            },
+            // Most likely a gauge running newer firmware than this, see the README for the order to upgrade in.
+            Err(DeserializeError::UnknownMagic(magic)) => {
+                warn!("Skipping a frame with unknown magic {}, is the downlink-processor older than the gauge?", magic)
             },
             Err(err) => {
                 error!("Error receiving packet: {:?}", err)
@@ -84,4 +170,59 @@ fn process(client: &Client, url:&str, file:&File) -> Result<(),ProcessError> {
     }
 
    Ok(())
-}
\ No newline at end of file
+}
+
+// Tell the gauge which of its readings the service has, so it stops sending them.  The downlink holds the ack until
+// the gauge next transmits.
+fn acknowledge(mut file:&File, device_id:[u8; 16], uplink:&Uplink) -> Result<(),ProcessError> {
+    if let Some(sequence) = uplink.acked(&device_id) {
+        let mut frame = [0u8; 255];
+        // An ack always fits.
+        let len = rainguage_messages::serialize_ack(&AckPacket { device_id, sequence }, &mut frame).unwrap();
+        to_downlink(&mut file, &frame[..len])?;
+    }
+    Ok(())
+}
+
+// The downlink takes the same framing it writes, XXXX, the length, then the frame, and sends the frame to the gauge
+// whose device id it has after the gauge next transmits.
+fn to_downlink(port:&mut impl Write, frame:&[u8]) -> std::io::Result<()> {
+    let mut bytes = b"XXXX".to_vec();
+    bytes.push(frame.len() as u8);
+    bytes.extend_from_slice(frame);
+    port.write_all(&bytes)
+}
+
+fn configure(file_name:&str, device_id:&str, options:&Options) -> Result<(), String> {
+    let mut config = ConfigPacket::new();
+    config.device_id = parse_device_id(device_id)?;
+    config.transmit_interval_secs = options.interval;
+    config.frequency_mhz = options.frequency;
+    config.tx_power_dbm = options.tx_power;
+    config.temperature_offset = options.temperature_offset;
+    config.humidity_offset = options.humidity_offset;
+
+    let mut frame = [0u8; 255];
+    let len = rainguage_messages::serialize_config(&config, &mut frame).map_err(|err| format!("{:?}", err))?;
+
+    let mut port = OpenOptions::new().write(true).open(file_name)
+        .map_err(|err| format!("Could not open {}: {}", file_name, err))?;
+    to_downlink(&mut port, &frame[..len]).map_err(|err| format!("Could not write to {}: {}", file_name, err))
+}
+
+fn parse_device_id(text:&str) -> Result<[u8; 16], String> {
+    let problem = || format!("{} is not a device id, which is 32 hex digits", text);
+    if text.len() != 32 || !text.is_ascii() {
+        return Err(problem());
+    }
+
+    let mut device_id = [0u8; 16];
+    for (i, byte) in device_id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).map_err(|_| problem())?;
+    }
+    Ok(device_id)
+}
+
+fn hex(bytes:&[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,311 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use dotenv::var;
+
+#[macro_use]
+extern crate log;
+
+/// What a setting has to be.  Only `Required` and `Url` have to be set, the rest are checked when they are.
+#[derive(Clone, Copy)]
+pub enum Kind {
+    /// Has to be set, there is no sensible default.
+    Required,
+    Text,
+    /// A key or password, which cannot be empty when it is set.
+    Secret,
+    /// true or false.
+    Bool,
+    /// A whole number no smaller than this.
+    Integer(i64),
+    /// A number above zero.
+    Positive,
+    Port,
+    /// Has to be set to an http or https url.
+    Url,
+    /// An http or https url when it is set.
+    OptionalUrl
+}
+
+/// The environment variable for a key in the config file or a --set, `postgres.port` is POSTGRES_PORT.
+fn env_name(key:&str) -> String {
+    key.replace('.', "_").to_uppercase()
+}
+
+fn is_setting(settings:&[(&str, Kind)], name:&str) -> bool {
+    settings.iter().any(|(setting, _)| *setting == name)
+}
+
+fn flatten(settings:&[(&str, Kind)], prefix:&str, table:&toml::value::Table, values:&mut Vec<(String, String)>,
+        problems:&mut Vec<String>) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+
+        let text = match value {
+            toml::Value::Table(table) => {
+                flatten(settings, &key, table, values, problems);
+                continue;
+            },
+            toml::Value::String(text) => text.clone(),
+            toml::Value::Integer(number) => number.to_string(),
+            toml::Value::Float(number) => number.to_string(),
+            toml::Value::Boolean(flag) => flag.to_string(),
+            // For settings that are a list, like the service's alert sinks.
+            toml::Value::Array(items) if items.iter().all(|item| item.is_str()) => items.iter()
+                .filter_map(|item| item.as_str())
+                .collect::<Vec<_>>()
+                .join(","),
+            _ => {
+                problems.push(format!("{} must be a string, number, boolean or list of strings", key));
+                continue;
+            }
+        };
+
+        let name = env_name(&key);
+        if is_setting(settings, &name) {
+            values.push((name, text));
+        } else {
+            problems.push(format!("{} is not a setting", key));
+        }
+    }
+}
+
+/// The settings in a config file, as environment variables and their values.
+fn from_toml(settings:&[(&str, Kind)], text:&str, problems:&mut Vec<String>) -> Vec<(String, String)> {
+    let mut values = vec![];
+    match text.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => flatten(settings, "", &table, &mut values, problems),
+        Ok(_) => problems.push("the config file must be a table".to_string()),
+        Err(err) => problems.push(format!("the config file is not valid toml: {}", err))
+    }
+    values
+}
+
+/// What to set in the environment for the settings from the file and the `name=value` overrides, given what is set
+/// already.  The environment wins over the file and the overrides win over both.
+fn to_set(settings:&[(&str, Kind)], file:Vec<(String, String)>, overrides:&[String],
+        lookup:&dyn Fn(&str) -> Option<String>, problems:&mut Vec<String>) -> Vec<(String, String)> {
+    let mut values = file.into_iter()
+        .filter(|(name, _)| lookup(name).is_none())
+        .collect::<Vec<_>>();
+
+    for setting in overrides {
+        match setting.find('=') {
+            Some(index) if is_setting(settings, &env_name(&setting[..index])) => {
+                values.push((env_name(&setting[..index]), setting[index + 1..].to_string()));
+            },
+            Some(index) => problems.push(format!("{} is not a setting", &setting[..index])),
+            None => problems.push(format!("--set {} should be name=value", setting))
+        }
+    }
+
+    values
+}
+
+fn check_value(name:&str, kind:Kind, value:&str) -> Option<String> {
+    let valid = match kind {
+        Kind::Required | Kind::Text => true,
+        Kind::Secret => !value.is_empty(),
+        Kind::Bool => value == "true" || value == "false",
+        Kind::Integer(min) => value.parse::<i64>().map(|number| number >= min).unwrap_or(false),
+        Kind::Positive => value.parse::<f64>().map(|number| number > 0.0 && number.is_finite()).unwrap_or(false),
+        Kind::Port => value.parse::<u16>().map(|port| port > 0).unwrap_or(false),
+        Kind::Url | Kind::OptionalUrl => value.starts_with("http://") || value.starts_with("https://")
+    };
+
+    if valid {
+        return None;
+    }
+
+    let expected = match kind {
+        Kind::Secret => return Some(format!("{} is empty, leave it out instead", name)),
+        Kind::Bool => "true or false".to_string(),
+        Kind::Integer(min) => format!("a whole number of at least {}", min),
+        Kind::Positive => "a number above zero".to_string(),
+        Kind::Url | Kind::OptionalUrl => "an http or https url".to_string(),
+        _ => "a port number".to_string()
+    };
+    Some(format!("{} must be {}, not {:?}", name, expected, value))
+}
+
+/// Every problem with the settings, looking each one up with `lookup`.
+pub fn check(settings:&[(&str, Kind)], lookup:&dyn Fn(&str) -> Option<String>) -> Vec<String> {
+    let mut problems = vec![];
+
+    for (name, kind) in settings.iter() {
+        match (lookup(name), kind) {
+            (Some(value), _) => problems.extend(check_value(name, *kind, &value)),
+            (None, Kind::Required) | (None, Kind::Url) => problems.push(format!("{} is not set", name)),
+            (None, _) => {}
+        }
+    }
+
+    problems
+}
+
+/// Load the config file into the environment and apply the `name=value` overrides from the command line.  The file is
+/// `default_file` when `path` is not given, and it is fine for that not to exist.  Returns the problems with the file
+/// and overrides, `check` the settings once they are loaded.
+pub fn load(settings:&[(&str, Kind)], default_file:&str, path:Option<&Path>, overrides:&[String]) -> Vec<String> {
+    let mut problems = vec![];
+
+    let file = match path {
+        Some(path) => Some(path),
+        None => Some(Path::new(default_file)).filter(|path| path.exists())
+    };
+
+    let values = match file.map(|file| (file, fs::read_to_string(file))) {
+        Some((_, Ok(text))) => from_toml(settings, &text, &mut problems),
+        Some((file, Err(err))) => {
+            problems.push(format!("could not read {}: {}", file.display(), err));
+            vec![]
+        },
+        None => vec![]
+    };
+
+    for (name, value) in to_set(settings, values, overrides, &|name| var(name).ok(), &mut problems) {
+        env::set_var(name, value);
+    }
+
+    problems
+}
+
+/// With --check-config, print the problems and exit, failing if there are any.  Otherwise exit when there are any,
+/// nothing should start until the settings are fixed.
+pub fn exit_on_problems(problems:&[String], check_config:bool) {
+    if check_config {
+        for problem in problems.iter() {
+            eprintln!("{}", problem);
+        }
+        if !problems.is_empty() {
+            std::process::exit(1);
+        }
+        eprintln!("The settings are fine");
+        std::process::exit(0);
+    }
+
+    if !problems.is_empty() {
+        for problem in problems.iter() {
+            error!("{}", problem);
+        }
+        error!("Not starting until the settings are fixed");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const SETTINGS:[(&str, Kind); 8] = [
+        ("POSTGRES_HOST", Kind::Required),
+        ("POSTGRES_PORT", Kind::Port),
+        ("AUTH_DISABLED", Kind::Bool),
+        ("RETENTION_5M_DAYS", Kind::Integer(0)),
+        ("RAINFALL_MM_PER_TIP", Kind::Positive),
+        ("ALERT_SINKS", Kind::Text),
+        ("HTTP_UPLINK_URL", Kind::Url),
+        ("HTTP_UPLINK_API_KEY", Kind::Secret)
+    ];
+
+    fn lookup(settings:&[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let map:HashMap<String, String> = settings.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| map.get(name).cloned()
+    }
+
+    #[test]
+    fn tables_become_environment_variables() {
+        let mut problems = vec![];
+        let values = from_toml(&SETTINGS, "
+            [postgres]
+            host = \"db\"
+            port = 5433
+
+            [retention]
+            5m_days = 365
+
+            [alert]
+            sinks = [\"log\", \"webhook\"]
+
+            [http_uplink]
+            api_key = \"secret\"
+        ", &mut problems);
+
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(vec![
+            ("ALERT_SINKS".to_string(), "log,webhook".to_string()),
+            ("HTTP_UPLINK_API_KEY".to_string(), "secret".to_string()),
+            ("POSTGRES_HOST".to_string(), "db".to_string()),
+            ("POSTGRES_PORT".to_string(), "5433".to_string()),
+            ("RETENTION_5M_DAYS".to_string(), "365".to_string())
+        ], values);
+    }
+
+    #[test]
+    fn unknown_keys_are_problems() {
+        let mut problems = vec![];
+        from_toml(&SETTINGS, "[postgres]\nhots = \"db\"\nport = [1, 2]", &mut problems);
+        assert_eq!(vec![
+            "postgres.hots is not a setting".to_string(),
+            "postgres.port must be a string, number, boolean or list of strings".to_string()
+        ], problems);
+
+        let mut problems = vec![];
+        from_toml(&SETTINGS, "[postgres", &mut problems);
+        assert_eq!(1, problems.len());
+    }
+
+    #[test]
+    fn environment_wins_over_the_file_and_set_over_both() {
+        let file = vec![
+            ("POSTGRES_HOST".to_string(), "file".to_string()),
+            ("HTTP_UPLINK_URL".to_string(), "http://file/telemetry".to_string()),
+            ("HTTP_UPLINK_API_KEY".to_string(), "from-file".to_string())
+        ];
+        let environment = lookup(&[("HTTP_UPLINK_URL", "http://env/telemetry"), ("HTTP_UPLINK_API_KEY", "from-env")]);
+        let overrides = ["http_uplink.api_key=from-set", "serial.baud=9600", "port"].iter()
+            .map(|setting| setting.to_string())
+            .collect::<Vec<String>>();
+
+        let mut problems = vec![];
+        assert_eq!(vec![
+            ("POSTGRES_HOST".to_string(), "file".to_string()),
+            ("HTTP_UPLINK_API_KEY".to_string(), "from-set".to_string())
+        ], to_set(&SETTINGS, file, &overrides, &environment, &mut problems));
+        assert_eq!(vec![
+            "serial.baud is not a setting".to_string(),
+            "--set port should be name=value".to_string()
+        ], problems);
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        assert_eq!(vec![
+            "POSTGRES_HOST is not set".to_string(),
+            "HTTP_UPLINK_URL is not set".to_string()
+        ], check(&SETTINGS, &lookup(&[])));
+
+        assert_eq!(vec![
+            "POSTGRES_PORT must be a port number, not \"five\"".to_string(),
+            "AUTH_DISABLED must be true or false, not \"yes\"".to_string(),
+            "RETENTION_5M_DAYS must be a whole number of at least 0, not \"-1\"".to_string(),
+            "RAINFALL_MM_PER_TIP must be a number above zero, not \"0\"".to_string(),
+            "HTTP_UPLINK_URL must be an http or https url, not \"rain/telemetry\"".to_string(),
+            "HTTP_UPLINK_API_KEY is empty, leave it out instead".to_string()
+        ], check(&SETTINGS, &lookup(&[
+            ("POSTGRES_HOST", "db"),
+            ("POSTGRES_PORT", "five"),
+            ("AUTH_DISABLED", "yes"),
+            ("RETENTION_5M_DAYS", "-1"),
+            ("RAINFALL_MM_PER_TIP", "0"),
+            ("ALERT_SINKS", "anything"),
+            ("HTTP_UPLINK_URL", "rain/telemetry"),
+            ("HTTP_UPLINK_API_KEY", "")
+        ])));
+    }
+}
@@ -0,0 +1,75 @@
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use structopt::StructOpt;
+
+mod port;
+
+use port::{Outcome, Port};
+
+#[derive(StructOpt)]
+#[structopt(about = "Talks to a rainguage's console over its usb serial port")]
+struct Options {
+    /// The gauge's usb serial port
+    #[structopt(long, short = "p", default_value = "/dev/ttyACM0", parse(from_os_str))]
+    port: PathBuf,
+    /// How long to wait for the gauge to answer, in seconds
+    #[structopt(long, default_value = "5")]
+    timeout: u64,
+    /// A command to run, such as `status` or `set interval 300`.  Without one, commands are read from stdin until it
+    /// ends, try `help`
+    command: Vec<String>
+}
+
+fn main() {
+    let options = Options::from_args();
+    let timeout = Duration::from_secs(options.timeout);
+
+    let mut port = match Port::open(&options.port) {
+        Ok(port) => port,
+        Err(err) => {
+            eprintln!("Could not open {}: {}", options.port.display(), err);
+            std::process::exit(1);
+        }
+    };
+
+    if !options.command.is_empty() {
+        if !run(&mut port, &options.command.join(" "), timeout) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let stdin = io::stdin();
+    prompt();
+    for line in stdin.lock().lines() {
+        let line = line.unwrap();
+        if !line.trim().is_empty() {
+            run(&mut port, &line, timeout);
+        }
+        prompt();
+    }
+}
+
+// Prints the gauge's answer, returning whether it was ok.
+fn run(port: &mut Port, line: &str, timeout: Duration) -> bool {
+    match port.command(line, timeout, |line| println!("{}", line)) {
+        Ok(Outcome::Ok) => true,
+        Ok(Outcome::Error(message)) => {
+            eprintln!("error: {}", message);
+            false
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            false
+        }
+    }
+}
+
+fn prompt() {
+    print!("> ");
+    io::stdout().flush().unwrap();
+}
@@ -0,0 +1,122 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How the gauge finished its answer.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Ok,
+    /// What followed `error: `.
+    Error(String)
+}
+
+/// The gauge's usb serial port.
+pub struct Port {
+    file: File
+}
+
+impl Port {
+    /// Opens the port in raw mode, with reads giving up after a tenth of a second so a quiet gauge can be noticed.
+    pub fn open(path: &Path) -> io::Result<Port> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let fd = file.as_raw_fd();
+
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(fd, &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            termios.c_cc[libc::VMIN] = 0;
+            termios.c_cc[libc::VTIME] = 1;
+            if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(Port {
+            file
+        })
+    }
+
+    /// Send one command, dropping anything the gauge wrote before it so the answer is not mixed up with it.  Each line
+    /// of the answer is passed to `on_line`.
+    pub fn command(&mut self, line: &str, timeout: Duration, on_line: impl FnMut(&str)) -> io::Result<Outcome> {
+        unsafe {
+            libc::tcflush(self.file.as_raw_fd(), libc::TCIFLUSH);
+        }
+
+        self.file.write_all(line.trim().as_bytes())?;
+        self.file.write_all(b"\r\n")?;
+        self.file.flush()?;
+
+        answer(&mut self.file, timeout, on_line)
+    }
+}
+
+/// Read lines until the `ok` or `error: ...` that ends every answer.  Gives up once nothing has come for `timeout`.
+pub fn answer(reader: &mut impl Read, timeout: Duration, mut on_line: impl FnMut(&str)) -> io::Result<Outcome> {
+    let mut line = vec![];
+    let mut last_read = Instant::now();
+    let mut buffer = [0; 256];
+
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            if last_read.elapsed() > timeout {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "the gauge did not answer"));
+            }
+            continue;
+        }
+        last_read = Instant::now();
+
+        for byte in buffer[..count].iter() {
+            if *byte != b'\n' {
+                line.push(*byte);
+                continue;
+            }
+
+            let text = String::from_utf8_lossy(&line).trim_end_matches('\r').to_string();
+            line.clear();
+            if text == "ok" {
+                return Ok(Outcome::Ok);
+            }
+            if let Some(message) = text.strip_prefix("error: ") {
+                return Ok(Outcome::Error(message.to_string()));
+            }
+            on_line(&text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &[u8]) -> (io::Result<Outcome>, Vec<String>) {
+        let mut lines = vec![];
+        let outcome = answer(&mut &input[..], Duration::from_millis(10), |line| lines.push(line.to_string()));
+        (outcome, lines)
+    }
+
+    #[test]
+    fn answers() {
+        let (outcome, lines) = read(b"temperature=21.5 relative_humidity=40 vbat=2606\r\nok\r\nstale");
+        assert_eq!(Outcome::Ok, outcome.unwrap());
+        assert_eq!(vec!["temperature=21.5 relative_humidity=40 vbat=2606"], lines);
+
+        let (outcome, lines) = read(b"error: unknown command, try help\r\n");
+        assert_eq!(Outcome::Error("unknown command, try help".to_string()), outcome.unwrap());
+        assert!(lines.is_empty());
+
+        let (outcome, lines) = read(b"loop_cnt=1\r\n");
+        assert_eq!(io::ErrorKind::TimedOut, outcome.unwrap_err().kind());
+        assert_eq!(vec!["loop_cnt=1"], lines);
+    }
+}
@@ -0,0 +1,90 @@
+// The RTC counts the 32.768kHz crystal, see hardware::Clock.
+pub const TICKS_PER_SECOND: u32 = 32768;
+
+/// Time since power on, from the RTC count.  The count wraps every 36 hours, so `update` has to see it at least that
+/// often to keep up.
+pub struct Uptime {
+    ticks: u64,
+    last: u32
+}
+
+impl Uptime {
+    pub const fn new(count: u32) -> Uptime {
+        Uptime {
+            ticks: count as u64,
+            last: count
+        }
+    }
+
+    /// Catch up with the RTC count, returning the milliseconds since power on.
+    pub fn update(&mut self, count: u32) -> u64 {
+        self.ticks = self.ticks + count.wrapping_sub(self.last) as u64;
+        self.last = count;
+        self.millis()
+    }
+
+    pub fn millis(&self) -> u64 {
+        self.ticks * 1000 / TICKS_PER_SECOND as u64
+    }
+
+    pub fn secs(&self) -> u32 {
+        (self.ticks / TICKS_PER_SECOND as u64) as u32
+    }
+}
+
+/// A task that runs every so many milliseconds.  It stays on the same schedule however long each run takes, and
+/// runs once rather than catching up when it has fallen behind.
+pub struct Every {
+    interval_ms: u64,
+    next_ms: u64
+}
+
+impl Every {
+    /// The first run is due straight away.
+    pub const fn new(interval_ms: u64) -> Every {
+        Every {
+            interval_ms,
+            next_ms: 0
+        }
+    }
+
+    pub fn due(&mut self, now_ms: u64) -> bool {
+        if now_ms < self.next_ms {
+            return false;
+        }
+
+        let missed = (now_ms - self.next_ms) / self.interval_ms;
+        self.next_ms = self.next_ms + (missed + 1) * self.interval_ms;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_counting_past_the_wrap() {
+        let mut uptime = Uptime::new(u32::MAX - TICKS_PER_SECOND + 1);
+        assert_eq!(131071, uptime.secs());
+
+        assert_eq!(131_074_000, uptime.update(2 * TICKS_PER_SECOND));
+        assert_eq!(131074, uptime.secs());
+
+        assert_eq!(131_074_500, uptime.update(2 * TICKS_PER_SECOND + TICKS_PER_SECOND / 2));
+    }
+
+    #[test]
+    fn every_interval() {
+        let mut every = Every::new(60_000);
+        assert!(every.due(100));
+        assert!(!every.due(59_999));
+        assert!(every.due(60_300));
+        assert!(!every.due(60_400));
+
+        // A long stall runs once and then picks up the schedule again.
+        assert!(every.due(250_000));
+        assert!(!every.due(299_999));
+        assert!(every.due(300_000));
+    }
+}
@@ -0,0 +1,244 @@
+use core::fmt;
+
+// The longest line the console keeps, anything longer is thrown away.
+pub const MAX_LINE: usize = 64;
+
+// The longest name a gauge can be given.
+pub const MAX_NAME: usize = 16;
+
+// What `set` accepts.  The rfm95 is the 868/915MHz module, and the sx127x's PA_BOOST pin gives 2 to 20dBm.
+pub const INTERVAL_SECS: (u32, u32) = (10, 24 * 60 * 60);
+pub const FREQUENCY_MHZ: (u32, u32) = (862, 1020);
+pub const TX_POWER_DBM: (u8, u8) = (2, 20);
+pub const TEMPERATURE_OFFSET: (f32, f32) = (-10.0, 10.0);
+pub const HUMIDITY_OFFSET: (f32, f32) = (-20.0, 20.0);
+
+/// Something asked of the gauge over its console, see Gauge::handle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    /// List the commands.
+    Help,
+    /// Report the loop count, uptime, settings and counters.
+    Status,
+    /// Report the last sensor and battery readings.
+    Readings,
+    /// Print the readings kept since power on, oldest first.
+    Dump,
+    /// Send telemetry on the next pass rather than waiting for the interval.
+    Transmit,
+    /// Restart into the bootloader, ready for new firmware.
+    Bootloader,
+    Set(Setting),
+    /// Go back to the default settings.
+    Defaults
+}
+
+/// A setting changed with `set <name> <value>`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Setting {
+    /// How often telemetry is sent, in seconds.
+    Interval(u32),
+    FrequencyMhz(u32),
+    TxPowerDbm(u8),
+    Name(Name),
+    /// Added to the sensor's temperature, in °C.
+    TemperatureOffset(f32),
+    /// Added to the sensor's relative humidity, in percent.
+    HumidityOffset(f32)
+}
+
+impl Setting {
+    /// Whether the value is one `set` would take, for settings that come from elsewhere.
+    pub fn valid(&self) -> bool {
+        match *self {
+            Setting::Interval(secs) => within(secs, INTERVAL_SECS),
+            Setting::FrequencyMhz(mhz) => within(mhz, FREQUENCY_MHZ),
+            Setting::TxPowerDbm(dbm) => within(dbm, TX_POWER_DBM),
+            Setting::Name(_) => true,
+            Setting::TemperatureOffset(offset) => within(offset, TEMPERATURE_OFFSET),
+            Setting::HumidityOffset(offset) => within(offset, HUMIDITY_OFFSET)
+        }
+    }
+}
+
+fn within<T: PartialOrd>(value: T, (min, max): (T, T)) -> bool {
+    value >= min && value <= max
+}
+
+/// The gauge's name, to tell gauges apart on the console.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Name {
+    bytes: [u8; MAX_NAME],
+    len: u8
+}
+
+impl Name {
+    pub fn new(name: &str) -> Option<Name> {
+        if name.is_empty() || name.len() > MAX_NAME || name.contains(char::is_whitespace) {
+            return None;
+        }
+
+        let mut bytes = [0; MAX_NAME];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Some(Name {
+            bytes,
+            len: name.len() as u8
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever made from a str, and cut on nothing but its end.
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandError {
+    Unknown,
+    TooLong,
+    /// The setting's value is missing, not a number or out of range.
+    BadValue
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Unknown => write!(f, "unknown command, try help"),
+            CommandError::TooLong => write!(f, "line longer than {} characters", MAX_LINE),
+            CommandError::BadValue => write!(f, "bad value, try help")
+        }
+    }
+}
+
+pub fn parse(line: &str) -> Result<Command, CommandError> {
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        Some("help") => Command::Help,
+        Some("status") => Command::Status,
+        Some("readings") => Command::Readings,
+        Some("dump") => Command::Dump,
+        Some("transmit") => Command::Transmit,
+        Some("bootloader") => Command::Bootloader,
+        Some("defaults") => Command::Defaults,
+        Some("set") => {
+            let name = words.next().ok_or(CommandError::Unknown)?;
+            let value = words.next().ok_or(CommandError::BadValue)?;
+            Command::Set(setting(name, value)?)
+        },
+        _ => return Err(CommandError::Unknown)
+    };
+
+    match words.next() {
+        Some(_) => Err(CommandError::BadValue),
+        None => Ok(command)
+    }
+}
+
+fn setting(name: &str, value: &str) -> Result<Setting, CommandError> {
+    let setting = match name {
+        "interval" => Setting::Interval(number(value)?),
+        "frequency" => Setting::FrequencyMhz(number(value)?),
+        "power" => Setting::TxPowerDbm(number(value)?),
+        "name" => Setting::Name(Name::new(value).ok_or(CommandError::BadValue)?),
+        "temperature-offset" => Setting::TemperatureOffset(number(value)?),
+        "humidity-offset" => Setting::HumidityOffset(number(value)?),
+        _ => return Err(CommandError::Unknown)
+    };
+
+    if setting.valid() {
+        Ok(setting)
+    } else {
+        Err(CommandError::BadValue)
+    }
+}
+
+fn number<T: core::str::FromStr>(value: &str) -> Result<T, CommandError> {
+    value.parse::<T>().map_err(|_| CommandError::BadValue)
+}
+
+/// Collects the console's bytes into lines, ended by either \r or \n.
+pub struct LineBuffer {
+    buffer: [u8; MAX_LINE],
+    len: usize,
+    too_long: bool
+}
+
+impl LineBuffer {
+    pub const fn new() -> LineBuffer {
+        LineBuffer {
+            buffer: [0; MAX_LINE],
+            len: 0,
+            too_long: false
+        }
+    }
+
+    /// Add a byte, returning the command once its line is complete.  Blank lines are skipped.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Command, CommandError>> {
+        if byte != b'\r' && byte != b'\n' {
+            if self.len < MAX_LINE {
+                self.buffer[self.len] = byte;
+                self.len = self.len + 1;
+            } else {
+                self.too_long = true;
+            }
+            return None;
+        }
+
+        let result = if self.too_long {
+            Some(Err(CommandError::TooLong))
+        } else {
+            match core::str::from_utf8(&self.buffer[..self.len]) {
+                Ok(line) if line.trim().is_empty() => None,
+                Ok(line) => Some(parse(line)),
+                Err(_) => Some(Err(CommandError::Unknown))
+            }
+        };
+
+        self.len = 0;
+        self.too_long = false;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(buffer: &mut LineBuffer, bytes: &[u8]) -> Vec<Result<Command, CommandError>> {
+        bytes.iter().filter_map(|byte| buffer.push(*byte)).collect()
+    }
+
+    #[test]
+    fn lines() {
+        let mut buffer = LineBuffer::new();
+        assert!(push(&mut buffer, b"stat").is_empty());
+        assert_eq!(vec![Ok(Command::Status), Ok(Command::Status)], push(&mut buffer, b"us\r\n\r\n  status \n"));
+        assert_eq!(vec![Err(CommandError::Unknown)], push(&mut buffer, b"reboot\n"));
+
+        let long = [b'x'; MAX_LINE + 1];
+        assert!(push(&mut buffer, &long).is_empty());
+        assert_eq!(vec![Err(CommandError::TooLong), Ok(Command::Status)], push(&mut buffer, b"\nstatus\n"));
+    }
+
+    #[test]
+    fn settings() {
+        assert_eq!(Ok(Command::Set(Setting::Interval(300))), parse("set interval 300"));
+        assert_eq!(Ok(Command::Set(Setting::FrequencyMhz(868))), parse(" set  frequency 868 "));
+        assert_eq!(Ok(Command::Set(Setting::TxPowerDbm(14))), parse("set power 14"));
+        assert_eq!(Ok(Command::Set(Setting::TemperatureOffset(-0.5))), parse("set temperature-offset -0.5"));
+        assert_eq!(Ok(Command::Defaults), parse("defaults"));
+        assert_eq!("north-field", match parse("set name north-field") {
+            Ok(Command::Set(Setting::Name(name))) => name,
+            other => panic!("{:?}", other)
+        }.as_str());
+
+        assert_eq!(Err(CommandError::BadValue), parse("set interval 5"));
+        assert_eq!(Err(CommandError::BadValue), parse("set power -3"));
+        assert_eq!(Err(CommandError::BadValue), parse("set humidity-offset 25"));
+        assert_eq!(Err(CommandError::BadValue), parse("set temperature-offset NaN"));
+        assert_eq!(Err(CommandError::BadValue), parse("set frequency"));
+        assert_eq!(Err(CommandError::BadValue), parse("set name a-name-longer-than-16"));
+        assert_eq!(Err(CommandError::BadValue), parse("status now"));
+        assert_eq!(Err(CommandError::Unknown), parse("set colour blue"));
+    }
+}
@@ -0,0 +1,331 @@
+use crc::{crc32, Hasher32};
+
+use crate::command::{Name, MAX_NAME};
+use crate::gauge::Settings;
+use crate::hardware::Storage;
+
+// Each saved copy of the settings takes a slot.
+pub const SLOT_SIZE: u32 = 64;
+
+// The store takes this many erase blocks, and fills them in turn.
+pub const BLOCKS: u32 = 2;
+
+// "RGCF", so an erased or unrelated slot is not mistaken for settings.
+const MAGIC: u32 = 0x5247_4346;
+
+// There is only the one version so far.  A later one would convert older copies as it loads them.
+pub const VERSION: u16 = 1;
+
+//   magic      4 bytes
+//   version    2 bytes
+//   len        2 bytes - length of the payload
+//   sequence   4 bytes - one more than the copy before it
+//   payload    `len` bytes - see encode
+//   checksum   4 bytes, a crc32 of everything before it
+// All little endian.
+const HEADER_LEN: usize = 12;
+const PAYLOAD_LEN: usize = 4 + 4 + 1 + 1 + MAX_NAME + 4 + 4;
+const LEN: usize = HEADER_LEN + PAYLOAD_LEN + 4;
+
+/// The settings kept in storage through resets.  Each save writes a new copy in the next slot rather than erasing the
+/// old one, so a block is only erased once every erase_size / SLOT_SIZE saves, and losing power part way through a save
+/// leaves the copy before it.  Loading takes the newest copy whose checksum is right.
+///
+/// The store takes BLOCKS erase blocks of storage from `start`, and the storage is passed to each call so the rest of
+/// it can be used for other things.
+pub struct ConfigStore {
+    start: u32,
+    // The address and sequence of the newest copy.
+    newest: Option<(u32, u32)>
+}
+
+impl ConfigStore {
+    pub const fn new(start: u32) -> ConfigStore {
+        ConfigStore {
+            start,
+            newest: None
+        }
+    }
+
+    /// How much storage it takes.
+    pub fn len<S: Storage>(storage: &S) -> u32 {
+        storage.erase_size() * BLOCKS
+    }
+
+    /// The saved settings, or None when nothing has been saved.  The settings that are not kept come from `defaults`.
+    pub fn load<S: Storage>(&mut self, storage: &mut S, defaults: &Settings) -> Result<Option<Settings>, S::Error> {
+        let end = self.start + Self::len(storage);
+        let mut newest: Option<(u32, u32, Settings)> = None;
+        let mut slot = [0; SLOT_SIZE as usize];
+
+        let mut address = self.start;
+        while address + SLOT_SIZE <= end {
+            storage.read(address, &mut slot)?;
+            if let Some((sequence, settings)) = decode(&slot, defaults) {
+                let newer = match newest {
+                    Some((_, newest_sequence, _)) => sequence.wrapping_sub(newest_sequence) as i32 > 0,
+                    None => true
+                };
+                if newer {
+                    newest = Some((address, sequence, settings));
+                }
+            }
+            address = address + SLOT_SIZE;
+        }
+
+        self.newest = newest.map(|(address, sequence, _)| (address, sequence));
+        Ok(newest.map(|(_, _, settings)| settings))
+    }
+
+    /// Save a copy in the slot after the newest, erasing the next block when the one it is in is full.  `load` first so
+    /// it knows which slot that is.
+    pub fn save<S: Storage>(&mut self, storage: &mut S, settings: &Settings) -> Result<(), S::Error> {
+        let block = storage.erase_size();
+        let end = self.start + Self::len(storage);
+
+        let (mut address, sequence) = match self.newest {
+            Some((address, sequence)) => (address + SLOT_SIZE, sequence.wrapping_add(1)),
+            None => (end, 0)
+        };
+
+        // A slot that is not blank was written when the power went, so it is skipped.
+        loop {
+            if address + SLOT_SIZE > end {
+                address = self.start;
+            }
+            if (address - self.start) % block == 0 {
+                storage.erase(address)?;
+                break;
+            }
+            if blank(storage, address)? {
+                break;
+            }
+            address = address + SLOT_SIZE;
+        }
+
+        let mut slot = [0xff; SLOT_SIZE as usize];
+        encode(settings, sequence, &mut slot);
+        storage.write(address, &slot)?;
+
+        self.newest = Some((address, sequence));
+        Ok(())
+    }
+}
+
+fn blank<S: Storage>(storage: &mut S, address: u32) -> Result<bool, S::Error> {
+    let mut slot = [0; SLOT_SIZE as usize];
+    storage.read(address, &mut slot)?;
+    Ok(slot.iter().all(|byte| *byte == 0xff))
+}
+
+fn encode(settings: &Settings, sequence: u32, slot: &mut [u8]) {
+    let name = settings.name.as_str().as_bytes();
+    let mut name_bytes = [0; MAX_NAME];
+    name_bytes[..name.len()].copy_from_slice(name);
+
+    let mut writer = Writer { buf: slot, len: 0 };
+    writer.put(&MAGIC.to_le_bytes());
+    writer.put(&VERSION.to_le_bytes());
+    writer.put(&(PAYLOAD_LEN as u16).to_le_bytes());
+    writer.put(&sequence.to_le_bytes());
+    writer.put(&((settings.transmit_interval_ms / 1000) as u32).to_le_bytes());
+    writer.put(&settings.frequency_mhz.to_le_bytes());
+    writer.put(&[settings.tx_power_dbm, name.len() as u8]);
+    writer.put(&name_bytes);
+    writer.put(&settings.temperature_offset.to_le_bytes());
+    writer.put(&settings.humidity_offset.to_le_bytes());
+
+    let checksum = checksum(&writer.buf[..writer.len]);
+    writer.put(&checksum.to_le_bytes());
+}
+
+fn decode(slot: &[u8], defaults: &Settings) -> Option<(u32, Settings)> {
+    let mut reader = Reader { buf: &slot[..LEN], len: 0 };
+    if reader.u32() != MAGIC || reader.u16() != VERSION || reader.u16() as usize != PAYLOAD_LEN {
+        return None;
+    }
+    if u32::from_le_bytes(word(&slot[LEN - 4..])) != checksum(&slot[..LEN - 4]) {
+        return None;
+    }
+
+    let sequence = reader.u32();
+    let mut settings = *defaults;
+    settings.transmit_interval_ms = reader.u32() as u64 * 1000;
+    settings.frequency_mhz = reader.u32();
+    settings.tx_power_dbm = reader.take(1)[0];
+    let name_len = (reader.take(1)[0] as usize).min(MAX_NAME);
+    let name = &reader.take(MAX_NAME)[..name_len];
+    settings.name = core::str::from_utf8(name).ok().and_then(Name::new).unwrap_or(defaults.name);
+    settings.temperature_offset = f32::from_le_bytes(word(reader.take(4)));
+    settings.humidity_offset = f32::from_le_bytes(word(reader.take(4)));
+
+    Some((sequence, settings))
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut digest = crc32::Digest::new(crc32::IEEE);
+    digest.write(bytes);
+    digest.sum32()
+}
+
+fn word(bytes: &[u8]) -> [u8; 4] {
+    [bytes[0], bytes[1], bytes[2], bytes[3]]
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize
+}
+
+impl<'a> Writer<'a> {
+    fn put(&mut self, bytes: &[u8]) {
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len = self.len + bytes.len();
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    len: usize
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        let bytes = &self.buf[self.len..self.len + len];
+        self.len = self.len + len;
+        bytes
+    }
+
+    fn u16(&mut self) -> u16 {
+        let bytes = self.take(2);
+        u16::from_le_bytes([bytes[0], bytes[1]])
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(word(self.take(4)))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Storage in RAM that acts like flash: writing can only clear bits, and only erasing sets them again.
+    pub struct TestStorage {
+        pub bytes: Vec<u8>,
+        pub erase_cnt: Vec<u32>
+    }
+
+    pub const ERASE_SIZE: u32 = 256;
+
+    impl TestStorage {
+        pub fn new(blocks: usize) -> TestStorage {
+            TestStorage {
+                bytes: vec![0; blocks * ERASE_SIZE as usize],
+                erase_cnt: vec![0; blocks]
+            }
+        }
+    }
+
+    impl Storage for TestStorage {
+        type Error = ();
+
+        fn capacity(&self) -> u32 {
+            self.bytes.len() as u32
+        }
+
+        fn erase_size(&self) -> u32 {
+            ERASE_SIZE
+        }
+
+        fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), ()> {
+            let address = address as usize;
+            buffer.copy_from_slice(self.bytes.get(address..address + buffer.len()).ok_or(())?);
+            Ok(())
+        }
+
+        fn write(&mut self, address: u32, data: &[u8]) -> Result<(), ()> {
+            let address = address as usize;
+            let bytes = self.bytes.get_mut(address..address + data.len()).ok_or(())?;
+            for (byte, new) in bytes.iter_mut().zip(data.iter()) {
+                *byte = *byte & *new;
+            }
+            Ok(())
+        }
+
+        fn erase(&mut self, address: u32) -> Result<(), ()> {
+            let block = (address / ERASE_SIZE) as usize;
+            let start = block * ERASE_SIZE as usize;
+            self.bytes.get_mut(start..start + ERASE_SIZE as usize).ok_or(())?.iter_mut().for_each(|byte| *byte = 0xff);
+            self.erase_cnt[block] = self.erase_cnt[block] + 1;
+            Ok(())
+        }
+    }
+
+    fn settings(interval_secs: u64) -> Settings {
+        Settings {
+            transmit_interval_ms: interval_secs * 1000,
+            frequency_mhz: 868,
+            tx_power_dbm: 14,
+            name: Name::new("north").unwrap(),
+            temperature_offset: -0.5,
+            humidity_offset: 2.0,
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let defaults = Settings::default();
+        let mut storage = TestStorage::new(3);
+        let mut store = ConfigStore::new(ERASE_SIZE);
+        assert_eq!(None, store.load(&mut storage, &defaults).unwrap());
+
+        store.save(&mut storage, &settings(300)).unwrap();
+        assert_eq!(Some(settings(300)), ConfigStore::new(ERASE_SIZE).load(&mut storage, &defaults).unwrap());
+        // Only its own blocks.
+        assert!(storage.bytes[..ERASE_SIZE as usize].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn spreads_the_erases() {
+        let defaults = Settings::default();
+        let mut storage = TestStorage::new(2);
+        let mut store = ConfigStore::new(0);
+        store.load(&mut storage, &defaults).unwrap();
+
+        let slots = (2 * ERASE_SIZE / SLOT_SIZE) as u64;
+        for interval_secs in 10..10 + 5 * slots {
+            store.save(&mut storage, &settings(interval_secs)).unwrap();
+
+            // A new store finds the newest copy, and carries on after it.
+            store = ConfigStore::new(0);
+            assert_eq!(Some(settings(interval_secs)), store.load(&mut storage, &defaults).unwrap());
+        }
+        assert_eq!(vec![5, 5], storage.erase_cnt);
+    }
+
+    #[test]
+    fn skips_damaged_copies() {
+        let defaults = Settings::default();
+        let mut storage = TestStorage::new(2);
+        let mut store = ConfigStore::new(0);
+        store.load(&mut storage, &defaults).unwrap();
+        store.save(&mut storage, &settings(100)).unwrap();
+        store.save(&mut storage, &settings(200)).unwrap();
+
+        // Power lost part way through writing the second.
+        storage.bytes[SLOT_SIZE as usize + 20] = 0;
+        let mut store = ConfigStore::new(0);
+        assert_eq!(Some(settings(100)), store.load(&mut storage, &defaults).unwrap());
+
+        // The damaged slot is left alone.
+        store.save(&mut storage, &settings(300)).unwrap();
+        assert_eq!(0xff, storage.bytes[SLOT_SIZE as usize * 3]);
+        assert_eq!(Some(settings(300)), ConfigStore::new(0).load(&mut storage, &defaults).unwrap());
+
+        // Another version's copies are not read.
+        storage.bytes[SLOT_SIZE as usize * 2 + 4] = 2;
+        assert_eq!(Some(settings(100)), ConfigStore::new(0).load(&mut storage, &defaults).unwrap());
+    }
+}
@@ -0,0 +1,114 @@
+// How many samples in a row the switch has to agree with itself before we believe it.  At the 1kHz the tip timer
+// samples at this rides out the couple of ms the reed switch bounces for, while the ~100ms it stays closed for each tip
+// is still counted.
+pub const SETTLE_SAMPLES: u8 = 5;
+
+/// Turns samples of the tipping bucket's reed switch into tips.  It is an integrator, each closed sample counts up and
+/// each open sample counts down, and the switch only changes state when the count reaches either end.  A tip is
+/// counted when the switch settles closed.
+pub struct Debouncer {
+    closed: bool,
+    integrator: u8
+}
+
+impl Debouncer {
+    pub const fn new() -> Debouncer {
+        Debouncer {
+            closed: false,
+            integrator: 0
+        }
+    }
+
+    /// Feed one sample, `closed` is true when the switch is closed.  Returns true when the sample completes a tip.
+    pub fn sample(&mut self, closed: bool) -> bool {
+        if closed {
+            if self.integrator < SETTLE_SAMPLES {
+                self.integrator = self.integrator + 1;
+            }
+        } else if self.integrator > 0 {
+            self.integrator = self.integrator - 1;
+        }
+
+        if !self.closed && self.integrator == SETTLE_SAMPLES {
+            self.closed = true;
+            return true;
+        }
+        if self.closed && self.integrator == 0 {
+            self.closed = false;
+        }
+
+        false
+    }
+
+    /// The switch has settled open, so there is nothing to sample until it closes again.
+    pub fn idle(&self) -> bool {
+        !self.closed && self.integrator == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1 is closed, 0 is open, one sample each.
+    fn tips(debouncer: &mut Debouncer, samples: &str) -> usize {
+        samples.chars()
+            .filter(|sample| *sample == '0' || *sample == '1')
+            .filter(|sample| debouncer.sample(*sample == '1'))
+            .count()
+    }
+
+    fn closed_for(samples: usize) -> String {
+        "1".repeat(samples)
+    }
+
+    #[test]
+    fn clean_tip() {
+        let mut debouncer = Debouncer::new();
+        assert!(debouncer.idle());
+
+        assert_eq!(1, tips(&mut debouncer, &(closed_for(100) + "00000")));
+        assert!(debouncer.idle());
+    }
+
+    #[test]
+    fn bounces_are_one_tip() {
+        let mut debouncer = Debouncer::new();
+
+        // Bouncing on the way in, held closed, then bouncing on the way out.
+        let tip = "1010110111".to_string() + &closed_for(80) + "0110100100" + "00000";
+        assert_eq!(1, tips(&mut debouncer, &tip));
+        assert!(debouncer.idle());
+    }
+
+    #[test]
+    fn glitches_are_ignored() {
+        let mut debouncer = Debouncer::new();
+
+        assert_eq!(0, tips(&mut debouncer, "0110000 1000 11110000 0"));
+        assert!(debouncer.idle());
+    }
+
+    #[test]
+    fn not_idle_until_settled_open() {
+        let mut debouncer = Debouncer::new();
+
+        assert_eq!(1, tips(&mut debouncer, &closed_for(10)));
+        assert!(!debouncer.idle());
+
+        // Open for less than the settle time and closed again is still the same tip.
+        assert_eq!(0, tips(&mut debouncer, &("000".to_string() + &closed_for(10))));
+        assert!(!debouncer.idle());
+
+        assert_eq!(0, tips(&mut debouncer, "00000"));
+        assert!(debouncer.idle());
+    }
+
+    #[test]
+    fn every_tip_in_a_downpour() {
+        let mut debouncer = Debouncer::new();
+
+        let tip = "10110".to_string() + &closed_for(50) + "01001" + &"0".repeat(200);
+        assert_eq!(20, tips(&mut debouncer, &tip.repeat(20)));
+    }
+}
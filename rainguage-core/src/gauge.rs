@@ -0,0 +1,1039 @@
+use core::fmt::Write;
+
+use crc::{crc32, Hasher32};
+use rainguage_messages::ConfigPacket;
+use rainguage_messages::HistoryPacket;
+use rainguage_messages::HistoryRecord;
+use rainguage_messages::Message;
+use rainguage_messages::MessageIterator;
+use rainguage_messages::PowerPacket;
+use rainguage_messages::TelemetryPacket;
+use rainguage_messages::TipsPacket;
+use rainguage_messages::MAX_HISTORY;
+
+use crate::clock::{Every, Uptime};
+use crate::command::{Command, Name, Setting};
+use crate::config::ConfigStore;
+use crate::hardware::{Adc, Clock, Radio, Sensor, Storage};
+use crate::history::{History, Record};
+use crate::power::PowerBudget;
+use crate::reading_log::ReadingLog;
+use crate::sensor::{Reading, SensorSchedule};
+
+// How long each loop takes, in clock ticks.  It is the 327.68ms the loop used to busy wait for, so loop_cnt still
+// matches the service's CLOCK_NOMINAL_PERIOD_MS.  The processor sleeps for whatever the loop does not use.
+pub const LOOP_TICKS: u32 = 10737;
+
+// The RadioHead library the downlink uses expects a 4-byte header, which we leave as zeros.
+pub const HEADER_LEN: usize = 4;
+
+// The longest frame the radio sends, each one only goes out as long as the header and its packet.
+pub const FRAME_LEN: usize = 255;
+
+// How many HistoryPackets go with each telemetry packet while the downlink is behind.
+pub const HISTORY_FRAMES: usize = 2;
+
+// How long the radio listens for settings and acks after the gauge transmits.  The downlink waits half a second to be
+// sure the gauge has finished, a full frame takes about 400ms, before it sends them.
+pub const RX_WINDOW_MS: u32 = 1500;
+
+/// How often the gauge does each thing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    pub transmit_interval_ms: u64,
+    pub temperature_interval_ms: u64,
+    /// A PowerPacket goes with the next telemetry packet after this.
+    pub power_interval_ms: u64,
+    pub frequency_mhz: u32,
+    pub tx_power_dbm: u8,
+    pub name: Name,
+    /// Added to the sensor's readings.
+    pub temperature_offset: f32,
+    pub humidity_offset: f32
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            transmit_interval_ms: 60_000,
+            temperature_interval_ms: 60_000,
+            power_interval_ms: 60 * 60_000,
+            frequency_mhz: 915,
+            tx_power_dbm: 20,
+            name: Name::new("rainguage").unwrap(),
+            temperature_offset: 0.0,
+            humidity_offset: 0.0
+        }
+    }
+}
+
+/// The counts the telemetry packet carries that the gauge does not keep itself.  The firmware fills in the usb ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counters {
+    pub usb_bytes_read: u32,
+    pub usb_bytes_written: u32,
+    pub usb_error_cnt: u32,
+    pub lora_tx_bytes: u32,
+    pub lora_rx_bytes: u32,
+    pub lora_error_cnt: u32,
+    /// Sensor reads, saving the settings and keeping the readings, that failed.
+    pub hardware_err_other_cnt: u32
+}
+
+/// Everything the main loop does between sleeps.  The hardware is passed in to `run`, and the tips come from the tip
+/// interrupt's TipCounter, so the gauge only holds what the loop keeps from one pass to the next.
+pub struct Gauge {
+    device_id: [u8; 16],
+    settings: Settings,
+    loop_cnt: u32,
+    uptime: Uptime,
+    budget: PowerBudget,
+    transmit_schedule: Every,
+    temperature_schedule: SensorSchedule,
+    power_schedule: Every,
+    // Asked for on the console.
+    transmit_now: bool,
+    reading: Reading,
+    vbat: u16,
+    history: History,
+    // The readings kept until the downlink has them, once `load` has found them.
+    log: Option<ReadingLog>,
+    // The next reading to send again, None to start again from the oldest.
+    resend: Option<u32>,
+    // How many receive windows since an ack was heard, nothing is sent again unless the downlink is answering.
+    quiet_windows: u32,
+    pub counters: Counters
+}
+
+impl Gauge {
+    /// `count` is the clock's count at power on.  Everything is due on the first pass.  The radio is expected to be
+    /// set to the settings' frequency and power already.
+    pub fn new(device_id: [u8; 16], settings: Settings, count: u32) -> Gauge {
+        let uptime = Uptime::new(count);
+
+        Gauge {
+            device_id,
+            settings,
+            loop_cnt: 0,
+            budget: PowerBudget::new(uptime.millis()),
+            uptime,
+            transmit_schedule: Every::new(settings.transmit_interval_ms),
+            temperature_schedule: SensorSchedule::new(settings.temperature_interval_ms),
+            power_schedule: Every::new(settings.power_interval_ms),
+            transmit_now: false,
+            reading: Reading {
+                temperature: 0.0,
+                humidity: 0.0
+            },
+            vbat: 0,
+            history: History::new(),
+            log: None,
+            resend: None,
+            quiet_windows: u32::MAX,
+            counters: Counters::default()
+        }
+    }
+
+    /// Find the readings kept in storage from before the reset, in the storage after the settings' ConfigStore.
+    /// Until then the readings are not kept.
+    pub fn load<St: Storage>(&mut self, storage: &mut St) -> Result<(), St::Error> {
+        let mut log = ReadingLog::new(storage, ConfigStore::len(storage));
+        log.load(storage)?;
+        self.log = Some(log);
+        Ok(())
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn loop_cnt(&self) -> u32 {
+        self.loop_cnt
+    }
+
+    pub fn uptime_secs(&self) -> u32 {
+        self.uptime.secs()
+    }
+
+    // How long it has been running, counting on through resets from the readings kept in flash.
+    fn running_secs(&self) -> u32 {
+        let resumed = self.log.as_ref().map(|log| log.resumed_secs()).unwrap_or(0);
+        resumed.saturating_add(self.uptime.secs())
+    }
+
+    /// Milliseconds since power on.
+    pub fn now(&mut self, clock: &mut impl Clock) -> u64 {
+        self.uptime.update(clock.count())
+    }
+
+    /// The processor has been running since it last slept.
+    pub fn awake(&mut self, clock: &mut impl Clock) {
+        let now = self.now(clock);
+        self.budget.awake(now);
+    }
+
+    /// The processor has been in standby since it last woke.
+    pub fn standby(&mut self, clock: &mut impl Clock) {
+        let now = self.now(clock);
+        self.budget.standby(now);
+    }
+
+    /// A tip woke the processor from standby before it was due.
+    pub fn tip_wake(&mut self) {
+        self.budget.tip_wake();
+    }
+
+    /// One pass of the main loop: read the battery, read the sensor when it is due and send telemetry when that is
+    /// due.  `tips` fills in the tips since the last telemetry packet, see TipCounter::report.
+    pub fn run<C, R, S, A, St, T>(&mut self, clock: &mut C, radio: &mut R, sensor: &mut S, adc: &mut A,
+        storage: &mut St, tips: T)
+        where C: Clock, R: Radio, S: Sensor, A: Adc, St: Storage, T: FnOnce(u32, &mut TipsPacket) {
+        let now = self.now(clock);
+        self.vbat = adc.read();
+
+        if self.temperature_schedule.due(now) {
+            match sensor.read() {
+                Ok(reading) => {
+                    self.reading = Reading {
+                        temperature: reading.temperature + self.settings.temperature_offset,
+                        humidity: (reading.humidity + self.settings.humidity_offset).max(0.0).min(100.0)
+                    };
+                },
+                Err(_) => {
+                    self.counters.hardware_err_other_cnt = self.counters.hardware_err_other_cnt.wrapping_add(1);
+                    self.temperature_schedule.failed(now);
+                }
+            }
+        }
+
+        let due = self.transmit_schedule.due(now);
+        if due || self.transmit_now {
+            self.transmit_now = false;
+            let mut tips_packet = TipsPacket::new();
+            tips(self.loop_cnt, &mut tips_packet);
+            self.report(now, clock, radio, storage, tips_packet);
+        }
+
+        self.loop_cnt = self.loop_cnt.wrapping_add(1);
+    }
+
+    fn report<C, R, St>(&mut self, now: u64, clock: &mut C, radio: &mut R, storage: &mut St, mut tips: TipsPacket)
+        where C: Clock, R: Radio, St: Storage {
+        let mut packet = self.telemetry(tips.tip_cnt);
+        let record = Record {
+            uptime_secs: self.uptime.secs(),
+            loop_cnt: packet.loop_cnt,
+            tip_cnt: packet.tip_cnt,
+            temperature: packet.temperature,
+            humidity: packet.relative_humidity,
+            vbat: packet.vbat
+        };
+        self.history.push(record);
+
+        // Kept until the downlink acknowledges it, along with any before it that it has not.  The log dates it by the
+        // running time, so it can still be dated after a reset.
+        let count = clock.count();
+        let kept = self.keep(storage, &Record { uptime_secs: self.running_secs(), ..record }, count);
+        if let Some((sequence, oldest)) = kept {
+            packet.epoch = self.log.as_ref().and_then(|log| log.epoch());
+            packet.sequence = Some(sequence);
+            packet.backlog = Some(sequence - oldest);
+        }
+
+        // Taken before this round of transmitting, which is counted in the next one.
+        let power = if self.power_schedule.due(now) {
+            Some(self.power())
+        } else {
+            None
+        };
+
+        let mut frame = [0; FRAME_LEN];
+        if let Ok(len) = rainguage_messages::serialize(&packet, &mut frame[HEADER_LEN..]) {
+            self.transmit(clock, radio, &frame[..HEADER_LEN + len]);
+        }
+
+        // When in the interval the rain fell, see TipsPacket.
+        if tips.len > 0 {
+            tips.device_id = self.device_id;
+
+            let mut frame = [0; FRAME_LEN];
+            if let Ok(len) = rainguage_messages::serialize_tips(&tips, &mut frame[HEADER_LEN..]) {
+                self.transmit(clock, radio, &frame[..HEADER_LEN + len]);
+            }
+        }
+
+        if let Some(power) = power {
+            let mut frame = [0; FRAME_LEN];
+            if let Ok(len) = rainguage_messages::serialize_power(&power, &mut frame[HEADER_LEN..]) {
+                self.transmit(clock, radio, &frame[..HEADER_LEN + len]);
+            }
+        }
+
+        // Only while the downlink is acknowledging, an ack in one of the last two windows, otherwise the history
+        // would only keep the gauge transmitting longer while nobody listens.
+        if let (Some((sequence, oldest)), true) = (kept, self.quiet_windows < 2) {
+            self.resend(clock, radio, storage, sequence, oldest);
+        }
+
+        self.listen(clock, radio, storage);
+
+        if radio.sleep().is_err() {
+            self.counters.lora_error_cnt = self.counters.lora_error_cnt.wrapping_add(1);
+        }
+    }
+
+    // Add the reading to the log, returning its sequence and the oldest reading that has not been acknowledged.  A log
+    // that has not been started, because the storage was erased, is started in a new epoch first.
+    fn keep<St: Storage>(&mut self, storage: &mut St, record: &Record, count: u32) -> Option<(u32, u32)> {
+        let log = self.log.as_mut()?;
+        let started = match log.epoch() {
+            Some(_) => Ok(()),
+            None => log.begin(storage, epoch(&self.device_id, count, record))
+        };
+        let kept = started
+            .and_then(|_| log.append(storage, record))
+            .and_then(|sequence| Ok((sequence, log.first_unacked(storage, sequence)?.unwrap_or(sequence))));
+
+        match kept {
+            Ok((sequence, oldest)) => Some((sequence, oldest)),
+            Err(_) => {
+                self.counters.hardware_err_other_cnt = self.counters.hardware_err_other_cnt.wrapping_add(1);
+                None
+            }
+        }
+    }
+
+    // Send the readings from `oldest` on again, HISTORY_FRAMES frames at a time, going round them until they are
+    // acknowledged.  The reading before `sequence` is left out, its ack is usually still on its way.
+    fn resend<C, R, St>(&mut self, clock: &mut C, radio: &mut R, storage: &mut St, sequence: u32, oldest: u32)
+        where C: Clock, R: Radio, St: Storage {
+        let end = sequence.saturating_sub(1);
+        let mut next = match self.resend {
+            Some(next) if next >= oldest && next < end => next,
+            _ => oldest
+        };
+
+        for _ in 0..HISTORY_FRAMES {
+            let mut history = HistoryPacket::new();
+            history.device_id = self.device_id;
+            history.running_secs = self.running_secs();
+            history.epoch = self.log.as_ref().and_then(|log| log.epoch()).unwrap_or(0);
+            history.oldest = oldest;
+
+            while next < end && (history.len as usize) < MAX_HISTORY {
+                let read = match &self.log {
+                    Some(log) => log.read(storage, next),
+                    None => return
+                };
+                match read {
+                    Ok(Some(logged)) => {
+                        history.records[history.len as usize] = HistoryRecord {
+                            sequence: logged.sequence,
+                            running_secs: logged.record.uptime_secs,
+                            loop_cnt: logged.record.loop_cnt,
+                            tip_cnt: logged.record.tip_cnt,
+                            vbat: logged.record.vbat as u16,
+                            temperature: logged.record.temperature,
+                            relative_humidity: logged.record.humidity
+                        };
+                        history.len = history.len + 1;
+                    },
+                    // Lost when the log was full, or damaged.
+                    Ok(None) => {},
+                    Err(_) => {
+                        self.counters.hardware_err_other_cnt = self.counters.hardware_err_other_cnt.wrapping_add(1);
+                    }
+                }
+                next = next + 1;
+            }
+            if history.len == 0 {
+                break;
+            }
+
+            let mut frame = [0; FRAME_LEN];
+            if let Ok(len) = rainguage_messages::serialize_history(&history, &mut frame[HEADER_LEN..]) {
+                self.transmit(clock, radio, &frame[..HEADER_LEN + len]);
+            }
+        }
+
+        self.resend = if next < end { Some(next) } else { None };
+    }
+
+    // Settings and acks from the downlink arrive straight after the gauge transmits, see ConfigPacket and AckPacket.
+    // It listens for the whole window, as the downlink can have one of each.
+    fn listen<C, R, St>(&mut self, clock: &mut C, radio: &mut R, storage: &mut St)
+        where C: Clock, R: Radio, St: Storage {
+        self.quiet_windows = self.quiet_windows.saturating_add(1);
+        let started = self.now(clock);
+        loop {
+            let waited = (self.now(clock) - started) as u32;
+            if waited >= RX_WINDOW_MS {
+                return;
+            }
+
+            let mut frame = [0; FRAME_LEN];
+            let len = match radio.receive(&mut frame, RX_WINDOW_MS - waited) {
+                Ok(0) => return,
+                Ok(len) => len,
+                Err(_) => {
+                    self.counters.lora_error_cnt = self.counters.lora_error_cnt.wrapping_add(1);
+                    return;
+                }
+            };
+            self.counters.lora_rx_bytes = self.counters.lora_rx_bytes.wrapping_add(len as u32);
+
+            for message in MessageIterator::new(frame[..len].iter().cloned()) {
+                match message {
+                    Ok(Message::Config(config)) if config.device_id == self.device_id => {
+                        self.configure(clock, radio, &config);
+                    },
+                    Ok(Message::Ack(ack)) if ack.device_id == self.device_id => {
+                        self.quiet_windows = 0;
+                        let acked = match self.log.as_mut() {
+                            Some(log) => log.ack(storage, ack.sequence),
+                            None => Ok(())
+                        };
+                        if acked.is_err() {
+                            self.counters.hardware_err_other_cnt =
+                                self.counters.hardware_err_other_cnt.wrapping_add(1);
+                        }
+                    },
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // Take the settings that are in range, and report back what it has now.  The report goes out before the frequency
+    // changes, so the downlink hears it.
+    fn configure<C: Clock, R: Radio>(&mut self, clock: &mut C, radio: &mut R, config: &ConfigPacket) {
+        let settings = [
+            config.transmit_interval_secs.map(Setting::Interval),
+            config.tx_power_dbm.map(Setting::TxPowerDbm),
+            config.temperature_offset.map(Setting::TemperatureOffset),
+            config.humidity_offset.map(Setting::HumidityOffset)
+        ];
+        for setting in settings.iter().filter_map(|setting| *setting).filter(Setting::valid) {
+            self.set(setting, radio);
+        }
+        let frequency = config.frequency_mhz.map(Setting::FrequencyMhz).filter(Setting::valid);
+
+        let mut report = self.config();
+        if let Some(Setting::FrequencyMhz(mhz)) = frequency {
+            report.frequency_mhz = Some(mhz);
+        }
+        let mut frame = [0; FRAME_LEN];
+        if let Ok(len) = rainguage_messages::serialize_config_report(&report, &mut frame[HEADER_LEN..]) {
+            self.transmit(clock, radio, &frame[..HEADER_LEN + len]);
+        }
+
+        if let Some(frequency) = frequency {
+            self.set(frequency, radio);
+        }
+    }
+
+    /// The settings the gauge has, as it reports them over the radio.
+    pub fn config(&self) -> ConfigPacket {
+        let mut config = ConfigPacket::new();
+        config.device_id = self.device_id;
+        config.transmit_interval_secs = Some((self.settings.transmit_interval_ms / 1000) as u32);
+        config.frequency_mhz = Some(self.settings.frequency_mhz);
+        config.tx_power_dbm = Some(self.settings.tx_power_dbm);
+        config.temperature_offset = Some(self.settings.temperature_offset);
+        config.humidity_offset = Some(self.settings.humidity_offset);
+        config
+    }
+
+    fn transmit<C: Clock, R: Radio>(&mut self, clock: &mut C, radio: &mut R, frame: &[u8]) {
+        let started = self.now(clock);
+        match radio.transmit(frame) {
+            Ok(bytes) => {
+                self.counters.lora_tx_bytes = self.counters.lora_tx_bytes.wrapping_add(bytes as u32);
+            },
+            Err(_) => {
+                self.counters.lora_error_cnt = self.counters.lora_error_cnt.wrapping_add(1);
+            }
+        }
+        let finished = self.now(clock);
+        self.budget.transmitted(finished - started);
+    }
+
+    /// The telemetry packet for this loop.
+    pub fn telemetry(&self, tip_cnt: u32) -> TelemetryPacket {
+        let mut packet = TelemetryPacket::new();
+        packet.device_id = self.device_id;
+        packet.loop_cnt = self.loop_cnt;
+        packet.vbat = self.vbat as u32;
+        packet.usb_bytes_read = self.counters.usb_bytes_read;
+        packet.usb_bytes_written = self.counters.usb_bytes_written;
+        packet.usb_error_cnt = self.counters.usb_error_cnt;
+        packet.lora_error_cnt = self.counters.lora_error_cnt;
+        packet.lora_tx_bytes = self.counters.lora_tx_bytes;
+        packet.lora_rx_bytes = self.counters.lora_rx_bytes;
+        packet.tip_cnt = tip_cnt;
+        packet.temperature = self.reading.temperature;
+        packet.relative_humidity = self.reading.humidity;
+        packet.hardware_err_other_cnt = self.counters.hardware_err_other_cnt;
+        packet.uptime_secs = Some(self.uptime.secs());
+        packet
+    }
+
+    fn power(&self) -> PowerPacket {
+        let mut power = PowerPacket::new();
+        power.device_id = self.device_id;
+        power.loop_cnt = self.loop_cnt;
+        power.awake_ms = self.budget.awake_ms();
+        power.standby_ms = self.budget.standby_ms();
+        power.tx_ms = self.budget.tx_ms();
+        power.tip_wake_cnt = self.budget.tip_wake_cnt();
+        power
+    }
+
+    /// Answer a command from the console.  Every answer ends with an `ok` or `error: ...` line.  Command::Bootloader
+    /// is left to whatever runs the gauge, as only it can restart the processor.
+    pub fn handle<R: Radio>(&mut self, command: Command, radio: &mut R, out: &mut dyn Write) -> core::fmt::Result {
+        match command {
+            Command::Help => {
+                write!(out, "status                      loop count, uptime, settings and counters\r\n")?;
+                write!(out, "readings                    the last temperature, humidity and battery readings\r\n")?;
+                write!(out, "dump                        the telemetry sent since power on, the last {} packets\r\n",
+                    crate::history::HISTORY_LEN)?;
+                write!(out, "transmit                    send telemetry now\r\n")?;
+                write!(out, "set interval <secs>         how often telemetry is sent\r\n")?;
+                write!(out, "set frequency <mhz>         the radio's frequency\r\n")?;
+                write!(out, "set power <dbm>             the radio's transmit power\r\n")?;
+                write!(out, "set name <name>             up to 16 characters, no spaces\r\n")?;
+                write!(out, "set temperature-offset <c>  added to the sensor's temperature\r\n")?;
+                write!(out, "set humidity-offset <%>     added to the sensor's relative humidity\r\n")?;
+                write!(out, "defaults                    go back to the default settings\r\n")?;
+                write!(out, "bootloader                  restart into the bootloader to load new firmware\r\n")?;
+            },
+            Command::Status => {
+                write!(out, "loop_cnt={} uptime_secs={} vbat={} temperature={} relative_humidity={}\r\n",
+                    self.loop_cnt, self.uptime.secs(), self.vbat, self.reading.temperature, self.reading.humidity)?;
+                write!(out, "name={} interval_secs={} frequency_mhz={} tx_power_dbm={}\r\n",
+                    self.settings.name.as_str(), self.settings.transmit_interval_ms / 1000,
+                    self.settings.frequency_mhz, self.settings.tx_power_dbm)?;
+                write!(out, "temperature_offset={} humidity_offset={}\r\n",
+                    self.settings.temperature_offset, self.settings.humidity_offset)?;
+                write!(out, "lora_tx_bytes={} lora_rx_bytes={} lora_error_cnt={} hardware_err_other_cnt={}\r\n",
+                    self.counters.lora_tx_bytes, self.counters.lora_rx_bytes, self.counters.lora_error_cnt,
+                    self.counters.hardware_err_other_cnt)?;
+                write!(out, "usb_bytes_read={} usb_bytes_written={} usb_error_cnt={}\r\n",
+                    self.counters.usb_bytes_read, self.counters.usb_bytes_written, self.counters.usb_error_cnt)?;
+                if let Some(log) = &self.log {
+                    write!(out, "sequence={} acked={}\r\n", Sequence(log.newest()), Sequence(log.acked()))?;
+                }
+            },
+            Command::Readings => {
+                write!(out, "temperature={} relative_humidity={} vbat={}\r\n",
+                    self.reading.temperature, self.reading.humidity, self.vbat)?;
+            },
+            Command::Dump => {
+                write!(out, "uptime_secs,loop_cnt,tip_cnt,temperature,relative_humidity,vbat\r\n")?;
+                for record in self.history.iter() {
+                    write!(out, "{},{},{},{},{},{}\r\n", record.uptime_secs, record.loop_cnt, record.tip_cnt,
+                        record.temperature, record.humidity, record.vbat)?;
+                }
+            },
+            Command::Transmit => {
+                self.transmit_now = true;
+            },
+            Command::Bootloader => {
+                return write!(out, "error: no bootloader here\r\n");
+            },
+            Command::Set(setting) => {
+                if !self.set(setting, radio) {
+                    return write!(out, "error: the radio did not take it\r\n");
+                }
+            },
+            Command::Defaults => {
+                let defaults = Settings::default();
+                let frequency = self.set(Setting::FrequencyMhz(defaults.frequency_mhz), radio);
+                let power = self.set(Setting::TxPowerDbm(defaults.tx_power_dbm), radio);
+                self.settings = Settings {
+                    frequency_mhz: self.settings.frequency_mhz,
+                    tx_power_dbm: self.settings.tx_power_dbm,
+                    ..defaults
+                };
+                self.transmit_schedule = Every::new(self.settings.transmit_interval_ms);
+                if !frequency || !power {
+                    return write!(out, "error: the radio did not take it\r\n");
+                }
+            }
+        }
+        write!(out, "ok\r\n")
+    }
+
+    // Returns false when the radio could not be changed, leaving the setting as it was.
+    fn set<R: Radio>(&mut self, setting: Setting, radio: &mut R) -> bool {
+        let result = match setting {
+            Setting::Interval(secs) => {
+                self.settings.transmit_interval_ms = secs as u64 * 1000;
+                // Due on the next pass.
+                self.transmit_schedule = Every::new(self.settings.transmit_interval_ms);
+                Ok(())
+            },
+            Setting::FrequencyMhz(mhz) => radio.set_frequency(mhz).map(|_| self.settings.frequency_mhz = mhz),
+            Setting::TxPowerDbm(dbm) => radio.set_tx_power(dbm).map(|_| self.settings.tx_power_dbm = dbm),
+            Setting::Name(name) => {
+                self.settings.name = name;
+                Ok(())
+            },
+            Setting::TemperatureOffset(offset) => {
+                self.settings.temperature_offset = offset;
+                Ok(())
+            },
+            Setting::HumidityOffset(offset) => {
+                self.settings.humidity_offset = offset;
+                Ok(())
+            }
+        };
+
+        if result.is_err() {
+            self.counters.lora_error_cnt = self.counters.lora_error_cnt.wrapping_add(1);
+        }
+        result.is_ok()
+    }
+}
+
+// There is no random number generator, so a new log's epoch is made from what differs from one time the storage is
+// erased to the next: the clock's count when the gauge gets to its first reading, and the reading itself.
+fn epoch(device_id: &[u8; 16], count: u32, record: &Record) -> u32 {
+    let mut digest = crc32::Digest::new(crc32::IEEE);
+    digest.write(device_id);
+    for word in [count, record.loop_cnt, record.vbat, record.temperature.to_bits(), record.humidity.to_bits()].iter() {
+        digest.write(&word.to_le_bytes());
+    }
+    digest.sum32()
+}
+
+// A sequence for the console, or none.
+struct Sequence(Option<u32>);
+
+impl core::fmt::Display for Sequence {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.0 {
+            Some(sequence) => write!(f, "{}", sequence),
+            None => write!(f, "none")
+        }
+    }
+}
+
+/// The device id from the four words of the processor's serial number.
+pub fn device_id(words: [u32; 4]) -> [u8; 16] {
+    let mut device_id = [0; 16];
+    for (i, word) in words.iter().enumerate() {
+        device_id[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    device_id
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use rainguage_messages::{Message, MessageIterator};
+
+    use crate::clock::TICKS_PER_SECOND;
+    use crate::config::tests::TestStorage;
+
+    use super::*;
+
+    // Shared with the radio, which moves it on while it transmits.
+    #[derive(Clone)]
+    struct TestClock(Rc<Cell<u32>>);
+
+    impl Clock for TestClock {
+        fn count(&mut self) -> u32 {
+            self.0.get()
+        }
+    }
+
+    impl TestClock {
+        fn advance_ms(&self, ms: u32) {
+            self.0.set(self.0.get().wrapping_add(ms * TICKS_PER_SECOND / 1000));
+        }
+    }
+
+    struct TestRadio {
+        clock: TestClock,
+        frames: Vec<Vec<u8>>,
+        // Heard after the next transmit.
+        reply: Option<Vec<u8>>,
+        frequency_mhz: u32
+    }
+
+    impl TestRadio {
+        fn new(clock: TestClock) -> TestRadio {
+            TestRadio { clock, frames: vec![], reply: None, frequency_mhz: 915 }
+        }
+    }
+
+    impl Radio for TestRadio {
+        type Error = ();
+
+        fn transmit(&mut self, frame: &[u8]) -> Result<usize, ()> {
+            self.clock.advance_ms(100);
+            self.frames.push(frame.to_vec());
+            Ok(frame.len())
+        }
+
+        fn sleep(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+
+        // Only 868 and 915.
+        fn set_frequency(&mut self, mhz: u32) -> Result<(), ()> {
+            if mhz != 868 && mhz != 915 {
+                return Err(());
+            }
+            self.frequency_mhz = mhz;
+            Ok(())
+        }
+
+        fn set_tx_power(&mut self, _dbm: u8) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn receive(&mut self, buffer: &mut [u8], _timeout_ms: u32) -> Result<usize, ()> {
+            match self.reply.take() {
+                Some(reply) => {
+                    buffer[..reply.len()].copy_from_slice(&reply);
+                    Ok(reply.len())
+                },
+                None => Ok(0)
+            }
+        }
+    }
+
+    // Fails the first `failures` reads.
+    struct TestSensor {
+        failures: usize
+    }
+
+    impl Sensor for TestSensor {
+        type Error = ();
+
+        fn read(&mut self) -> Result<Reading, ()> {
+            if self.failures > 0 {
+                self.failures = self.failures - 1;
+                return Err(());
+            }
+            Ok(Reading { temperature: 21.5, humidity: 40.0 })
+        }
+    }
+
+    struct TestAdc;
+
+    impl Adc for TestAdc {
+        fn read(&mut self) -> u16 {
+            2048
+        }
+    }
+
+    fn messages(radio: &TestRadio) -> Vec<Message> {
+        let bytes = radio.frames.iter().flat_map(|frame| frame.iter().cloned()).collect::<Vec<u8>>();
+        MessageIterator::new(bytes.into_iter()).map(|message| message.unwrap()).collect()
+    }
+
+    #[test]
+    fn sends_on_schedule() {
+        let mut clock = TestClock(Rc::new(Cell::new(0)));
+        let mut radio = TestRadio::new(clock.clone());
+        let mut sensor = TestSensor { failures: 1 };
+        let mut storage = TestStorage::new(0);
+        let mut gauge = Gauge::new(device_id([1, 2, 3, 4]), Settings::default(), 0);
+
+        // A pass every second for two minutes, with a tip in the first.
+        for second in 0..120 {
+            gauge.run(&mut clock, &mut radio, &mut sensor, &mut TestAdc, &mut storage, |loop_cnt, tips| {
+                if second == 0 {
+                    tips.tip_cnt = 1;
+                    tips.loop_cnt = loop_cnt;
+                    tips.len = 1;
+                }
+            });
+            gauge.awake(&mut clock);
+            clock.advance_ms(900);
+            gauge.standby(&mut clock);
+        }
+
+        let messages = messages(&radio);
+        assert_eq!(4, messages.len());
+        // Only as long as each packet, not the radio's longest frame.
+        assert!(radio.frames.iter().all(|frame| frame.len() < FRAME_LEN / 2 && frame[..HEADER_LEN] == [0; HEADER_LEN]));
+        let sent = radio.frames[..3].iter().map(|frame| frame.len() as u32).sum::<u32>();
+
+        match (&messages[0], &messages[1], &messages[2], &messages[3]) {
+            (Message::Telemetry(first), Message::Tips(tips), Message::Power(power), Message::Telemetry(second)) => {
+                assert_eq!([0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4], first.device_id);
+                assert_eq!(0, first.loop_cnt);
+                assert_eq!(1, first.tip_cnt);
+                assert_eq!(1, first.hardware_err_other_cnt);
+                assert_eq!(0.0, first.temperature);
+                assert_eq!(Some(0), first.uptime_secs);
+                assert_eq!(first.device_id, tips.device_id);
+                assert_eq!(0, power.tx_ms);
+
+                // The retry two seconds later worked.
+                assert_eq!(21.5, second.temperature);
+                assert_eq!(2048, second.vbat);
+                assert_eq!(sent, second.lora_tx_bytes);
+                assert_eq!(Some(60), second.uptime_secs);
+            },
+            _ => panic!("unexpected messages {:?}", messages)
+        }
+    }
+
+    fn handle(gauge: &mut Gauge, radio: &mut TestRadio, line: &str) -> String {
+        let mut out = String::new();
+        gauge.handle(crate::command::parse(line).unwrap(), radio, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn status() {
+        let mut radio = TestRadio::new(TestClock(Rc::new(Cell::new(0))));
+        let mut gauge = Gauge::new([0; 16], Settings::default(), 0);
+        gauge.counters.lora_error_cnt = 3;
+
+        let out = handle(&mut gauge, &mut radio, "status");
+        assert!(out.starts_with("loop_cnt=0 uptime_secs=0 "));
+        assert!(out.contains(" lora_error_cnt=3 "));
+        assert!(out.contains("name=rainguage interval_secs=60 frequency_mhz=915 tx_power_dbm=20\r\n"));
+        assert!(out.ends_with("\r\nok\r\n"));
+    }
+
+    #[test]
+    fn settings_and_transmit() {
+        let mut clock = TestClock(Rc::new(Cell::new(0)));
+        let mut storage = TestStorage::new(0);
+        let mut radio = TestRadio::new(clock.clone());
+        let mut gauge = Gauge::new([0; 16], Settings::default(), 0);
+
+        assert_eq!("ok\r\n", handle(&mut gauge, &mut radio, "set frequency 868"));
+        assert_eq!("error: the radio did not take it\r\n", handle(&mut gauge, &mut radio, "set frequency 900"));
+        assert_eq!(868, gauge.settings().frequency_mhz);
+        assert_eq!(1, gauge.counters.lora_error_cnt);
+        handle(&mut gauge, &mut radio, "set power 14");
+        handle(&mut gauge, &mut radio, "set name north");
+        assert_eq!(14, gauge.settings().tx_power_dbm);
+        assert_eq!("north", gauge.settings().name.as_str());
+
+        // Sent on the first pass and then not for another 5 minutes, unless asked.
+        handle(&mut gauge, &mut radio, "set interval 300");
+        let mut pass = |gauge: &mut Gauge, radio: &mut TestRadio| {
+            gauge.run(&mut clock, radio, &mut TestSensor { failures: 0 }, &mut TestAdc, &mut storage, |_, _| {});
+            clock.advance_ms(1000);
+        };
+        pass(&mut gauge, &mut radio);
+        pass(&mut gauge, &mut radio);
+        handle(&mut gauge, &mut radio, "transmit");
+        pass(&mut gauge, &mut radio);
+        pass(&mut gauge, &mut radio);
+
+        let telemetry = messages(&radio).iter()
+            .filter_map(|message| match message {
+                Message::Telemetry(packet) => Some(packet.loop_cnt),
+                _ => None
+            })
+            .collect::<Vec<u32>>();
+        assert_eq!(vec![0, 2], telemetry);
+
+        let dump = handle(&mut gauge, &mut radio, "dump");
+        let lines = dump.split("\r\n").collect::<Vec<&str>>();
+        assert_eq!("uptime_secs,loop_cnt,tip_cnt,temperature,relative_humidity,vbat", lines[0]);
+        assert_eq!("0,0,0,21.5,40,2048", lines[1]);
+        assert!(lines[2].starts_with("2,2,0,"));
+        assert_eq!("ok", lines[3]);
+    }
+
+    #[test]
+    fn settings_from_the_downlink() {
+        let mut clock = TestClock(Rc::new(Cell::new(0)));
+        let mut storage = TestStorage::new(0);
+        let mut radio = TestRadio::new(clock.clone());
+        let mut gauge = Gauge::new(device_id([1, 2, 3, 4]), Settings::default(), 0);
+
+        let mut config = ConfigPacket::new();
+        config.device_id = device_id([1, 2, 3, 4]);
+        config.frequency_mhz = Some(868);
+        config.tx_power_dbm = Some(99);
+        config.temperature_offset = Some(-1.5);
+        let mut frame = vec![0; HEADER_LEN];
+        let mut buf = [0; FRAME_LEN];
+        let len = rainguage_messages::serialize_config(&config, &mut buf).unwrap();
+        frame.extend_from_slice(&buf[..len]);
+
+        // Another gauge's are ignored.
+        let mut other = config.clone();
+        other.device_id = [9; 16];
+        let len = rainguage_messages::serialize_config(&other, &mut buf).unwrap();
+        radio.reply = Some(buf[..len].to_vec());
+        gauge.run(&mut clock, &mut radio, &mut TestSensor { failures: 0 }, &mut TestAdc, &mut storage, |_, _| {});
+        assert_eq!(Settings::default(), *gauge.settings());
+        assert_eq!(len as u32, gauge.counters.lora_rx_bytes);
+
+        radio.reply = Some(frame);
+        gauge.transmit_now = true;
+        gauge.run(&mut clock, &mut radio, &mut TestSensor { failures: 0 }, &mut TestAdc, &mut storage, |_, _| {});
+        assert_eq!(868, gauge.settings().frequency_mhz);
+        assert_eq!(868, radio.frequency_mhz);
+        assert_eq!(-1.5, gauge.settings().temperature_offset);
+        // Out of range.
+        assert_eq!(20, gauge.settings().tx_power_dbm);
+
+        match messages(&radio).last() {
+            Some(Message::ConfigReport(report)) => {
+                assert_eq!(gauge.config(), *report);
+                assert_eq!(Some(60), report.transmit_interval_secs);
+            },
+            other => panic!("unexpected message {:?}", other)
+        }
+
+        // The offset is applied to the next reading.
+        clock.advance_ms(60_000);
+        gauge.run(&mut clock, &mut radio, &mut TestSensor { failures: 0 }, &mut TestAdc, &mut storage, |_, _| {});
+        match messages(&radio).last() {
+            Some(Message::Telemetry(packet)) => assert_eq!(20.0, packet.temperature),
+            other => panic!("unexpected message {:?}", other)
+        }
+    }
+
+    #[test]
+    fn resends_until_acked() {
+        let mut clock = TestClock(Rc::new(Cell::new(0)));
+        let mut radio = TestRadio::new(clock.clone());
+        let mut storage = TestStorage::new(8);
+        let mut gauge = Gauge::new(device_id([1, 2, 3, 4]), Settings::default(), 0);
+        gauge.load(&mut storage).unwrap();
+
+        let ack = |sequence| {
+            let ack = rainguage_messages::AckPacket { device_id: device_id([1, 2, 3, 4]), sequence };
+            let mut buf = [0; FRAME_LEN];
+            let len = rainguage_messages::serialize_ack(&ack, &mut buf).unwrap();
+            buf[..len].to_vec()
+        };
+        let mut pass = |gauge: &mut Gauge, radio: &mut TestRadio| {
+            radio.frames.clear();
+            gauge.transmit_now = true;
+            gauge.run(&mut clock, radio, &mut TestSensor { failures: 0 }, &mut TestAdc, &mut storage, |_, _| {});
+            let mut telemetry = None;
+            let mut resent = vec![];
+            for message in messages(radio) {
+                match message {
+                    Message::Telemetry(packet) => telemetry = Some((packet.sequence.unwrap(), packet.backlog.unwrap())),
+                    Message::History(history) => {
+                        resent.extend(history.records().iter().map(|record| record.sequence));
+                    },
+                    _ => {}
+                }
+            }
+            (telemetry.unwrap(), resent)
+        };
+
+        // Nothing is heard from the downlink, so nothing is sent again.
+        assert_eq!(((1, 0), vec![]), pass(&mut gauge, &mut radio));
+        assert_eq!(((2, 1), vec![]), pass(&mut gauge, &mut radio));
+        assert_eq!(((3, 2), vec![]), pass(&mut gauge, &mut radio));
+        assert_eq!(((4, 3), vec![]), pass(&mut gauge, &mut radio));
+
+        // Once it acknowledges, the reading before is given a pass to be acked.
+        radio.reply = Some(ack(3));
+        assert_eq!(((5, 4), vec![]), pass(&mut gauge, &mut radio));
+        radio.reply = Some(ack(3));
+        assert_eq!(((6, 2), vec![4]), pass(&mut gauge, &mut radio));
+
+        // Twelve at a time, going round them.
+        let mut resent = vec![];
+        for _ in 0..20 {
+            radio.reply = Some(ack(3));
+            let (_, sent) = pass(&mut gauge, &mut radio);
+            assert!(sent.len() <= 2 * MAX_HISTORY);
+            resent.extend(sent);
+        }
+        assert!((4..24).all(|sequence| resent.contains(&sequence)));
+        assert_eq!((27, 23), pass(&mut gauge, &mut radio).0);
+
+        // Without acks it carries on for one more pass, then stops.
+        assert!(!pass(&mut gauge, &mut radio).1.is_empty());
+        assert_eq!(((29, 25), vec![]), pass(&mut gauge, &mut radio));
+
+        // The ack is kept through a reset.
+        let mut gauge = Gauge::new(device_id([1, 2, 3, 4]), Settings::default(), 0);
+        gauge.load(&mut storage).unwrap();
+        let out = handle(&mut gauge, &mut radio, "status");
+        assert!(out.contains("sequence=29 acked=3\r\n"), "{}", out);
+    }
+
+    #[test]
+    fn keeps_its_epoch_until_the_storage_is_erased() {
+        let mut clock = TestClock(Rc::new(Cell::new(0)));
+        let mut radio = TestRadio::new(clock.clone());
+        let mut pass = |storage: &mut TestStorage, radio: &mut TestRadio| {
+            radio.frames.clear();
+            clock.advance_ms(1_500);
+            let count = clock.count();
+            let mut gauge = Gauge::new(device_id([1, 2, 3, 4]), Settings::default(), count);
+            gauge.load(storage).unwrap();
+            gauge.run(&mut clock, radio, &mut TestSensor { failures: 0 }, &mut TestAdc, storage, |_, _| {});
+            match messages(radio).first() {
+                Some(Message::Telemetry(packet)) => (packet.epoch.unwrap(), packet.sequence.unwrap()),
+                other => panic!("unexpected message {:?}", other)
+            }
+        };
+
+        let mut storage = TestStorage::new(8);
+        let (epoch, sequence) = pass(&mut storage, &mut radio);
+        assert_eq!(1, sequence);
+        // After a reset.
+        assert_eq!((epoch, 2), pass(&mut storage, &mut radio));
+
+        let (erased, sequence) = pass(&mut TestStorage::new(8), &mut radio);
+        assert_eq!(1, sequence);
+        assert_ne!(epoch, erased);
+    }
+
+    #[test]
+    fn dates_readings_from_before_a_reset() {
+        let mut clock = TestClock(Rc::new(Cell::new(0)));
+        let mut radio = TestRadio::new(clock.clone());
+        let mut storage = TestStorage::new(8);
+        let mut gauge = Gauge::new(device_id([1, 2, 3, 4]), Settings::default(), 0);
+        gauge.load(&mut storage).unwrap();
+
+        let mut pass = |gauge: &mut Gauge, radio: &mut TestRadio, storage: &mut TestStorage| {
+            radio.frames.clear();
+            clock.advance_ms(60_000);
+            gauge.transmit_now = true;
+            gauge.run(&mut clock, radio, &mut TestSensor { failures: 0 }, &mut TestAdc, storage, |_, _| {});
+            messages(radio).into_iter()
+                .filter_map(|message| match message {
+                    Message::History(history) => Some(history),
+                    _ => None
+                })
+                .flat_map(|history| history.records().iter()
+                    .map(|record| (record.sequence, history.age_secs(record).unwrap()))
+                    .collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+
+        // A reading a minute that the downlink does not hear, then a reset.
+        for _ in 0..3 {
+            pass(&mut gauge, &mut radio, &mut storage);
+        }
+        radio.clock.0.set(0);
+        let mut gauge = Gauge::new(device_id([1, 2, 3, 4]), Settings::default(), 0);
+        gauge.load(&mut storage).unwrap();
+
+        // Once the downlink is back they are sent again, dated by the running time the gauge carried on from.
+        let ack = rainguage_messages::AckPacket { device_id: device_id([1, 2, 3, 4]), sequence: 0 };
+        let mut buf = [0; FRAME_LEN];
+        let len = rainguage_messages::serialize_ack(&ack, &mut buf).unwrap();
+        radio.reply = Some(buf[..len].to_vec());
+        assert_eq!(Vec::<(u32, u32)>::new(), pass(&mut gauge, &mut radio, &mut storage));
+        assert_eq!(vec![(1, 240), (2, 180), (3, 120)], pass(&mut gauge, &mut radio, &mut storage));
+    }
+}
@@ -0,0 +1,54 @@
+use crate::sensor::Reading;
+
+/// The RTC, counting clock::TICKS_PER_SECOND since power on.  It wraps back to 0 every 36 hours.
+pub trait Clock {
+    fn count(&mut self) -> u32;
+}
+
+/// The LoRa radio.
+pub trait Radio {
+    type Error;
+
+    /// Send a frame, returning once it has gone.  Returns how many bytes were sent.
+    fn transmit(&mut self, frame: &[u8]) -> Result<usize, Self::Error>;
+
+    /// Put the radio to sleep until the next transmit.
+    fn sleep(&mut self) -> Result<(), Self::Error>;
+
+    fn set_frequency(&mut self, mhz: u32) -> Result<(), Self::Error>;
+
+    fn set_tx_power(&mut self, dbm: u8) -> Result<(), Self::Error>;
+
+    /// Listen for a frame for up to `timeout_ms`, returning its length, or 0 when nothing came.
+    fn receive(&mut self, buffer: &mut [u8], timeout_ms: u32) -> Result<usize, Self::Error>;
+}
+
+/// The temperature and humidity sensor.
+pub trait Sensor {
+    type Error;
+
+    fn read(&mut self) -> Result<Reading, Self::Error>;
+}
+
+/// The battery voltage, as the raw ADC reading.
+pub trait Adc {
+    fn read(&mut self) -> u16;
+}
+
+/// Non volatile storage, addressed from 0.  Like flash it is erased a block at a time, and written bytes have to be
+/// erased before they can be written again.
+pub trait Storage {
+    type Error;
+
+    fn capacity(&self) -> u32;
+
+    /// The bytes in each erase block.
+    fn erase_size(&self) -> u32;
+
+    fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), Self::Error>;
+
+    fn write(&mut self, address: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Erase the block holding `address`.
+    fn erase(&mut self, address: u32) -> Result<(), Self::Error>;
+}
@@ -0,0 +1,67 @@
+// How many telemetry packets are kept for the console's dump, about half an hour at the default interval.
+pub const HISTORY_LEN: usize = 32;
+
+/// What one telemetry packet said.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Record {
+    pub uptime_secs: u32,
+    pub loop_cnt: u32,
+    pub tip_cnt: u32,
+    pub temperature: f32,
+    pub humidity: f32,
+    pub vbat: u32
+}
+
+/// The last HISTORY_LEN records, the oldest is dropped to make room.  Only kept in RAM, so lost on reset.
+pub struct History {
+    records: [Option<Record>; HISTORY_LEN],
+    next: usize
+}
+
+impl History {
+    pub const fn new() -> History {
+        History {
+            records: [None; HISTORY_LEN],
+            next: 0
+        }
+    }
+
+    pub fn push(&mut self, record: Record) {
+        self.records[self.next] = Some(record);
+        self.next = (self.next + 1) % HISTORY_LEN;
+    }
+
+    /// Oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &Record> {
+        let (newer, older) = self.records.split_at(self.next);
+        older.iter().chain(newer.iter()).filter_map(|record| record.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(loop_cnt: u32) -> Record {
+        Record { uptime_secs: 0, loop_cnt, tip_cnt: 0, temperature: 0.0, humidity: 0.0, vbat: 0 }
+    }
+
+    #[test]
+    fn keeps_the_newest() {
+        let mut history = History::new();
+        assert_eq!(0, history.iter().count());
+
+        for loop_cnt in 0..3 {
+            history.push(record(loop_cnt));
+        }
+        assert_eq!(vec![0, 1, 2], history.iter().map(|record| record.loop_cnt).collect::<Vec<u32>>());
+
+        for loop_cnt in 3..HISTORY_LEN as u32 + 5 {
+            history.push(record(loop_cnt));
+        }
+        let loops = history.iter().map(|record| record.loop_cnt).collect::<Vec<u32>>();
+        assert_eq!(HISTORY_LEN, loops.len());
+        assert_eq!(5, loops[0]);
+        assert_eq!(HISTORY_LEN as u32 + 4, loops[HISTORY_LEN - 1]);
+    }
+}
@@ -0,0 +1,16 @@
+//! The rainguage's logic, apart from the hardware it runs on.  The firmware and the simulator supply the hardware
+//! through the traits in `hardware`, so all of this can be tested on the host with `cargo test`.
+#![cfg_attr(not(test), no_std)]
+
+pub mod clock;
+pub mod command;
+pub mod config;
+pub mod debounce;
+pub mod gauge;
+pub mod hardware;
+pub mod history;
+pub mod power;
+pub mod reading_log;
+pub mod sensor;
+pub mod tip_log;
+pub mod tips;
@@ -0,0 +1,127 @@
+// An alarm closer than this might be missed while the RTC synchronises, so it is treated as already past.
+const MIN_AHEAD: u32 = 4;
+
+/// True once the RTC count `now` has reached `at`, allowing for the count wrapping.
+pub fn reached(now: u32, at: u32) -> bool {
+    (now.wrapping_sub(at) as i32) >= 0
+}
+
+/// When to wake next, `period` ticks after `previous` so the loop keeps a steady pace however long it was awake.  When
+/// the loop overran, the periods it missed are skipped rather than run back to back.
+pub fn next_wake(previous: u32, now: u32, period: u32) -> u32 {
+    let next = previous.wrapping_add(period);
+    let ahead = next.wrapping_sub(now) as i32;
+    if ahead >= MIN_AHEAD as i32 {
+        return next;
+    }
+
+    let behind = (MIN_AHEAD as i32 - ahead) as u32;
+    next.wrapping_add((behind + period - 1) / period * period)
+}
+
+/// Where the time since power on has gone, in milliseconds, for the PowerPacket.  The main loop says what it was doing
+/// each time it changes between awake and asleep.
+pub struct PowerBudget {
+    last: u64,
+    awake: u64,
+    standby: u64,
+    tx: u64,
+    tip_wake_cnt: u32
+}
+
+impl PowerBudget {
+    pub const fn new(now: u64) -> PowerBudget {
+        PowerBudget {
+            last: now,
+            awake: 0,
+            standby: 0,
+            tx: 0,
+            tip_wake_cnt: 0
+        }
+    }
+
+    /// The processor was running from the last change until `now`.
+    pub fn awake(&mut self, now: u64) {
+        self.awake = self.awake + (now - self.last);
+        self.last = now;
+    }
+
+    /// The processor was in standby from the last change until `now`.
+    pub fn standby(&mut self, now: u64) {
+        self.standby = self.standby + (now - self.last);
+        self.last = now;
+    }
+
+    /// The radio spent `ms` transmitting.
+    pub fn transmitted(&mut self, ms: u64) {
+        self.tx = self.tx + ms;
+    }
+
+    pub fn tip_wake(&mut self) {
+        self.tip_wake_cnt = self.tip_wake_cnt.wrapping_add(1);
+    }
+
+    pub fn awake_ms(&self) -> u32 {
+        self.awake as u32
+    }
+
+    pub fn standby_ms(&self) -> u32 {
+        self.standby as u32
+    }
+
+    pub fn tx_ms(&self) -> u32 {
+        self.tx as u32
+    }
+
+    pub fn tip_wake_cnt(&self) -> u32 {
+        self.tip_wake_cnt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steady_pace() {
+        assert_eq!(1100, next_wake(1000, 1010, 100));
+        assert_eq!(1100, next_wake(1000, 1090, 100));
+    }
+
+    #[test]
+    fn skips_missed_periods() {
+        assert_eq!(1200, next_wake(1000, 1150, 100));
+        assert_eq!(1200, next_wake(1000, 1099, 100));
+        assert_eq!(1300, next_wake(1000, 1200, 100));
+    }
+
+    #[test]
+    fn wraps() {
+        assert_eq!(u32::MAX, next_wake(u32::MAX - 100, u32::MAX - 50, 100));
+        assert_eq!(99, next_wake(u32::MAX - 100, u32::MAX - 50, 200));
+        assert_eq!(149, next_wake(u32::MAX - 100, 100, 50));
+
+        assert!(reached(5, u32::MAX - 5));
+        assert!(!reached(u32::MAX - 5, 5));
+        assert!(reached(5, 5));
+    }
+
+    #[test]
+    fn budget() {
+        let mut budget = PowerBudget::new(1000);
+        budget.awake(2000);
+        budget.standby(12000);
+        budget.transmitted(250);
+        budget.awake(13000);
+        budget.tip_wake();
+
+        assert_eq!(2000, budget.awake_ms());
+        assert_eq!(10000, budget.standby_ms());
+        assert_eq!(250, budget.tx_ms());
+
+        // The packet's counts wrap.
+        budget.standby(13000 + u32::MAX as u64 + 1);
+        assert_eq!(10000, budget.standby_ms());
+        assert_eq!(1, budget.tip_wake_cnt());
+    }
+}
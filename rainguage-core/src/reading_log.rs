@@ -0,0 +1,361 @@
+use crc::{crc32, Hasher32};
+
+use crate::hardware::Storage;
+use crate::history::Record;
+
+// Each reading takes a slot, 8 to an erase block of 256 bytes.
+pub const SLOT_SIZE: u32 = 32;
+
+// The first erase block holds the log's epoch, picked when the log is started, so the readings from before the storage
+// was erased can be told from the new ones with the same sequences.
+//   epoch        4 bytes
+//   checksum     4 bytes, a crc32 of the epoch
+const HEADER_LEN: usize = 8;
+
+//   sequence     4 bytes
+//   uptime_secs  4 bytes - how long the gauge has been running, which carries on from the newest reading after a
+//                reset, see ReadingLog::resumed_secs
+//   loop_cnt     4 bytes
+//   tip_cnt      4 bytes
+//   temperature  4 bytes
+//   humidity     4 bytes
+//   vbat         2 bytes - the raw ADC reading, which is only 12 bits
+//   checksum     4 bytes, a crc32 of everything before it
+//   acked        2 bytes - left erased until the downlink acknowledges this reading, then cleared.  It is outside the
+//                checksum so it can be written after the rest.
+// All little endian.
+const LEN: usize = 26;
+const ACKED_OFFSET: u32 = LEN as u32 + 4;
+
+/// A reading with its sequence, the number the gauge gave it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Logged {
+    pub sequence: u32,
+    pub record: Record
+}
+
+/// Every reading the gauge sends, kept in storage until the downlink acknowledges it so it can be sent again when the
+/// downlink was not listening.  The readings go into the slots in turn, each with a sequence one more than the one
+/// before, from `start` to the end of the storage and then round again, erasing each block as it gets to it.  When the
+/// downlink has been away long enough to fill it the oldest readings go, acknowledged or not.  The sequences belong to
+/// the log's epoch, and start again from 1 in a new epoch when the storage is erased.
+///
+/// An ack marks the reading it names, so after a reset the newest marked reading says where the downlink is up to.
+pub struct ReadingLog {
+    // Where the epoch is, the slots are in the erase blocks after it.
+    header: u32,
+    start: u32,
+    slots: u32,
+    epoch: Option<u32>,
+    // The slot and sequence of the newest reading.
+    newest: Option<(u32, u32)>,
+    acked: Option<u32>,
+    // The newest reading's uptime_secs when it was loaded.
+    resumed_secs: u32
+}
+
+impl ReadingLog {
+    /// Keeps its epoch in the erase block at `start` and its readings from the next one to the end of the storage.
+    pub fn new<S: Storage>(storage: &S, start: u32) -> ReadingLog {
+        ReadingLog {
+            header: start,
+            start: start + storage.erase_size(),
+            slots: (storage.capacity() - start - storage.erase_size()) / SLOT_SIZE,
+            epoch: None,
+            newest: None,
+            acked: None,
+            resumed_secs: 0
+        }
+    }
+
+    /// Find the epoch, the newest reading and the newest acknowledged one.
+    pub fn load<S: Storage>(&mut self, storage: &mut S) -> Result<(), S::Error> {
+        self.newest = None;
+        self.acked = None;
+        self.resumed_secs = 0;
+
+        let mut header = [0; HEADER_LEN];
+        storage.read(self.header, &mut header)?;
+        self.epoch = if word(&header, 4) == checksum(&header[..4]) { Some(word(&header, 0)) } else { None };
+
+        let mut slot = [0; SLOT_SIZE as usize];
+        for index in 0..self.slots {
+            storage.read(self.address(index), &mut slot)?;
+            if let Some(logged) = decode(&slot) {
+                if self.newest.map(|(_, sequence)| logged.sequence > sequence).unwrap_or(true) {
+                    self.newest = Some((index, logged.sequence));
+                    self.resumed_secs = logged.record.uptime_secs;
+                }
+                if acked(&slot) && self.acked.map(|sequence| logged.sequence > sequence).unwrap_or(true) {
+                    self.acked = Some(logged.sequence);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The epoch the sequences belong to, None until the log is started.
+    pub fn epoch(&self) -> Option<u32> {
+        self.epoch
+    }
+
+    /// Start the log in a new epoch, which should be random so it is unlikely to be one the gauge had before.
+    pub fn begin<S: Storage>(&mut self, storage: &mut S, epoch: u32) -> Result<(), S::Error> {
+        let mut header = [0xff; HEADER_LEN];
+        header[..4].copy_from_slice(&epoch.to_le_bytes());
+        let checksum = checksum(&header[..4]);
+        header[4..].copy_from_slice(&checksum.to_le_bytes());
+
+        storage.erase(self.header)?;
+        storage.write(self.header, &header)?;
+        self.epoch = Some(epoch);
+        Ok(())
+    }
+
+    /// The sequence of the newest reading.
+    pub fn newest(&self) -> Option<u32> {
+        self.newest.map(|(_, sequence)| sequence)
+    }
+
+    /// Everything up to this sequence has been acknowledged.
+    pub fn acked(&self) -> Option<u32> {
+        self.acked
+    }
+
+    /// Where the readings' running time carries on from after a reset, the uptime_secs of the newest reading there was
+    /// when it was loaded.  The time from that reading to the reset, and any time the gauge was off, is not counted,
+    /// so older readings look that much more recent.
+    pub fn resumed_secs(&self) -> u32 {
+        self.resumed_secs
+    }
+
+    /// Add a reading, returning its sequence.
+    pub fn append<S: Storage>(&mut self, storage: &mut S, record: &Record) -> Result<u32, S::Error> {
+        let block = storage.erase_size() / SLOT_SIZE;
+        let (mut index, mut sequence) = match self.newest {
+            Some((index, sequence)) => ((index + 1) % self.slots, sequence.wrapping_add(1)),
+            // From 1, so the downlink has 0 to acknowledge before it has any of them.
+            None => (0, 1)
+        };
+
+        // A slot that is not blank was being written when the power went.  It still takes a sequence, so that a
+        // reading's slot can be worked out from its sequence.
+        loop {
+            if index % block == 0 {
+                storage.erase(self.address(index))?;
+                break;
+            }
+            if blank(storage, self.address(index))? {
+                break;
+            }
+            index = (index + 1) % self.slots;
+            sequence = sequence.wrapping_add(1);
+        }
+
+        let mut slot = [0xff; SLOT_SIZE as usize];
+        encode(&Logged { sequence, record: *record }, &mut slot);
+        storage.write(self.address(index), &slot)?;
+
+        self.newest = Some((index, sequence));
+        Ok(sequence)
+    }
+
+    /// The reading with this sequence, None when it is not kept.
+    pub fn read<S: Storage>(&self, storage: &mut S, sequence: u32) -> Result<Option<Logged>, S::Error> {
+        let address = match self.slot(sequence) {
+            Some(index) => self.address(index),
+            None => return Ok(None)
+        };
+
+        let mut slot = [0; SLOT_SIZE as usize];
+        storage.read(address, &mut slot)?;
+        Ok(decode(&slot).filter(|logged| logged.sequence == sequence))
+    }
+
+    /// The oldest reading before `before` that is kept and has not been acknowledged.
+    pub fn first_unacked<S: Storage>(&self, storage: &mut S, before: u32) -> Result<Option<u32>, S::Error> {
+        let newest = match self.newest {
+            Some((_, newest)) => newest,
+            None => return Ok(None)
+        };
+
+        let oldest = (newest + 1).saturating_sub(self.slots);
+        let mut sequence = self.acked.map(|acked| acked + 1).unwrap_or(0).max(oldest);
+        while sequence < before.min(newest + 1) {
+            if self.read(storage, sequence)?.is_some() {
+                return Ok(Some(sequence));
+            }
+            sequence = sequence + 1;
+        }
+        Ok(None)
+    }
+
+    /// The downlink has every reading up to `sequence`.
+    pub fn ack<S: Storage>(&mut self, storage: &mut S, sequence: u32) -> Result<(), S::Error> {
+        let newer = self.acked.map(|acked| sequence > acked).unwrap_or(true);
+        if !newer || self.newest().map(|newest| sequence > newest).unwrap_or(true) {
+            return Ok(());
+        }
+
+        self.acked = Some(sequence);
+        if self.read(storage, sequence)?.is_some() {
+            let address = self.address(self.slot(sequence).unwrap());
+            storage.write(address + ACKED_OFFSET, &[0, 0])?;
+        }
+        Ok(())
+    }
+
+    fn slot(&self, sequence: u32) -> Option<u32> {
+        let (index, newest) = self.newest?;
+        if sequence > newest || newest - sequence >= self.slots {
+            return None;
+        }
+        Some((index + self.slots - (newest - sequence)) % self.slots)
+    }
+
+    fn address(&self, index: u32) -> u32 {
+        self.start + index * SLOT_SIZE
+    }
+}
+
+fn blank<S: Storage>(storage: &mut S, address: u32) -> Result<bool, S::Error> {
+    let mut slot = [0; SLOT_SIZE as usize];
+    storage.read(address, &mut slot)?;
+    Ok(slot.iter().all(|byte| *byte == 0xff))
+}
+
+fn acked(slot: &[u8]) -> bool {
+    slot[ACKED_OFFSET as usize..ACKED_OFFSET as usize + 2] != [0xff, 0xff]
+}
+
+fn encode(logged: &Logged, slot: &mut [u8]) {
+    let record = &logged.record;
+    let words = [logged.sequence, record.uptime_secs, record.loop_cnt, record.tip_cnt, record.temperature.to_bits(),
+        record.humidity.to_bits()];
+    for (i, word) in words.iter().enumerate() {
+        slot[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    slot[24..LEN].copy_from_slice(&(record.vbat.min(u16::MAX as u32) as u16).to_le_bytes());
+
+    let checksum = checksum(&slot[..LEN]);
+    slot[LEN..LEN + 4].copy_from_slice(&checksum.to_le_bytes());
+}
+
+fn decode(slot: &[u8]) -> Option<Logged> {
+    if word(slot, LEN) != checksum(&slot[..LEN]) {
+        return None;
+    }
+
+    Some(Logged {
+        sequence: word(slot, 0),
+        record: Record {
+            uptime_secs: word(slot, 4),
+            loop_cnt: word(slot, 8),
+            tip_cnt: word(slot, 12),
+            temperature: f32::from_bits(word(slot, 16)),
+            humidity: f32::from_bits(word(slot, 20)),
+            vbat: u16::from_le_bytes([slot[24], slot[25]]) as u32
+        }
+    })
+}
+
+fn word(slot: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([slot[offset], slot[offset + 1], slot[offset + 2], slot[offset + 3]])
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut digest = crc32::Digest::new(crc32::IEEE);
+    digest.write(bytes);
+    digest.sum32()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::tests::{TestStorage, ERASE_SIZE};
+
+    use super::*;
+
+    fn record(loop_cnt: u32) -> Record {
+        Record { uptime_secs: loop_cnt / 3, loop_cnt, tip_cnt: 2, temperature: 12.5, humidity: 80.0, vbat: 2600 }
+    }
+
+    #[test]
+    fn keeps_readings_until_they_are_acked() {
+        let mut storage = TestStorage::new(5);
+        let mut log = ReadingLog::new(&storage, ERASE_SIZE);
+        log.load(&mut storage).unwrap();
+        assert_eq!(None, log.first_unacked(&mut storage, 100).unwrap());
+        assert_eq!(None, log.epoch());
+        log.begin(&mut storage, 0x1234_5678).unwrap();
+
+        for loop_cnt in 1..21 {
+            assert_eq!(loop_cnt, log.append(&mut storage, &record(loop_cnt)).unwrap());
+        }
+        assert_eq!(Some(Logged { sequence: 7, record: record(7) }), log.read(&mut storage, 7).unwrap());
+        assert_eq!(Some(1), log.first_unacked(&mut storage, 21).unwrap());
+        assert_eq!(None, log.first_unacked(&mut storage, 1).unwrap());
+
+        log.ack(&mut storage, 11).unwrap();
+        assert_eq!(Some(12), log.first_unacked(&mut storage, 21).unwrap());
+        // Going back, or past the newest, is ignored.
+        log.ack(&mut storage, 4).unwrap();
+        log.ack(&mut storage, 21).unwrap();
+        assert_eq!(Some(11), log.acked());
+
+        // After a reset.
+        let mut log = ReadingLog::new(&storage, ERASE_SIZE);
+        log.load(&mut storage).unwrap();
+        assert_eq!(Some(0x1234_5678), log.epoch());
+        assert_eq!(Some(20), log.newest());
+        assert_eq!(Some(11), log.acked());
+        assert_eq!(record(20).uptime_secs, log.resumed_secs());
+        assert_eq!(21, log.append(&mut storage, &record(21)).unwrap());
+        // Not the storage before it.
+        assert!(storage.bytes[..ERASE_SIZE as usize].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn drops_the_oldest_when_full() {
+        let mut storage = TestStorage::new(3);
+        let mut log = ReadingLog::new(&storage, 0);
+        log.load(&mut storage).unwrap();
+
+        // The first block is the epoch's.
+        let slots = 2 * ERASE_SIZE / SLOT_SIZE;
+        for loop_cnt in 1..slots + 4 {
+            log.append(&mut storage, &record(loop_cnt)).unwrap();
+        }
+
+        // The first block of readings was erased for the newest 3, the rest are still there.
+        let block = ERASE_SIZE / SLOT_SIZE;
+        assert_eq!(None, log.read(&mut storage, block).unwrap());
+        assert_eq!(Some(block + 1), log.first_unacked(&mut storage, slots + 4).unwrap());
+        assert_eq!(Some(record(slots + 3)), log.read(&mut storage, slots + 3).unwrap().map(|logged| logged.record));
+        assert_eq!(vec![0, 2, 1], storage.erase_cnt);
+
+        let mut log = ReadingLog::new(&storage, 0);
+        log.load(&mut storage).unwrap();
+        assert_eq!(Some(slots + 3), log.newest());
+        assert_eq!(None, log.acked());
+    }
+
+    #[test]
+    fn skips_a_damaged_slot() {
+        let mut storage = TestStorage::new(2);
+        let mut log = ReadingLog::new(&storage, 0);
+        log.load(&mut storage).unwrap();
+        for loop_cnt in 1..4 {
+            log.append(&mut storage, &record(loop_cnt)).unwrap();
+        }
+
+        // Power lost part way through writing the fourth.
+        storage.bytes[(ERASE_SIZE + 3 * SLOT_SIZE) as usize + 5] = 0;
+        let mut log = ReadingLog::new(&storage, 0);
+        log.load(&mut storage).unwrap();
+        assert_eq!(Some(3), log.newest());
+
+        assert_eq!(5, log.append(&mut storage, &record(5)).unwrap());
+        assert_eq!(None, log.read(&mut storage, 4).unwrap());
+        assert_eq!(Some(record(5)), log.read(&mut storage, 5).unwrap().map(|logged| logged.record));
+    }
+}
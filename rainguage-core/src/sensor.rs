@@ -0,0 +1,81 @@
+use crate::clock::Every;
+
+/// A temperature and humidity reading.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reading {
+    pub temperature: f32,
+    pub humidity: f32
+}
+
+// The DHT22 needs two seconds between reads.
+pub const RETRY_DELAY_MS: u64 = 2_000;
+
+// How many more times a failed read is tried before waiting for the next interval.
+pub const RETRIES: u8 = 2;
+
+/// When to read the sensor.  A failed read is tried again RETRY_DELAY_MS later, up to RETRIES times, rather than
+/// leaving the last reading in place for a whole interval.
+pub struct SensorSchedule {
+    every: Every,
+    retry_at: Option<u64>,
+    retries_left: u8
+}
+
+impl SensorSchedule {
+    pub const fn new(interval_ms: u64) -> SensorSchedule {
+        SensorSchedule {
+            every: Every::new(interval_ms),
+            retry_at: None,
+            retries_left: 0
+        }
+    }
+
+    pub fn due(&mut self, now_ms: u64) -> bool {
+        if self.every.due(now_ms) {
+            self.retry_at = None;
+            self.retries_left = RETRIES;
+            return true;
+        }
+
+        match self.retry_at {
+            Some(at) if now_ms >= at => {
+                self.retry_at = None;
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// The read that was due at `now_ms` failed.
+    pub fn failed(&mut self, now_ms: u64) {
+        if self.retries_left > 0 {
+            self.retries_left = self.retries_left - 1;
+            self.retry_at = Some(now_ms + RETRY_DELAY_MS);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_then_waits_for_the_interval() {
+        let mut schedule = SensorSchedule::new(60_000);
+        assert!(schedule.due(0));
+        schedule.failed(0);
+
+        assert!(!schedule.due(1_999));
+        assert!(schedule.due(2_000));
+        schedule.failed(2_000);
+        assert!(schedule.due(4_100));
+        schedule.failed(4_100);
+
+        // Out of retries.
+        assert!(!schedule.due(30_000));
+        assert!(schedule.due(60_000));
+
+        // A read that worked is not retried.
+        assert!(!schedule.due(62_000));
+    }
+}
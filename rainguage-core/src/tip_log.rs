@@ -0,0 +1,96 @@
+// Enough for a downpour between two telemetry packets, the same as rainguage_messages::MAX_TIPS.
+const SIZE: usize = 16;
+
+/// The loop count at each tip since the last TipsPacket.  When there are more tips than fit the oldest are dropped,
+/// they are still counted in tip_cnt.
+pub struct TipLog {
+    loops: [u32; SIZE],
+    len: usize,
+    next: usize
+}
+
+impl TipLog {
+    pub const fn new() -> TipLog {
+        TipLog {
+            loops: [0; SIZE],
+            len: 0,
+            next: 0
+        }
+    }
+
+    pub fn record(&mut self, loop_cnt: u32) {
+        self.loops[self.next] = loop_cnt;
+        self.next = (self.next + 1) % SIZE;
+        if self.len < SIZE {
+            self.len = self.len + 1;
+        }
+    }
+
+    /// Write how many loops before `loop_cnt` each tip happened into `loops_before`, oldest first, and empty the log.
+    /// Only the newest tips are written when `loops_before` is too short for them all.  Returns how many were written.
+    pub fn drain(&mut self, loop_cnt: u32, loops_before: &mut [u16]) -> usize {
+        let count = self.len.min(loops_before.len());
+
+        for i in 0..count {
+            let index = (self.next + SIZE - count + i) % SIZE;
+            let before = loop_cnt.wrapping_sub(self.loops[index]);
+            loops_before[i] = if before > u16::MAX as u32 { u16::MAX } else { before as u16 };
+        }
+
+        self.len = 0;
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loops_before_the_report() {
+        let mut log = TipLog::new();
+        log.record(100);
+        log.record(150);
+        log.record(199);
+
+        let mut loops_before = [0; 16];
+        assert_eq!(3, log.drain(200, &mut loops_before));
+        assert_eq!([100, 50, 1], loops_before[..3]);
+
+        assert_eq!(0, log.drain(400, &mut loops_before));
+    }
+
+    #[test]
+    fn keeps_the_newest() {
+        let mut log = TipLog::new();
+        for loop_cnt in 0..40 {
+            log.record(loop_cnt);
+        }
+
+        let mut loops_before = [0; 16];
+        assert_eq!(16, log.drain(40, &mut loops_before));
+        assert_eq!(16, loops_before[0]);
+        assert_eq!(1, loops_before[15]);
+
+        log.record(41);
+        log.record(42);
+        let mut short = [0; 1];
+        assert_eq!(1, log.drain(42, &mut short));
+        assert_eq!([0], short);
+    }
+
+    #[test]
+    fn wraps_and_saturates() {
+        let mut log = TipLog::new();
+        log.record(u32::MAX - 1);
+        log.record(0);
+
+        let mut loops_before = [0; 16];
+        assert_eq!(2, log.drain(100_000, &mut loops_before));
+        assert_eq!([u16::MAX, u16::MAX], loops_before[..2]);
+
+        log.record(u32::MAX - 1);
+        assert_eq!(1, log.drain(3, &mut loops_before));
+        assert_eq!(5, loops_before[0]);
+    }
+}
@@ -0,0 +1,76 @@
+use rainguage_messages::TipsPacket;
+
+use crate::debounce::Debouncer;
+use crate::tip_log::TipLog;
+
+/// Counts the bucket's tips from samples of its reed switch, and notes the loop count at each one for the TipsPacket.
+pub struct TipCounter {
+    debouncer: Debouncer,
+    log: TipLog,
+    tip_cnt: u32
+}
+
+impl TipCounter {
+    pub const fn new() -> TipCounter {
+        TipCounter {
+            debouncer: Debouncer::new(),
+            log: TipLog::new(),
+            tip_cnt: 0
+        }
+    }
+
+    /// Feed one sample of the switch, taken during loop `loop_cnt`.  Returns true when the sample completes a tip.
+    pub fn sample(&mut self, closed: bool, loop_cnt: u32) -> bool {
+        if !self.debouncer.sample(closed) {
+            return false;
+        }
+
+        self.tip_cnt = self.tip_cnt.wrapping_add(1);
+        self.log.record(loop_cnt);
+        true
+    }
+
+    /// The switch has settled open, so it does not need sampling until it closes again.
+    pub fn idle(&self) -> bool {
+        self.debouncer.idle()
+    }
+
+    /// Tips since power on.
+    pub fn tip_cnt(&self) -> u32 {
+        self.tip_cnt
+    }
+
+    /// Fill in the tips since the last report for a telemetry packet sent in loop `loop_cnt`, and empty the log.  The
+    /// count and the log are read together, so a tip cannot be in one and not the other.
+    pub fn report(&mut self, loop_cnt: u32, tips: &mut TipsPacket) {
+        tips.len = self.log.drain(loop_cnt, &mut tips.loops_before) as u8;
+        tips.loop_cnt = loop_cnt;
+        tips.tip_cnt = self.tip_cnt;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_and_logs_tips() {
+        let mut counter = TipCounter::new();
+        for loop_cnt in [10, 20].iter() {
+            let tips = (0..20).filter(|_| counter.sample(true, *loop_cnt)).count()
+                + (0..20).filter(|_| counter.sample(false, *loop_cnt)).count();
+            assert_eq!(1, tips);
+            assert!(counter.idle());
+        }
+
+        let mut tips = TipsPacket::new();
+        counter.report(25, &mut tips);
+        assert_eq!(2, tips.tip_cnt);
+        assert_eq!(25, tips.loop_cnt);
+        assert_eq!([15, 5], tips.loops_before());
+
+        counter.report(30, &mut tips);
+        assert_eq!(2, tips.tip_cnt);
+        assert_eq!(0, tips.len);
+    }
+}
@@ -1,7 +1,7 @@
 MEMORY
 {
-  /* Leave 8k for the default bootloader on the Feather M0 */
-  FLASH (rx) : ORIGIN = 0x00000000 + 8K, LENGTH = 256K - 8K
+  /* Leave 8k for the default bootloader on the Feather M0, and the top 64k for storage, see flash.rs */
+  FLASH (rx) : ORIGIN = 0x00000000 + 8K, LENGTH = 256K - 8K - 64K
   RAM (xrw)  : ORIGIN = 0x20000000, LENGTH = 32K
 }
 _stack_start = ORIGIN(RAM) + LENGTH(RAM);
@@ -1,6 +1,7 @@
 extern crate feather_m0 as hal;
 use crate::hal::pac::ADC;
 use crate::hal::clock::GenericClockController;
+use rainguage_core::hardware::Adc;
 
 pub struct AnalogPin {
     adc: ADC
@@ -59,11 +60,13 @@ impl AnalogPin {
     fn sync_adc(&mut self) {
         while self.adc.status.read().syncbusy().bit_is_set() { }
     }
+}
 
+impl Adc for AnalogPin {
     /*
      * Read the voltage from a pin.
      */
-    pub fn read(&mut self) -> u16 {
+    fn read(&mut self) -> u16 {
         self.sync_adc(); 
         self.adc.inputctrl.write(|w| w.muxpos().pin7());        
 
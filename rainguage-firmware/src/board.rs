@@ -0,0 +1,120 @@
+extern crate feather_m0 as hal;
+use crate::hal::gpio::{OpenDrain, Output, Pa16, Port};
+use core::ptr;
+use cortex_m::asm::delay as cycle_delay;
+use cortex_m::peripheral::SCB;
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::OutputPin;
+use rainguage_core::hardware::{Radio, Sensor};
+use rainguage_core::sensor::Reading;
+use sx127x_lora::{Error, LoRa, RadioMode};
+
+use crate::dht22;
+use crate::dht22::DhtError;
+
+// The processor runs from the 48MHz GCLK0.
+const CYCLES_PER_US: u32 = 48;
+
+// The feather's bootloader stays put after a reset, rather than starting the firmware, when it finds this in the last
+// word of RAM.  It is what double tapping the reset button leaves there.
+const BOOTLOADER_MAGIC: u32 = 0x0773_8135;
+const BOOTLOADER_MAGIC_ADDRESS: usize = 0x2000_7ffc;
+
+/// The DHT22 on D5 (PA16).  The pin is an output to wake the sensor and an input to read it.
+pub struct Dht22 {
+    // Only None while a read has it as an input.
+    pin: Option<Pa16<Output<OpenDrain>>>,
+    port: Port
+}
+
+impl Dht22 {
+    pub fn new(pin: Pa16<Output<OpenDrain>>, port: Port) -> Dht22 {
+        Dht22 {
+            pin: Some(pin),
+            port
+        }
+    }
+}
+
+impl Sensor for Dht22 {
+    type Error = DhtError;
+
+    fn read(&mut self) -> Result<Reading, DhtError> {
+        let mut pin = self.pin.take().ok_or(DhtError::IO)?;
+        let mut delay_us = |us: u32| cycle_delay(us * CYCLES_PER_US);
+
+        let result = match dht22::init(&mut pin, &mut delay_us) {
+            Ok(_) => {
+                let mut in_pin = pin.into_floating_input(&mut self.port);
+                let result = dht22::read::<()>(&mut in_pin);
+                pin = in_pin.into_open_drain_output(&mut self.port);
+                result
+            },
+            Err(err) => Err(err)
+        };
+
+        self.pin = Some(pin);
+        result
+    }
+}
+
+/// The rfm95.  Frames go out whole, the gauge leaves room for the RadioHead header.
+pub struct Lora<SPI, CS, RESET, DELAY> {
+    lora: LoRa<SPI, CS, RESET, DELAY>
+}
+
+impl<SPI, CS, RESET, DELAY> Lora<SPI, CS, RESET, DELAY> {
+    pub fn new(lora: LoRa<SPI, CS, RESET, DELAY>) -> Lora<SPI, CS, RESET, DELAY> {
+        Lora {
+            lora
+        }
+    }
+}
+
+impl<SPI, CS, RESET, DELAY, E> Radio for Lora<SPI, CS, RESET, DELAY>
+    where SPI: Transfer<u8, Error = E> + Write<u8, Error = E>, CS: OutputPin, RESET: OutputPin, DELAY: DelayMs<u8> {
+    type Error = Error<E, CS::Error, RESET::Error>;
+
+    fn transmit(&mut self, frame: &[u8]) -> Result<usize, Self::Error> {
+        let mut buffer = [0; 255];
+        buffer[..frame.len()].copy_from_slice(frame);
+        self.lora.transmit_payload_busy(buffer, frame.len())
+    }
+
+    fn sleep(&mut self) -> Result<(), Self::Error> {
+        self.lora.set_mode(RadioMode::Sleep)
+    }
+
+    fn set_frequency(&mut self, mhz: u32) -> Result<(), Self::Error> {
+        self.lora.set_frequency(mhz as i64)
+    }
+
+    // Through PA_BOOST, the only amplifier the rfm95 has wired to the antenna.
+    fn set_tx_power(&mut self, dbm: u8) -> Result<(), Self::Error> {
+        self.lora.set_tx_power(dbm as i32, 1)
+    }
+
+    fn receive(&mut self, buffer: &mut [u8], timeout_ms: u32) -> Result<usize, Self::Error> {
+        match self.lora.poll_irq(Some(timeout_ms as i32)) {
+            Ok(len) => {
+                let packet = self.lora.read_packet()?;
+                let len = len.min(buffer.len());
+                buffer[..len].copy_from_slice(&packet[..len]);
+                Ok(len)
+            },
+            // Nothing came before the timeout.
+            Err(Error::Uninformative) => Ok(0),
+            Err(err) => Err(err)
+        }
+    }
+}
+
+/// Restart into the bootloader, so new firmware can be loaded without pressing reset.
+pub fn reboot_to_bootloader() -> ! {
+    // Time for the usb interrupt to send whatever was written last.
+    cycle_delay(100_000 * CYCLES_PER_US);
+
+    unsafe { ptr::write_volatile(BOOTLOADER_MAGIC_ADDRESS as *mut u32, BOOTLOADER_MAGIC); }
+    SCB::sys_reset()
+}
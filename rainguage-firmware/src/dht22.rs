@@ -1,5 +1,6 @@
 use core::prelude::v1::Result;
 use embedded_hal::digital::v2::{InputPin, OutputPin};
+use rainguage_core::sensor::Reading;
 
 #[derive(Debug)]
 pub enum DhtError {
@@ -8,13 +9,6 @@ pub enum DhtError {
     Timeout
 }
 
-/// A temperature and humidity reading from the DHT22.
-#[derive(Debug)]
-pub struct Reading {
-    pub temperature: f32,
-    pub humidity: f32
-}
-
 const MAX_COUNT:usize = 2000000;
 const DHT_PULSES:usize = 41;
 
@@ -0,0 +1,127 @@
+extern crate feather_m0 as hal;
+use core::ptr;
+use crate::hal::pac::NVMCTRL;
+use rainguage_core::hardware::Storage;
+
+// memory.x leaves the top 64K of flash out of the firmware's, for storage.
+const START: u32 = 0x0003_0000;
+const CAPACITY: u32 = 0x0001_0000;
+
+// The SAMD21 writes flash a 64 byte page at a time, and erases a 4 page row.
+const PAGE_SIZE: u32 = 64;
+const ROW_SIZE: u32 = 256;
+
+// NVMCTRL CTRLA commands, which only run with the key in the top byte.
+const CMDEX_KEY: u16 = 0xa5 << 8;
+const CMD_ERASE_ROW: u16 = 0x02;
+const CMD_WRITE_PAGE: u16 = 0x04;
+const CMD_PAGE_BUFFER_CLEAR: u16 = 0x44;
+
+// The STATUS bits for a command that failed, a programming error, a locked region and an NVM error.
+const STATUS_ERRORS: u16 = 0x1c;
+
+#[derive(Debug)]
+pub enum FlashError {
+    OutOfRange,
+    /// NVMCTRL refused the command.
+    Nvm
+}
+
+/// The flash kept for storage, addressed from 0.
+pub struct Flash {
+    nvmctrl: NVMCTRL
+}
+
+impl Flash {
+    pub fn new(nvmctrl: NVMCTRL) -> Flash {
+        // Pages are written by command rather than as soon as the last word of the page buffer is.
+        nvmctrl.ctrlb.modify(|_, w| w.manw().set_bit());
+
+        Flash {
+            nvmctrl
+        }
+    }
+
+    fn check(&self, address: u32, len: usize) -> Result<(), FlashError> {
+        if address as u64 + len as u64 > CAPACITY as u64 {
+            return Err(FlashError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    fn wait_ready(&self) {
+        while self.nvmctrl.intflag.read().ready().bit_is_clear() { }
+    }
+
+    fn execute(&mut self, command: u16, address: u32) -> Result<(), FlashError> {
+        self.wait_ready();
+        unsafe {
+            self.nvmctrl.status.write(|w| w.bits(STATUS_ERRORS));
+            // In 16 bit words.
+            self.nvmctrl.addr.write(|w| w.bits(address >> 1));
+            self.nvmctrl.ctrla.write(|w| w.bits(CMDEX_KEY | command));
+        }
+        self.wait_ready();
+
+        if self.nvmctrl.status.read().bits() & STATUS_ERRORS != 0 {
+            return Err(FlashError::Nvm);
+        }
+        Ok(())
+    }
+
+    // `bytes` is the whole page, the page buffer only takes 32 bit words.
+    fn write_page(&mut self, page: u32, bytes: &[u8; PAGE_SIZE as usize]) -> Result<(), FlashError> {
+        self.execute(CMD_PAGE_BUFFER_CLEAR, page)?;
+        for (i, word) in bytes.chunks(4).enumerate() {
+            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            unsafe { ptr::write_volatile((page + i as u32 * 4) as *mut u32, word); }
+        }
+        self.execute(CMD_WRITE_PAGE, page)
+    }
+}
+
+impl Storage for Flash {
+    type Error = FlashError;
+
+    fn capacity(&self) -> u32 {
+        CAPACITY
+    }
+
+    fn erase_size(&self) -> u32 {
+        ROW_SIZE
+    }
+
+    fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), FlashError> {
+        self.check(address, buffer.len())?;
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = unsafe { ptr::read_volatile((START + address + i as u32) as *const u8) };
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        self.check(address, data.len())?;
+
+        let mut written = 0;
+        while written < data.len() {
+            let at = START + address + written as u32;
+            let page = at - at % PAGE_SIZE;
+            let offset = (at - page) as usize;
+            let len = (PAGE_SIZE as usize - offset).min(data.len() - written);
+
+            // Writing a 1 leaves a bit as it was, so the rest of the page is left alone.
+            let mut bytes = [0xff; PAGE_SIZE as usize];
+            bytes[offset..offset + len].copy_from_slice(&data[written..written + len]);
+            self.write_page(page, &bytes)?;
+
+            written = written + len;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), FlashError> {
+        self.check(address, 1)?;
+        let at = START + address;
+        self.execute(CMD_ERASE_ROW, at - at % ROW_SIZE)
+    }
+}
@@ -9,42 +9,54 @@ extern crate usb_device;
 extern crate usbd_serial;
 extern crate embedded_hal;
 extern crate sx127x_lora;
-extern crate rainguage_messages;
+extern crate rainguage_core;
 
 mod analog_pin;
+mod board;
 mod dht22;
+mod flash;
 mod metrics;
+mod rtc;
 mod usb_write;
 
-use rainguage_messages::TelemetryPacket;
+use rainguage_core::command::{Command, CommandError, LineBuffer};
+use rainguage_core::config::ConfigStore;
+use rainguage_core::gauge;
+use rainguage_core::gauge::{Gauge, Settings, LOOP_TICKS};
+use rainguage_core::hardware::Clock;
+use rainguage_core::power;
+use rainguage_core::tips::TipCounter;
 
 use analog_pin::AnalogPin;
+use board::{Dht22, Lora};
+use core::cell::{Cell, RefCell};
 use core::fmt::Write;
 use cortex_m::asm::delay as cycle_delay;
+use cortex_m::interrupt::{CriticalSection, Mutex};
 use cortex_m::peripheral::NVIC;
+use cortex_m::peripheral::SCB;
+use embedded_hal::digital::v2::InputPin;
 use embedded_hal::digital::v2::OutputPin;
+use flash::Flash;
 use hal::clock::GenericClockController;
 use hal::delay::Delay;
+use hal::eic::pin::{ExtInt4, Sense};
 use hal::entry;
-use hal::pac::{interrupt, CorePeripherals, Peripherals};
+use hal::gpio::{Interrupt, Pa20, PullUp};
+use hal::pac::{interrupt, CorePeripherals, Peripherals, EIC as EicRegisters, TC3 as Tc3Registers};
 use hal::prelude::*;
-use hal::time::MegaHertz;
+use hal::time::{KiloHertz, MegaHertz};
+use hal::timer::{TimerCounter, TimerCounter3};
 
 use hal::usb::UsbBus;
+use rtc::Rtc;
 use sx127x_lora::LoRa;
+use sx127x_lora::RadioMode;
 use usb_device::bus::UsbBusAllocator;
 use usb_device::prelude::*;
 use usb_write::UsbWrite;
 use usbd_serial::{SerialPort, USB_CLASS_CDC};
 
-const FREQUENCY: i64 = 915;
-
-// How frequently should we transmit.  So every TRANSMIT_CYCLE loops we will sent a telemetry packer.
-// 200 is roughly once per minute.
-const TRANSMIT_CYCLE: usize = 160;
-
-// How frequently should we measure temperature.
-const TEMPERATURE_CYCLE: usize = TRANSMIT_CYCLE * 1;
 
 #[entry]
 fn main() -> ! {
@@ -63,35 +75,36 @@ fn main() -> ! {
 
     let mut parts = peripherals.PORT.split();
 
+    // The settings are kept at the start of the flash set aside for storage, and the readings after them.
+    let mut flash = Flash::new(peripherals.NVMCTRL);
+    let mut config = ConfigStore::new(0);
+
     let usb_dm = parts.pa24;
     let usb_dp = parts.pa25;
 
     // Sleep for 5s on the startup to aid resetting to booting mode.
     cycle_delay(60 * 1024 * 1024);
 
-    let bus_allocator = unsafe {
-        USB_ALLOCATOR = Some(hal::usb_allocator(
+    let bus_allocator: &'static UsbBusAllocator<UsbBus> = cortex_m::singleton!(: UsbBusAllocator<UsbBus> =
+        hal::usb_allocator(
             peripherals.USB,
             &mut clocks,
             &mut peripherals.PM,
             usb_dm,
             usb_dp,
             &mut parts.port,
-        ));
-        USB_ALLOCATOR.as_ref().unwrap()
+        )).unwrap();
+
+    let usb = Usb {
+        serial: SerialPort::new(bus_allocator),
+        device: UsbDeviceBuilder::new(bus_allocator, UsbVidPid(0x16c0, 0x27dd))
+            .manufacturer("Fake company")
+            .product("Serial port")
+            .serial_number("TEST")
+            .device_class(USB_CLASS_CDC)
+            .build()
     };
-
-    unsafe {
-        USB_SERIAL = Some(SerialPort::new(&bus_allocator));
-        USB_BUS = Some(
-            UsbDeviceBuilder::new(&bus_allocator, UsbVidPid(0x16c0, 0x27dd))
-                .manufacturer("Fake company")
-                .product("Serial port")
-                .serial_number("TEST")
-                .device_class(USB_CLASS_CDC)
-                .build()
-        );
-    }
+    cortex_m::interrupt::free(|cs| USB.borrow(cs).replace(Some(usb)));
 
     unsafe {
         core.NVIC.set_priority(interrupt::USB, 1);
@@ -125,13 +138,19 @@ fn main() -> ! {
     let reset_out = parts.pa8.into_open_drain_output(&mut parts.port);
     let _ = parts.pa9.into_open_drain_output(&mut parts.port); // int_out lora interrupt line
 
-    unsafe {
-        DELAY = Some(Delay::new(core.SYST, &mut clocks));
-    }
-
-    let lora_delay = SharedDelay { };    
+    // The radio has SysTick to itself, the DHT22 counts cycles instead.
+    let lora_delay = Delay::new(core.SYST, &mut clocks);
+    let defaults = Settings::default();
+    let mut settings = match config.load(&mut flash, &defaults) {
+        Ok(Some(settings)) => settings,
+        Ok(None) => defaults,
+        Err(err) => {
+            write!(usb_write, "Error loading the settings:{:?}", err).unwrap();
+            defaults
+        }
+    };
     let mut lora = match LoRa::new(
-         lora_spi, cs_out, reset_out, FREQUENCY,
+         lora_spi, cs_out, reset_out, settings.frequency_mhz as i64,
          lora_delay) {
             Ok(lora) => lora,
             Err(_) => {
@@ -139,156 +158,148 @@ fn main() -> ! {
             }
         };
 
-    if let Err(err) = lora.set_tx_power(20, 1) {
+    if let Err(err) = lora.set_tx_power(settings.tx_power_dbm as i32, 1) {
         write!(usb_write, "Error setting power:{:?}", err).unwrap();
     }
 
-    let mut dht22_pin = parts.pa16.into_open_drain_output(&mut parts.port);    
+    // The radio sleeps between transmissions, transmit_payload_busy wakes it.
+    if let Err(err) = lora.set_mode(RadioMode::Sleep) {
+        write!(usb_write, "Error putting lora to sleep:{:?}", err).unwrap();
+    }
+
+    let dht22_pin = parts.pa16.into_open_drain_output(&mut parts.port);
+
+    let mut rtc = Rtc::new(&mut clocks, peripherals.RTC);
+
+    // The tipping bucket's reed switch pulls D6 to ground each time the bucket tips, see the EIC and TC3 interrupts.
+    // The EIC runs from the 32kHz clock the RTC keeps running in standby, so that a tip wakes the processor.
+    let gclk0 = clocks.gclk0();
+    let gclk1 = clocks.gclk1();
+    let mut eic = hal::eic::EIC::init(&mut peripherals.PM, clocks.eic(&gclk1).unwrap(), peripherals.EIC);
+    let mut tip_pin = ExtInt4::new(parts.pa20.into_pull_up_ei(&mut parts.port));
+    tip_pin.sense(&mut eic, Sense::FALL);
+    tip_pin.enable_interrupt(&mut eic);
+    unsafe { (*EicRegisters::ptr()).wakeup.modify(|_, w| w.wakeupen4().set_bit()); }
+
+    let tip_timer = TimerCounter::tc3_(&clocks.tcc2_tc3(&gclk0).unwrap(), peripherals.TC3, &mut peripherals.PM);
+
+    cortex_m::interrupt::free(|cs| TIP.borrow(cs).replace(Some(Tip { pin: tip_pin, timer: tip_timer })));
+
+    unsafe {
+        // The same priority so that neither interrupts the other.
+        core.NVIC.set_priority(interrupt::EIC, 2);
+        core.NVIC.set_priority(interrupt::TC3, 2);
+        NVIC::unmask(interrupt::EIC);
+        NVIC::unmask(interrupt::TC3);
+
+        // Only there to wake the processor.
+        core.NVIC.set_priority(interrupt::RTC, 3);
+        NVIC::unmask(interrupt::RTC);
+    }
+
+    // The DHT22 switches its pin between output and input, so it keeps the port.
+    let mut dht22 = Dht22::new(dht22_pin, parts.port);
+    let mut lora = Lora::new(lora);
 
     let id_word0 = unsafe { *(0x0080A00C as *const u32) };
     let id_word1 = unsafe { *(0x0080A040 as *const u32) };
     let id_word2 = unsafe { *(0x0080A044 as *const u32) };
     let id_word3 = unsafe { *(0x0080A048 as *const u32) };
+    let device_id = gauge::device_id([id_word0, id_word1, id_word2, id_word3]);
 
-    let mut loop_cnt: u32 = 0;
-    let mut transmit_counter = TRANSMIT_CYCLE;
-    let mut temperature_counter = 0;
+    let mut gauge = Gauge::new(device_id, settings, rtc.count());
+    // The readings from before the reset that the downlink has not acknowledged yet.
+    if let Err(err) = gauge.load(&mut flash) {
+        write!(usb_write, "Error loading the readings:{:?}", err).unwrap();
+    }
+    let mut wake_at = rtc.count();
 
-    let mut reading = dht22::Reading {
-        temperature: 0.0,
-        humidity: 0.0
-    };
-    
     loop {
-        cycle_delay(15 * 1024 * 1024);
+        metrics::set_loop_cnt(gauge.loop_cnt());
+        wake_at = power::next_wake(wake_at, rtc.count(), LOOP_TICKS);
+        sleep_until(&mut rtc, &mut core.SCB, &mut gauge, wake_at);
         red_led.set_high().unwrap();
 
-        let vbat_value = vbat.read();
-
-        let usb_serial_bytes_read = unsafe{ USB_SERIAL_BYTES_READ };
+        gauge.counters.usb_bytes_read = metrics::get_usb_bytes_read();
+        gauge.counters.usb_bytes_written = metrics::get_usb_bytes_written();
+        gauge.counters.usb_error_cnt = metrics::get_usb_error_cnt();
 
-        if temperature_counter >= TEMPERATURE_CYCLE {
-            temperature_counter = 0;
-            let mut delay2 = SharedDelay { };    
-
-            let mut delay_us = |us| { delay2.delay_us(us)};
+        gauge.run(&mut rtc, &mut lora, &mut dht22, &mut vbat, &mut flash, |loop_cnt, tips| {
+            cortex_m::interrupt::free(|cs| TIP_COUNTER.borrow(cs).borrow_mut().report(loop_cnt, tips));
+        });
 
-            if let Ok(_) = dht22::init(&mut dht22_pin, &mut delay_us) {
-                let mut in_pin = dht22_pin.into_floating_input(&mut parts.port);
-                
-                match dht22::read::<()>(&mut in_pin) {
-                    Ok(new_reading) => {
-                        reading = new_reading;
-                    },
-                    Err(_err) => {
-                        
-                    }
+        if let Some(command) = cortex_m::interrupt::free(|cs| COMMAND.borrow(cs).take()) {
+            match command {
+                Ok(Command::Bootloader) => {
+                    let _ = write!(usb_write, "ok\r\n");
+                    board::reboot_to_bootloader();
+                },
+                Ok(command) => {
+                    let _ = gauge.handle(command, &mut lora, &mut usb_write);
+                },
+                Err(err) => {
+                    let _ = write!(usb_write, "error: {}\r\n", err);
                 }
-    
-                dht22_pin = in_pin.into_open_drain_output(&mut parts.port);
-            } else {
-                
             }
-        } else {
-            temperature_counter = temperature_counter + 1;
         }
 
-        if transmit_counter >= TRANSMIT_CYCLE {
-            transmit_counter = 0;
-            let mut buffer:[u8; 255] = [0; 255];
-
-            let mut packet = TelemetryPacket::new();
-            packet.device_id = encode_device_id(id_word0, id_word1, id_word2, id_word3);
-            packet.loop_cnt = loop_cnt;
-            packet.vbat = vbat_value as u32;
-            packet.usb_bytes_read = usb_serial_bytes_read;
-            packet.usb_bytes_written = metrics::get_usb_bytes_written();
-            packet.usb_error_cnt = metrics::get_usb_error_cnt();
-            packet.lora_error_cnt = metrics::get_lora_transmit_error_cnt();
-            packet.lora_tx_bytes = metrics::get_lora_transmit_bytes();
-
-            // TODO the future
-            packet.tip_cnt = 0;
-            packet.temperature = reading.temperature;
-            packet.relative_humidity = reading.humidity;
-            packet.lora_rx_bytes = 0;
-            packet.hardware_err_other_cnt= 0;
-            
-            // The RadioHead library we are currently using on the download firmware includes a 4-byte header.  So
-            // we leave 4 0 bytes at the beginning of our buffer.
-            rainguage_messages::serialize(&packet, &mut buffer[4..]).unwrap();
-
-            match lora.transmit_payload_busy(buffer, buffer.len()) {
-                Ok(bytes) => { 
-                    metrics::increment_lora_transmit_bytes(bytes);
-                },
-                Err(_) => {
-                    metrics::increment_lora_transmit_error_cnt();
-                }
+        // Changed from the console or the downlink.
+        if *gauge.settings() != settings {
+            settings = *gauge.settings();
+            if config.save(&mut flash, &settings).is_err() {
+                gauge.counters.hardware_err_other_cnt = gauge.counters.hardware_err_other_cnt.wrapping_add(1);
             }
         }
-        transmit_counter = transmit_counter + 1;
 
         red_led.set_low().unwrap();
-
-        loop_cnt = loop_cnt.wrapping_add(1);
      }
 }
 
-fn encode_device_id(word0: u32, word1: u32, word2: u32, word3: u32) -> [u8; 16] {
-    let mut device_id = [0; 16];
-
-    let word0_bytes = word0.to_be_bytes();
-    device_id[0] = word0_bytes[0];
-    device_id[1] = word0_bytes[1];
-    device_id[2] = word0_bytes[2];
-    device_id[3] = word0_bytes[3];
-
-    let word1_bytes = word1.to_be_bytes();
-    device_id[4] = word1_bytes[0];
-    device_id[5] = word1_bytes[1];
-    device_id[6] = word1_bytes[2];
-    device_id[7] = word1_bytes[3];
-
-    let word2_bytes = word2.to_be_bytes();
-    device_id[8] = word2_bytes[0];
-    device_id[9] = word2_bytes[1];
-    device_id[10] = word2_bytes[2];
-    device_id[11] = word2_bytes[3];
+/// Sleep until the RTC reaches `wake_at`.  Standby stops every clock but the 32kHz one, so it is only used when nothing
+/// else needs them.  While a tip is being debounced or usb is connected the processor idles instead, which keeps the
+/// clocks running for the TC3 and USB interrupts.
+fn sleep_until(rtc: &mut Rtc, scb: &mut SCB, gauge: &mut Gauge, wake_at: u32) {
+    rtc.set_alarm(wake_at);
+    gauge.awake(rtc);
 
-    let word3_bytes = word3.to_be_bytes();
-    device_id[12] = word3_bytes[0];
-    device_id[13] = word3_bytes[1];
-    device_id[14] = word3_bytes[2];
-    device_id[15] = word3_bytes[3];
-
-    device_id
-
-}
-
-struct SharedDelay {
+    loop {
+        // Interrupts are held off between deciding and sleeping, so a tip in between cannot start TC3 just before
+        // standby stops it.  A pending interrupt still wakes the processor, and runs once they are turned back on.
+        let standby = cortex_m::interrupt::free(|cs| {
+            let standby = can_standby(cs);
+            if standby {
+                scb.set_sleepdeep();
+            } else {
+                scb.clear_sleepdeep();
+            }
+            cortex_m::asm::wfi();
+            standby
+        });
 
-}
+        let reached = power::reached(rtc.count(), wake_at);
+        if standby {
+            gauge.standby(rtc);
+            // Only the RTC and the tip switch can wake it from standby.
+            if !reached {
+                gauge.tip_wake();
+            }
+        } else {
+            gauge.awake(rtc);
+        }
 
-impl embedded_hal::blocking::delay::DelayMs<u8> for SharedDelay {
-    fn delay_ms(&mut self, ms: u8) {
-        unsafe {
-            DELAY.as_mut().map(|d| {
-                d.delay_ms(ms);
-            });
+        if reached {
+            return;
         }
     }
 }
 
-impl embedded_hal::blocking::delay::DelayUs<u32> for SharedDelay {
-    fn delay_us(&mut self, us: u32) {
-        unsafe {
-            DELAY.as_mut().map(|d| {
-                d.delay_us(us);
-            });
-        }
-    }
+fn can_standby(cs: &CriticalSection) -> bool {
+    let usb_connected = USB.borrow(cs).borrow().as_ref()
+        .map(|usb| usb.device.state() == UsbDeviceState::Configured)
+        .unwrap_or(false);
+    TIP_COUNTER.borrow(cs).borrow().idle() && !usb_connected
 }
+
 /// If we fail in a fatal way, the best we can do is print that error
 /// over and over
 fn fatal_error(msg: &str,
@@ -314,28 +325,94 @@ fn fatal_error(msg: &str,
     }
 }
 
-static mut DELAY: Option<Delay> = None;
-static mut USB_ALLOCATOR: Option<UsbBusAllocator<UsbBus>> = None;
-static mut USB_BUS: Option<UsbDevice<UsbBus>> = None;
-static mut USB_SERIAL: Option<SerialPort<UsbBus>> = None;
-static mut USB_SERIAL_BYTES_READ: u32 = 0;
+// Everything the interrupts share with the main loop is behind a Mutex, only borrowed with interrupts held off.
+struct Usb {
+    device: UsbDevice<'static, UsbBus>,
+    serial: SerialPort<'static, UsbBus>
+}
 
-fn poll_usb() {
-    unsafe {
-        USB_BUS.as_mut().map(|usb_dev| {
-            USB_SERIAL.as_mut().map(|serial| {
-                usb_dev.poll(&mut [serial]);
-                let mut buf = [0u8; 64];
+static USB: Mutex<RefCell<Option<Usb>>> = Mutex::new(RefCell::new(None));
+
+// Lines typed on the usb serial port, and the last command in them for the main loop to answer.
+static CONSOLE: Mutex<RefCell<LineBuffer>> = Mutex::new(RefCell::new(LineBuffer::new()));
+static COMMAND: Mutex<Cell<Option<Result<Command, CommandError>>>> = Mutex::new(Cell::new(None));
 
-                if let Ok(count) = serial.read(&mut buf) {
-                    USB_SERIAL_BYTES_READ = USB_SERIAL_BYTES_READ + count as u32;
+fn poll_usb() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(usb) = USB.borrow(cs).borrow_mut().as_mut() {
+            usb.device.poll(&mut [&mut usb.serial]);
+            let mut buf = [0u8; 64];
+
+            if let Ok(count) = usb.serial.read(&mut buf) {
+                metrics::increment_usb_bytes_read(count as u32);
+                let mut console = CONSOLE.borrow(cs).borrow_mut();
+                for byte in buf[..count].iter() {
+                    if let Some(command) = console.push(*byte) {
+                        COMMAND.borrow(cs).set(Some(command));
+                    }
                 }
-            });
-        });
-    };
+            }
+        }
+    });
 }
 
 #[interrupt]
 fn USB() {
     poll_usb();
 }
+
+#[interrupt]
+fn RTC() {
+    rtc::clear_interrupt();
+}
+
+// The tip switch's pin and the timer that debounces it.
+struct Tip {
+    pin: ExtInt4<Pa20<Interrupt<PullUp>>>,
+    timer: TimerCounter3
+}
+
+static TIP: Mutex<RefCell<Option<Tip>>> = Mutex::new(RefCell::new(None));
+static TIP_COUNTER: Mutex<RefCell<TipCounter>> = Mutex::new(RefCell::new(TipCounter::new()));
+
+// The switch closing starts the tip timer, which samples it until it has settled open again.  Bounces while the timer
+// is running restart it, which only delays the next sample.
+#[interrupt]
+fn EIC() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(tip) = TIP.borrow(cs).borrow_mut().as_mut() {
+            tip.pin.clear_interrupt();
+            tip.timer.start(KiloHertz(1));
+            tip.timer.enable_interrupt();
+        }
+    });
+}
+
+#[interrupt]
+fn TC3() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(tip) = TIP.borrow(cs).borrow_mut().as_mut() {
+            // Clears the overflow flag.
+            let _ = tip.timer.wait();
+
+            let closed = tip.pin.is_low().unwrap_or(false);
+
+            let mut counter = TIP_COUNTER.borrow(cs).borrow_mut();
+            counter.sample(closed, metrics::get_loop_cnt());
+
+            if counter.idle() {
+                tip.timer.disable_interrupt();
+                stop_tip_timer();
+            }
+        }
+    });
+}
+
+// The HAL's timer cannot be stopped, only restarted, so TC3 is turned off directly until the next tip starts it.
+fn stop_tip_timer() {
+    unsafe {
+        let tc3 = &*Tc3Registers::ptr();
+        tc3.count16().ctrla.modify(|_, w| w.enable().clear_bit());
+        while tc3.count16().status.read().syncbusy().bit_is_set() {}
+    }
+}
@@ -1,41 +1,51 @@
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering;
 
-static mut USB_ERROR_CNT:u32 = 0;
+// Counters written from the main loop and the USB interrupt.  The M0 only has atomic loads and stores, not fetch_add,
+// so they are incremented with interrupts held off.
+static USB_ERROR_CNT:AtomicU32 = AtomicU32::new(0);
 
-// This is incremented if there is an error transmitting via lora.
-static mut LORA_TRANSMIT_ERROR_CNT:u32 = 0;
+static USB_BYTES_WRITTEN:AtomicU32 = AtomicU32::new(0);
 
-static mut LORA_TRANSMIT_BYTES:u32 = 0;
+static USB_BYTES_READ:AtomicU32 = AtomicU32::new(0);
 
-static mut USB_BYTES_WRITTEN:u32 = 0;
+fn increment(counter: &AtomicU32, by: u32) {
+    cortex_m::interrupt::free(|_| {
+        counter.store(counter.load(Ordering::Relaxed).wrapping_add(by), Ordering::Relaxed);
+    });
+}
 
 pub fn increment_usb_error_cnt() {
-    unsafe { USB_ERROR_CNT = USB_ERROR_CNT.wrapping_add(1); }
+    increment(&USB_ERROR_CNT, 1);
 }
 
 pub fn get_usb_error_cnt() -> u32 {
-    unsafe { USB_ERROR_CNT }
+    USB_ERROR_CNT.load(Ordering::Relaxed)
 }
 
 pub fn increment_usb_bytes_written(bytes: u32) {
-    unsafe { USB_BYTES_WRITTEN = USB_BYTES_WRITTEN.wrapping_add(bytes) }
+    increment(&USB_BYTES_WRITTEN, bytes);
 }
 
 pub fn get_usb_bytes_written() -> u32 {
-    unsafe { USB_BYTES_WRITTEN }
+    USB_BYTES_WRITTEN.load(Ordering::Relaxed)
 }
 
-pub fn increment_lora_transmit_error_cnt() {
-    unsafe { LORA_TRANSMIT_ERROR_CNT = LORA_TRANSMIT_ERROR_CNT.wrapping_add(1); }
+pub fn increment_usb_bytes_read(bytes: u32) {
+    increment(&USB_BYTES_READ, bytes);
 }
 
-pub fn get_lora_transmit_error_cnt() -> u32 {
-    unsafe { LORA_TRANSMIT_ERROR_CNT }
+pub fn get_usb_bytes_read() -> u32 {
+    USB_BYTES_READ.load(Ordering::Relaxed)
 }
 
-pub fn increment_lora_transmit_bytes(bytes:usize) {
-    unsafe { LORA_TRANSMIT_BYTES = LORA_TRANSMIT_BYTES.wrapping_add(bytes as u32); }
+// The main loop's count, so the tip interrupt can note when each tip happened.
+static LOOP_CNT:AtomicU32 = AtomicU32::new(0);
+
+pub fn set_loop_cnt(loop_cnt: u32) {
+    LOOP_CNT.store(loop_cnt, Ordering::Relaxed);
 }
 
-pub fn get_lora_transmit_bytes() -> u32 {
-    unsafe { LORA_TRANSMIT_BYTES }
-}
\ No newline at end of file
+pub fn get_loop_cnt() -> u32 {
+    LOOP_CNT.load(Ordering::Relaxed)
+}
@@ -0,0 +1,76 @@
+extern crate feather_m0 as hal;
+use crate::hal::clock::GenericClockController;
+use crate::hal::pac::{GCLK, RTC, SYSCTRL};
+use rainguage_core::hardware::Clock;
+
+/// The RTC as a 32 bit counter of the 32.768kHz crystal, with an alarm to wake the processor.  It is the only clock
+/// that keeps running in standby.
+pub struct Rtc {
+    rtc: RTC
+}
+
+impl Rtc {
+    pub fn new(clocks:&mut GenericClockController, rtc:RTC) -> Rtc {
+        // with_external_32kosc feeds the crystal into GCLK1, but neither of them is left running in standby.
+        let gclk1 = clocks.gclk1();
+        unsafe {
+            (*SYSCTRL::ptr()).xosc32k.modify(|_, w| w.runstdby().set_bit());
+
+            let gclk = &*GCLK::ptr();
+            gclk.genctrl.write(|w| w.id().bits(1).src().xosc32k().genen().set_bit().runstdby().set_bit());
+            while gclk.status.read().syncbusy().bit_is_set() { }
+        }
+        // Undivided, so the count is in rainguage_core::clock::TICKS_PER_SECOND.
+        clocks.rtc(&gclk1).unwrap();
+
+        let mut result = Rtc {
+            rtc
+        };
+
+        result.initialize();
+
+        result
+    }
+
+    fn initialize(&mut self) {
+        let mode0 = self.rtc.mode0();
+
+        mode0.ctrl.write(|w| w.swrst().set_bit());
+        while mode0.ctrl.read().swrst().bit_is_set() { }
+
+        // Keep the count synchronised so it can be read straight away.
+        mode0.readreq.write(|w| unsafe { w.rreq().set_bit().rcont().set_bit().addr().bits(0x10) });
+
+        self.sync_rtc();
+        mode0.ctrl.write(|w| w.mode().count32().prescaler().div1());
+        mode0.intenset.write(|w| w.cmp0().set_bit());
+
+        self.sync_rtc();
+        mode0.ctrl.modify(|_, w| w.enable().set_bit());
+        self.sync_rtc();
+    }
+
+    #[inline(always)]
+    fn sync_rtc(&self) {
+        while self.rtc.mode0().status.read().syncbusy().bit_is_set() { }
+    }
+
+    /// Fire the RTC interrupt when the count reaches `ticks`.
+    pub fn set_alarm(&mut self, ticks:u32) {
+        self.sync_rtc();
+        self.rtc.mode0().comp0.write(|w| unsafe { w.comp().bits(ticks) });
+        self.sync_rtc();
+    }
+}
+
+impl Clock for Rtc {
+    fn count(&mut self) -> u32 {
+        self.sync_rtc();
+        self.rtc.mode0().count.read().bits()
+    }
+}
+
+/// Acknowledge the alarm, from the RTC interrupt.
+pub fn clear_interrupt() {
+    unsafe { (*RTC::ptr()).mode0().intflag.write(|w| w.cmp0().set_bit()); }
+}
@@ -17,31 +17,26 @@ impl Write for UsbWrite {
     fn write_str(&mut self, s: &str) -> Result {
         let bytes = s.as_bytes();
         let mut written = 0;
-        let mut stop = false;
-        while !stop  {
-            unsafe {
-                super::USB_BUS.as_mut().map(|_usb_dev| {
-                    super::USB_SERIAL.as_mut().map(|serial| {
-                        match serial.write(&bytes[written..]) {
-                            Ok(bytes_written) => {
-                                written += bytes_written;
-
-                                super::metrics::increment_usb_bytes_written(written as u32);
-                                if written == bytes.len() {
-                                    stop = true;
-                                }
-                            },
-                            Err(_) => {
-                                super::metrics::increment_usb_error_cnt();
-                                // wouldblock is stalling for some reason.
-                                stop = true;
-                            }
-                        }
-                    });
-                });
-            };
+        while written < bytes.len() {
+            // Each attempt holds the port only briefly, so the USB interrupt can keep polling in between.
+            let result = cortex_m::interrupt::free(|cs| {
+                super::USB.borrow(cs).borrow_mut().as_mut().map(|usb| usb.serial.write(&bytes[written..]))
+            });
+            match result {
+                Some(Ok(bytes_written)) => {
+                    written += bytes_written;
+                    super::metrics::increment_usb_bytes_written(bytes_written as u32);
+                },
+                Some(Err(_)) => {
+                    super::metrics::increment_usb_error_cnt();
+                    // wouldblock is stalling for some reason.
+                    break;
+                },
+                // Not set up yet.
+                None => break
+            }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
@@ -7,7 +7,34 @@ use core::iter::Iterator;
 use byteorder::ByteOrder;
 use byteorder::NetworkEndian;
 
-const MAGIC:[u8;3] = [125, 8, 141];
+mod received;
+
+pub use received::Received;
+
+const MAGIC:[u8;3] = [125, 8, 147];
+
+// The other frames only differ in the last byte of the magic.  141 is telemetry from gauges without uptime_secs, and
+// 144 from gauges without sequence.
+const V1_MAGIC:[u8;3] = [125, 8, 141];
+const V2_MAGIC:[u8;3] = [125, 8, 144];
+const TIPS_MAGIC:[u8;3] = [125, 8, 142];
+const POWER_MAGIC:[u8;3] = [125, 8, 143];
+// Settings for a gauge, sent to it through the downlink, and the settings a gauge has, sent back once it takes them.
+const CONFIG_MAGIC:[u8;3] = [125, 8, 145];
+const CONFIG_REPORT_MAGIC:[u8;3] = [125, 8, 146];
+// Readings a gauge is sending again, and the downlink's acknowledgement of what it has.
+const HISTORY_MAGIC:[u8;3] = [125, 8, 148];
+const ACK_MAGIC:[u8;3] = [125, 8, 149];
+
+/// The largest payload the iterator will read, all that is left of a 255 byte LoRa frame after the RadioHead header,
+/// the magic, the length and the checksum.
+pub const MAX_PAYLOAD:usize = 255 - 4 - 3 - 1 - 4;
+
+/// The most tips a TipsPacket can hold while still fitting in a 64 byte frame.
+pub const MAX_TIPS:usize = 16;
+
+/// The most readings a HistoryPacket can hold while still fitting in a frame.
+pub const MAX_HISTORY:usize = 6;
 
 #[derive(Debug)]
 pub enum SerializeError {
@@ -25,9 +52,11 @@ impl From<postcard::Error> for SerializeError {
 pub enum DeserializeError {
     SerializeError(postcard::Error),
     InvalidLength,
+    /// A frame whose magic only differs in the last byte, most likely a message from firmware newer than this crate.
+    UnknownMagic(u8),
     InvalidChecksum{
         crc32_buf: [u8;4],
-        msg_buf: [u8; 64],
+        msg_buf: [u8; MAX_PAYLOAD],
         msg_len: u8
     }
 }
@@ -39,6 +68,35 @@ impl From<postcard::Error> for DeserializeError {
     
 }
 
+/// Everything the rainguage sends, and what is sent to it.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+    Telemetry(TelemetryPacket),
+    Tips(TipsPacket),
+    Power(PowerPacket),
+    /// To a gauge.
+    Config(ConfigPacket),
+    /// From a gauge.
+    ConfigReport(ConfigPacket),
+    History(HistoryPacket),
+    /// To a gauge.
+    Ack(AckPacket)
+}
+
+// Which message a frame holds, from its magic.
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Telemetry,
+    TelemetryV1,
+    TelemetryV2,
+    Tips,
+    Power,
+    Config,
+    ConfigReport,
+    History,
+    Ack
+}
+
 //
 // ReadingMagic -> ReadingLength -> ReadingBytes -> ReadingChecksum
 //
@@ -47,29 +105,33 @@ enum IteratorState {
     ReadingMagic{
         bytes_read:u8
     },
-    ReadingLength,
+    ReadingLength {
+        kind: Kind
+    },
     ReadingBytes{
+        kind: Kind,
         msg_len: u8,
         num_read: usize,
-        msg_buf: [u8; 64]
+        msg_buf: [u8; MAX_PAYLOAD]
     },
     ReadingChecksum {
+        kind: Kind,
         num_read: usize,
         crc32_buf: [u8;4],
-        msg_buf: [u8; 64],
+        msg_buf: [u8; MAX_PAYLOAD],
         msg_len: u8
     }
 }
 
 // Wraps an iterator of bytes
-pub struct PacketIterator <I:Iterator<Item=u8>> {
+pub struct MessageIterator <I:Iterator<Item=u8>> {
     byte_iter:I,
     state:IteratorState
 }
 
-impl <I:Iterator<Item=u8>> PacketIterator<I> {
-    pub fn new(byte_iter:I) -> PacketIterator<I> {
-        PacketIterator {
+impl <I:Iterator<Item=u8>> MessageIterator<I> {
+    pub fn new(byte_iter:I) -> MessageIterator<I> {
+        MessageIterator {
             byte_iter,
             state:IteratorState::ReadingMagic {
                 bytes_read: 0
@@ -79,44 +141,65 @@ impl <I:Iterator<Item=u8>> PacketIterator<I> {
 }
 
 
-impl <'a, I:Iterator<Item=u8>> Iterator for PacketIterator<I> {
-    type Item = Result<TelemetryPacket, DeserializeError>;
+impl <'a, I:Iterator<Item=u8>> Iterator for MessageIterator<I> {
+    type Item = Result<Message, DeserializeError>;
 
-    fn next(&mut self) -> Option<Result<TelemetryPacket, DeserializeError>> {
+    fn next(&mut self) -> Option<Result<Message, DeserializeError>> {
         loop {
             match self.byte_iter.next() {
                 Some(byte) => {
                     //println!("byte={}", byte);
                     match self.state {
                         IteratorState::ReadingMagic {ref mut bytes_read } => {
-                            if *bytes_read > 3 {
-                                *bytes_read = 0;
-                            } else if byte == MAGIC[*bytes_read as usize] {
+                            if *bytes_read == 2 && byte == MAGIC[2] {
+                                self.state = IteratorState::ReadingLength { kind: Kind::Telemetry };
+                            } else if *bytes_read == 2 && byte == V1_MAGIC[2] {
+                                self.state = IteratorState::ReadingLength { kind: Kind::TelemetryV1 };
+                            } else if *bytes_read == 2 && byte == V2_MAGIC[2] {
+                                self.state = IteratorState::ReadingLength { kind: Kind::TelemetryV2 };
+                            } else if *bytes_read == 2 && byte == TIPS_MAGIC[2] {
+                                self.state = IteratorState::ReadingLength { kind: Kind::Tips };
+                            } else if *bytes_read == 2 && byte == POWER_MAGIC[2] {
+                                self.state = IteratorState::ReadingLength { kind: Kind::Power };
+                            } else if *bytes_read == 2 && byte == CONFIG_MAGIC[2] {
+                                self.state = IteratorState::ReadingLength { kind: Kind::Config };
+                            } else if *bytes_read == 2 && byte == CONFIG_REPORT_MAGIC[2] {
+                                self.state = IteratorState::ReadingLength { kind: Kind::ConfigReport };
+                            } else if *bytes_read == 2 && byte == HISTORY_MAGIC[2] {
+                                self.state = IteratorState::ReadingLength { kind: Kind::History };
+                            } else if *bytes_read == 2 && byte == ACK_MAGIC[2] {
+                                self.state = IteratorState::ReadingLength { kind: Kind::Ack };
+                            } else if *bytes_read == 2 {
+                                *bytes_read = if byte == MAGIC[0] { 1 } else { 0 };
+                                return Some(Result::Err(DeserializeError::UnknownMagic(byte)));
+                            } else if *bytes_read < 2 && byte == MAGIC[*bytes_read as usize] {
                                 *bytes_read = *bytes_read + 1;
-                            }
-    
-                            if *bytes_read == 3 {
-                                self.state = IteratorState::ReadingLength;
+                            } else if byte == MAGIC[0] {
+                                *bytes_read = 1;
+                            } else {
+                                *bytes_read = 0;
                             }
                         },
-                        IteratorState::ReadingLength => {
-                            if byte > 64 {
+                        IteratorState::ReadingLength { kind } => {
+                            if byte as usize > MAX_PAYLOAD {
                                 self.state = IteratorState::ReadingMagic{ bytes_read:0 };
                                 return Some(Result::Err(DeserializeError::InvalidLength));
                             }
 
                             self.state = IteratorState::ReadingBytes {
+                                kind,
                                 msg_len:byte,
                                 num_read:0,
-                                msg_buf: [0u8; 64]
+                                msg_buf: [0u8; MAX_PAYLOAD]
                             };
                         },
-                        IteratorState::ReadingBytes{msg_len, ref mut num_read, ref mut msg_buf} => {
+                        IteratorState::ReadingBytes{kind, msg_len, ref mut num_read, ref mut msg_buf} => {
                             msg_buf[*num_read] = byte;
                             *num_read += 1;
 
                             if *num_read >= msg_len.into() {                                
                                 self.state = IteratorState::ReadingChecksum {
+                                    kind,
                                     num_read:0,
                                     msg_buf:*msg_buf,
                                     msg_len,
@@ -124,7 +207,7 @@ impl <'a, I:Iterator<Item=u8>> Iterator for PacketIterator<I> {
                                 };
                             }
                         },
-                        IteratorState::ReadingChecksum{ref mut num_read, msg_buf, msg_len, ref mut crc32_buf} => {
+                        IteratorState::ReadingChecksum{kind, ref mut num_read, msg_buf, msg_len, ref mut crc32_buf} => {
                             crc32_buf[*num_read] = byte;
                             *num_read += 1;
 
@@ -138,9 +221,23 @@ impl <'a, I:Iterator<Item=u8>> Iterator for PacketIterator<I> {
                                 self.state = IteratorState::ReadingMagic { bytes_read:0 };
 
                                 if calculated_sum == provided_sum {
-                                    match postcard::from_bytes(&msg_buf[0..msg_len as usize]) {
-                                        Ok(packet) => {
-                                            return Some(Result::Ok(packet));
+                                    let bytes = &msg_buf[0..msg_len as usize];
+                                    let message = match kind {
+                                        Kind::Telemetry => postcard::from_bytes(bytes).map(Message::Telemetry),
+                                        Kind::TelemetryV1 => postcard::from_bytes::<TelemetryPacketV1>(bytes)
+                                            .map(|packet| Message::Telemetry(packet.into())),
+                                        Kind::TelemetryV2 => postcard::from_bytes::<TelemetryPacketV2>(bytes)
+                                            .map(|packet| Message::Telemetry(packet.into())),
+                                        Kind::Tips => postcard::from_bytes(bytes).map(Message::Tips),
+                                        Kind::Power => postcard::from_bytes(bytes).map(Message::Power),
+                                        Kind::Config => postcard::from_bytes(bytes).map(Message::Config),
+                                        Kind::ConfigReport => postcard::from_bytes(bytes).map(Message::ConfigReport),
+                                        Kind::History => postcard::from_bytes(bytes).map(Message::History),
+                                        Kind::Ack => postcard::from_bytes(bytes).map(Message::Ack)
+                                    };
+                                    match message {
+                                        Ok(message) => {
+                                            return Some(Result::Ok(message));
                                         },
                                         Err(err) => {
                                             return Some(Result::Err(DeserializeError::SerializeError(err)));
@@ -165,14 +262,40 @@ impl <'a, I:Iterator<Item=u8>> Iterator for PacketIterator<I> {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// Only the telemetry packets from an iterator of bytes, anything else the rainguage sends is skipped.
+pub struct PacketIterator <I:Iterator<Item=u8>> {
+    messages:MessageIterator<I>
+}
+
+impl <I:Iterator<Item=u8>> PacketIterator<I> {
+    pub fn new(byte_iter:I) -> PacketIterator<I> {
+        PacketIterator {
+            messages: MessageIterator::new(byte_iter)
+        }
+    }
+}
+
+impl <I:Iterator<Item=u8>> Iterator for PacketIterator<I> {
+    type Item = Result<TelemetryPacket, DeserializeError>;
+
+    fn next(&mut self) -> Option<Result<TelemetryPacket, DeserializeError>> {
+        loop {
+            match self.messages.next()? {
+                Ok(Message::Telemetry(packet)) => return Some(Ok(packet)),
+                Ok(_) => {},
+                Err(err) => return Some(Err(err))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 /// TelemetryPacket is sent from the rainguage.
 pub struct TelemetryPacket {
     /// The hardware identifier 
     pub device_id: [u8; 16],
 
-    /// The number of loops that have been run.  The device has no clock so this is an approximation of time.  It
-    /// will wrap back to 0.
+    /// The number of loops that have been run, one every 327.68ms.  It will wrap back to 0.
     pub loop_cnt: u32,
 
     /// The number of times the rainguage has tipped over.
@@ -199,7 +322,21 @@ pub struct TelemetryPacket {
 
     /// The number of other hardware errors that occured.  This is an error outside of a more specific hardware error.  Things like flashing
     /// leds.
-    pub hardware_err_other_cnt: u32
+    pub hardware_err_other_cnt: u32,
+
+    /// Seconds since power on, from the RTC.  None from gauges that do not send it.
+    pub uptime_secs: Option<u32>,
+
+    /// A random number the gauge picked when it started keeping its readings, which changes when its flash is erased
+    /// and the sequences start again from 1.  None from gauges that do not keep their readings.
+    pub epoch: Option<u32>,
+
+    /// The gauge's number for this reading, one more than the last.  It keeps the reading until the downlink
+    /// acknowledges it, see HistoryPacket.  None from gauges that do not keep their readings.
+    pub sequence: Option<u32>,
+
+    /// How many readings before this one the gauge still has waiting to be acknowledged.
+    pub backlog: Option<u32>
 }
 
 impl TelemetryPacket {
@@ -217,27 +354,361 @@ impl TelemetryPacket {
             lora_rx_bytes: 0,
             lora_tx_bytes: 0,
             lora_error_cnt: 0,
-            hardware_err_other_cnt: 0
+            hardware_err_other_cnt: 0,
+            uptime_secs: None,
+            epoch: None,
+            sequence: None,
+            backlog: None
+        }
+    }
+}
+
+// TelemetryPacket as it was before uptime_secs, which older gauges still send.
+#[derive(Serialize, Deserialize)]
+struct TelemetryPacketV1 {
+    device_id: [u8; 16],
+    loop_cnt: u32,
+    tip_cnt: u32,
+    vbat: u32,
+    temperature: f32,
+    relative_humidity: f32,
+    usb_bytes_read: u32,
+    usb_bytes_written: u32,
+    usb_error_cnt: u32,
+    lora_rx_bytes: u32,
+    lora_tx_bytes: u32,
+    lora_error_cnt: u32,
+    hardware_err_other_cnt: u32
+}
+
+impl From<TelemetryPacketV1> for TelemetryPacket {
+    fn from(packet: TelemetryPacketV1) -> Self {
+        TelemetryPacket {
+            device_id: packet.device_id,
+            loop_cnt: packet.loop_cnt,
+            tip_cnt: packet.tip_cnt,
+            vbat: packet.vbat,
+            temperature: packet.temperature,
+            relative_humidity: packet.relative_humidity,
+            usb_bytes_read: packet.usb_bytes_read,
+            usb_bytes_written: packet.usb_bytes_written,
+            usb_error_cnt: packet.usb_error_cnt,
+            lora_rx_bytes: packet.lora_rx_bytes,
+            lora_tx_bytes: packet.lora_tx_bytes,
+            lora_error_cnt: packet.lora_error_cnt,
+            hardware_err_other_cnt: packet.hardware_err_other_cnt,
+            uptime_secs: None,
+            epoch: None,
+            sequence: None,
+            backlog: None
+        }
+    }
+}
+
+// TelemetryPacket as it was before epoch, sequence and backlog.
+#[derive(Serialize, Deserialize)]
+struct TelemetryPacketV2 {
+    device_id: [u8; 16],
+    loop_cnt: u32,
+    tip_cnt: u32,
+    vbat: u32,
+    temperature: f32,
+    relative_humidity: f32,
+    usb_bytes_read: u32,
+    usb_bytes_written: u32,
+    usb_error_cnt: u32,
+    lora_rx_bytes: u32,
+    lora_tx_bytes: u32,
+    lora_error_cnt: u32,
+    hardware_err_other_cnt: u32,
+    uptime_secs: Option<u32>
+}
+
+impl From<TelemetryPacketV2> for TelemetryPacket {
+    fn from(packet: TelemetryPacketV2) -> Self {
+        TelemetryPacket {
+            device_id: packet.device_id,
+            loop_cnt: packet.loop_cnt,
+            tip_cnt: packet.tip_cnt,
+            vbat: packet.vbat,
+            temperature: packet.temperature,
+            relative_humidity: packet.relative_humidity,
+            usb_bytes_read: packet.usb_bytes_read,
+            usb_bytes_written: packet.usb_bytes_written,
+            usb_error_cnt: packet.usb_error_cnt,
+            lora_rx_bytes: packet.lora_rx_bytes,
+            lora_tx_bytes: packet.lora_tx_bytes,
+            lora_error_cnt: packet.lora_error_cnt,
+            hardware_err_other_cnt: packet.hardware_err_other_cnt,
+            uptime_secs: packet.uptime_secs,
+            epoch: None,
+            sequence: None,
+            backlog: None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+/// TipsPacket is sent by the rainguage along with a telemetry packet when the bucket has tipped since the last one, so
+/// that the server can tell when the rain fell.
+pub struct TipsPacket {
+    /// The hardware identifier
+    pub device_id: [u8; 16],
+
+    /// The loop_cnt of the telemetry packet sent with it.
+    pub loop_cnt: u32,
+
+    /// The tip_cnt after the newest tip.
+    pub tip_cnt: u32,
+
+    /// How many of `loops_before` are used.  When the bucket tips more than MAX_TIPS times between packets only the
+    /// newest tips are sent, the rest are only counted in tip_cnt.
+    pub len: u8,
+
+    /// How many loops before `loop_cnt` each tip happened, oldest first.
+    pub loops_before: [u16; MAX_TIPS]
+}
+
+impl TipsPacket {
+    pub fn new() -> TipsPacket {
+        TipsPacket {
+            device_id: [0; 16],
+            loop_cnt: 0,
+            tip_cnt: 0,
+            len: 0,
+            loops_before: [0; MAX_TIPS]
         }
     }
+
+    /// The tips that are used, oldest first.
+    pub fn loops_before(&self) -> &[u16] {
+        &self.loops_before[..(self.len as usize).min(MAX_TIPS)]
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+/// PowerPacket is sent by the rainguage every so often to say where its power goes.  The times are milliseconds since
+/// power on, counted by the RTC, and wrap back to 0.
+pub struct PowerPacket {
+    /// The hardware identifier
+    pub device_id: [u8; 16],
+
+    /// The loop_cnt of the telemetry packet sent with it.
+    pub loop_cnt: u32,
+
+    /// How long the processor has been running.
+    pub awake_ms: u32,
+
+    /// How long the processor has been in standby sleep.
+    pub standby_ms: u32,
+
+    /// How long the radio has been transmitting, this is part of `awake_ms`.  The radio sleeps the rest of the time.
+    pub tx_ms: u32,
+
+    /// How many times a tip woke the processor before its alarm.
+    pub tip_wake_cnt: u32
+}
+
+impl PowerPacket {
+    pub fn new() -> PowerPacket {
+        PowerPacket {
+            device_id: [0; 16],
+            loop_cnt: 0,
+            awake_ms: 0,
+            standby_ms: 0,
+            tx_ms: 0,
+            tip_wake_cnt: 0
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+/// ConfigPacket changes a gauge's settings.  The downlink holds it until the gauge next transmits and sends it while
+/// the gauge listens.  Only the settings that are given change, and the gauge keeps them through resets.  The gauge
+/// answers with a ConfigPacket of its own, as a ConfigReport with every setting given.
+pub struct ConfigPacket {
+    /// The gauge it is for, or from.
+    pub device_id: [u8; 16],
+
+    /// How often telemetry is sent.
+    pub transmit_interval_secs: Option<u32>,
+
+    pub frequency_mhz: Option<u32>,
+
+    pub tx_power_dbm: Option<u8>,
+
+    /// Added to the sensor's temperature, in °C.
+    pub temperature_offset: Option<f32>,
+
+    /// Added to the sensor's relative humidity, in percent.
+    pub humidity_offset: Option<f32>
+}
+
+impl ConfigPacket {
+    pub fn new() -> ConfigPacket {
+        ConfigPacket {
+            device_id: [0; 16],
+            transmit_interval_secs: None,
+            frequency_mhz: None,
+            tx_power_dbm: None,
+            temperature_offset: None,
+            humidity_offset: None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+/// One reading in a HistoryPacket, what the telemetry packet with the same sequence said.
+pub struct HistoryRecord {
+    pub sequence: u32,
+    /// How long the gauge had been running when it took the reading, counting on through restarts, unlike
+    /// uptime_secs, so it can be compared with HistoryPacket::running_secs.
+    pub running_secs: u32,
+    pub loop_cnt: u32,
+    pub tip_cnt: u32,
+    pub vbat: u16,
+    pub temperature: f32,
+    pub relative_humidity: f32
+}
+
+impl HistoryRecord {
+    pub fn new() -> HistoryRecord {
+        HistoryRecord {
+            sequence: 0,
+            running_secs: 0,
+            loop_cnt: 0,
+            tip_cnt: 0,
+            vbat: 0,
+            temperature: 0.0,
+            relative_humidity: 0.0
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+/// HistoryPacket is sent by the rainguage along with a telemetry packet when readings from before it have not been
+/// acknowledged, say while the downlink was down.  The gauge keeps its readings in flash until an AckPacket says the
+/// downlink has them, and sends them again oldest first, a few each time it transmits.
+pub struct HistoryPacket {
+    /// The hardware identifier
+    pub device_id: [u8; 16],
+
+    /// The gauge's running_secs when it sent this, so a record was taken `running_secs - record.running_secs`
+    /// seconds before.
+    pub running_secs: u32,
+
+    /// The epoch the records' sequences belong to, the same as TelemetryPacket::epoch.
+    pub epoch: u32,
+
+    /// The oldest reading the gauge still has waiting.  Older ones were acknowledged, or lost when the gauge ran out
+    /// of room for them, so the downlink should not wait for them.
+    pub oldest: u32,
+
+    /// How many of `records` are used.
+    pub len: u8,
+
+    pub records: [HistoryRecord; MAX_HISTORY]
+}
+
+impl HistoryPacket {
+    pub fn new() -> HistoryPacket {
+        HistoryPacket {
+            device_id: [0; 16],
+            running_secs: 0,
+            epoch: 0,
+            oldest: 0,
+            len: 0,
+            records: [HistoryRecord::new(); MAX_HISTORY]
+        }
+    }
+
+    /// The records that are used, oldest first.
+    pub fn records(&self) -> &[HistoryRecord] {
+        &self.records[..(self.len as usize).min(MAX_HISTORY)]
+    }
+
+    /// How many seconds before this was sent the record was taken, None when the gauge's clock says it is from after.
+    pub fn age_secs(&self, record:&HistoryRecord) -> Option<u32> {
+        self.running_secs.checked_sub(record.running_secs)
+    }
+
+    /// A record as the telemetry packet it was sent as, without the counters or the uptime, which are not kept.
+    pub fn telemetry(&self, record:&HistoryRecord) -> TelemetryPacket {
+        let mut packet = TelemetryPacket::new();
+        packet.device_id = self.device_id;
+        packet.loop_cnt = record.loop_cnt;
+        packet.tip_cnt = record.tip_cnt;
+        packet.vbat = record.vbat as u32;
+        packet.temperature = record.temperature;
+        packet.relative_humidity = record.relative_humidity;
+        packet.epoch = Some(self.epoch);
+        packet.sequence = Some(record.sequence);
+        packet
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+/// AckPacket tells a gauge which of its readings the downlink has stored, so it can stop sending them.  The downlink
+/// holds it until the gauge next transmits, the same as a ConfigPacket.
+pub struct AckPacket {
+    /// The gauge it is for.
+    pub device_id: [u8; 16],
+
+    /// Every reading up to and including this sequence has been stored, see Received.
+    pub sequence: u32
 }
 
 // Serialize a telemetry packet into a byte buffer returning the length of the written bytes.
 //
 // The packet is written including a magic value, bytes and a checksum.  The format is
 //
-//   magic      3 bytes - always 125, 8, 141
+//   magic      3 bytes - 125, 8, 147 for telemetry, 125, 8, 142 for tips, 125, 8, 143 for power, 125, 8, 145 for
+//              config, 125, 8, 146 for a config report, 125, 8, 148 for history and 125, 8, 149 for an ack
 //   len        1 byte - length of bytes packet)
 //   bytes      `len` bytes  - payload
 //   checksum   4 bytes, a crc32 checksum of `bytes` (u32 in network byte order)
 pub fn serialize(telem:&TelemetryPacket, buf:&mut [u8]) -> Result<usize, SerializeError> {
+    frame(&MAGIC, telem, buf)
+}
+
+// Serialize a tips packet the same way as `serialize`.
+pub fn serialize_tips(tips:&TipsPacket, buf:&mut [u8]) -> Result<usize, SerializeError> {
+    frame(&TIPS_MAGIC, tips, buf)
+}
+
+// Serialize a power packet the same way as `serialize`.
+pub fn serialize_power(power:&PowerPacket, buf:&mut [u8]) -> Result<usize, SerializeError> {
+    frame(&POWER_MAGIC, power, buf)
+}
+
+// Serialize settings for a gauge the same way as `serialize`.
+pub fn serialize_config(config:&ConfigPacket, buf:&mut [u8]) -> Result<usize, SerializeError> {
+    frame(&CONFIG_MAGIC, config, buf)
+}
+
+// Serialize a gauge's own settings the same way as `serialize`.
+pub fn serialize_config_report(config:&ConfigPacket, buf:&mut [u8]) -> Result<usize, SerializeError> {
+    frame(&CONFIG_REPORT_MAGIC, config, buf)
+}
+
+// Serialize readings sent again the same way as `serialize`.
+pub fn serialize_history(history:&HistoryPacket, buf:&mut [u8]) -> Result<usize, SerializeError> {
+    frame(&HISTORY_MAGIC, history, buf)
+}
+
+// Serialize an acknowledgement for a gauge the same way as `serialize`.
+pub fn serialize_ack(ack:&AckPacket, buf:&mut [u8]) -> Result<usize, SerializeError> {
+    frame(&ACK_MAGIC, ack, buf)
+}
+
+fn frame<T:Serialize>(magic:&[u8; 3], message:&T, buf:&mut [u8]) -> Result<usize, SerializeError> {
     // Write magic into the first three bytes
-    buf[0] = MAGIC[0];
-    buf[1] = MAGIC[1];
-    buf[2] = MAGIC[2];
+    buf[0] = magic[0];
+    buf[1] = magic[1];
+    buf[2] = magic[2];
 
-    // Serialize the telemetry packet
-    let result = postcard::to_slice(telem, &mut buf[4..])?;
+    // Serialize the message
+    let result = postcard::to_slice(message, &mut buf[4..])?;
     let len = result.len();
 
     // Calculate the crc32 checksum
@@ -276,12 +747,94 @@ mod tests {
         packet.lora_tx_bytes = u32::MAX;
         packet.lora_error_cnt = u32::MAX;
         packet.hardware_err_other_cnt = u32::MAX;
+        packet.uptime_secs = Some(u32::MAX);
+        packet.epoch = Some(u32::MAX);
+        packet.sequence = Some(u32::MAX);
+        packet.backlog = Some(u32::MAX);
 
         let mut buf:[u8; 127] = [0; 127];
         
         let cnt = super::serialize(&packet, &mut buf).unwrap();
         println!("{:?}", buf);
-        assert_eq!(72, cnt);
+        assert_eq!(92, cnt);
+    }
+
+    #[test]
+    fn reads_telemetry_without_uptime() {
+        let mut buf:[u8; 255] = [0; 255];
+
+        let mut packet = super::TelemetryPacket::new();
+        packet.loop_cnt = 180;
+        packet.tip_cnt = 7;
+        let v1 = super::TelemetryPacketV1 {
+            device_id: packet.device_id,
+            loop_cnt: 180,
+            tip_cnt: 7,
+            vbat: 0,
+            temperature: 0.0,
+            relative_humidity: 0.0,
+            usb_bytes_read: 0,
+            usb_bytes_written: 0,
+            usb_error_cnt: 0,
+            lora_rx_bytes: 0,
+            lora_tx_bytes: 0,
+            lora_error_cnt: 0,
+            hardware_err_other_cnt: 0
+        };
+
+        // The largest payload there used to be room for.
+        assert_eq!(3 + 1 + 64 + 4, super::frame(&super::V1_MAGIC, &v1, &mut buf).unwrap());
+
+        let bytes = buf.iter()
+            .map(|byte| *byte);
+
+        let mut iter = super::PacketIterator::new(bytes);
+        assert_eq!(Some(Ok(packet)), iter.next());
+        assert_eq!(None, iter.next());
+    }
+
+    #[test]
+    fn reads_telemetry_without_sequence() {
+        let mut buf:[u8; 255] = [0; 255];
+
+        let mut packet = super::TelemetryPacket::new();
+        packet.loop_cnt = 180;
+        packet.uptime_secs = Some(60);
+        let v2 = super::TelemetryPacketV2 {
+            device_id: packet.device_id,
+            loop_cnt: 180,
+            tip_cnt: 0,
+            vbat: 0,
+            temperature: 0.0,
+            relative_humidity: 0.0,
+            usb_bytes_read: 0,
+            usb_bytes_written: 0,
+            usb_error_cnt: 0,
+            lora_rx_bytes: 0,
+            lora_tx_bytes: 0,
+            lora_error_cnt: 0,
+            hardware_err_other_cnt: 0,
+            uptime_secs: Some(60)
+        };
+        super::frame(&super::V2_MAGIC, &v2, &mut buf).unwrap();
+
+        let mut iter = super::PacketIterator::new(buf.iter().cloned());
+        assert_eq!(Some(Ok(packet)), iter.next());
+        assert_eq!(None, iter.next());
+    }
+
+    #[test]
+    fn reports_unknown_magic() {
+        let mut buf:[u8; 255] = [0; 255];
+
+        let packet = super::TelemetryPacket::new();
+        let len = super::frame(&[125, 8, 200], &packet, &mut buf).unwrap();
+        super::serialize(&packet, &mut buf[len..]).unwrap();
+
+        let mut iter = super::PacketIterator::new(buf.iter().cloned());
+        assert_eq!(Some(Err(super::DeserializeError::UnknownMagic(200))), iter.next());
+        assert_eq!(Some(Ok(packet)), iter.next());
+        assert_eq!(None, iter.next());
     }
 
     #[test]
@@ -311,6 +864,7 @@ mod tests {
         packet.usb_error_cnt = 210;
         packet.lora_error_cnt = 220;
         packet.lora_tx_bytes = 230;
+        packet.uptime_secs = Some(240);
 
         super::serialize(&packet, &mut buf).unwrap();
 
@@ -323,6 +877,136 @@ mod tests {
         assert_eq!(None, iter.next());
     }
 
+    fn tips() -> super::TipsPacket {
+        let mut tips = super::TipsPacket::new();
+        tips.device_id = [10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 120, 130, 140, 150, 160, 170];
+        tips.loop_cnt = 180;
+        tips.tip_cnt = 7;
+        tips.len = 3;
+        tips.loops_before[0] = 150;
+        tips.loops_before[1] = 42;
+        tips.loops_before[2] = 3;
+        tips
+    }
+
+    #[test]
+    fn largest_tips() {
+        let mut tips = super::TipsPacket::new();
+        tips.device_id = [255; 16];
+        tips.loop_cnt = u32::MAX;
+        tips.tip_cnt = u32::MAX;
+        tips.len = super::MAX_TIPS as u8;
+        tips.loops_before = [u16::MAX; super::MAX_TIPS];
+
+        let mut buf:[u8; 127] = [0; 127];
+
+        // Older readers only take frames with up to 64 bytes of payload.
+        let cnt = super::serialize_tips(&tips, &mut buf).unwrap();
+        assert!(cnt <= 3 + 1 + 64 + 4);
+    }
+
+    #[test]
+    fn test_serialize_deserialize_messages() {
+        let mut buf:[u8; 255] = [0; 255];
+
+        let mut packet = super::TelemetryPacket::new();
+        packet.loop_cnt = 180;
+        packet.tip_cnt = 7;
+
+        let len = super::serialize(&packet, &mut buf).unwrap();
+        let mut power = super::PowerPacket::new();
+        power.loop_cnt = 180;
+        power.awake_ms = 61_000;
+        power.standby_ms = 3_539_000;
+        power.tx_ms = 2_500;
+        power.tip_wake_cnt = 7;
+
+        let len = len + super::serialize_tips(&tips(), &mut buf[len..]).unwrap();
+        super::serialize_power(&power, &mut buf[len..]).unwrap();
+
+        let bytes = buf.iter()
+            .map(|byte| *byte);
+
+        let mut iter = super::MessageIterator::new(bytes);
+        assert_eq!(Some(Ok(super::Message::Telemetry(packet))), iter.next());
+        assert_eq!(Some(Ok(super::Message::Tips(tips()))), iter.next());
+        assert_eq!(Some(Ok(super::Message::Power(power))), iter.next());
+        assert_eq!(None, iter.next());
+        assert_eq!(&[150, 42, 3], tips().loops_before());
+    }
+
+    #[test]
+    fn config() {
+        let mut buf:[u8; 255] = [0; 255];
+
+        let mut config = super::ConfigPacket::new();
+        config.device_id = [7; 16];
+        config.transmit_interval_secs = Some(300);
+        config.temperature_offset = Some(-0.5);
+        let mut report = config.clone();
+        report.frequency_mhz = Some(915);
+        report.tx_power_dbm = Some(20);
+        report.humidity_offset = Some(0.0);
+
+        let len = super::serialize_config(&config, &mut buf).unwrap();
+        let report_len = super::serialize_config_report(&report, &mut buf[len..]).unwrap();
+        assert!(report_len <= 3 + 1 + 64 + 4);
+
+        let mut iter = super::MessageIterator::new(buf.iter().cloned());
+        assert_eq!(Some(Ok(super::Message::Config(config))), iter.next());
+        assert_eq!(Some(Ok(super::Message::ConfigReport(report))), iter.next());
+        assert_eq!(None, iter.next());
+    }
+
+    #[test]
+    fn history() {
+        let mut buf:[u8; 512] = [0; 512];
+
+        let mut history = super::HistoryPacket::new();
+        history.device_id = [255; 16];
+        history.running_secs = u32::MAX;
+        history.epoch = u32::MAX;
+        history.oldest = u32::MAX;
+        history.len = super::MAX_HISTORY as u8;
+        history.records = [super::HistoryRecord {
+            sequence: u32::MAX,
+            running_secs: u32::MAX,
+            loop_cnt: u32::MAX,
+            tip_cnt: u32::MAX,
+            vbat: u16::MAX,
+            temperature: -40.0,
+            relative_humidity: 100.0
+        }; super::MAX_HISTORY];
+        let ack = super::AckPacket { device_id: [255; 16], sequence: u32::MAX };
+
+        // The gauge sends it after a 4 byte header, in a frame of 255.
+        let len = super::serialize_history(&history, &mut buf).unwrap();
+        assert!(len <= 255 - 4);
+        super::serialize_ack(&ack, &mut buf[len..]).unwrap();
+
+        let mut iter = super::MessageIterator::new(buf.iter().cloned());
+        assert_eq!(Some(Ok(super::Message::History(history))), iter.next());
+        assert_eq!(Some(Ok(super::Message::Ack(ack))), iter.next());
+        assert_eq!(None, iter.next());
+    }
+
+    #[test]
+    fn packets_skip_tips() {
+        let mut buf:[u8; 255] = [0; 255];
+
+        let packet = super::TelemetryPacket::new();
+        let len = super::serialize_tips(&tips(), &mut buf).unwrap();
+        let len = len + super::serialize_power(&super::PowerPacket::new(), &mut buf[len..]).unwrap();
+        super::serialize(&packet, &mut buf[len..]).unwrap();
+
+        let bytes = buf.iter()
+            .map(|byte| *byte);
+
+        let mut iter = super::PacketIterator::new(bytes);
+        assert_eq!(Some(Ok(packet)), iter.next());
+        assert_eq!(None, iter.next());
+    }
+
     #[test]
     fn test_bad_checksum() {
         let mut buf:[u8; 128] = [0; 128];
@@ -0,0 +1,261 @@
+use crate::{HistoryPacket, HistoryRecord, TelemetryPacket, MAX_HISTORY};
+
+// How far past the oldest reading still missing it keeps track, more than a gauge keeps in flash.
+const WINDOW:u32 = 4096;
+
+/// Which of a gauge's readings have been stored, by sequence, for the AckPacket that tells it.  Readings can come
+/// twice and out of order, as the gauge sends live telemetry while it catches up on its history, so the ack is the
+/// highest sequence everything before has been stored up to.
+///
+/// The sequences belong to the gauge's epoch, so when a new one turns up, because the gauge's flash was erased, it
+/// starts again.  So it does when a live reading comes with a sequence before ones already stored.
+pub struct Received {
+    // The epoch the sequences belong to.
+    epoch: Option<u32>,
+    // Everything before `next` has been stored, or the gauge no longer has it.  None until the gauge says where its
+    // readings start.
+    next: Option<u32>,
+    // The readings from `next` on that have been stored, a bit each, indexed by sequence % WINDOW.
+    bits: [u32; WINDOW as usize / 32]
+}
+
+impl Received {
+    pub fn new() -> Received {
+        Received {
+            epoch: None,
+            next: None,
+            bits: [0; WINDOW as usize / 32]
+        }
+    }
+
+    /// The oldest reading the gauge still has waiting, from TelemetryPacket::backlog or HistoryPacket::oldest.
+    pub fn oldest(&mut self, oldest:u32) {
+        match self.next {
+            Some(next) if next >= oldest => {},
+            Some(_) => self.skip_to(oldest),
+            None => self.next = Some(oldest)
+        }
+        self.advance();
+    }
+
+    /// Whether the reading has not been stored yet.  Before `oldest` is known every reading is new.
+    pub fn is_new(&self, sequence:u32) -> bool {
+        match self.next {
+            Some(next) => sequence >= next && (sequence - next >= WINDOW || !self.bit(sequence)),
+            None => true
+        }
+    }
+
+    /// The reading has been stored.
+    pub fn stored(&mut self, sequence:u32) {
+        if let Some(next) = self.next {
+            if sequence >= next && sequence - next < WINDOW {
+                self.bits[(sequence % WINDOW) as usize / 32] |= 1 << (sequence % 32);
+                self.advance();
+            }
+        }
+    }
+
+    /// Take the gauge's backlog from a telemetry packet, and return whether the packet still needs storing.  Mark it
+    /// `stored` once it is, and not before, so the gauge is only told about readings that are there.
+    pub fn telemetry(&mut self, packet:&TelemetryPacket) -> bool {
+        // The live reading is the gauge's newest, so one before what has been stored means it numbered them again.
+        if let Some(sequence) = packet.sequence {
+            let older = self.next.map(|next| sequence.saturating_add(1) < next).unwrap_or(false);
+            self.restart(packet.epoch, older);
+        }
+        if let (Some(sequence), Some(backlog)) = (packet.sequence, packet.backlog) {
+            self.oldest(sequence.saturating_sub(backlog));
+        }
+        packet.sequence.map(|sequence| self.is_new(sequence)).unwrap_or(true)
+    }
+
+    /// The records in a history packet that still need storing, with how many seconds before the packet each was
+    /// taken, None when it cannot be dated.  As with `telemetry`, mark each `stored` once it is.
+    pub fn unstored<'a>(&mut self, history:&'a HistoryPacket)
+        -> impl Iterator<Item=(&'a HistoryRecord, Option<u32>)> + 'a {
+        // An oldest before `next` is not a restart, the gauge may not have had the ack for them yet.
+        self.restart(Some(history.epoch), false);
+        self.oldest(history.oldest);
+
+        let mut new = [false; MAX_HISTORY];
+        for (new, record) in new.iter_mut().zip(history.records()) {
+            *new = self.is_new(record.sequence);
+        }
+
+        history.records().iter()
+            .enumerate()
+            .filter(move |(index, _)| new[*index])
+            .map(move |(_, record)| (record, history.age_secs(record)))
+    }
+
+    /// The sequence to acknowledge, None when there is nothing to.
+    pub fn acked(&self) -> Option<u32> {
+        self.next.and_then(|next| next.checked_sub(1))
+    }
+
+    // Forget the readings from before, which say nothing about the new ones.
+    fn restart(&mut self, epoch:Option<u32>, older:bool) {
+        if epoch != self.epoch || older {
+            *self = Received::new();
+            self.epoch = epoch;
+        }
+    }
+
+    fn bit(&self, sequence:u32) -> bool {
+        self.bits[(sequence % WINDOW) as usize / 32] & 1 << (sequence % 32) != 0
+    }
+
+    // Move past the stored readings.
+    fn advance(&mut self) {
+        while let Some(next) = self.next {
+            if !self.bit(next) {
+                break;
+            }
+            self.skip_to(next + 1);
+        }
+    }
+
+    // The bits before `to` are reused for the readings WINDOW after them, so they are cleared.
+    fn skip_to(&mut self, to:u32) {
+        let next = self.next.unwrap_or(to);
+        if to - next >= WINDOW {
+            self.bits = [0; WINDOW as usize / 32];
+        } else {
+            for sequence in next..to {
+                self.bits[(sequence % WINDOW) as usize / 32] &= !(1 << (sequence % 32));
+            }
+        }
+        self.next = Some(to);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    use crate::{HistoryPacket, HistoryRecord, TelemetryPacket};
+
+    use super::Received;
+
+    #[test]
+    fn acks_what_has_no_gaps() {
+        let mut received = Received::new();
+        assert!(received.is_new(5));
+        received.stored(5);
+        assert_eq!(None, received.acked());
+
+        // Live telemetry while 3 and 4 are still to come.
+        received.oldest(3);
+        received.stored(5);
+        assert_eq!(Some(2), received.acked());
+        assert!(!received.is_new(5));
+        received.stored(3);
+        assert_eq!(Some(3), received.acked());
+        received.stored(4);
+        assert_eq!(Some(5), received.acked());
+        assert!(!received.is_new(4));
+
+        // An old copy of the gauge's backlog does not move it back.
+        received.oldest(1);
+        assert_eq!(Some(5), received.acked());
+
+        // The gauge lost 6 to 9.
+        received.stored(12);
+        received.oldest(10);
+        assert_eq!(Some(9), received.acked());
+        received.stored(11);
+        received.stored(10);
+        assert_eq!(Some(12), received.acked());
+
+        // The bits are reused.
+        received.oldest(10_000);
+        assert!(received.is_new(10_000 + 12));
+        received.stored(10_001);
+        received.stored(10_000);
+        assert_eq!(Some(10_001), received.acked());
+    }
+
+    #[test]
+    fn only_new_readings_need_storing() {
+        let mut received = Received::new();
+
+        // The gauge has 2 to 4 still waiting.
+        let mut packet = TelemetryPacket::new();
+        packet.epoch = Some(0);
+        packet.sequence = Some(5);
+        packet.backlog = Some(3);
+        assert!(received.telemetry(&packet));
+        received.stored(5);
+        assert!(!received.telemetry(&packet));
+
+        // 4 is dated after the packet, so it cannot be.
+        let mut history = HistoryPacket::new();
+        history.running_secs = 300;
+        history.oldest = 2;
+        history.len = 4;
+        for (index, (sequence, running_secs)) in [(2, 180), (3, 240), (4, 400), (5, 300)].iter().enumerate() {
+            history.records[index] = HistoryRecord { sequence: *sequence, running_secs: *running_secs,
+                ..HistoryRecord::new() };
+        }
+        let unstored = received.unstored(&history)
+            .map(|(record, age_secs)| (record.sequence, age_secs))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(2, Some(120)), (3, Some(60)), (4, None)], unstored);
+
+        // Nothing is stored until it is marked, so they come again.
+        assert_eq!(3, received.unstored(&history).count());
+        assert_eq!(Some(1), received.acked());
+
+        // A backlog past the first reading is taken as all of them, and without a sequence it always needs storing.
+        packet.sequence = Some(1);
+        packet.backlog = Some(4);
+        assert!(!received.telemetry(&packet));
+        assert_eq!(Some(1), received.acked());
+        assert!(received.telemetry(&TelemetryPacket::new()));
+    }
+    #[test]
+    fn starts_again_when_the_gauge_does() {
+        let mut received = Received::new();
+        let mut packet = TelemetryPacket::new();
+        packet.epoch = Some(7);
+        packet.sequence = Some(40);
+        packet.backlog = Some(0);
+        assert!(received.telemetry(&packet));
+        received.stored(40);
+        assert_eq!(Some(40), received.acked());
+
+        // Its flash is erased and it numbers its readings from 1 again, in a new epoch.
+        packet.epoch = Some(8);
+        packet.sequence = Some(1);
+        assert!(received.telemetry(&packet));
+        assert_eq!(Some(0), received.acked());
+        received.stored(1);
+        assert_eq!(Some(1), received.acked());
+
+        // The first word from the next epoch can be history.
+        let mut history = HistoryPacket::new();
+        history.epoch = 9;
+        history.oldest = 1;
+        history.len = 1;
+        history.records[0] = HistoryRecord { sequence: 1, ..HistoryRecord::new() };
+        assert_eq!(1, received.unstored(&history).count());
+        assert_eq!(Some(0), received.acked());
+
+        // Without an epoch, a live reading before those stored.  The same reading again is not.
+        let mut received = Received::new();
+        packet.epoch = None;
+        packet.sequence = Some(40);
+        assert!(received.telemetry(&packet));
+        received.stored(40);
+        packet.sequence = Some(3);
+        assert!(received.telemetry(&packet));
+        assert_eq!(Some(2), received.acked());
+        received.stored(3);
+        assert!(!received.telemetry(&packet));
+        assert_eq!(Some(3), received.acked());
+    }
+}
@@ -0,0 +1,312 @@
+use rand::Rng;
+
+use rainguage_core::clock::TICKS_PER_SECOND;
+use rainguage_core::gauge;
+use rainguage_core::gauge::{Gauge, Settings, LOOP_TICKS};
+use rainguage_core::hardware::Clock;
+use rainguage_core::power;
+use rainguage_core::sensor::Reading;
+use rainguage_core::tips::TipCounter;
+use rainguage_messages::{AckPacket, Message, MessageIterator, Received};
+
+use crate::hardware::{SimAdc, SimClock, SimRadio, SimSensor, SimStorage};
+use crate::world::{Battery, Weather};
+
+// The firmware waits before it starts, to make it easier to reset into the bootloader.
+const BOOT_MS: u64 = 5_000;
+
+// How long the processor is awake each loop, apart from transmitting.
+const AWAKE_MS: u32 = 5;
+
+// The reed switch stays closed for about 100ms each tip, and the tip timer samples it every millisecond.
+const TIP_CLOSED_SAMPLES: usize = 100;
+const TIP_OPEN_SAMPLES: usize = 10;
+
+const MM_PER_TIP: f64 = 0.2;
+
+/// One simulated gauge.  Times are in milliseconds since the simulation started, the gauge's own clock starts again
+/// from zero each time it reboots.
+pub struct SimGauge {
+    device_id: [u8; 16],
+    clock: SimClock,
+    radio: SimRadio,
+    sensor: SimSensor,
+    adc: SimAdc,
+    storage: SimStorage,
+    tips: TipCounter,
+    gauge: Gauge,
+    wake_at: u32,
+    boot_ms: u64,
+    // When it last woke.
+    last_ms: u64,
+    // Rain in the bucket that has not tipped it yet.
+    bucket_mm: f64,
+    battery: Battery,
+    reboots_per_day: f64,
+    next_reboot_ms: Option<u64>,
+    // What the downlink has heard from it, to acknowledge.
+    received: Received
+}
+
+impl SimGauge {
+    /// Gauge `number`, powered on at `boot_ms`.
+    pub fn new(number: u32, boot_ms: u64, battery: Battery, reboots_per_day: f64, rng: &mut impl Rng) -> SimGauge {
+        let clock = SimClock::new();
+
+        let mut result = SimGauge {
+            // "sim" in the first word, so simulated gauges are easy to spot.
+            device_id: gauge::device_id([0x7369_6d00, 0, 0, number]),
+            radio: SimRadio::new(clock.clone()),
+            clock,
+            sensor: SimSensor { reading: Reading { temperature: 0.0, humidity: 0.0 } },
+            adc: SimAdc { vbat: 0 },
+            storage: SimStorage::new(),
+            tips: TipCounter::new(),
+            gauge: Gauge::new([0; 16], Settings::default(), 0),
+            wake_at: 0,
+            boot_ms: 0,
+            last_ms: 0,
+            bucket_mm: 0.0,
+            battery,
+            reboots_per_day,
+            next_reboot_ms: None,
+            received: Received::new()
+        };
+
+        result.boot(boot_ms, rng);
+        result
+    }
+
+    pub fn device_id(&self) -> [u8; 16] {
+        self.device_id
+    }
+
+    /// Power on again at `at_ms`, with everything but the battery, the water in the bucket and the readings kept in
+    /// flash starting over.
+    fn boot(&mut self, at_ms: u64, rng: &mut impl Rng) {
+        self.boot_ms = at_ms + BOOT_MS;
+        self.last_ms = self.boot_ms;
+        self.clock.set(0);
+        self.tips = TipCounter::new();
+        self.gauge = Gauge::new(self.device_id, Settings::default(), self.clock.count());
+        self.gauge.load(&mut self.storage).unwrap();
+        self.wake_at = power::next_wake(0, self.clock.count(), LOOP_TICKS);
+
+        self.next_reboot_ms = if self.reboots_per_day > 0.0 {
+            let days = -(1.0 - rng.gen::<f64>()).ln() / self.reboots_per_day;
+            Some(at_ms + (days * 86_400_000.0) as u64)
+        } else {
+            None
+        };
+    }
+
+    fn now_ms(&mut self) -> u64 {
+        self.boot_ms + self.gauge.now(&mut self.clock)
+    }
+
+    /// When the gauge next wakes up.
+    pub fn next_ms(&mut self) -> u64 {
+        let ahead = self.wake_at.wrapping_sub(self.clock.count()) as u64;
+        self.now_ms() + ahead * 1000 / TICKS_PER_SECOND as u64
+    }
+
+    /// A flat battery stops it for good.
+    pub fn alive(&mut self) -> bool {
+        let now = self.now_ms();
+        !self.battery.flat(now)
+    }
+
+    /// Sleep until the next wake and run one loop, returning the frames the gauge sent.
+    pub fn step(&mut self, weather: &Weather, rng: &mut impl Rng) -> Vec<Vec<u8>> {
+        let next_ms = self.next_ms();
+        if let Some(reboot_ms) = self.next_reboot_ms {
+            if reboot_ms <= next_ms {
+                self.boot(reboot_ms, rng);
+                return vec![];
+            }
+        }
+
+        self.gauge.awake(&mut self.clock);
+        self.clock.set(self.wake_at);
+        self.gauge.standby(&mut self.clock);
+        let now = self.now_ms();
+
+        // Each tip while it slept would have woken it to debounce the switch.
+        self.bucket_mm = self.bucket_mm + weather.rain_mm(self.last_ms, now);
+        self.last_ms = now;
+        while self.bucket_mm >= MM_PER_TIP {
+            self.bucket_mm = self.bucket_mm - MM_PER_TIP;
+            self.tip();
+            self.gauge.tip_wake();
+        }
+
+        self.sensor.reading = Reading {
+            temperature: weather.temperature(now) as f32,
+            humidity: weather.humidity(now) as f32
+        };
+        self.adc.vbat = self.battery.vbat(now);
+
+        let tips = &mut self.tips;
+        self.gauge.run(&mut self.clock, &mut self.radio, &mut self.sensor, &mut self.adc, &mut self.storage,
+            |loop_cnt, packet| tips.report(loop_cnt, packet));
+        self.clock.advance_ms(AWAKE_MS);
+        self.wake_at = power::next_wake(self.wake_at, self.clock.count(), LOOP_TICKS);
+
+        self.radio.take_frames()
+    }
+
+    /// The downlink heard `frame`.  It acknowledges the readings in it the way the downlink-processor does, and the
+    /// gauge hears the ack the next time it listens.
+    pub fn heard(&mut self, frame: &[u8]) {
+        for message in MessageIterator::new(frame.iter().cloned()) {
+            match message {
+                Ok(Message::Telemetry(packet)) => {
+                    if let (true, Some(sequence)) = (self.received.telemetry(&packet), packet.sequence) {
+                        self.received.stored(sequence);
+                    }
+                },
+                Ok(Message::History(history)) => {
+                    let sequences = self.received.unstored(&history)
+                        .map(|(record, _)| record.sequence)
+                        .collect::<Vec<_>>();
+                    for sequence in sequences {
+                        self.received.stored(sequence);
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        if let Some(sequence) = self.received.acked() {
+            let mut frame = [0; gauge::FRAME_LEN];
+            let ack = AckPacket { device_id: self.device_id, sequence };
+            let len = rainguage_messages::serialize_ack(&ack, &mut frame).unwrap();
+            self.radio.reply(frame[..len].to_vec());
+        }
+    }
+
+    fn tip(&mut self) {
+        let loop_cnt = self.gauge.loop_cnt();
+        for _ in 0..TIP_CLOSED_SAMPLES {
+            self.tips.sample(true, loop_cnt);
+        }
+        for _ in 0..TIP_OPEN_SAMPLES {
+            self.tips.sample(false, loop_cnt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use rainguage_messages::{Message, MessageIterator};
+
+    use crate::world::Climate;
+
+    use super::*;
+
+    fn messages(frames: Vec<Vec<u8>>) -> Vec<Message> {
+        MessageIterator::new(frames.into_iter().flatten()).map(|message| message.unwrap()).collect()
+    }
+
+    #[test]
+    fn reports_the_rain_and_reboots() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let climate = Climate { storms_per_day: 4.0, storm_hours: 2.0, storm_mm: 10.0, temperature: 10.0,
+            temperature_swing: 5.0 };
+        let weather = Weather::new(climate, 48 * 3_600_000, &mut rng);
+        let mut gauge = SimGauge::new(7, 0, Battery::new(30.0), 2.0, &mut rng);
+
+        let mut frames = vec![];
+        while gauge.next_ms() < 48 * 3_600_000 {
+            assert!(gauge.alive());
+            frames.extend(gauge.step(&weather, &mut rng));
+        }
+        let messages = messages(frames);
+
+        // The tips count starts again after each reboot.
+        let mut tips = 0;
+        let mut last_tip_cnt = 0;
+        let mut reboots = 0;
+        for message in messages.iter() {
+            if let Message::Telemetry(packet) = message {
+                assert_eq!([0x73, 0x69, 0x6d, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7], packet.device_id);
+                if packet.loop_cnt == 0 {
+                    reboots = reboots + 1;
+                    last_tip_cnt = 0;
+                }
+                tips = tips + packet.tip_cnt - last_tip_cnt;
+                last_tip_cnt = packet.tip_cnt;
+            }
+        }
+
+        // Rain in the minute before each reboot is lost with the gauge's count.
+        let rain = weather.rain_mm(0, 48 * 3_600_000);
+        assert!(reboots > 1, "{} boots", reboots);
+        assert!((tips as f64 * MM_PER_TIP - rain).abs() < (reboots as f64 + 1.0) * 3.0, "{} tips for {}mm", tips, rain);
+    }
+
+    #[test]
+    fn runs_flat() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut gauge = SimGauge::new(1, 0, Battery::new(0.5), 0.0, &mut rng);
+        let weather = Weather::new(Climate { storms_per_day: 0.0, storm_hours: 1.0, storm_mm: 1.0, temperature: 10.0,
+            temperature_swing: 5.0 }, 0, &mut rng);
+
+        let mut vbat = vec![];
+        while gauge.alive() {
+            for message in messages(gauge.step(&weather, &mut rng)) {
+                if let Message::Telemetry(packet) = message {
+                    vbat.push(packet.vbat);
+                }
+            }
+        }
+
+        assert_eq!(12 * 60, vbat.len());
+        assert_eq!(2606, vbat[0]);
+        assert!(vbat.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(vbat[vbat.len() - 1] < 2100);
+    }
+
+    #[test]
+    fn catches_up_after_an_outage() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let weather = Weather::new(Climate { storms_per_day: 0.0, storm_hours: 1.0, storm_mm: 1.0, temperature: 10.0,
+            temperature_swing: 5.0 }, 0, &mut rng);
+        let mut gauge = SimGauge::new(1, 0, Battery::new(0.0), 0.0, &mut rng);
+
+        // The downlink is away for the first two hours, and the gauge restarts half way through.
+        let mut sequences = std::collections::BTreeSet::new();
+        let mut rebooted = false;
+        while gauge.next_ms() < 4 * 3_600_000 {
+            if gauge.next_ms() >= 3_600_000 && !rebooted {
+                gauge.next_reboot_ms = Some(gauge.next_ms());
+                rebooted = true;
+            }
+
+            for frame in gauge.step(&weather, &mut rng) {
+                if gauge.next_ms() < 2 * 3_600_000 {
+                    continue;
+                }
+                gauge.heard(&frame);
+                for message in messages(vec![frame]) {
+                    match message {
+                        Message::Telemetry(packet) => {
+                            sequences.insert(packet.sequence.unwrap());
+                        },
+                        Message::History(history) => sequences.extend(history.records().iter().map(|r| r.sequence)),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        // Everything, and the gauge knows.
+        let newest = *sequences.iter().last().unwrap();
+        assert!(newest > 230, "{}", newest);
+        assert_eq!((1..=newest).collect::<Vec<u32>>(), sequences.into_iter().collect::<Vec<u32>>());
+        assert_eq!(Some(newest), gauge.received.acked());
+    }
+}
@@ -0,0 +1,173 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use rainguage_core::clock::TICKS_PER_SECOND;
+use rainguage_core::hardware::{Adc, Clock, Radio, Sensor, Storage};
+use rainguage_core::sensor::Reading;
+
+// Roughly how long a full frame takes to send at SF7 and 125kHz.
+const AIRTIME_MS: u32 = 400;
+
+// The same as the feather's flash kept for storage.
+const STORAGE_SIZE: usize = 64 * 1024;
+const ERASE_SIZE: u32 = 256;
+
+/// The simulated RTC.  Nothing moves it on but the simulation and the radio.
+#[derive(Clone)]
+pub struct SimClock {
+    count: Rc<Cell<u32>>
+}
+
+impl SimClock {
+    pub fn new() -> SimClock {
+        SimClock {
+            count: Rc::new(Cell::new(0))
+        }
+    }
+
+    pub fn set(&self, count: u32) {
+        self.count.set(count);
+    }
+
+    pub fn advance_ms(&self, ms: u32) {
+        let ticks = (ms as u64 * TICKS_PER_SECOND as u64 / 1000) as u32;
+        self.count.set(self.count.get().wrapping_add(ticks));
+    }
+}
+
+impl Clock for SimClock {
+    fn count(&mut self) -> u32 {
+        self.count.get()
+    }
+}
+
+/// Keeps each frame just as the radio would send it, for the simulation to pass on.
+pub struct SimRadio {
+    clock: SimClock,
+    frames: Vec<Vec<u8>>,
+    // Heard the next time the gauge listens.
+    reply: Option<Vec<u8>>
+}
+
+impl SimRadio {
+    pub fn new(clock: SimClock) -> SimRadio {
+        SimRadio {
+            clock,
+            frames: vec![],
+            reply: None
+        }
+    }
+
+    /// Send the gauge a frame once it next listens, replacing any not sent yet, as the downlink holds them.
+    pub fn reply(&mut self, frame: Vec<u8>) {
+        self.reply = Some(frame);
+    }
+
+    /// The frames sent since the last call.
+    pub fn take_frames(&mut self) -> Vec<Vec<u8>> {
+        std::mem::replace(&mut self.frames, vec![])
+    }
+}
+
+impl Radio for SimRadio {
+    type Error = ();
+
+    fn transmit(&mut self, frame: &[u8]) -> Result<usize, ()> {
+        self.clock.advance_ms(AIRTIME_MS);
+        self.frames.push(frame.to_vec());
+        Ok(frame.len())
+    }
+
+    fn sleep(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn set_frequency(&mut self, _mhz: u32) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn set_tx_power(&mut self, _dbm: u8) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn receive(&mut self, buffer: &mut [u8], _timeout_ms: u32) -> Result<usize, ()> {
+        match self.reply.take() {
+            Some(reply) => {
+                self.clock.advance_ms(AIRTIME_MS);
+                buffer[..reply.len()].copy_from_slice(&reply);
+                Ok(reply.len())
+            },
+            None => Ok(0)
+        }
+    }
+}
+
+/// Flash in memory.  Writing can only clear bits and erasing sets a block's again, and it lasts through reboots.
+pub struct SimStorage {
+    bytes: Vec<u8>
+}
+
+impl SimStorage {
+    pub fn new() -> SimStorage {
+        SimStorage {
+            bytes: vec![0xff; STORAGE_SIZE]
+        }
+    }
+}
+
+impl Storage for SimStorage {
+    type Error = ();
+
+    fn capacity(&self) -> u32 {
+        self.bytes.len() as u32
+    }
+
+    fn erase_size(&self) -> u32 {
+        ERASE_SIZE
+    }
+
+    fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), ()> {
+        let address = address as usize;
+        buffer.copy_from_slice(self.bytes.get(address..address + buffer.len()).ok_or(())?);
+        Ok(())
+    }
+
+    fn write(&mut self, address: u32, data: &[u8]) -> Result<(), ()> {
+        let address = address as usize;
+        let bytes = self.bytes.get_mut(address..address + data.len()).ok_or(())?;
+        for (byte, new) in bytes.iter_mut().zip(data.iter()) {
+            *byte = *byte & *new;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), ()> {
+        let start = (address - address % ERASE_SIZE) as usize;
+        self.bytes.get_mut(start..start + ERASE_SIZE as usize).ok_or(())?.iter_mut().for_each(|byte| *byte = 0xff);
+        Ok(())
+    }
+}
+
+/// Reads whatever the simulation last set.
+pub struct SimSensor {
+    pub reading: Reading
+}
+
+impl Sensor for SimSensor {
+    type Error = ();
+
+    fn read(&mut self) -> Result<Reading, ()> {
+        Ok(self.reading)
+    }
+}
+
+/// A raw ADC reading for the battery.
+pub struct SimAdc {
+    pub vbat: u16
+}
+
+impl Adc for SimAdc {
+    fn read(&mut self) -> u16 {
+        self.vbat
+    }
+}
@@ -0,0 +1,208 @@
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Instant;
+
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+use structopt::StructOpt;
+
+#[macro_use]
+extern crate log;
+
+mod gauge;
+mod hardware;
+mod output;
+mod world;
+
+use gauge::SimGauge;
+use output::Output;
+use rainguage_uplink::{Uplink, Urls};
+use world::{Battery, Climate, Weather};
+
+#[derive(StructOpt)]
+#[structopt(about = "Simulates rainguages and passes on the frames they send, for working on the service without one")]
+struct Options {
+    /// How many gauges
+    #[structopt(long, default_value = "1")]
+    gauges: u32,
+    /// How long to simulate, in hours
+    #[structopt(long, default_value = "24")]
+    hours: f64,
+    /// When the simulation starts, as an rfc3339 timestamp.  The time the service is given for each packet sent
+    /// over http, by default so that the simulation ends now, or starts now with --realtime
+    #[structopt(long)]
+    start: Option<String>,
+    /// Keep pace with the clock rather than running as fast as possible
+    #[structopt(long)]
+    realtime: bool,
+    /// For the storms and everything else left to chance, the same seed gives the same frames
+    #[structopt(long, default_value = "1")]
+    seed: u64,
+    #[structopt(long, default_value = "1")]
+    storms_per_day: f64,
+    /// The average length of a storm
+    #[structopt(long, default_value = "2")]
+    storm_hours: f64,
+    /// The average rain in a storm
+    #[structopt(long, default_value = "8")]
+    storm_mm: f64,
+    /// The average temperature, in °C
+    #[structopt(long, default_value = "12")]
+    temperature: f64,
+    /// How far the temperature goes either side of the average over the day
+    #[structopt(long, default_value = "6")]
+    temperature_swing: f64,
+    /// How long a battery lasts, after which the gauge stops.  Zero never runs flat
+    #[structopt(long, default_value = "0")]
+    battery_days: f64,
+    /// How often each gauge restarts
+    #[structopt(long, default_value = "0")]
+    reboots_per_day: f64,
+    /// The share of frames the downlink does not hear, from 0 to 1
+    #[structopt(long, default_value = "0")]
+    loss: f64,
+    /// Write the frames to this file, by default they go to stdout
+    #[structopt(long, short = "o", parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Write the frames to a new pseudo terminal, for the downlink-processor's SERIAL_PORT
+    #[structopt(long)]
+    pty: bool,
+    /// Post the packets to the telemetry service's /telemetry url instead
+    #[structopt(long)]
+    url: Option<String>,
+    /// The ingest key to post with
+    #[structopt(long)]
+    api_key: Option<String>,
+    /// With --url, where readings that cannot be dated are written, as the downlink-processor's DEAD_LETTER_FILE
+    #[structopt(long, default_value = "dead-letter.ndjson")]
+    dead_letter_file: String
+}
+
+fn main() -> io::Result<()> {
+    let options = Options::from_args();
+
+    simplelog::SimpleLogger::init(simplelog::LevelFilter::Info, simplelog::Config::default()).unwrap();
+
+    if let Err(problem) = check(&options) {
+        error!("{}", problem);
+        std::process::exit(1);
+    }
+
+    let duration_ms = (options.hours * 3_600_000.0) as u64;
+    let start = match &options.start {
+        Some(start) => DateTime::parse_from_rfc3339(start).unwrap().with_timezone(&Utc),
+        None if options.realtime => Utc::now(),
+        None => Utc::now() - Duration::milliseconds(duration_ms as i64)
+    };
+
+    // Held open until we exit, see open_pty.
+    let mut _pty = None;
+    let mut pty_path = None;
+    let mut output = if let Some(url) = &options.url {
+        Output::Http(Uplink::new(Urls::new(url), options.api_key.clone(), &options.dead_letter_file))
+    } else if options.pty {
+        let (master, slave, path) = output::open_pty()?;
+        info!("Writing frames to {}", path);
+        // The downlink-processor writes its acks back, but the simulated downlink acknowledges what it hears itself.
+        let mut acks = master.try_clone()?;
+        thread::spawn(move || io::copy(&mut acks, &mut io::sink()));
+        _pty = Some(slave);
+        pty_path = Some(path);
+        Output::Frames(Box::new(master))
+    } else if let Some(path) = &options.output {
+        Output::Frames(Box::new(File::create(path)?))
+    } else {
+        Output::Frames(Box::new(io::stdout()))
+    };
+
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let climate = Climate {
+        storms_per_day: options.storms_per_day,
+        storm_hours: options.storm_hours,
+        storm_mm: options.storm_mm,
+        temperature: options.temperature,
+        temperature_swing: options.temperature_swing
+    };
+    let weather = Weather::new(climate, duration_ms, &mut rng);
+    info!("{} storms, {:.1}mm of rain", weather.storms().len(), weather.rain_mm(0, duration_ms));
+
+    // Powered on over the first minute, so they do not all transmit together.
+    let mut gauges = (0..options.gauges)
+        .map(|number| {
+            let boot_ms = rng.gen_range(0, 60_000);
+            SimGauge::new(number + 1, boot_ms, Battery::new(options.battery_days), options.reboots_per_day, &mut rng)
+        })
+        .collect::<Vec<SimGauge>>();
+
+    let started = Instant::now();
+    loop {
+        // The gauge that wakes next.
+        let next = gauges.iter_mut()
+            .enumerate()
+            .filter_map(|(index, gauge)| if gauge.alive() { Some((index, gauge.next_ms())) } else { None })
+            .min_by_key(|(_, next_ms)| *next_ms);
+        let (index, next_ms) = match next {
+            Some((index, next_ms)) if next_ms < duration_ms => (index, next_ms),
+            _ => break
+        };
+
+        if options.realtime {
+            let elapsed = started.elapsed().as_millis() as u64;
+            if next_ms > elapsed {
+                thread::sleep(std::time::Duration::from_millis(next_ms - elapsed));
+            }
+        }
+
+        let received_at = start + Duration::milliseconds(next_ms as i64);
+        for frame in gauges[index].step(&weather, &mut rng) {
+            if rng.gen::<f64>() < options.loss {
+                continue;
+            }
+            gauges[index].heard(&frame);
+            output.send(received_at, &frame)?;
+        }
+    }
+
+    for gauge in gauges.iter_mut() {
+        if !gauge.alive() {
+            info!("{} ran flat", hex(&gauge.device_id()));
+        }
+    }
+
+    // The pty goes when we do, so keep it for whatever has not been read yet.
+    if let Some(path) = pty_path {
+        info!("Finished, stop with ctrl-c once {} has been read", path);
+        loop {
+            thread::park();
+        }
+    }
+
+    Ok(())
+}
+
+fn check(options: &Options) -> Result<(), String> {
+    let outputs = [options.output.is_some(), options.pty, options.url.is_some()];
+    if outputs.iter().filter(|output| **output).count() > 1 {
+        return Err("Only one of --output, --pty and --url can be given".to_string());
+    }
+    if options.loss < 0.0 || options.loss > 1.0 {
+        return Err("--loss must be from 0 to 1".to_string());
+    }
+    if let Some(start) = &options.start {
+        if let Err(err) = DateTime::parse_from_rfc3339(start) {
+            return Err(format!("--start is not an rfc3339 timestamp: {}", err));
+        }
+    }
+
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,80 @@
+use std::ffi::CStr;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::os::unix::io::FromRawFd;
+
+use chrono::DateTime;
+use chrono::Utc;
+
+use rainguage_messages::MessageIterator;
+use rainguage_uplink::Uplink;
+
+/// Where the frames go.
+pub enum Output {
+    /// Written out as the radio sent them, for the downlink-processor to read.
+    Frames(Box<dyn Write>),
+    /// Posted straight to the telemetry service, as the downlink-processor would have.
+    Http(Uplink)
+}
+
+impl Output {
+    /// Pass on a frame the downlink heard at `received_at`.
+    pub fn send(&mut self, received_at: DateTime<Utc>, frame: &[u8]) -> io::Result<()> {
+        match self {
+            Output::Frames(out) => {
+                out.write_all(frame)?;
+                out.flush()
+            },
+            // A failed post is logged and the simulation carries on, like packets the downlink did not hear.
+            Output::Http(uplink) => {
+                for message in MessageIterator::new(frame.iter().cloned()) {
+                    match message {
+                        Ok(message) => {
+                            if let Err(err) = uplink.send(received_at, &message) {
+                                warn!("Could not post: {}", err);
+                            }
+                        },
+                        Err(err) => error!("The simulated gauge sent a bad frame: {:?}", err)
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A pseudo terminal in raw mode, standing in for the downlink's serial port.  Returns the end to write to, the end
+/// that is held open so nothing is lost before the downlink-processor opens it, and the path to open.
+pub fn open_pty() -> io::Result<(File, File, String)> {
+    unsafe {
+        let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if master < 0 || libc::grantpt(master) != 0 || libc::unlockpt(master) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master_file = File::from_raw_fd(master);
+
+        let mut name = [0 as libc::c_char; 128];
+        if libc::ptsname_r(master, name.as_mut_ptr(), name.len()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let path = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+
+        let slave = libc::open(name.as_ptr(), libc::O_RDWR | libc::O_NOCTTY);
+        if slave < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let slave_file = File::from_raw_fd(slave);
+
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(slave, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(slave, libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok((master_file, slave_file, path))
+    }
+}
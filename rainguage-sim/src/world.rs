@@ -0,0 +1,207 @@
+use std::f64::consts::PI;
+
+use rand::Rng;
+
+const HOUR_MS: f64 = 3_600_000.0;
+
+// Resting voltage against state of charge for a single cell LiPo, highest first.  The same curve the service uses.
+const DISCHARGE_CURVE: [(f64, f64); 21] = [
+    (4.20, 100.0), (4.15, 95.0), (4.11, 90.0), (4.08, 85.0), (4.02, 80.0), (3.98, 75.0), (3.95, 70.0),
+    (3.91, 65.0), (3.87, 60.0), (3.85, 55.0), (3.84, 50.0), (3.82, 45.0), (3.80, 40.0), (3.79, 35.0),
+    (3.77, 30.0), (3.75, 25.0), (3.73, 20.0), (3.71, 15.0), (3.69, 10.0), (3.61, 5.0), (3.27, 0.0)
+];
+
+// The feather's battery reading at full scale, see the service's battery::Hardware.
+const ADC_FULL_SCALE_VOLTS: f64 = 6.6;
+const ADC_MAX: f64 = 4095.0;
+
+/// A spell of steady rain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Storm {
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub mm_per_hour: f64
+}
+
+/// What the storms, temperature and humidity do.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Climate {
+    pub storms_per_day: f64,
+    /// The average length of a storm.
+    pub storm_hours: f64,
+    /// The average rain in a storm.
+    pub storm_mm: f64,
+    pub temperature: f64,
+    /// How far the temperature goes either side of `temperature` over the day, warmest mid afternoon.
+    pub temperature_swing: f64
+}
+
+/// The weather every gauge sees, from the start of the simulation.
+pub struct Weather {
+    climate: Climate,
+    storms: Vec<Storm>
+}
+
+impl Weather {
+    /// Storms for the first `duration_ms`, arriving at random at `storms_per_day` with random lengths and totals.
+    pub fn new(climate: Climate, duration_ms: u64, rng: &mut impl Rng) -> Weather {
+        let mut storms = vec![];
+
+        if climate.storms_per_day > 0.0 {
+            let mut start = exponential(rng, 24.0 * HOUR_MS / climate.storms_per_day);
+            while start < duration_ms as f64 {
+                let hours = exponential(rng, climate.storm_hours).max(0.1);
+                let mm = exponential(rng, climate.storm_mm);
+                storms.push(Storm {
+                    start_ms: start as u64,
+                    end_ms: (start + hours * HOUR_MS) as u64,
+                    mm_per_hour: mm / hours
+                });
+                start = start + exponential(rng, 24.0 * HOUR_MS / climate.storms_per_day);
+            }
+        }
+
+        Weather {
+            climate,
+            storms
+        }
+    }
+
+    pub fn storms(&self) -> &[Storm] {
+        &self.storms
+    }
+
+    /// The rain that fell between `from_ms` and `to_ms`.
+    pub fn rain_mm(&self, from_ms: u64, to_ms: u64) -> f64 {
+        self.storms.iter()
+            .map(|storm| {
+                let from = from_ms.max(storm.start_ms);
+                let to = to_ms.min(storm.end_ms);
+                if to > from {
+                    (to - from) as f64 / HOUR_MS * storm.mm_per_hour
+                } else {
+                    0.0
+                }
+            })
+            .fold(0.0, |total, mm| total + mm)
+    }
+
+    fn raining(&self, at_ms: u64) -> bool {
+        self.storms.iter().any(|storm| storm.start_ms <= at_ms && at_ms < storm.end_ms)
+    }
+
+    /// The simulation starts at midnight.
+    pub fn temperature(&self, at_ms: u64) -> f64 {
+        self.climate.temperature + self.climate.temperature_swing * daily(at_ms)
+    }
+
+    /// Highest before dawn and while it rains.
+    pub fn humidity(&self, at_ms: u64) -> f64 {
+        if self.raining(at_ms) {
+            return 97.0;
+        }
+        (65.0 - 20.0 * daily(at_ms)).max(0.0).min(100.0)
+    }
+}
+
+// 1 mid afternoon and -1 before dawn.
+fn daily(at_ms: u64) -> f64 {
+    let hour = (at_ms as f64 / HOUR_MS) % 24.0;
+    (2.0 * PI * (hour - 15.0) / 24.0).cos()
+}
+
+fn exponential(rng: &mut impl Rng, mean: f64) -> f64 {
+    -mean * (1.0 - rng.gen::<f64>()).ln()
+}
+
+/// A battery that runs flat at a steady rate.
+pub struct Battery {
+    full_ms: f64
+}
+
+impl Battery {
+    /// `days` from full to flat, or never for zero.
+    pub fn new(days: f64) -> Battery {
+        Battery {
+            full_ms: days * 24.0 * HOUR_MS
+        }
+    }
+
+    /// Percent, after running for `at_ms`.
+    pub fn state_of_charge(&self, at_ms: u64) -> f64 {
+        if self.full_ms <= 0.0 {
+            return 100.0;
+        }
+        (100.0 - at_ms as f64 / self.full_ms * 100.0).max(0.0)
+    }
+
+    pub fn flat(&self, at_ms: u64) -> bool {
+        self.state_of_charge(at_ms) <= 0.0
+    }
+
+    pub fn volts(&self, at_ms: u64) -> f64 {
+        let charge = self.state_of_charge(at_ms);
+        for pair in DISCHARGE_CURVE.windows(2) {
+            let (high_volts, high_charge) = pair[0];
+            let (low_volts, low_charge) = pair[1];
+            if charge >= low_charge {
+                return low_volts + (high_volts - low_volts) * (charge - low_charge) / (high_charge - low_charge);
+            }
+        }
+        DISCHARGE_CURVE[DISCHARGE_CURVE.len() - 1].0
+    }
+
+    /// What the gauge's ADC reads for the battery.
+    pub fn vbat(&self, at_ms: u64) -> u16 {
+        (self.volts(at_ms) / ADC_FULL_SCALE_VOLTS * ADC_MAX).round() as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn climate() -> Climate {
+        Climate { storms_per_day: 2.0, storm_hours: 2.0, storm_mm: 10.0, temperature: 10.0, temperature_swing: 5.0 }
+    }
+
+    #[test]
+    fn storms() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let days = 100;
+        let weather = Weather::new(climate(), days * 24 * HOUR_MS as u64, &mut rng);
+
+        let storms = weather.storms().len() as f64 / days as f64;
+        assert!(storms > 1.5 && storms < 2.5, "{} storms a day", storms);
+
+        let storm = weather.storms()[0];
+        let total = storm.mm_per_hour * (storm.end_ms - storm.start_ms) as f64 / HOUR_MS;
+        assert!((weather.rain_mm(storm.start_ms, storm.end_ms) - total).abs() < 1e-6);
+        assert_eq!(97.0, weather.humidity(storm.start_ms));
+        assert_eq!(0.0, Weather::new(Climate { storms_per_day: 0.0, ..climate() }, 1_000_000, &mut rng)
+            .rain_mm(0, 1_000_000));
+    }
+
+    #[test]
+    fn temperature_cycle() {
+        let weather = Weather::new(Climate { storms_per_day: 0.0, ..climate() }, 0, &mut StdRng::seed_from_u64(1));
+        assert!((weather.temperature(15 * HOUR_MS as u64) - 15.0).abs() < 1e-9);
+        assert!((weather.temperature(3 * HOUR_MS as u64) - 5.0).abs() < 1e-9);
+        assert!((weather.temperature(27 * HOUR_MS as u64) - 5.0).abs() < 1e-9);
+        assert!((weather.humidity(3 * HOUR_MS as u64) - 85.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn discharge() {
+        let battery = Battery::new(10.0);
+        assert_eq!(2606, battery.vbat(0));
+        assert!((battery.volts(5 * 24 * HOUR_MS as u64) - 3.84).abs() < 1e-9);
+        assert!(!battery.flat(5 * 24 * HOUR_MS as u64));
+        assert!(battery.flat(10 * 24 * HOUR_MS as u64));
+
+        assert_eq!(100.0, Battery::new(0.0).state_of_charge(u64::MAX));
+    }
+}
@@ -0,0 +1,29 @@
+#[macro_use]
+extern crate log;
+
+mod uplink;
+
+pub use uplink::Uplink;
+pub use uplink::Urls;
+
+/// The service takes tips and power next to telemetry, so http://host/telemetry becomes http://host/tips.  It is the
+/// default for both the downlink-processor and the simulator.
+pub fn sibling_url(url:&str, name:&str) -> String {
+    let url = url.trim_end_matches('/');
+    match url.rfind('/') {
+        Some(index) => format!("{}/{}", &url[..index], name),
+        None => format!("{}/{}", url, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swaps_the_last_segment() {
+        assert_eq!("http://host:8000/tips", sibling_url("http://host:8000/telemetry", "tips"));
+        assert_eq!("http://host/api/power", sibling_url("http://host/api/telemetry/", "power"));
+        assert_eq!("host/tips", sibling_url("host", "tips"));
+    }
+}
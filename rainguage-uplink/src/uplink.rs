@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use reqwest::blocking::Client;
+use serde::Serialize;
+
+use rainguage_messages::HistoryPacket;
+use rainguage_messages::Message;
+use rainguage_messages::Received;
+use rainguage_messages::TelemetryPacket;
+
+use crate::sibling_url;
+
+/// Where each kind of packet is posted.
+pub struct Urls {
+    pub telemetry: String,
+    pub tips: String,
+    pub power: String
+}
+
+impl Urls {
+    /// Tips and power next to `telemetry`, where the service has them.
+    pub fn new(telemetry:&str) -> Urls {
+        Urls {
+            telemetry: telemetry.to_string(),
+            tips: sibling_url(telemetry, "tips"),
+            power: sibling_url(telemetry, "power")
+        }
+    }
+}
+
+// A reading the gauge sent again.  The history does not keep the counters, so the service stores them as NULL rather
+// than the zeros in the packet.
+#[derive(Serialize)]
+struct Resent<'a> {
+    #[serde(flatten)]
+    packet: &'a TelemetryPacket,
+    history: bool
+}
+
+/// Posts what the gauges send to the telemetry service.  It keeps track of which of each gauge's readings the
+/// service has, so those sent again are posted once and the gauge can be told to stop sending them.
+pub struct Uplink {
+    client: Client,
+    urls: Urls,
+    api_key: Option<String>,
+    dead_letter_file: String,
+    received: HashMap<[u8; 16], Received>
+}
+
+impl Uplink {
+    /// `api_key` is an ingest key from `telemetry-http-service keys create --scope ingest`.
+    pub fn new(urls:Urls, api_key:Option<String>, dead_letter_file:&str) -> Uplink {
+        Uplink {
+            client: Client::new(),
+            urls,
+            api_key,
+            dead_letter_file: dead_letter_file.to_string(),
+            received: HashMap::new()
+        }
+    }
+
+    /// Post a message the downlink heard at `received_at`, the service dates packets by when they were heard rather
+    /// than when they reach it.  Settings and acks are not for the service.
+    pub fn send(&mut self, received_at:DateTime<Utc>, message:&Message) -> Result<(), reqwest::Error> {
+        match message {
+            Message::Telemetry(packet) => self.telemetry(received_at, packet),
+            Message::History(history) => self.history(received_at, history),
+            Message::Tips(tips) => {
+                info!("received:{:?}, posting to {}", tips, self.urls.tips);
+                post(&self.client, &self.urls.tips, self.api_key.as_deref(), received_at, tips).map(|_| ())
+            },
+            Message::Power(power) => {
+                info!("received:{:?}, posting to {}", power, self.urls.power);
+                post(&self.client, &self.urls.power, self.api_key.as_deref(), received_at, power).map(|_| ())
+            },
+            Message::Config(_) | Message::ConfigReport(_) | Message::Ack(_) => Ok(())
+        }
+    }
+
+    /// The sequence to acknowledge to the gauge, everything up to it has been stored.
+    pub fn acked(&self, device_id:&[u8; 16]) -> Option<u32> {
+        self.received.get(device_id).and_then(|gauge| gauge.acked())
+    }
+
+    fn telemetry(&mut self, received_at:DateTime<Utc>, packet:&TelemetryPacket) -> Result<(), reqwest::Error> {
+        let gauge = self.received.entry(packet.device_id).or_insert_with(Received::new);
+        if !gauge.telemetry(packet) {
+            return Ok(());
+        }
+
+        info!("received:{:?}, posting to {}", packet, self.urls.telemetry);
+        let posted = post(&self.client, &self.urls.telemetry, self.api_key.as_deref(), received_at, packet)?;
+        if let (true, Some(sequence)) = (posted, packet.sequence) {
+            gauge.stored(sequence);
+        }
+        Ok(())
+    }
+
+    // The readings a gauge sent again, as telemetry dated from how long before the packet the gauge took them.
+    // Those the gauge's clock cannot date go to the dead letter file instead.  Either way they are only marked as
+    // stored, and so acknowledged, once they are somewhere.
+    fn history(&mut self, received_at:DateTime<Utc>, history:&HistoryPacket) -> Result<(), reqwest::Error> {
+        let gauge = self.received.entry(history.device_id).or_insert_with(Received::new);
+        let url = &self.urls.telemetry;
+        let dead_letter_file = &self.dead_letter_file;
+
+        for (record, age_secs) in gauge.unstored(history).collect::<Vec<_>>() {
+            let packet = history.telemetry(record);
+            let resent = Resent { packet: &packet, history: true };
+            let stored = match age_secs {
+                Some(age_secs) => {
+                    let taken_at = received_at - Duration::seconds(age_secs as i64);
+                    info!("received:{:?} from {}, posting to {}", packet, taken_at.to_rfc3339(), url);
+                    post(&self.client, url, self.api_key.as_deref(), taken_at, &resent)?
+                },
+                None => {
+                    warn!("received:{:?}, which cannot be dated, writing it to {}", packet, dead_letter_file);
+                    match write_dead_letter(dead_letter_file, received_at, &packet) {
+                        Ok(()) => true,
+                        Err(err) => {
+                            error!("Could not write to {}, not acknowledging it: {:?}", dead_letter_file, err);
+                            false
+                        }
+                    }
+                }
+            };
+            if stored {
+                gauge.stored(record.sequence);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Returns whether the service took it.
+fn post<T:Serialize>(client:&Client, url:&str, api_key:Option<&str>, received_at:DateTime<Utc>, body:&T)
+    -> Result<bool, reqwest::Error> {
+    let mut request = client.post(url)
+        .header("X-Received-At", received_at.to_rfc3339())
+        .json(body);
+    if let Some(api_key) = api_key {
+        request = request.bearer_auth(api_key);
+    }
+    let res = request.send()?;
+
+    info!("response: {}", res.status());
+    if res.status() == reqwest::StatusCode::UNAUTHORIZED || res.status() == reqwest::StatusCode::FORBIDDEN {
+        error!("The telemetry service rejected the api key, it needs an ingest key");
+    } else if !res.status().is_success() {
+        warn!("{} answered {}", url, res.status());
+    }
+
+    Ok(res.status().is_success())
+}
+
+// The same as the telemetry service's dead letter file, so once the readings are dated `telemetry-http-service import`
+// can load it.  Until then received_at is when the history was heard, which is some time after the reading was taken,
+// and the reading is marked undated so the import refuses it.
+fn write_dead_letter(dead_letter_file:&str, received_at:DateTime<Utc>, packet:&TelemetryPacket) -> io::Result<()> {
+    let mut line = serde_json::to_value(Resent { packet, history: true })?;
+    line["received_at"] = serde_json::Value::String(received_at.to_rfc3339());
+    line["undated"] = serde_json::Value::Bool(true);
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dead_letter_file)?;
+    writeln!(file, "{}", line)?;
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dead_letters_are_the_services_format() {
+        let path = std::env::temp_dir().join("dead_letters_are_the_services_format.ndjson");
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+
+        let mut packet = TelemetryPacket::new();
+        packet.tip_cnt = 7;
+        packet.sequence = Some(12);
+        let received_at = DateTime::parse_from_rfc3339("2020-09-01T12:00:00Z").unwrap().with_timezone(&Utc);
+        write_dead_letter(path, received_at, &packet).unwrap();
+        write_dead_letter(path, received_at, &packet).unwrap();
+
+        let text = std::fs::read_to_string(path).unwrap();
+        let lines = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect::<Vec<serde_json::Value>>();
+        assert_eq!(2, lines.len());
+        assert_eq!("2020-09-01T12:00:00+00:00", lines[0]["received_at"]);
+        assert_eq!(7, lines[0]["tip_cnt"]);
+        assert_eq!(12, lines[0]["sequence"]);
+        assert_eq!(true, lines[0]["history"]);
+        assert_eq!(true, lines[0]["undated"]);
+    }
+}
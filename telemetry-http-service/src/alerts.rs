@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use dotenv::var;
+
+use rainguage_messages::TelemetryPacket;
+
+use crate::battery;
+use crate::battery::Hardware;
+use crate::counters;
+use crate::database::Pool;
+use crate::device::DeviceId;
+use crate::registry::Latest;
+use crate::registry::Registry;
+use crate::sinks::Notification;
+use crate::sinks::Sink;
+use crate::sinks::State;
+
+// About as low as a LiPo should go.
+const DEFAULT_VBAT_MIN:f64 = 3.5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Rule {
+    /// No packets for a while.
+    Silent,
+    /// The battery is nearly flat.
+    LowBattery,
+    /// One of the error counters went up.
+    ErrorsIncreasing,
+    /// The temperature and humidity are both 0.0, which is what the gauge sends until the DHT22 gives a reading.
+    TemperatureStuck
+}
+
+impl Rule {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rule::Silent => "silent",
+            Rule::LowBattery => "low_battery",
+            Rule::ErrorsIncreasing => "errors_increasing",
+            Rule::TemperatureStuck => "temperature_stuck"
+        }
+    }
+}
+
+pub struct Rules {
+    /// How often the rules are checked.
+    pub interval: Duration,
+    /// A device is silent after this long without a packet.
+    pub silent_after: Duration,
+    /// The battery voltage below which it is low, after the device's calibration.
+    pub vbat_min: f64,
+    /// A firing alert is sent again after this long.
+    pub repeat_after: Duration
+}
+
+fn env_i64(name:&str, default:i64) -> i64 {
+    var(name).ok().and_then(|value| value.parse::<i64>().ok()).unwrap_or(default)
+}
+
+fn env_f64(name:&str, default:f64) -> f64 {
+    var(name).ok().and_then(|value| value.parse::<f64>().ok()).unwrap_or(default)
+}
+
+impl Rules {
+    pub fn from_env() -> Rules {
+        Rules {
+            interval: Duration::seconds(env_i64("ALERT_INTERVAL_SECS", 60)),
+            silent_after: Duration::minutes(env_i64("ALERT_SILENT_MINUTES", 30)),
+            vbat_min: env_f64("ALERT_VBAT_MIN", DEFAULT_VBAT_MIN),
+            repeat_after: Duration::hours(env_i64("ALERT_REPEAT_HOURS", 24))
+        }
+    }
+}
+
+struct Firing {
+    notified_at: DateTime<Utc>
+}
+
+/// Works out which alerts are firing and only reports changes, so each problem is sent once when it starts, again
+/// every `repeat_after` while it continues, and once more when it recovers.
+pub struct Alerter {
+    rules: Rules,
+    // The hardware and vbat scale of each device, those not here are a feather m0 with no correction.
+    calibrations: HashMap<DeviceId, (Hardware, f64)>,
+    firing: HashMap<(DeviceId, Rule), Firing>,
+    // The packet from the previous check and whether its error counters had gone up.
+    previous: HashMap<DeviceId, (Latest, Option<String>)>
+}
+
+fn error_increase(prev:&TelemetryPacket, cur:&TelemetryPacket) -> Option<String> {
+    let rebooted = counters::rebooted(prev.loop_cnt, cur.loop_cnt);
+    let errors = [
+        ("usb_error_cnt", prev.usb_error_cnt, cur.usb_error_cnt),
+        ("lora_error_cnt", prev.lora_error_cnt, cur.lora_error_cnt),
+        ("hardware_err_other_cnt", prev.hardware_err_other_cnt, cur.hardware_err_other_cnt)
+    ];
+
+    let increases = errors.iter()
+        .map(|(name, prev, cur)| (name, counters::delta(*prev, *cur, rebooted)))
+        .filter(|(_, delta)| *delta > 0)
+        .map(|(name, delta)| format!("{} +{}", name, delta))
+        .collect::<Vec<String>>();
+
+    if increases.is_empty() {
+        None
+    } else {
+        Some(format!("error counters went up: {}", increases.join(", ")))
+    }
+}
+
+impl Alerter {
+    pub fn new(rules:Rules) -> Alerter {
+        Alerter {
+            rules,
+            calibrations: HashMap::new(),
+            firing: HashMap::new(),
+            previous: HashMap::new()
+        }
+    }
+
+    /// How to turn a device's vbat into volts, from battery::calibrated.
+    pub fn calibrate(&mut self, device_id:DeviceId, hardware:Hardware, vbat_scale:f64) {
+        self.calibrations.insert(device_id, (hardware, vbat_scale));
+    }
+
+    /// What is wrong with a device right now, as a message for each rule that applies.
+    fn check(&mut self, device_id:&DeviceId, latest:&Latest, now:DateTime<Utc>) -> Vec<(Rule, Option<String>)> {
+        let packet = &latest.packet;
+
+        let silent = if now - latest.received_at > self.rules.silent_after {
+            Some(format!("no packets for {} minutes, last seen {}", (now - latest.received_at).num_minutes(), latest.received_at.to_rfc3339()))
+        } else {
+            None
+        };
+
+        let volts = match self.calibrations.get(device_id) {
+            Some((hardware, vbat_scale)) => hardware.volts(packet.vbat, *vbat_scale),
+            None => Hardware::default().volts(packet.vbat, 1.0)
+        };
+        let low_battery = if volts < self.rules.vbat_min {
+            Some(format!("battery {:.2}V is below {:.2}V", volts, self.rules.vbat_min))
+        } else {
+            None
+        };
+
+        // Only compare counters when a new packet has arrived, otherwise keep what the last packet said.
+        let errors = match self.previous.get(device_id) {
+            Some((previous, errors)) if previous.received_at == latest.received_at => errors.clone(),
+            Some((previous, _)) => error_increase(&previous.packet, packet),
+            None => None
+        };
+        self.previous.insert(*device_id, (latest.clone(), errors.clone()));
+
+        let stuck = if packet.temperature == 0.0 && packet.relative_humidity == 0.0 {
+            Some("temperature and humidity are both 0.0, the DHT22 is not giving readings".to_string())
+        } else {
+            None
+        };
+
+        vec![
+            (Rule::Silent, silent),
+            (Rule::LowBattery, low_battery),
+            (Rule::ErrorsIncreasing, errors),
+            (Rule::TemperatureStuck, stuck)
+        ]
+    }
+
+    pub fn evaluate(&mut self, devices:&[(DeviceId, Latest)], now:DateTime<Utc>) -> Vec<Notification> {
+        let mut notifications = vec![];
+
+        for (device_id, latest) in devices {
+            for (rule, message) in self.check(device_id, latest, now) {
+                let key = (*device_id, rule);
+                let notified_at = self.firing.get(&key).map(|firing| firing.notified_at);
+
+                match (notified_at, message) {
+                    (None, Some(message)) => {
+                        self.firing.insert(key, Firing { notified_at: now });
+                        notifications.push(Notification { device_id: *device_id, rule: rule.name(), state: State::Firing, message });
+                    },
+                    (Some(notified_at), Some(message)) => {
+                        if now - notified_at >= self.rules.repeat_after {
+                            self.firing.insert(key, Firing { notified_at: now });
+                            notifications.push(Notification { device_id: *device_id, rule: rule.name(), state: State::Firing, message });
+                        }
+                    },
+                    (Some(_), None) => {
+                        self.firing.remove(&key);
+                        notifications.push(Notification {
+                            device_id: *device_id,
+                            rule: rule.name(),
+                            state: State::Resolved,
+                            message: format!("{} has recovered", rule.name())
+                        });
+                    },
+                    (None, None) => {}
+                }
+            }
+        }
+
+        notifications
+    }
+}
+
+pub fn start(pool:Pool, registry:Arc<Registry>, mut sinks:Vec<Box<dyn Sink>>) {
+    let rules = Rules::from_env();
+    let interval = rules.interval.to_std().unwrap_or(std::time::Duration::from_secs(60));
+    let mut alerter = Alerter::new(rules);
+
+    info!("Alerting to {}", sinks.iter().map(|sink| sink.name()).collect::<Vec<&str>>().join(", "));
+
+    thread::spawn(move|| {
+        loop {
+            thread::sleep(interval);
+
+            let devices = registry.snapshot();
+            // Calibrations change rarely, but looking them up each time keeps the alerts in line with /battery.
+            for (device_id, _) in devices.iter() {
+                match battery::calibrated(&pool, device_id) {
+                    Ok((hardware, vbat_scale)) => alerter.calibrate(*device_id, hardware, vbat_scale),
+                    Err(err) => warn!("Could not load the battery calibration of {}: {:?}", device_id, err)
+                }
+            }
+
+            for notification in alerter.evaluate(&devices, Utc::now()) {
+                for sink in sinks.iter_mut() {
+                    if let Err(err) = sink.notify(&notification) {
+                        error!("Could not send {} to {}: {:?}", notification.subject(), sink.name(), err);
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn rules() -> Rules {
+        Rules {
+            interval: Duration::minutes(1),
+            silent_after: Duration::minutes(30),
+            vbat_min: 3.5,
+            repeat_after: Duration::hours(24)
+        }
+    }
+
+    fn latest(minute:i64) -> (DeviceId, Latest) {
+        let mut packet = TelemetryPacket::new();
+        packet.device_id = [1; 16];
+        packet.vbat = 2500;
+        packet.temperature = 20.0;
+        packet.relative_humidity = 50.0;
+
+        (DeviceId(packet.device_id), Latest { packet, received_at: time(minute) })
+    }
+
+    fn time(minute:i64) -> DateTime<Utc> {
+        Utc.ymd(2020, 9, 1).and_hms(0, 0, 0) + Duration::minutes(minute)
+    }
+
+    fn summary(notifications:&[Notification]) -> Vec<(&'static str, State)> {
+        notifications.iter().map(|n| (n.rule, n.state)).collect()
+    }
+
+    #[test]
+    fn silent_fires_once_then_recovers() {
+        let mut alerter = Alerter::new(rules());
+        let devices = [latest(0)];
+
+        assert!(alerter.evaluate(&devices, time(10)).is_empty());
+        assert_eq!(vec![("silent", State::Firing)], summary(&alerter.evaluate(&devices, time(31))));
+        assert!(alerter.evaluate(&devices, time(60)).is_empty());
+        assert_eq!(vec![("silent", State::Firing)], summary(&alerter.evaluate(&devices, time(31 + 24 * 60))));
+
+        let devices = [latest(24 * 60 + 40)];
+        assert_eq!(vec![("silent", State::Resolved)], summary(&alerter.evaluate(&devices, time(24 * 60 + 41))));
+    }
+
+    #[test]
+    fn errors_increasing_needs_a_new_packet() {
+        let mut alerter = Alerter::new(rules());
+
+        let (device_id, mut first) = latest(0);
+        first.packet.loop_cnt = 100;
+        assert!(alerter.evaluate(&[(device_id, first.clone())], time(0)).is_empty());
+
+        let mut second = first.clone();
+        second.received_at = time(1);
+        second.packet.loop_cnt = 300;
+        second.packet.lora_error_cnt = 2;
+        assert_eq!(vec![("errors_increasing", State::Firing)], summary(&alerter.evaluate(&[(device_id, second.clone())], time(1))));
+
+        // No new packet, so nothing has changed.
+        assert!(alerter.evaluate(&[(device_id, second.clone())], time(2)).is_empty());
+
+        let mut third = second.clone();
+        third.received_at = time(3);
+        third.packet.loop_cnt = 500;
+        assert_eq!(vec![("errors_increasing", State::Resolved)], summary(&alerter.evaluate(&[(device_id, third)], time(3))));
+    }
+
+    #[test]
+    fn battery_and_stuck_sensor() {
+        let mut alerter = Alerter::new(rules());
+
+        let (device_id, mut flat) = latest(0);
+        flat.packet.vbat = 1500;
+        flat.packet.temperature = 0.0;
+        flat.packet.relative_humidity = 0.0;
+
+        assert_eq!(vec![("low_battery", State::Firing), ("temperature_stuck", State::Firing)],
+            summary(&alerter.evaluate(&[(device_id, flat)], time(1))));
+    }
+
+    #[test]
+    fn battery_uses_the_calibration() {
+        let mut alerter = Alerter::new(rules());
+
+        // 2200 is 3.55V on a feather m0.
+        let (device_id, mut low) = latest(0);
+        low.packet.vbat = 2200;
+        assert!(alerter.evaluate(&[(device_id, low.clone())], time(1)).is_empty());
+
+        // But this one's divider reads high.
+        alerter.calibrate(device_id, Hardware::default(), 0.95);
+        assert_eq!(vec![("low_battery", State::Firing)], summary(&alerter.evaluate(&[(device_id, low)], time(2))));
+    }
+}
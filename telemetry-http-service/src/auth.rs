@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Cursor;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use chrono::DateTime;
+use chrono::Utc;
+use rand::RngCore;
+use sha2::Digest;
+use sha2::Sha256;
+
+use rocket::http::ContentType;
+use rocket::http::Status;
+use rocket::request;
+use rocket::request::FromRequest;
+use rocket::request::Request;
+use rocket::response;
+use rocket::response::Responder;
+use rocket::response::Response;
+use rocket::Outcome;
+use rocket::State;
+
+use crate::database::DatabaseError;
+use crate::database::Pool;
+
+// Keys look like rgk_ followed by 64 hex digits, the prefix makes them easy to spot if they leak.
+const KEY_PREFIX:&str = "rgk_";
+
+// How long a key that has been checked against the database is trusted for.  Revoking a key takes this long to
+// take effect.
+const CACHE_TTL:Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    /// POST /telemetry, used by the downlink-processor.
+    Ingest,
+    /// Everything that reads telemetry back.
+    Read,
+    /// Read, plus changing calibration and hardware revisions.
+    Admin
+}
+
+impl Scope {
+    fn allows(&self, needed:Scope) -> bool {
+        *self == needed || (*self == Scope::Admin && needed == Scope::Read)
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scope::Ingest => write!(f, "ingest"),
+            Scope::Read => write!(f, "read"),
+            Scope::Admin => write!(f, "admin")
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ingest" => Ok(Scope::Ingest),
+            "read" => Ok(Scope::Read),
+            "admin" => Ok(Scope::Admin),
+            other => Err(format!("unknown scope {}, expected ingest, read or admin", other))
+        }
+    }
+}
+
+fn hash(key:&str) -> Vec<u8> {
+    Sha256::digest(key.as_bytes()).to_vec()
+}
+
+/// Checks api keys against the hashes in the database.
+pub struct Authenticator {
+    // None when authentication is turned off.
+    pool: Option<Pool>,
+    cache: Mutex<HashMap<Vec<u8>, (Scope, Instant)>>
+}
+
+impl Authenticator {
+    pub fn new(pool:Pool) -> Authenticator {
+        Authenticator {
+            pool: Some(pool),
+            cache: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Let every request through.
+    pub fn disabled() -> Authenticator {
+        Authenticator {
+            pool: None,
+            cache: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Trust a key without asking the database.
+    #[cfg(test)]
+    pub fn remember(&self, key:&str, scope:Scope) {
+        self.cache.lock().unwrap().insert(hash(key), (scope, Instant::now() + Duration::from_secs(3600)));
+    }
+
+    fn scope(&self, key:&str) -> Result<Option<Scope>, DatabaseError> {
+        let key_hash = hash(key);
+
+        if let Some((scope, expires)) = self.cache.lock().unwrap().get(&key_hash) {
+            if *expires > Instant::now() {
+                return Ok(Some(*scope));
+            }
+        }
+
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return Ok(None)
+        };
+
+        let mut client = pool.get()?;
+        let rows = client.query("SELECT scope FROM api_key WHERE key_hash = $1", &[&key_hash])?;
+        let scope = rows.first().and_then(|row| row.get::<_, String>(0).parse::<Scope>().ok());
+
+        if let Some(scope) = scope {
+            self.cache.lock().unwrap().insert(key_hash, (scope, Instant::now() + CACHE_TTL));
+        }
+
+        Ok(scope)
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+    WrongScope,
+    DatabaseError(DatabaseError)
+}
+
+/// Pull the key out of `Authorization: Bearer`, `X-Api-Key`, or the `access_token` query parameter for clients
+/// like EventSource that cannot set headers.
+fn key(request:&Request) -> Option<String> {
+    if let Some(authorization) = request.headers().get_one("Authorization") {
+        let mut parts = authorization.splitn(2, ' ');
+        if let (Some(kind), Some(token)) = (parts.next(), parts.next()) {
+            if kind.eq_ignore_ascii_case("bearer") {
+                return Some(token.trim().to_string());
+            }
+        }
+    }
+
+    if let Some(key) = request.headers().get_one("X-Api-Key") {
+        return Some(key.trim().to_string());
+    }
+
+    request.get_query_value::<String>("access_token").and_then(|key| key.ok())
+}
+
+fn authorize(request:&Request, needed:Scope) -> request::Outcome<(), AuthError> {
+    let authenticator = match request.guard::<State<Authenticator>>() {
+        Outcome::Success(authenticator) => authenticator,
+        _ => return Outcome::Failure((Status::InternalServerError, AuthError::Missing))
+    };
+
+    if authenticator.pool.is_none() {
+        return Outcome::Success(());
+    }
+
+    let key = match key(request) {
+        Some(key) => key,
+        None => return Outcome::Failure((Status::Unauthorized, AuthError::Missing))
+    };
+
+    match authenticator.scope(&key) {
+        Ok(Some(scope)) if scope.allows(needed) => Outcome::Success(()),
+        Ok(Some(scope)) => {
+            warn!("A {} key was used for {} {}", scope, request.method(), request.uri().path());
+            Outcome::Failure((Status::Forbidden, AuthError::WrongScope))
+        },
+        Ok(None) => Outcome::Failure((Status::Unauthorized, AuthError::Invalid)),
+        Err(err) => {
+            error!("Could not check an api key: {:?}", err);
+            Outcome::Failure((Status::ServiceUnavailable, AuthError::DatabaseError(err)))
+        }
+    }
+}
+
+/// A request guard for routes that need an ingest key.
+pub struct IngestKey;
+
+impl<'a, 'r> FromRequest<'a, 'r> for IngestKey {
+    type Error = AuthError;
+
+    fn from_request(request:&'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        authorize(request, Scope::Ingest).map(|_| IngestKey)
+    }
+}
+
+/// A request guard for routes that need a read or admin key.
+pub struct ReadKey;
+
+impl<'a, 'r> FromRequest<'a, 'r> for ReadKey {
+    type Error = AuthError;
+
+    fn from_request(request:&'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        authorize(request, Scope::Read).map(|_| ReadKey)
+    }
+}
+
+/// A request guard for routes that need an admin key.
+pub struct AdminKey;
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminKey {
+    type Error = AuthError;
+
+    fn from_request(request:&'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        authorize(request, Scope::Admin).map(|_| AdminKey)
+    }
+}
+
+/// The 401 response, telling the client how to authenticate.
+pub struct Challenge;
+
+impl<'r> Responder<'r> for Challenge {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .status(Status::Unauthorized)
+            .raw_header("WWW-Authenticate", "Bearer realm=\"rainguage\"")
+            .header(ContentType::Plain)
+            .sized_body(Cursor::new("an api key is required"))
+            .ok()
+    }
+}
+
+#[catch(401)]
+pub fn unauthorized(_request:&Request) -> Challenge {
+    Challenge
+}
+
+/// Make a new key, store its hash and return the key.  The key itself is not kept anywhere.
+pub fn create_key(pool:&Pool, name:&str, scope:Scope) -> Result<String, DatabaseError> {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let key = format!("{}{}", KEY_PREFIX, secret.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+
+    let mut client = pool.get()?;
+    client.execute("INSERT INTO api_key (name, key_hash, scope) VALUES ($1, $2, $3)",
+        &[&name, &hash(&key), &scope.to_string()])?;
+
+    Ok(key)
+}
+
+pub fn list_keys(pool:&Pool) -> Result<Vec<(String, String, DateTime<Utc>)>, DatabaseError> {
+    let mut client = pool.get()?;
+    let rows = client.query("SELECT name, scope, created_at FROM api_key ORDER BY name", &[])?;
+
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1), row.get(2))).collect())
+}
+
+/// Returns false when there was no key with that name.
+pub fn revoke_key(pool:&Pool, name:&str) -> Result<bool, DatabaseError> {
+    let mut client = pool.get()?;
+    Ok(client.execute("DELETE FROM api_key WHERE name = $1", &[&name])? > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_can_read_but_not_ingest() {
+        assert!(Scope::Admin.allows(Scope::Read));
+        assert!(Scope::Admin.allows(Scope::Admin));
+        assert!(!Scope::Admin.allows(Scope::Ingest));
+        assert!(!Scope::Read.allows(Scope::Admin));
+        assert!(!Scope::Ingest.allows(Scope::Read));
+    }
+
+    #[test]
+    fn scopes_round_trip() {
+        for scope in [Scope::Ingest, Scope::Read, Scope::Admin].iter() {
+            assert_eq!(Ok(*scope), scope.to_string().parse::<Scope>());
+        }
+        assert!("write".parse::<Scope>().is_err());
+    }
+}
@@ -0,0 +1,386 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use dotenv::var;
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+
+use crate::auth::AdminKey;
+use crate::auth::ReadKey;
+use crate::database::Pool;
+use crate::device::DeviceId;
+use crate::query;
+use crate::query::Format;
+use crate::query::Output;
+use crate::query::QueryError;
+
+// The hardware revision used for devices that have not been given one.
+const DEFAULT_HARDWARE_REVISION:&str = "feather-m0";
+
+// The discharge rate is worked out from this much history.
+const DEFAULT_WINDOW_HOURS:i64 = 72;
+
+// Resting voltage against state of charge for a single cell LiPo, highest first.
+const DISCHARGE_CURVE:[(f64, f64); 21] = [
+    (4.20, 100.0), (4.15, 95.0), (4.11, 90.0), (4.08, 85.0), (4.02, 80.0), (3.98, 75.0), (3.95, 70.0),
+    (3.91, 65.0), (3.87, 60.0), (3.85, 55.0), (3.84, 50.0), (3.82, 45.0), (3.80, 40.0), (3.79, 35.0),
+    (3.77, 30.0), (3.75, 25.0), (3.73, 20.0), (3.71, 15.0), (3.69, 10.0), (3.61, 5.0), (3.27, 0.0)
+];
+
+/// How the battery is wired to the adc on a hardware revision.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Hardware {
+    /// The battery voltage is divided by this before it reaches the pin.
+    pub divider: f64,
+    /// The adc reference voltage.
+    pub reference_volts: f64,
+    /// The adc input gain.
+    pub gain: f64,
+    /// The reading at full scale.
+    pub adc_max: u32
+}
+
+impl Default for Hardware {
+    /// The feather m0 has a 100k/100k divider on the battery into pin 7, and the firmware reads it with a gain of
+    /// 1/2 against VDDANA/2, so full scale is 3.3V at the pin and 6.6V at the battery.
+    fn default() -> Hardware {
+        Hardware {
+            divider: 2.0,
+            reference_volts: 1.65,
+            gain: 0.5,
+            adc_max: 4095
+        }
+    }
+}
+
+impl Hardware {
+    /// The battery voltage for a raw reading.  `scale` corrects for the tolerance of a particular device's divider.
+    pub fn volts(&self, vbat:u32, scale:f64) -> f64 {
+        vbat as f64 / self.adc_max as f64 * self.reference_volts / self.gain * self.divider * scale
+    }
+
+    fn validate(&self) -> Result<(), QueryError> {
+        let positive = |value:f64| value.is_finite() && value > 0.0;
+        if !positive(self.divider) || !positive(self.reference_volts) || !positive(self.gain) || self.adc_max == 0 {
+            return Err(QueryError::BadRequest("divider, reference_volts, gain and adc_max must be positive".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Which hardware a device is and the correction for its divider.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BatteryCalibration {
+    pub hardware_revision: String,
+    pub vbat_scale: f64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reading {
+    pub ts: DateTime<Utc>,
+    pub vbat: u32,
+    pub volts: f64,
+    pub state_of_charge: f64
+}
+
+/// The estimated remaining runtime from the recent discharge.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Runtime {
+    /// Percent per day.
+    pub discharge_rate: f64,
+    pub days_remaining: f64
+}
+
+/// Estimate the percentage of charge left from the battery voltage.
+pub fn state_of_charge(volts:f64) -> f64 {
+    let (highest, _) = DISCHARGE_CURVE[0];
+    let (lowest, _) = DISCHARGE_CURVE[DISCHARGE_CURVE.len() - 1];
+
+    if volts >= highest {
+        return 100.0;
+    }
+    if volts <= lowest {
+        return 0.0;
+    }
+
+    for pair in DISCHARGE_CURVE.windows(2) {
+        let (high_volts, high_soc) = pair[0];
+        let (low_volts, low_soc) = pair[1];
+
+        if volts >= low_volts {
+            return low_soc + (volts - low_volts) / (high_volts - low_volts) * (high_soc - low_soc);
+        }
+    }
+
+    0.0
+}
+
+/// Fit a line through the readings since the battery was last at its fullest and work out how long until it is
+/// empty.  Returns None while the battery is charging or holding steady, or when there is too little to go on.
+pub fn runtime(readings:&[Reading]) -> Option<Runtime> {
+    // Anything before the last peak is from before a charge.
+    let peak = readings.iter()
+        .enumerate()
+        .fold(None, |peak:Option<(usize, f64)>, (i, reading)| match peak {
+            Some((_, soc)) if soc > reading.state_of_charge => peak,
+            _ => Some((i, reading.state_of_charge))
+        })?
+        .0;
+    let readings = &readings[peak..];
+
+    let last = readings.last()?;
+    if readings.len() < 3 || last.ts - readings[0].ts < Duration::hours(1) {
+        return None;
+    }
+
+    let days = |reading:&Reading| (reading.ts - readings[0].ts).num_seconds() as f64 / 86400.0;
+    let n = readings.len() as f64;
+    let mean_t = readings.iter().map(days).sum::<f64>() / n;
+    let mean_soc = readings.iter().map(|reading| reading.state_of_charge).sum::<f64>() / n;
+
+    let covariance = readings.iter().map(|reading| (days(reading) - mean_t) * (reading.state_of_charge - mean_soc)).sum::<f64>();
+    let variance = readings.iter().map(|reading| (days(reading) - mean_t).powi(2)).sum::<f64>();
+    if variance == 0.0 {
+        return None;
+    }
+
+    let discharge_rate = -covariance / variance;
+    if discharge_rate <= 0.0 {
+        return None;
+    }
+
+    Some(Runtime {
+        discharge_rate,
+        days_remaining: last.state_of_charge / discharge_rate
+    })
+}
+
+fn default_hardware_revision() -> String {
+    var("BATTERY_HARDWARE_REVISION").unwrap_or_else(|_| DEFAULT_HARDWARE_REVISION.to_string())
+}
+
+fn calibration(pool:&Pool, device_id:&DeviceId) -> Result<BatteryCalibration, QueryError> {
+    let mut client = pool.get()?;
+    let rows = client.query("SELECT hardware_revision, vbat_scale FROM calibration WHERE device_id = $1", &[&device_id.as_bytes()])?;
+
+    Ok(BatteryCalibration {
+        hardware_revision: rows.first()
+            .and_then(|row| row.get::<_, Option<String>>(0))
+            .unwrap_or_else(default_hardware_revision),
+        vbat_scale: rows.first()
+            .and_then(|row| row.get::<_, Option<f64>>(1))
+            .unwrap_or(1.0)
+    })
+}
+
+fn hardware(pool:&Pool, revision:&str) -> Result<Option<Hardware>, QueryError> {
+    let mut client = pool.get()?;
+    let rows = client.query("
+        SELECT divider, reference_volts, gain, adc_max FROM hardware_revision WHERE name = $1", &[&revision])?;
+
+    Ok(rows.first().map(|row| Hardware {
+        divider: row.get(0),
+        reference_volts: row.get(1),
+        gain: row.get(2),
+        adc_max: row.get::<_, i64>(3) as u32
+    }))
+}
+
+/// The hardware a device is and the scale for its divider, to pass to `Hardware::volts`.
+pub fn calibrated(pool:&Pool, device_id:&DeviceId) -> Result<(Hardware, f64), QueryError> {
+    let calibration = calibration(pool, device_id)?;
+    let hardware = match hardware(pool, &calibration.hardware_revision)? {
+        Some(hardware) => hardware,
+        None => {
+            warn!("Unknown hardware revision {} for {}, using the feather m0", calibration.hardware_revision, device_id);
+            Hardware::default()
+        }
+    };
+
+    Ok((hardware, calibration.vbat_scale))
+}
+
+fn load_readings(pool:&Pool, device_id:&DeviceId, from:DateTime<Utc>, to:DateTime<Utc>) -> Result<Vec<Reading>, QueryError> {
+    let (hardware, vbat_scale) = calibrated(pool, device_id)?;
+
+    let mut client = pool.get()?;
+    let rows = client.query("
+        SELECT ts, vbat
+        FROM telemetry
+        WHERE device_id = $1 AND ts >= $2 AND ts < $3 AND vbat IS NOT NULL
+        ORDER BY ts", &[&device_id.as_bytes(), &from, &to])?;
+
+    Ok(rows.iter()
+        .map(|row| {
+            let vbat = row.get::<_, i64>(1) as u32;
+            let volts = hardware.volts(vbat, vbat_scale);
+            Reading { ts: row.get(0), vbat, volts, state_of_charge: state_of_charge(volts) }
+        })
+        .collect())
+}
+
+/// Battery voltage and state of charge for each reading.  Defaults to the last week.
+#[get("/devices/<device_id>/battery?<from>&<to>&<format>")]
+pub fn battery(device_id:DeviceId, from:Option<String>, to:Option<String>, format:Option<String>,
+        pool:State<Pool>, _key:ReadKey) -> Result<Output, QueryError> {
+    let format = Format::parse(format)?;
+    let to = query::parse_time("to", to)?.unwrap_or_else(Utc::now);
+    let from = query::parse_time("from", from)?.unwrap_or_else(|| to - Duration::days(7));
+
+    let readings = load_readings(&pool, &device_id, from, to)?;
+
+    match format {
+        Format::Json => {
+            let readings = readings.iter()
+                .map(|reading| json!({
+                    "ts": reading.ts.to_rfc3339(),
+                    "vbat": reading.vbat,
+                    "volts": reading.volts,
+                    "state_of_charge": reading.state_of_charge
+                }))
+                .collect();
+            Ok(Output::Json(Json(serde_json::Value::Array(readings))))
+        },
+        Format::Csv => {
+            let records = readings.iter()
+                .map(|reading| vec![
+                    reading.ts.to_rfc3339(),
+                    reading.vbat.to_string(),
+                    reading.volts.to_string(),
+                    reading.state_of_charge.to_string()
+                ])
+                .collect();
+            Ok(Output::Csv(Content(ContentType::CSV, query::csv(&["ts", "vbat", "volts", "state_of_charge"], records))))
+        }
+    }
+}
+
+/// The latest state of charge and how many days are left at the rate it has been going down over the last
+/// `window` hours.
+#[get("/devices/<device_id>/battery/runtime?<window>")]
+pub fn battery_runtime(device_id:DeviceId, window:Option<i64>, pool:State<Pool>,
+        _key:ReadKey) -> Result<Json<serde_json::Value>, QueryError> {
+    let window = window.unwrap_or(DEFAULT_WINDOW_HOURS);
+    if window < 1 {
+        return Err(QueryError::BadRequest("window must be at least one hour".to_string()));
+    }
+
+    let to = Utc::now();
+    let readings = load_readings(&pool, &device_id, to - Duration::hours(window), to)?;
+    let latest = readings.last().ok_or(QueryError::NotFound)?;
+    let runtime = runtime(&readings);
+
+    Ok(Json(json!({
+        "ts": latest.ts.to_rfc3339(),
+        "volts": latest.volts,
+        "state_of_charge": latest.state_of_charge,
+        "discharge_rate_per_day": runtime.map(|runtime| runtime.discharge_rate),
+        "days_remaining": runtime.map(|runtime| runtime.days_remaining)
+    })))
+}
+
+#[get("/devices/<device_id>/battery/calibration")]
+pub fn get_battery_calibration(device_id:DeviceId, pool:State<Pool>,
+        _key:ReadKey) -> Result<Json<BatteryCalibration>, QueryError> {
+    Ok(Json(calibration(&pool, &device_id)?))
+}
+
+#[put("/devices/<device_id>/battery/calibration", format = "json", data = "<calibration>")]
+pub fn put_battery_calibration(device_id:DeviceId, calibration:Json<BatteryCalibration>,
+        pool:State<Pool>, _key:AdminKey) -> Result<Json<BatteryCalibration>, QueryError> {
+    if !calibration.vbat_scale.is_finite() || calibration.vbat_scale <= 0.0 {
+        return Err(QueryError::BadRequest("vbat_scale must be positive".to_string()));
+    }
+    if hardware(&pool, &calibration.hardware_revision)?.is_none() {
+        return Err(QueryError::BadRequest(format!("unknown hardware revision {}", calibration.hardware_revision)));
+    }
+
+    let mut client = pool.get()?;
+    client.execute("
+        INSERT INTO calibration (device_id, hardware_revision, vbat_scale) VALUES ($1, $2, $3)
+        ON CONFLICT (device_id) DO UPDATE SET
+            hardware_revision = EXCLUDED.hardware_revision, vbat_scale = EXCLUDED.vbat_scale",
+        &[&device_id.as_bytes(), &calibration.hardware_revision, &calibration.vbat_scale])?;
+
+    Ok(calibration)
+}
+
+#[get("/hardware/<revision>")]
+pub fn get_hardware(revision:String, pool:State<Pool>, _key:ReadKey) -> Result<Json<Hardware>, QueryError> {
+    hardware(&pool, &revision)?
+        .map(Json)
+        .ok_or(QueryError::NotFound)
+}
+
+#[put("/hardware/<revision>", format = "json", data = "<hardware>")]
+pub fn put_hardware(revision:String, hardware:Json<Hardware>, pool:State<Pool>,
+        _key:AdminKey) -> Result<Json<Hardware>, QueryError> {
+    hardware.validate()?;
+
+    let mut client = pool.get()?;
+    client.execute("
+        INSERT INTO hardware_revision (name, divider, reference_volts, gain, adc_max) VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (name) DO UPDATE SET
+            divider = EXCLUDED.divider, reference_volts = EXCLUDED.reference_volts, gain = EXCLUDED.gain,
+            adc_max = EXCLUDED.adc_max",
+        &[&revision, &hardware.divider, &hardware.reference_volts, &hardware.gain, &(hardware.adc_max as i64)])?;
+
+    Ok(hardware)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn reading(hour:i64, state_of_charge:f64) -> Reading {
+        Reading {
+            ts: Utc.ymd(2020, 9, 1).and_hms(0, 0, 0) + Duration::hours(hour),
+            vbat: 0,
+            volts: 0.0,
+            state_of_charge
+        }
+    }
+
+    #[test]
+    fn feather_m0_full_scale() {
+        let hardware = Hardware::default();
+
+        assert!((hardware.volts(4095, 1.0) - 6.6).abs() < 1e-9);
+        assert!((hardware.volts(2172, 1.0) - 3.5).abs() < 0.01);
+        assert!((hardware.volts(2172, 1.02) - 3.57).abs() < 0.01);
+    }
+
+    #[test]
+    fn state_of_charge_follows_the_curve() {
+        assert_eq!(100.0, state_of_charge(4.3));
+        assert_eq!(0.0, state_of_charge(3.0));
+        assert!((state_of_charge(3.84) - 50.0).abs() < 1e-9);
+        assert!((state_of_charge(3.65) - 7.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn runtime_from_discharge_after_last_charge() {
+        // Charged back up at hour 2, then down 1% every 6 hours.
+        let mut readings = vec![reading(0, 40.0), reading(1, 35.0), reading(2, 90.0)];
+        readings.extend((1..=8).map(|i| reading(2 + 6 * i, 90.0 - i as f64)));
+
+        let runtime = runtime(&readings).unwrap();
+        assert!((runtime.discharge_rate - 4.0).abs() < 1e-9);
+        assert!((runtime.days_remaining - 20.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn no_runtime_while_charging() {
+        let readings = (0..10).map(|i| reading(i, 50.0 + i as f64)).collect::<Vec<Reading>>();
+        assert_eq!(None, runtime(&readings));
+    }
+}
@@ -0,0 +1,705 @@
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use chrono::DateTime;
+use chrono::TimeZone;
+use chrono::Utc;
+use postgres::types::ToSql;
+use postgres::Row;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::database::DatabaseError;
+use crate::database::Pool;
+use crate::device::DeviceId;
+use crate::ingest::Received;
+
+// How many rows are fetched or inserted at a time, and how many go in each parquet row group.
+const BATCH_SIZE:usize = 10_000;
+
+// Every telemetry column that is exported, with its sql type.
+const COLUMNS:[(&str, &str); 19] = [
+    ("device_id", "BYTEA"),
+    ("ts", "TIMESTAMPTZ"),
+    ("received_at", "TIMESTAMPTZ"),
+    ("ts_reconstructed", "BOOL"),
+    ("loop_cnt", "BIGINT"),
+    ("tip_cnt", "BIGINT"),
+    ("vbat", "BIGINT"),
+    ("temperature", "REAL"),
+    ("relative_humidity", "REAL"),
+    ("usb_bytes_read", "BIGINT"),
+    ("usb_bytes_written", "BIGINT"),
+    ("usb_err_cnt", "BIGINT"),
+    ("lora_rx_bytes", "BIGINT"),
+    ("lora_tx_bytes", "BIGINT"),
+    ("lora_error_cnt", "BIGINT"),
+    ("hardware_error_other_cnt", "BIGINT"),
+    ("uptime_secs", "BIGINT"),
+    ("epoch", "BIGINT"),
+    ("sequence", "BIGINT")
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileFormat {
+    Csv,
+    Ndjson,
+    Parquet
+}
+
+impl FileFormat {
+    /// Guess the format from a file extension.
+    pub fn from_path(path:&Path) -> Option<FileFormat> {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| extension.parse().ok())
+    }
+}
+
+impl FromStr for FileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(FileFormat::Csv),
+            "ndjson" | "jsonl" => Ok(FileFormat::Ndjson),
+            "parquet" => Ok(FileFormat::Parquet),
+            other => Err(format!("unknown format {}, expected csv, ndjson or parquet", other))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BulkError {
+    Io(io::Error),
+    Csv(csv::Error),
+    #[cfg(feature = "parquet")]
+    Parquet(parquet::errors::ParquetError),
+    DatabaseError(DatabaseError),
+    /// A record that could not be read, with where it was.
+    Invalid(String),
+    #[cfg(not(feature = "parquet"))]
+    Unsupported(String)
+}
+
+impl From<io::Error> for BulkError {
+    fn from(err: io::Error) -> Self {
+        BulkError::Io(err)
+    }
+}
+
+impl From<csv::Error> for BulkError {
+    fn from(err: csv::Error) -> Self {
+        BulkError::Csv(err)
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl From<parquet::errors::ParquetError> for BulkError {
+    fn from(err: parquet::errors::ParquetError) -> Self {
+        BulkError::Parquet(err)
+    }
+}
+
+impl From<postgres::Error> for BulkError {
+    fn from(err: postgres::Error) -> Self {
+        BulkError::DatabaseError(DatabaseError::PostgresError(err))
+    }
+}
+
+impl From<r2d2::Error> for BulkError {
+    fn from(err: r2d2::Error) -> Self {
+        BulkError::DatabaseError(DatabaseError::PoolError(err))
+    }
+}
+
+/// One telemetry row as it is exported.  Device ids are hex and times are rfc3339, the same as the API.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub device_id: String,
+    pub ts: DateTime<Utc>,
+    pub received_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub ts_reconstructed: bool,
+    pub loop_cnt: Option<i64>,
+    pub tip_cnt: Option<i64>,
+    pub vbat: Option<i64>,
+    pub temperature: Option<f32>,
+    pub relative_humidity: Option<f32>,
+    pub usb_bytes_read: Option<i64>,
+    pub usb_bytes_written: Option<i64>,
+    pub usb_err_cnt: Option<i64>,
+    pub lora_rx_bytes: Option<i64>,
+    pub lora_tx_bytes: Option<i64>,
+    pub lora_error_cnt: Option<i64>,
+    pub hardware_error_other_cnt: Option<i64>,
+    // Exports from before it was added do not have it.
+    #[serde(default)]
+    pub uptime_secs: Option<i64>,
+    // Nor these.
+    #[serde(default)]
+    pub epoch: Option<i64>,
+    #[serde(default)]
+    pub sequence: Option<i64>
+}
+
+/// A packet from the dead letter file.  It never got as far as the database, so it is dated by when it was received.
+/// A reading sent again in a history packet has no counters, the same as when the persister stores it.
+impl From<Received> for Record {
+    fn from(received:Received) -> Self {
+        let packet = received.packet;
+        let history = received.history;
+        let counter = |value:u32| if history { None } else { Some(value as i64) };
+        Record {
+            device_id: DeviceId(packet.device_id).to_string(),
+            ts: received.received_at,
+            received_at: Some(received.received_at),
+            ts_reconstructed: false,
+            loop_cnt: Some(packet.loop_cnt as i64),
+            tip_cnt: Some(packet.tip_cnt as i64),
+            vbat: Some(packet.vbat as i64),
+            temperature: Some(packet.temperature),
+            relative_humidity: Some(packet.relative_humidity),
+            usb_bytes_read: counter(packet.usb_bytes_read),
+            usb_bytes_written: counter(packet.usb_bytes_written),
+            usb_err_cnt: counter(packet.usb_error_cnt),
+            lora_rx_bytes: counter(packet.lora_rx_bytes),
+            lora_tx_bytes: counter(packet.lora_tx_bytes),
+            lora_error_cnt: counter(packet.lora_error_cnt),
+            hardware_error_other_cnt: counter(packet.hardware_err_other_cnt),
+            uptime_secs: packet.uptime_secs.map(|secs| secs as i64),
+            epoch: packet.epoch.map(|epoch| epoch as i64),
+            sequence: packet.sequence.map(|sequence| sequence as i64)
+        }
+    }
+}
+
+fn record(row:&Row) -> Record {
+    Record {
+        device_id: DeviceId::from_slice(row.get::<_, &[u8]>(0)).map(|device_id| device_id.to_string()).unwrap_or_default(),
+        ts: row.get(1),
+        received_at: row.get(2),
+        ts_reconstructed: row.get(3),
+        loop_cnt: row.get(4),
+        tip_cnt: row.get(5),
+        vbat: row.get(6),
+        temperature: row.get(7),
+        relative_humidity: row.get(8),
+        usb_bytes_read: row.get(9),
+        usb_bytes_written: row.get(10),
+        usb_err_cnt: row.get(11),
+        lora_rx_bytes: row.get(12),
+        lora_tx_bytes: row.get(13),
+        lora_error_cnt: row.get(14),
+        hardware_error_other_cnt: row.get(15),
+        uptime_secs: row.get(16),
+        epoch: row.get(17),
+        sequence: row.get(18)
+    }
+}
+
+// A reading in a downlink-processor's dead letter file that the gauge could not date.  Its received_at is when the
+// processor heard it, not when it was taken, so it is not imported until someone dates it and takes the mark off.
+#[derive(Deserialize)]
+struct Undated {
+    #[serde(default)]
+    undated: bool
+}
+
+/// A line from an ndjson export, or from the dead letter file.
+fn parse_line(line:&str) -> Result<Record, String> {
+    if serde_json::from_str::<Undated>(line).map(|line| line.undated).unwrap_or(false) {
+        return Err("the reading is undated, set received_at to when it was taken and remove undated".to_string());
+    }
+
+    serde_json::from_str::<Record>(line)
+        .or_else(|err| serde_json::from_str::<Received>(line).map(Record::from).map_err(|_| err.to_string()))
+}
+
+/// Which telemetry to export.  An empty list of devices exports all of them.
+pub struct Filter {
+    pub devices: Vec<DeviceId>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>
+}
+
+trait RecordWriter {
+    fn write(&mut self, records:&[Record]) -> Result<(), BulkError>;
+    fn finish(self:Box<Self>) -> Result<(), BulkError>;
+}
+
+struct CsvWriter<W:Write>(csv::Writer<W>);
+
+impl<W:Write> RecordWriter for CsvWriter<W> {
+    fn write(&mut self, records:&[Record]) -> Result<(), BulkError> {
+        for record in records {
+            self.0.serialize(record)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self:Box<Self>) -> Result<(), BulkError> {
+        Ok(self.0.flush()?)
+    }
+}
+
+struct NdjsonWriter<W:Write>(W);
+
+impl<W:Write> RecordWriter for NdjsonWriter<W> {
+    fn write(&mut self, records:&[Record]) -> Result<(), BulkError> {
+        for record in records {
+            serde_json::to_writer(&mut self.0, record).map_err(io::Error::from)?;
+            self.0.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self:Box<Self>) -> Result<(), BulkError> {
+        Ok(self.0.flush()?)
+    }
+}
+
+fn writer<W:Write + Send + 'static>(format:FileFormat, out:W) -> Result<Box<dyn RecordWriter>, BulkError> {
+    match format {
+        FileFormat::Csv => Ok(Box::new(CsvWriter(csv::Writer::from_writer(out)))),
+        FileFormat::Ndjson => Ok(Box::new(NdjsonWriter(out))),
+        FileFormat::Parquet => parquet_writer(out)
+    }
+}
+
+/// Stream the telemetry matching `filter` to `out`, ordered by device and time.  Returns how many rows were written.
+pub fn export<W:Write + Send + 'static>(pool:&Pool, filter:&Filter, format:FileFormat, out:W) -> Result<usize, BulkError> {
+    let mut writer = writer(format, out)?;
+
+    let all_devices = filter.devices.is_empty();
+    let devices = filter.devices.iter().map(|device_id| device_id.as_bytes().to_vec()).collect::<Vec<Vec<u8>>>();
+    let from = filter.from.unwrap_or_else(|| Utc.timestamp(0, 0));
+    let to = filter.to.unwrap_or_else(|| Utc.ymd(9999, 1, 1).and_hms(0, 0, 0));
+
+    let sql = format!("
+        SELECT {}
+        FROM telemetry
+        WHERE device_id IS NOT NULL AND ts >= $1 AND ts < $2 AND ($3 OR device_id = ANY($4))
+        ORDER BY device_id, ts, id", COLUMNS.iter().map(|(name, _)| *name).collect::<Vec<&str>>().join(", "));
+
+    // A portal fetches the rows a batch at a time instead of all at once.
+    let mut client = pool.get()?;
+    let mut transaction = client.transaction()?;
+    let portal = transaction.bind(sql.as_str(), &[&from, &to, &all_devices, &devices])?;
+
+    let mut count = 0;
+    loop {
+        let rows = transaction.query_portal(&portal, BATCH_SIZE as i32)?;
+        if rows.is_empty() {
+            break;
+        }
+
+        writer.write(&rows.iter().map(record).collect::<Vec<Record>>())?;
+        count += rows.len();
+    }
+
+    writer.finish()?;
+    Ok(count)
+}
+
+/// Insert records that are not already there.  A reading is already there when the device has one with the same epoch
+/// and sequence, the same as the persister, and a row from before readings were numbered when the device has one with
+/// the same time and loop count.  So importing the same file twice changes nothing.  Returns how many rows were
+/// inserted.
+fn insert(transaction:&mut postgres::Transaction, records:&[Record]) -> Result<usize, BulkError> {
+    let sql = format!("
+        INSERT INTO telemetry ({})
+        SELECT {}
+        WHERE ($18::BIGINT IS NOT NULL AND $19::BIGINT IS NOT NULL) OR NOT EXISTS (
+            SELECT 1 FROM telemetry WHERE device_id = $1 AND ts = $2 AND loop_cnt IS NOT DISTINCT FROM $5)
+        ON CONFLICT (device_id, epoch, sequence) DO NOTHING",
+        COLUMNS.iter().map(|(name, _)| *name).collect::<Vec<&str>>().join(", "),
+        COLUMNS.iter().enumerate().map(|(i, (_, sql_type))| format!("${}::{}", i + 1, sql_type)).collect::<Vec<String>>().join(", "));
+    let statement = transaction.prepare(sql.as_str())?;
+
+    let mut inserted = 0;
+    for record in records {
+        let device_id = record.device_id.parse::<DeviceId>()
+            .map_err(|_| BulkError::Invalid(format!("{} is not a device id", record.device_id)))?;
+        let device_id = device_id.as_bytes();
+
+        let params:[&(dyn ToSql + Sync); 19] = [
+            &device_id,
+            &record.ts,
+            &record.received_at,
+            &record.ts_reconstructed,
+            &record.loop_cnt,
+            &record.tip_cnt,
+            &record.vbat,
+            &record.temperature,
+            &record.relative_humidity,
+            &record.usb_bytes_read,
+            &record.usb_bytes_written,
+            &record.usb_err_cnt,
+            &record.lora_rx_bytes,
+            &record.lora_tx_bytes,
+            &record.lora_error_cnt,
+            &record.hardware_error_other_cnt,
+            &record.uptime_secs,
+            &record.epoch,
+            &record.sequence
+        ];
+        inserted += transaction.execute(&statement, &params)? as usize;
+    }
+
+    Ok(inserted)
+}
+
+/// Load a file written by export, or a dead letter file.  Each batch is committed as it goes, so an import that
+/// fails part way can be run again.  Returns how many rows were read and how many of those were new.
+pub fn import(pool:&Pool, path:&Path, format:FileFormat) -> Result<(usize, usize), BulkError> {
+    let mut client = pool.get()?;
+    let mut read = 0;
+    let mut inserted = 0;
+
+    let mut flush = |batch:&mut Vec<Record>| -> Result<(), BulkError> {
+        let mut transaction = client.transaction()?;
+        inserted += insert(&mut transaction, batch)?;
+        transaction.commit()?;
+        read += batch.len();
+        batch.clear();
+        Ok(())
+    };
+
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    for record in records(path, format)? {
+        batch.push(record?);
+        if batch.len() == BATCH_SIZE {
+            flush(&mut batch)?;
+        }
+    }
+    flush(&mut batch)?;
+
+    Ok((read, inserted))
+}
+
+fn records(path:&Path, format:FileFormat) -> Result<Box<dyn Iterator<Item = Result<Record, BulkError>>>, BulkError> {
+    let file = File::open(path)?;
+
+    match format {
+        FileFormat::Csv => {
+            let records = csv::Reader::from_reader(file).into_deserialize::<Record>()
+                .map(|record| record.map_err(BulkError::from));
+            Ok(Box::new(records))
+        },
+        FileFormat::Ndjson => {
+            let records = BufReader::new(file).lines()
+                .enumerate()
+                .filter(|(_, line)| line.as_ref().map(|line| !line.trim().is_empty()).unwrap_or(true))
+                .map(|(i, line)| {
+                    let line = line?;
+                    parse_line(&line).map_err(|err| BulkError::Invalid(format!("line {}: {}", i + 1, err)))
+                });
+            Ok(Box::new(records))
+        },
+        FileFormat::Parquet => parquet_records(file)
+    }
+}
+
+#[cfg(not(feature = "parquet"))]
+fn parquet_writer<W:Write + Send + 'static>(_out:W) -> Result<Box<dyn RecordWriter>, BulkError> {
+    Err(BulkError::Unsupported("parquet support was not built in, rebuild with --features parquet".to_string()))
+}
+
+#[cfg(not(feature = "parquet"))]
+fn parquet_records(_file:File) -> Result<Box<dyn Iterator<Item = Result<Record, BulkError>>>, BulkError> {
+    Err(BulkError::Unsupported("parquet support was not built in, rebuild with --features parquet".to_string()))
+}
+
+#[cfg(feature = "parquet")]
+const PARQUET_SCHEMA:&str = "
+    message telemetry {
+        REQUIRED BYTE_ARRAY device_id (UTF8);
+        REQUIRED INT64 ts (TIMESTAMP(MICROS,true));
+        OPTIONAL INT64 received_at (TIMESTAMP(MICROS,true));
+        REQUIRED BOOLEAN ts_reconstructed;
+        OPTIONAL INT64 loop_cnt;
+        OPTIONAL INT64 tip_cnt;
+        OPTIONAL INT64 vbat;
+        OPTIONAL FLOAT temperature;
+        OPTIONAL FLOAT relative_humidity;
+        OPTIONAL INT64 usb_bytes_read;
+        OPTIONAL INT64 usb_bytes_written;
+        OPTIONAL INT64 usb_err_cnt;
+        OPTIONAL INT64 lora_rx_bytes;
+        OPTIONAL INT64 lora_tx_bytes;
+        OPTIONAL INT64 lora_error_cnt;
+        OPTIONAL INT64 hardware_error_other_cnt;
+        OPTIONAL INT64 uptime_secs;
+        OPTIONAL INT64 epoch;
+        OPTIONAL INT64 sequence;
+    }";
+
+#[cfg(feature = "parquet")]
+fn micros(ts:DateTime<Utc>) -> i64 {
+    ts.timestamp() * 1_000_000 + ts.timestamp_subsec_micros() as i64
+}
+
+#[cfg(feature = "parquet")]
+fn from_micros(micros:i64) -> DateTime<Utc> {
+    Utc.timestamp(micros.div_euclid(1_000_000), (micros.rem_euclid(1_000_000) * 1000) as u32)
+}
+
+#[cfg(feature = "parquet")]
+struct ParquetWriter<W:Write + Send>(Option<parquet::file::writer::SerializedFileWriter<W>>);
+
+#[cfg(feature = "parquet")]
+fn parquet_writer<W:Write + Send + 'static>(out:W) -> Result<Box<dyn RecordWriter>, BulkError> {
+    use std::sync::Arc;
+
+    use parquet::basic::Compression;
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+    let properties = Arc::new(WriterProperties::builder().set_compression(Compression::SNAPPY).build());
+
+    Ok(Box::new(ParquetWriter(Some(SerializedFileWriter::new(out, schema, properties)?))))
+}
+
+#[cfg(feature = "parquet")]
+impl<W:Write + Send> RecordWriter for ParquetWriter<W> {
+    /// Each call is written as one row group, a column at a time in the order of the schema.
+    fn write(&mut self, records:&[Record]) -> Result<(), BulkError> {
+        use parquet::data_type::BoolType;
+        use parquet::data_type::ByteArray;
+        use parquet::data_type::ByteArrayType;
+        use parquet::data_type::DataType;
+        use parquet::data_type::FloatType;
+        use parquet::data_type::Int64Type;
+        use parquet::errors::ParquetError;
+        use parquet::file::writer::SerializedRowGroupWriter;
+
+        fn column<W:Write + Send, T:DataType>(row_group:&mut SerializedRowGroupWriter<W>,
+                values:impl Iterator<Item = Option<T::T>>) -> Result<(), ParquetError> {
+            let mut present = vec![];
+            let mut levels = vec![];
+            for value in values {
+                levels.push(if value.is_some() { 1 } else { 0 });
+                present.extend(value);
+            }
+
+            let mut column = row_group.next_column()?
+                .ok_or_else(|| ParquetError::General("the schema has fewer columns than a record".to_string()))?;
+            let required = column.typed::<T>().get_descriptor().max_def_level() == 0;
+            column.typed::<T>().write_batch(&present, if required { None } else { Some(&levels) }, None)?;
+            column.close()
+        }
+
+        let writer = self.0.as_mut().expect("write after finish");
+        let mut row_group = writer.next_row_group()?;
+
+        column::<_, ByteArrayType>(&mut row_group, records.iter().map(|r| Some(ByteArray::from(r.device_id.as_str()))))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| Some(micros(r.ts))))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| r.received_at.map(micros)))?;
+        column::<_, BoolType>(&mut row_group, records.iter().map(|r| Some(r.ts_reconstructed)))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| r.loop_cnt))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| r.tip_cnt))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| r.vbat))?;
+        column::<_, FloatType>(&mut row_group, records.iter().map(|r| r.temperature))?;
+        column::<_, FloatType>(&mut row_group, records.iter().map(|r| r.relative_humidity))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| r.usb_bytes_read))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| r.usb_bytes_written))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| r.usb_err_cnt))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| r.lora_rx_bytes))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| r.lora_tx_bytes))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| r.lora_error_cnt))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| r.hardware_error_other_cnt))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| r.uptime_secs))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| r.epoch))?;
+        column::<_, Int64Type>(&mut row_group, records.iter().map(|r| r.sequence))?;
+
+        row_group.close()?;
+        Ok(())
+    }
+
+    fn finish(mut self:Box<Self>) -> Result<(), BulkError> {
+        if let Some(writer) = self.0.take() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the columns by name, so files that were written by other tools load as long as the names and types match.
+#[cfg(feature = "parquet")]
+fn parquet_records(file:File) -> Result<Box<dyn Iterator<Item = Result<Record, BulkError>>>, BulkError> {
+    use parquet::file::serialized_reader::SerializedFileReader;
+    use parquet::record::Field;
+
+    fn to_json(field:Field) -> serde_json::Value {
+        match field {
+            Field::Bool(value) => serde_json::Value::Bool(value),
+            Field::Int(value) => value.into(),
+            Field::Long(value) => value.into(),
+            Field::Float(value) => (value as f64).into(),
+            Field::Double(value) => value.into(),
+            Field::Str(value) => value.into(),
+            Field::TimestampMillis(value) => from_micros(value * 1000).to_rfc3339().into(),
+            Field::TimestampMicros(value) => from_micros(value).to_rfc3339().into(),
+            _ => serde_json::Value::Null
+        }
+    }
+
+    let records = SerializedFileReader::new(file)?.into_iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let object = row?.into_columns().into_iter()
+                .map(|(name, field)| (name, to_json(field)))
+                .collect::<serde_json::Map<String, serde_json::Value>>();
+            serde_json::from_value::<Record>(serde_json::Value::Object(object))
+                .map_err(|err| BulkError::Invalid(format!("row {}: {}", i + 1, err)))
+        });
+    Ok(Box::new(records))
+}
+
+#[cfg(test)]
+mod tests {
+    use rainguage_messages::TelemetryPacket;
+
+    use super::*;
+
+    fn record() -> Record {
+        Record {
+            device_id: "0a141e28323c46505a64788c96a0aab4".to_string(),
+            ts: Utc.ymd(2020, 9, 1).and_hms_micro(12, 0, 0, 123_456),
+            received_at: None,
+            ts_reconstructed: false,
+            loop_cnt: Some(180),
+            tip_cnt: Some(4),
+            vbat: Some(2500),
+            temperature: Some(21.5),
+            relative_humidity: None,
+            usb_bytes_read: Some(0),
+            usb_bytes_written: Some(0),
+            usb_err_cnt: Some(0),
+            lora_rx_bytes: Some(0),
+            lora_tx_bytes: Some(3000),
+            lora_error_cnt: Some(1),
+            hardware_error_other_cnt: Some(u32::MAX as i64),
+            uptime_secs: Some(3600),
+            epoch: Some(7),
+            sequence: Some(12)
+        }
+    }
+
+    #[test]
+    fn formats_from_extensions() {
+        assert_eq!(Some(FileFormat::Csv), FileFormat::from_path(Path::new("export.csv")));
+        assert_eq!(Some(FileFormat::Ndjson), FileFormat::from_path(Path::new("dead-letter.ndjson")));
+        assert_eq!(Some(FileFormat::Parquet), FileFormat::from_path(Path::new("/tmp/2020.parquet")));
+        assert_eq!(None, FileFormat::from_path(Path::new("export")));
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.serialize(record()).unwrap();
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+
+        let records = csv::Reader::from_reader(csv.as_bytes()).into_deserialize::<Record>()
+            .collect::<Result<Vec<Record>, csv::Error>>()
+            .unwrap();
+        assert_eq!(vec![record()], records);
+    }
+
+    #[test]
+    fn reads_dead_letters() {
+        let mut packet = TelemetryPacket::new();
+        packet.device_id = [10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 120, 140, 150, 160, 170, 180];
+        packet.loop_cnt = 180;
+        let received_at = Utc.ymd(2020, 9, 1).and_hms(12, 0, 0);
+        let line = serde_json::to_string(&Received { packet, received_at, history: false }).unwrap();
+
+        let record = parse_line(&line).unwrap();
+        assert_eq!("0a141e28323c46505a64788c96a0aab4", record.device_id);
+        assert_eq!(received_at, record.ts);
+        assert_eq!(Some(180), record.loop_cnt);
+        assert_eq!(Some(0), record.lora_tx_bytes);
+
+        // A reading the gauge sent again, without its counters.
+        let mut history = serde_json::from_str::<serde_json::Value>(&line).unwrap();
+        history["history"] = true.into();
+        let record = parse_line(&history.to_string()).unwrap();
+        assert_eq!(Some(180), record.loop_cnt);
+        assert_eq!(None, record.lora_tx_bytes);
+
+        // One the gauge could not date, until it is.
+        history["undated"] = true.into();
+        assert!(parse_line(&history.to_string()).unwrap_err().contains("undated"));
+        history.as_object_mut().unwrap().remove("undated");
+        assert!(parse_line(&history.to_string()).is_ok());
+
+        assert_eq!(Ok(self::record()), parse_line(&serde_json::to_string(&self::record()).unwrap()));
+        assert!(parse_line("{}").is_err());
+    }
+
+    #[test]
+    fn reads_files_from_before_uptime() {
+        let mut old = record();
+        old.uptime_secs = None;
+        old.epoch = None;
+        old.sequence = None;
+
+        let mut json = serde_json::to_value(record()).unwrap();
+        for column in ["uptime_secs", "epoch", "sequence"].iter() {
+            json.as_object_mut().unwrap().remove(*column);
+        }
+        assert_eq!(Ok(old.clone()), parse_line(&json.to_string()));
+
+        let received = Received { packet: TelemetryPacket::new(), received_at: old.ts, history: false };
+        let mut packet = serde_json::to_value(&received).unwrap();
+        packet.as_object_mut().unwrap().remove("uptime_secs");
+        assert_eq!(None, parse_line(&packet.to_string()).unwrap().uptime_secs);
+
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.serialize(record()).unwrap();
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap()
+            .lines()
+            .map(|line| line.rsplitn(4, ',').last().unwrap().to_string() + "\n")
+            .collect::<String>();
+
+        let records = csv::Reader::from_reader(csv.as_bytes()).into_deserialize::<Record>()
+            .collect::<Result<Vec<Record>, csv::Error>>()
+            .unwrap();
+        assert_eq!(vec![old], records);
+    }
+    #[test]
+    fn imports_a_reading_once() {
+        let pool = match crate::database::test_pool() {
+            Some(pool) => pool,
+            None => return
+        };
+        let device_id = record().device_id.parse::<DeviceId>().unwrap();
+        let mut client = pool.get().unwrap();
+        client.execute("DELETE FROM telemetry WHERE device_id = $1", &[&device_id.as_bytes()]).unwrap();
+
+        // The same reading dated differently, as one from a dead letter file can be, and a row from before readings
+        // were numbered.
+        let mut later = record();
+        later.ts = later.ts + chrono::Duration::seconds(60);
+        let mut unnumbered = record();
+        unnumbered.ts = unnumbered.ts + chrono::Duration::seconds(3600);
+        unnumbered.epoch = None;
+        unnumbered.sequence = None;
+
+        let mut transaction = client.transaction().unwrap();
+        assert_eq!(2, insert(&mut transaction, &[record(), unnumbered.clone()]).unwrap());
+        assert_eq!(0, insert(&mut transaction, &[later.clone(), unnumbered]).unwrap());
+        // In a new epoch it is another reading.
+        later.epoch = Some(8);
+        assert_eq!(1, insert(&mut transaction, &[later]).unwrap());
+        transaction.rollback().unwrap();
+    }
+}
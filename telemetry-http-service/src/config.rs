@@ -0,0 +1,135 @@
+use std::path::Path;
+
+use dotenv::var;
+use rainguage_config::Kind;
+
+// Read when --config is not given, it is fine for it not to exist.
+const DEFAULT_FILE:&str = "telemetry-http-service.toml";
+
+// Every setting the service reads, see config.example.toml for what they do and their defaults.  The ROCKET_ ones are
+// read by rocket itself.
+const SETTINGS:[(&str, Kind); 36] = [
+    ("POSTGRES_HOST", Kind::Required),
+    ("POSTGRES_PORT", Kind::Port),
+    ("POSTGRES_USER", Kind::Required),
+    ("POSTGRES_PASSWORD", Kind::Required),
+    ("POSTGRES_POOL_SIZE", Kind::Integer(1)),
+    ("ROCKET_ADDRESS", Kind::Text),
+    ("ROCKET_PORT", Kind::Port),
+    ("ROCKET_WORKERS", Kind::Integer(1)),
+    ("AUTH_DISABLED", Kind::Bool),
+    ("DEAD_LETTER_FILE", Kind::Text),
+    ("EVENTS_MAX_SUBSCRIBERS", Kind::Integer(1)),
+    ("EVENTS_HEARTBEAT_SECS", Kind::Integer(1)),
+    ("RECONSTRUCT_TOLERANCE_SECS", Kind::Integer(0)),
+    ("CLOCK_NOMINAL_PERIOD_MS", Kind::Positive),
+    ("ROLLUP_INTERVAL_SECS", Kind::Integer(1)),
+    ("RETENTION_RAW_DAYS", Kind::Integer(0)),
+    ("RETENTION_5M_DAYS", Kind::Integer(0)),
+    ("RETENTION_1H_DAYS", Kind::Integer(0)),
+    ("RETENTION_1D_DAYS", Kind::Integer(0)),
+    ("RAINFALL_MM_PER_TIP", Kind::Positive),
+    ("BATTERY_HARDWARE_REVISION", Kind::Text),
+    ("POWER_AWAKE_MA", Kind::Positive),
+    ("POWER_STANDBY_MA", Kind::Positive),
+    ("POWER_TX_MA", Kind::Positive),
+    ("POWER_BATTERY_MAH", Kind::Positive),
+    ("ALERT_INTERVAL_SECS", Kind::Integer(1)),
+    ("ALERT_SILENT_MINUTES", Kind::Integer(1)),
+    ("ALERT_VBAT_MIN", Kind::Positive),
+    ("ALERT_REPEAT_HOURS", Kind::Integer(1)),
+    ("ALERT_SINKS", Kind::Text),
+    ("ALERT_WEBHOOK_URL", Kind::Text),
+    ("ALERT_EMAIL_FROM", Kind::Text),
+    ("ALERT_EMAIL_TO", Kind::Text),
+    ("SMTP_HOST", Kind::Text),
+    ("SMTP_USER", Kind::Text),
+    ("SMTP_PASSWORD", Kind::Text)
+];
+
+fn check_sinks(sinks:&str, lookup:&dyn Fn(&str) -> Option<String>, problems:&mut Vec<String>) {
+    for sink in sinks.split(',').map(|sink| sink.trim()).filter(|sink| !sink.is_empty()) {
+        let needs:&[&str] = match sink {
+            "log" => &[],
+            "webhook" => &["ALERT_WEBHOOK_URL"],
+            "email" => &["SMTP_HOST", "ALERT_EMAIL_FROM", "ALERT_EMAIL_TO"],
+            other => {
+                problems.push(format!("ALERT_SINKS can only have log, webhook and email, not {:?}", other));
+                continue;
+            }
+        };
+
+        for name in needs.iter().filter(|name| lookup(name).is_none()) {
+            problems.push(format!("the {} alert sink needs {}", sink, name));
+        }
+    }
+}
+
+/// Every problem with the settings, looking each one up with `lookup`.
+fn check(lookup:&dyn Fn(&str) -> Option<String>) -> Vec<String> {
+    let mut problems = rainguage_config::check(&SETTINGS, lookup);
+    // See sinks::from_env.
+    if let Some(sinks) = lookup("ALERT_SINKS") {
+        check_sinks(&sinks, lookup, &mut problems);
+    }
+    problems
+}
+
+/// Load the config file into the environment, apply the `name=value` overrides from the command line and check the
+/// result.  The environment wins over the file and the overrides win over both.  Returns every problem found, the
+/// service should only start when there are none.
+pub fn load(path:Option<&Path>, overrides:&[String]) -> Vec<String> {
+    let mut problems = rainguage_config::load(&SETTINGS, DEFAULT_FILE, path, overrides);
+    problems.extend(check(&|name| var(name).ok()));
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn check_map(settings:&[(&str, &str)]) -> Vec<String> {
+        let map:HashMap<String, String> = settings.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        check(&|name| map.get(name).cloned())
+    }
+
+    const POSTGRES:[(&str, &str); 3] = [("POSTGRES_HOST", "db"), ("POSTGRES_USER", "rain"), ("POSTGRES_PASSWORD", "secret")];
+
+    #[test]
+    fn defaults_are_enough_apart_from_postgres() {
+        assert_eq!(vec![
+            "POSTGRES_HOST is not set".to_string(),
+            "POSTGRES_USER is not set".to_string(),
+            "POSTGRES_PASSWORD is not set".to_string()
+        ], check_map(&[]));
+
+        assert!(check_map(&POSTGRES).is_empty());
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let mut settings = POSTGRES.to_vec();
+        settings.extend_from_slice(&[
+            ("POSTGRES_PORT", "five"),
+            ("AUTH_DISABLED", "yes"),
+            ("RAINFALL_MM_PER_TIP", "0"),
+            ("RETENTION_RAW_DAYS", "-1"),
+            ("ALERT_SINKS", "log, email, pager")
+        ]);
+
+        assert_eq!(vec![
+            "POSTGRES_PORT must be a port number, not \"five\"".to_string(),
+            "AUTH_DISABLED must be true or false, not \"yes\"".to_string(),
+            "RETENTION_RAW_DAYS must be a whole number of at least 0, not \"-1\"".to_string(),
+            "RAINFALL_MM_PER_TIP must be a number above zero, not \"0\"".to_string(),
+            "the email alert sink needs SMTP_HOST".to_string(),
+            "the email alert sink needs ALERT_EMAIL_FROM".to_string(),
+            "the email alert sink needs ALERT_EMAIL_TO".to_string(),
+            "ALERT_SINKS can only have log, webhook and email, not \"pager\"".to_string()
+        ], check_map(&settings));
+    }
+}
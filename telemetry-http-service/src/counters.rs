@@ -0,0 +1,266 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde_json::json;
+use serde_json::Map;
+
+use crate::auth::ReadKey;
+use crate::database::Pool;
+use crate::device::DeviceId;
+use crate::query;
+use crate::query::Format;
+use crate::query::Output;
+use crate::query::QueryError;
+
+/// The telemetry columns that hold cumulative u32 counters.  They only ever go up, wrapping back to zero, until the
+/// gauge restarts and they all start again from zero.
+pub const COUNTERS:[&str; 9] = [
+    "loop_cnt",
+    "tip_cnt",
+    "usb_bytes_read",
+    "usb_bytes_written",
+    "usb_err_cnt",
+    "lora_rx_bytes",
+    "lora_tx_bytes",
+    "lora_error_cnt",
+    "hardware_error_other_cnt"
+];
+
+// A counter that drops from within this distance of u32::MAX to within this distance of zero is assumed to have
+// wrapped rather than been reset by a reboot.
+const WRAP_WINDOW:u32 = 1 << 20;
+
+/// A reading of some of the counters, in the same order as the fields that were asked for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub ts: DateTime<Utc>,
+    pub loop_cnt: u32,
+    pub values: Vec<Option<u32>>
+}
+
+/// How much each counter went up between two readings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delta {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub rebooted: bool,
+    pub values: Vec<Option<u32>>
+}
+
+impl Delta {
+    /// The rate of each counter per second over the interval.
+    pub fn rates(&self) -> Vec<Option<f64>> {
+        let seconds = (self.end - self.start).num_milliseconds() as f64 / 1000.0;
+        self.values.iter()
+            .map(|value| value.and_then(|value| if seconds > 0.0 { Some(value as f64 / seconds) } else { None }))
+            .collect()
+    }
+}
+
+pub fn wrapped(prev:u32, cur:u32) -> bool {
+    prev > u32::MAX - WRAP_WINDOW && cur < WRAP_WINDOW
+}
+
+/// Did the gauge restart between two readings?  The first packet after power on is sent with a loop_cnt of zero,
+/// after that the loop counter only goes backwards when the gauge restarts.
+pub fn rebooted(prev_loop_cnt:u32, cur_loop_cnt:u32) -> bool {
+    (cur_loop_cnt == 0 || cur_loop_cnt < prev_loop_cnt) && !wrapped(prev_loop_cnt, cur_loop_cnt)
+}
+
+/// How much a counter went up between two readings.  After a reboot, or any other unexplained drop, the new value is
+/// what has been counted since the counter was reset.
+pub fn delta(prev:u32, cur:u32, rebooted:bool) -> u32 {
+    if rebooted {
+        cur
+    } else if cur >= prev {
+        cur - prev
+    } else if wrapped(prev, cur) {
+        cur.wrapping_sub(prev)
+    } else {
+        cur
+    }
+}
+
+/// Turn readings, oldest first, into the change between each pair of readings.
+pub fn deltas(samples:&[Sample]) -> Vec<Delta> {
+    samples.windows(2)
+        .map(|pair| {
+            let rebooted = rebooted(pair[0].loop_cnt, pair[1].loop_cnt);
+            let values = pair[0].values.iter().zip(pair[1].values.iter())
+                .map(|(prev, cur)| match (prev, cur) {
+                    (Some(prev), Some(cur)) => Some(delta(*prev, *cur, rebooted)),
+                    (None, Some(cur)) if rebooted => Some(*cur),
+                    _ => None
+                })
+                .collect();
+
+            Delta {
+                start: pair[0].ts,
+                end: pair[1].ts,
+                rebooted,
+                values
+            }
+        })
+        .collect()
+}
+
+fn parse_counters(fields:Option<String>) -> Result<Vec<&'static str>, QueryError> {
+    let fields = match fields {
+        Some(fields) => fields,
+        None => {
+            return Ok(COUNTERS.to_vec());
+        }
+    };
+
+    let mut result = vec![];
+    for name in fields.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+        match COUNTERS.iter().find(|counter| **counter == name) {
+            Some(counter) => result.push(*counter),
+            None => {
+                return Err(QueryError::BadRequest(format!("{} is not a counter", name)));
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Load the counters between `from` and `to`, along with the reading just before `from` so the first delta in the
+/// range can be computed.
+pub fn load_samples(pool:&Pool, device_id:&DeviceId, counters:&[&str], from:DateTime<Utc>, to:DateTime<Utc>) -> Result<Vec<Sample>, QueryError> {
+    let mut client = pool.get()?;
+    let device_id = device_id.as_bytes();
+    let columns = counters.join(", ");
+
+    let previous = client.query(format!("
+        SELECT ts, loop_cnt, {}
+        FROM telemetry
+        WHERE device_id = $1 AND ts < $2
+        ORDER BY ts DESC
+        LIMIT 1", columns).as_str(), &[&device_id, &from])?;
+
+    let rows = client.query(format!("
+        SELECT ts, loop_cnt, {}
+        FROM telemetry
+        WHERE device_id = $1 AND ts >= $2 AND ts < $3
+        ORDER BY ts", columns).as_str(), &[&device_id, &from, &to])?;
+
+    Ok(previous.iter().chain(rows.iter())
+        .map(|row| Sample {
+            ts: row.get(0),
+            loop_cnt: row.get::<_, Option<i64>>(1).unwrap_or(0) as u32,
+            values: (0..counters.len())
+                .map(|i| row.get::<_, Option<i64>>(i + 2).map(|value| value as u32))
+                .collect()
+        })
+        .collect())
+}
+
+/// The change in each counter between readings, with the per second rate.  Defaults to the last day.
+#[get("/devices/<device_id>/counters?<from>&<to>&<fields>&<format>")]
+pub fn counters(device_id:DeviceId, from:Option<String>, to:Option<String>, fields:Option<String>, format:Option<String>,
+        pool:State<Pool>, _key:ReadKey) -> Result<Output, QueryError> {
+    let format = Format::parse(format)?;
+    let counters = parse_counters(fields)?;
+    let to = query::parse_time("to", to)?.unwrap_or_else(Utc::now);
+    let from = query::parse_time("from", from)?.unwrap_or_else(|| to - Duration::days(1));
+
+    let deltas = deltas(&load_samples(&pool, &device_id, &counters, from, to)?);
+
+    match format {
+        Format::Json => {
+            let deltas = deltas.iter()
+                .map(|delta| {
+                    let mut object = Map::new();
+                    object.insert("start".to_string(), json!(delta.start.to_rfc3339()));
+                    object.insert("end".to_string(), json!(delta.end.to_rfc3339()));
+                    object.insert("rebooted".to_string(), json!(delta.rebooted));
+                    for ((counter, value), rate) in counters.iter().zip(delta.values.iter()).zip(delta.rates()) {
+                        object.insert(counter.to_string(), json!(value));
+                        object.insert(format!("{}_per_sec", counter), json!(rate));
+                    }
+                    serde_json::Value::Object(object)
+                })
+                .collect();
+
+            Ok(Output::Json(Json(serde_json::Value::Array(deltas))))
+        },
+        Format::Csv => {
+            let mut header = vec!["start".to_string(), "end".to_string(), "rebooted".to_string()];
+            for counter in counters.iter() {
+                header.push(counter.to_string());
+                header.push(format!("{}_per_sec", counter));
+            }
+
+            let records = deltas.iter()
+                .map(|delta| {
+                    let mut record = vec![delta.start.to_rfc3339(), delta.end.to_rfc3339(), delta.rebooted.to_string()];
+                    for (value, rate) in delta.values.iter().zip(delta.rates()) {
+                        record.push(value.map(|value| value.to_string()).unwrap_or_default());
+                        record.push(rate.map(|rate| rate.to_string()).unwrap_or_default());
+                    }
+                    record
+                })
+                .collect();
+
+            let header = header.iter().map(|h| h.as_str()).collect::<Vec<&str>>();
+            Ok(Output::Csv(Content(ContentType::CSV, query::csv(&header, records))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn sample(minute:i64, loop_cnt:u32, values:Vec<Option<u32>>) -> Sample {
+        Sample {
+            ts: Utc.ymd(2020, 9, 1).and_hms(0, 0, 0) + Duration::minutes(minute),
+            loop_cnt,
+            values
+        }
+    }
+
+    #[test]
+    fn wraparound_is_not_a_reboot() {
+        assert!(!rebooted(u32::MAX - 10, 5));
+        assert_eq!(16, delta(u32::MAX - 10, 5, false));
+    }
+
+    #[test]
+    fn loop_cnt_zero_is_a_boot() {
+        assert!(rebooted(0, 0));
+        assert!(rebooted(500, 0));
+        assert!(rebooted(500, 200));
+        assert!(!rebooted(200, 500));
+    }
+
+    #[test]
+    fn deltas_and_rates() {
+        let samples = [
+            sample(0, 100, vec![Some(1000), Some(3)]),
+            sample(1, 300, vec![Some(1600), Some(3)]),
+            sample(2, 0, vec![Some(60), Some(0)]),
+            sample(3, 200, vec![None, Some(2)])
+        ];
+
+        let deltas = deltas(&samples);
+        assert_eq!(3, deltas.len());
+
+        assert!(!deltas[0].rebooted);
+        assert_eq!(vec![Some(600), Some(0)], deltas[0].values);
+        assert_eq!(vec![Some(10.0), Some(0.0)], deltas[0].rates());
+
+        assert!(deltas[1].rebooted);
+        assert_eq!(vec![Some(60), Some(0)], deltas[1].values);
+
+        assert!(!deltas[2].rebooted);
+        assert_eq!(vec![None, Some(2)], deltas[2].values);
+    }
+}
@@ -0,0 +1,23 @@
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+use rocket::response::content::Html;
+
+// The dashboard is compiled in so that the service is still a single binary.
+const INDEX:&str = include_str!("../static/index.html");
+const SCRIPT:&str = include_str!("../static/dashboard.js");
+const STYLE:&str = include_str!("../static/dashboard.css");
+
+#[get("/")]
+pub fn index() -> Html<&'static str> {
+    Html(INDEX)
+}
+
+#[get("/static/dashboard.js")]
+pub fn script() -> Content<&'static str> {
+    Content(ContentType::JavaScript, SCRIPT)
+}
+
+#[get("/static/dashboard.css")]
+pub fn style() -> Content<&'static str> {
+    Content(ContentType::CSS, STYLE)
+}
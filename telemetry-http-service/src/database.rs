@@ -0,0 +1,253 @@
+use std::time::Duration;
+
+use dotenv::var;
+
+use postgres::Config;
+use postgres::NoTls;
+use r2d2_postgres::PostgresConnectionManager;
+
+const DEFAULT_PORT:u16 = 5432;
+
+pub type Pool = r2d2::Pool<PostgresConnectionManager<NoTls>>;
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    PostgresError(postgres::Error),
+    PoolError(r2d2::Error)
+}
+
+impl From<postgres::Error> for DatabaseError {
+    fn from(err: postgres::Error) -> Self {
+        DatabaseError::PostgresError(err)
+    }
+}
+
+impl From<r2d2::Error> for DatabaseError {
+    fn from(err: r2d2::Error) -> Self {
+        DatabaseError::PoolError(err)
+    }
+}
+
+// config::load has made sure the settings are there and make sense.
+fn config() -> Config {
+    let mut pg_config = Config::new();
+    pg_config.host(&var("POSTGRES_HOST").unwrap());
+    pg_config.user(&var("POSTGRES_USER").unwrap());
+    pg_config.password(&var("POSTGRES_PASSWORD").unwrap());
+    pg_config.port(var("POSTGRES_PORT").ok()
+        .and_then(|port| port.parse::<u16>().ok())
+        .unwrap_or(DEFAULT_PORT));
+    pg_config.connect_timeout(Duration::from_secs(10));
+
+    pg_config
+}
+
+/// Create the connection pool.  Connections are established lazily so the pool can be created while the database
+/// is down; callers find out when they try to get a connection.
+pub fn pool() -> Pool {
+    let max_size = var("POSTGRES_POOL_SIZE").ok()
+        .and_then(|size| size.parse::<u32>().ok())
+        .unwrap_or(4);
+
+    let manager = PostgresConnectionManager::new(config(), NoTls);
+
+    r2d2::Pool::builder()
+        .max_size(max_size)
+        .connection_timeout(Duration::from_secs(5))
+        .build_unchecked(manager)
+}
+
+/// A pool on the database in `TEST_POSTGRES_URL`, for the tests that need a real one, or None to skip them.  The
+/// tests add and delete rows for their own device ids, but it should still not be a database that matters.
+#[cfg(test)]
+pub fn test_pool() -> Option<Pool> {
+    static INIT:std::sync::Once = std::sync::Once::new();
+
+    let url = var("TEST_POSTGRES_URL").ok()?;
+    let manager = PostgresConnectionManager::new(url.parse().expect("TEST_POSTGRES_URL is not valid"), NoTls);
+    let pool = r2d2::Pool::builder().max_size(4).build(manager).expect("Could not connect to TEST_POSTGRES_URL");
+    INIT.call_once(|| init_database(&pool).unwrap());
+    Some(pool)
+}
+
+// The u32 columns were originally INTEGER and written with `as i32`, so anything past i32::MAX went in negative.
+const U32_COLUMNS:[&str; 10] = [
+    "loop_cnt",
+    "vbat",
+    "tip_cnt",
+    "usb_bytes_read",
+    "usb_bytes_written",
+    "usb_err_cnt",
+    "lora_rx_bytes",
+    "lora_tx_bytes",
+    "lora_error_cnt",
+    "hardware_error_other_cnt"
+];
+
+/// Convert the u32 columns to BIGINT, turning the negative values back into what the device sent.
+fn widen_counters(client:&mut postgres::Client) -> Result<(), DatabaseError> {
+    for column in U32_COLUMNS.iter() {
+        let rows = client.query("
+            SELECT data_type FROM information_schema.columns
+            WHERE table_name = 'telemetry' AND column_name = $1", &[column])?;
+
+        let data_type = rows.first().map(|row| row.get::<_, String>(0));
+        if data_type.as_ref().map(|t| t.as_str()) == Some("integer") {
+            info!("Converting telemetry.{} to BIGINT", column);
+            client.execute(format!("
+                ALTER TABLE telemetry ALTER COLUMN {column} TYPE BIGINT
+                USING (CASE WHEN {column} < 0 THEN {column}::BIGINT + 4294967296 ELSE {column} END)",
+                column = column).as_str(), &[])?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init_database(pool:&Pool) -> Result<(), DatabaseError> {
+    let mut client = pool.get()?;
+
+    client.execute("
+        CREATE TABLE IF NOT EXISTS telemetry (
+            id SERIAL NOT NULL,
+            PRIMARY KEY (id),
+            ts TIMESTAMP WITH TIME ZONE NOT NULL,
+            loop_cnt INTEGER,
+            vbat INTEGER,
+            usb_int_cnt INTEGER,
+            usb_ser_read INTEGER,
+            usb_err_cnt INTEGER,
+            lora_xmit_cnt INTEGER,
+            device_id BYTEA
+        );
+    ", &[])?;
+
+    client.execute("ALTER TABLE telemetry DROP COLUMN IF EXISTS lora_xmit_cnt", &[])?;
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS lora_rx_bytes INTEGER", &[])?;
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS lora_tx_bytes INTEGER", &[])?;
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS corrupt BOOL DEFAULT false", &[])?;
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS tip_cnt INTEGER", &[])?;
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS temperature REAL", &[])?;
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS relative_humidity REAL", &[])?;
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS usb_bytes_read INTEGER", &[])?;
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS usb_bytes_written INTEGER", &[])?;
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS lora_error_cnt INTEGER", &[])?;
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS hardware_error_other_cnt INTEGER", &[])?;
+    widen_counters(&mut client)?;
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS uptime_secs BIGINT", &[])?;
+
+    // ts is when the packet was sent as best we know, received_at is when the gateway heard it.  ts_reconstructed is
+    // set when ts was worked out from the loop count because the packet was held up on the way.
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS received_at TIMESTAMPTZ", &[])?;
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS ts_reconstructed BOOL NOT NULL DEFAULT false", &[])?;
+
+    client.execute("CREATE INDEX IF NOT EXISTS telemetry_device_id_ts ON telemetry (device_id, ts)", &[])?;
+
+    // The gauge's number for the reading, which it sends again until the downlink acknowledges it, so a reading posted
+    // twice is only stored once.  A gauge whose flash is erased numbers its readings from 1 again in a new epoch, so
+    // the epoch is in the key as well.  Packets without them never conflict.
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS sequence BIGINT", &[])?;
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS epoch BIGINT", &[])?;
+    client.execute("DROP INDEX IF EXISTS telemetry_device_id_sequence", &[])?;
+    client.execute("CREATE UNIQUE INDEX IF NOT EXISTS telemetry_device_id_epoch_sequence ON telemetry (device_id, epoch, sequence)", &[])?;
+
+    // Set once a row has been counted in the rollups, see rollup::run.
+    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS rolled_up BOOL NOT NULL DEFAULT false", &[])?;
+    client.execute("CREATE INDEX IF NOT EXISTS telemetry_not_rolled_up ON telemetry (device_id) WHERE NOT rolled_up", &[])?;
+
+    // The rollups name their columns after the telemetry they summarise so the same queries work on both.  ts is the
+    // start of the bucket and temperature, relative_humidity and vbat are the means.
+    for table in ["telemetry_5m", "telemetry_1h", "telemetry_1d"].iter() {
+        client.execute(format!("
+            CREATE TABLE IF NOT EXISTS {} (
+                device_id BYTEA NOT NULL,
+                ts TIMESTAMPTZ NOT NULL,
+                PRIMARY KEY (device_id, ts),
+                packets BIGINT NOT NULL,
+                temperature REAL,
+                temperature_min REAL,
+                temperature_max REAL,
+                relative_humidity REAL,
+                relative_humidity_min REAL,
+                relative_humidity_max REAL,
+                vbat REAL,
+                vbat_min REAL,
+                vbat_max REAL,
+                tips BIGINT NOT NULL,
+                rainfall_mm DOUBLE PRECISION NOT NULL
+            );
+        ", table).as_str(), &[])?;
+    }
+
+    // When each tip happened, from the tips packets, see tips::tip_times.  tip_cnt is what the tip took the count to
+    // and loop_cnt the loop it happened on, which together tell a tip apart from the same one heard again.
+    client.execute("
+        CREATE TABLE IF NOT EXISTS tip (
+            id SERIAL NOT NULL,
+            PRIMARY KEY (id),
+            device_id BYTEA NOT NULL,
+            tip_cnt BIGINT NOT NULL,
+            ts TIMESTAMPTZ NOT NULL,
+            received_at TIMESTAMPTZ NOT NULL
+        );
+    ", &[])?;
+    client.execute("CREATE INDEX IF NOT EXISTS tip_device_id_ts ON tip (device_id, ts)", &[])?;
+    client.execute("ALTER TABLE tip ADD COLUMN IF NOT EXISTS loop_cnt BIGINT", &[])?;
+    // Tips were once kept apart by when they were received, which lets every copy in.
+    client.execute("ALTER TABLE tip DROP CONSTRAINT IF EXISTS tip_device_id_received_at_tip_cnt_key", &[])?;
+    client.execute("CREATE UNIQUE INDEX IF NOT EXISTS tip_device_id_tip_cnt_loop_cnt ON tip (device_id, tip_cnt, loop_cnt)", &[])?;
+
+    // The power reports, see power::intervals.  ts is when the gateway heard them and the rest are as sent.
+    client.execute("
+        CREATE TABLE IF NOT EXISTS power (
+            device_id BYTEA NOT NULL,
+            ts TIMESTAMPTZ NOT NULL,
+            PRIMARY KEY (device_id, ts),
+            loop_cnt BIGINT NOT NULL,
+            awake_ms BIGINT NOT NULL,
+            standby_ms BIGINT NOT NULL,
+            tx_ms BIGINT NOT NULL,
+            tip_wake_cnt BIGINT NOT NULL
+        );
+    ", &[])?;
+
+    client.execute("
+        CREATE TABLE IF NOT EXISTS calibration (
+            device_id BYTEA NOT NULL,
+            PRIMARY KEY (device_id),
+            mm_per_tip DOUBLE PRECISION
+        );
+    ", &[])?;
+    client.execute("ALTER TABLE calibration ADD COLUMN IF NOT EXISTS hardware_revision TEXT", &[])?;
+    client.execute("ALTER TABLE calibration ADD COLUMN IF NOT EXISTS vbat_scale DOUBLE PRECISION", &[])?;
+
+    client.execute("
+        CREATE TABLE IF NOT EXISTS hardware_revision (
+            name TEXT NOT NULL,
+            PRIMARY KEY (name),
+            divider DOUBLE PRECISION NOT NULL,
+            reference_volts DOUBLE PRECISION NOT NULL,
+            gain DOUBLE PRECISION NOT NULL,
+            adc_max BIGINT NOT NULL
+        );
+    ", &[])?;
+
+    // The original feather m0 gauge, see battery::Hardware.
+    client.execute("
+        INSERT INTO hardware_revision (name, divider, reference_volts, gain, adc_max)
+        VALUES ('feather-m0', 2.0, 1.65, 0.5, 4095)
+        ON CONFLICT (name) DO NOTHING", &[])?;
+
+    // Only a hash of each api key is kept, see auth::create_key.
+    client.execute("
+        CREATE TABLE IF NOT EXISTS api_key (
+            name TEXT NOT NULL,
+            PRIMARY KEY (name),
+            key_hash BYTEA NOT NULL UNIQUE,
+            scope TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+    ", &[])?;
+
+    Ok(())
+}
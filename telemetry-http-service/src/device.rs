@@ -0,0 +1,118 @@
+use std::fmt;
+use std::str::FromStr;
+
+use rocket::http::RawStr;
+use rocket::request::FromParam;
+use serde::Serialize;
+use serde::Serializer;
+
+/// The 16 byte hardware identifier of a rainguage.  It is written as 32 lowercase hex characters in urls and
+/// responses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeviceId(pub [u8; 16]);
+
+#[derive(Debug)]
+pub enum DeviceIdError {
+    InvalidLength,
+    InvalidHex
+}
+
+impl fmt::Display for DeviceIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceIdError::InvalidLength => write!(f, "a device id must be 32 characters long"),
+            DeviceIdError::InvalidHex => write!(f, "a device id can only contain hex digits")
+        }
+    }
+}
+
+impl DeviceId {
+    /// Convert the BYTEA device_id column back into an id.  Anything that is not 16 bytes was not written by us.
+    pub fn from_slice(bytes:&[u8]) -> Option<DeviceId> {
+        if bytes.len() != 16 {
+            return None;
+        }
+
+        let mut device_id = [0u8; 16];
+        device_id.copy_from_slice(bytes);
+        Some(DeviceId(device_id))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for DeviceId {
+    type Err = DeviceIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 32 {
+            return Err(DeviceIdError::InvalidLength);
+        }
+
+        // from_str_radix would take a sign as well.
+        if !s.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(DeviceIdError::InvalidHex);
+        }
+
+        let mut device_id = [0u8; 16];
+        for (i, byte) in device_id.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| DeviceIdError::InvalidHex)?;
+        }
+
+        Ok(DeviceId(device_id))
+    }
+}
+
+impl<'a> FromParam<'a> for DeviceId {
+    type Error = DeviceIdError;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, Self::Error> {
+        param.as_str().parse()
+    }
+}
+
+impl Serialize for DeviceId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_what_it_writes() {
+        let device_id = DeviceId([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 254, 255]);
+        assert_eq!("000102030405060708090a0b0c0dfeff", device_id.to_string());
+        assert_eq!(device_id, "000102030405060708090a0b0c0dfeff".parse().unwrap());
+        // Upper case is accepted, but never written.
+        assert_eq!(device_id, "000102030405060708090A0B0C0DFEFF".parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        let parse = |s:&str| s.parse::<DeviceId>().map_err(|err| err.to_string());
+        assert_eq!(Err("a device id must be 32 characters long".to_string()), parse("0001"));
+        assert_eq!(Err("a device id must be 32 characters long".to_string()), parse(""));
+        assert_eq!(Err("a device id can only contain hex digits".to_string()),
+            parse("000102030405060708090a0b0c0dfexx"));
+        assert_eq!(Err("a device id can only contain hex digits".to_string()),
+            parse("+f0102030405060708090a0b0c0dfeff"));
+        // 32 bytes, but not 32 characters.
+        assert_eq!(Err("a device id can only contain hex digits".to_string()),
+            parse("é0102030405060708090a0b0c0dfeff"));
+    }
+}
@@ -0,0 +1,319 @@
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::DateTime;
+use chrono::Utc;
+use dotenv::var;
+use serde_json::json;
+
+use rocket::http::ContentType;
+use rocket::http::Status;
+use rocket::request;
+use rocket::request::FromRequest;
+use rocket::request::Request;
+use rocket::response::content::Content;
+use rocket::response::status::Custom;
+use rocket::response::Stream;
+use rocket::Outcome;
+use rocket::State;
+
+use rainguage_messages::TelemetryPacket;
+
+use crate::auth::ReadKey;
+use crate::device::DeviceId;
+
+// Events kept for clients that reconnect with Last-Event-ID.
+const BACKLOG_SIZE:usize = 256;
+
+// Events waiting to be written to a client.  A client that falls this far behind is dropped and has to reconnect.
+const SUBSCRIBER_QUEUE_SIZE:usize = 64;
+
+// Every connected client holds on to one of rocket's workers, so leave some for everything else.
+const DEFAULT_MAX_SUBSCRIBERS:usize = 4;
+
+const DEFAULT_HEARTBEAT_SECS:u64 = 15;
+
+// How long EventSource should wait before reconnecting.
+const RETRY_MILLIS:u32 = 5000;
+
+const CHUNK_SIZE:usize = 4096;
+
+/// A packet that has been accepted, formatted as a server-sent event.
+#[derive(Debug)]
+pub struct Event {
+    id: u64,
+    device_id: DeviceId,
+    data: String
+}
+
+impl Event {
+    fn to_sse(&self) -> String {
+        format!("id: {}\nevent: telemetry\ndata: {}\n\n", self.id, self.data)
+    }
+}
+
+struct Subscribers {
+    next_id: u64,
+    backlog: VecDeque<Arc<Event>>,
+    // The token is shared with the client's EventStream, and is only held here once the client has gone.
+    senders: Vec<(SyncSender<Arc<Event>>, Arc<()>)>
+}
+
+/// Hands every accepted packet to the clients connected to /events.
+pub struct Broadcaster {
+    subscribers: Mutex<Subscribers>,
+    max_subscribers: usize
+}
+
+impl Broadcaster {
+    pub fn new() -> Broadcaster {
+        Broadcaster {
+            subscribers: Mutex::new(Subscribers {
+                // Start from the time so that ids keep going up across restarts, otherwise a client reconnecting with
+                // the Last-Event-ID from before the restart would never see the replay.
+                next_id: Utc::now().timestamp_millis() as u64,
+                backlog: VecDeque::with_capacity(BACKLOG_SIZE),
+                senders: vec![]
+            }),
+            max_subscribers: var("EVENTS_MAX_SUBSCRIBERS").ok()
+                .and_then(|max| max.parse::<usize>().ok())
+                .unwrap_or(DEFAULT_MAX_SUBSCRIBERS)
+        }
+    }
+
+    pub fn publish(&self, packet:&TelemetryPacket, received_at:DateTime<Utc>) {
+        let device_id = DeviceId(packet.device_id);
+
+        let mut data = serde_json::to_value(packet).unwrap();
+        data["device_id"] = json!(device_id);
+        data["received_at"] = json!(received_at.to_rfc3339());
+
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let event = Arc::new(Event { id: subscribers.next_id, device_id, data: data.to_string() });
+        subscribers.next_id += 1;
+
+        if subscribers.backlog.len() == BACKLOG_SIZE {
+            subscribers.backlog.pop_front();
+        }
+        subscribers.backlog.push_back(event.clone());
+
+        subscribers.senders.retain(|(tx, _)| match tx.try_send(event.clone()) {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("Dropping an event stream client that has fallen behind");
+                false
+            },
+            Err(TrySendError::Disconnected(_)) => false
+        });
+    }
+
+    /// Start a stream, replaying anything after `last_event_id` that is still in the backlog.  Returns None when
+    /// there are already too many clients.
+    pub fn subscribe(&self, device_id:Option<DeviceId>, last_event_id:Option<u64>, heartbeat:Duration) -> Option<EventStream> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        subscribers.senders.retain(|(_, token)| Arc::strong_count(token) > 1);
+
+        if subscribers.senders.len() >= self.max_subscribers {
+            return None;
+        }
+
+        let backlog = match last_event_id {
+            Some(last_event_id) => subscribers.backlog.iter()
+                .filter(|event| event.id > last_event_id)
+                .cloned()
+                .collect(),
+            None => VecDeque::new()
+        };
+
+        let (tx, rx) = sync_channel(SUBSCRIBER_QUEUE_SIZE);
+        let token = Arc::new(());
+        subscribers.senders.push((tx, token.clone()));
+
+        Some(EventStream {
+            device_id,
+            backlog,
+            rx,
+            _token: token,
+            heartbeat,
+            pending: format!("retry: {}\n\n", RETRY_MILLIS).into_bytes(),
+            position: 0
+        })
+    }
+}
+
+/// The body of an /events response.  Reading blocks until there is an event or it is time for a heartbeat, and ends
+/// when the broadcaster drops the client.
+pub struct EventStream {
+    device_id: Option<DeviceId>,
+    backlog: VecDeque<Arc<Event>>,
+    rx: Receiver<Arc<Event>>,
+    _token: Arc<()>,
+    heartbeat: Duration,
+    pending: Vec<u8>,
+    position: usize
+}
+
+impl EventStream {
+    fn wanted(&self, event:&Event) -> bool {
+        self.device_id.map(|device_id| device_id == event.device_id).unwrap_or(true)
+    }
+
+    /// The next thing to send, or None when the stream is over.
+    fn next(&mut self) -> Option<String> {
+        while let Some(event) = self.backlog.pop_front() {
+            if self.wanted(&event) {
+                return Some(event.to_sse());
+            }
+        }
+
+        loop {
+            match self.rx.recv_timeout(self.heartbeat) {
+                Ok(event) => {
+                    if self.wanted(&event) {
+                        return Some(event.to_sse());
+                    }
+                },
+                // A comment line keeps proxies from closing the connection and lets us notice when the client has gone.
+                Err(RecvTimeoutError::Timeout) => return Some(": heartbeat\n\n".to_string()),
+                Err(RecvTimeoutError::Disconnected) => return None
+            }
+        }
+    }
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf:&mut [u8]) -> io::Result<usize> {
+        // Rocket keeps reading until its chunk is full, a short buffer means something has already been read for
+        // this chunk.  WouldBlock tells it to send that now rather than waiting for more.
+        if buf.len() < CHUNK_SIZE {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        if self.position == self.pending.len() {
+            match self.next() {
+                Some(next) => {
+                    self.pending = next.into_bytes();
+                    self.position = 0;
+                },
+                None => return Ok(0)
+            }
+        }
+
+        // Never fill the chunk, so that the WouldBlock above is always reached.
+        let n = min(self.pending.len() - self.position, CHUNK_SIZE - 1);
+        buf[..n].copy_from_slice(&self.pending[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// The id of the last event a reconnecting EventSource saw.
+pub struct LastEventId(Option<u64>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for LastEventId {
+    type Error = ();
+
+    fn from_request(request:&'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(LastEventId(request.headers().get_one("Last-Event-ID").and_then(|id| id.parse::<u64>().ok())))
+    }
+}
+
+/// Every accepted packet as a server-sent event, optionally only those from `device_id`.
+#[get("/events?<device_id>")]
+pub fn events(device_id:Option<String>, last_event_id:LastEventId,
+        broadcaster:State<Arc<Broadcaster>>, _key:ReadKey) -> Result<Content<Stream<EventStream>>, Custom<String>> {
+    let device_id = match device_id {
+        Some(device_id) => match DeviceId::from_str(&device_id) {
+            Ok(device_id) => Some(device_id),
+            Err(_) => return Err(Custom(Status::BadRequest, format!("{} is not a device id", device_id)))
+        },
+        None => None
+    };
+
+    let heartbeat = Duration::from_secs(var("EVENTS_HEARTBEAT_SECS").ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_HEARTBEAT_SECS));
+
+    match broadcaster.subscribe(device_id, last_event_id.0, heartbeat) {
+        Some(stream) => Ok(Content(ContentType::new("text", "event-stream"), Stream::chunked(stream, CHUNK_SIZE as u64))),
+        None => Err(Custom(Status::ServiceUnavailable, "too many event stream clients".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(id:u8) -> TelemetryPacket {
+        let mut packet = TelemetryPacket::new();
+        packet.device_id = [id; 16];
+        packet
+    }
+
+    fn read_event(stream:&mut EventStream) -> String {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let n = stream.read(&mut buf).unwrap();
+        assert_eq!(io::ErrorKind::WouldBlock, stream.read(&mut buf[n..]).unwrap_err().kind());
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn filters_by_device() {
+        let broadcaster = Broadcaster::new();
+        let mut stream = broadcaster.subscribe(Some(DeviceId([2; 16])), None, Duration::from_millis(10)).unwrap();
+        assert_eq!("retry: 5000\n\n", read_event(&mut stream));
+
+        broadcaster.publish(&packet(1), Utc::now());
+        broadcaster.publish(&packet(2), Utc::now());
+
+        let event = read_event(&mut stream);
+        assert!(event.contains("\"device_id\":\"02020202020202020202020202020202\""), "{}", event);
+        assert_eq!(": heartbeat\n\n", read_event(&mut stream));
+    }
+
+    #[test]
+    fn replays_after_last_event_id() {
+        let broadcaster = Broadcaster::new();
+        let first_id = broadcaster.subscribers.lock().unwrap().next_id;
+        broadcaster.publish(&packet(1), Utc::now());
+        broadcaster.publish(&packet(2), Utc::now());
+
+        let mut stream = broadcaster.subscribe(None, Some(first_id), Duration::from_millis(10)).unwrap();
+        read_event(&mut stream);
+
+        let event = read_event(&mut stream);
+        assert!(event.starts_with(&format!("id: {}\n", first_id + 1)), "{}", event);
+        assert_eq!(": heartbeat\n\n", read_event(&mut stream));
+    }
+
+    #[test]
+    fn ends_when_dropped_for_falling_behind() {
+        let broadcaster = Broadcaster::new();
+        let mut stream = broadcaster.subscribe(None, None, Duration::from_millis(10)).unwrap();
+
+        for _ in 0..SUBSCRIBER_QUEUE_SIZE + 1 {
+            broadcaster.publish(&packet(1), Utc::now());
+        }
+
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut events = 0;
+        while stream.read(&mut buf).unwrap() > 0 {
+            events += 1;
+        }
+
+        // The retry line and everything that fitted in the queue.
+        assert_eq!(SUBSCRIBER_QUEUE_SIZE + 1, events);
+    }
+}
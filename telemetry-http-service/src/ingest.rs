@@ -0,0 +1,210 @@
+use std::io::Cursor;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
+
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+
+use rocket::http::ContentType;
+use rocket::http::Status;
+use rocket::request;
+use rocket::request::FromRequest;
+use rocket::request::Request;
+use rocket::response;
+use rocket::response::Responder;
+use rocket::response::Response;
+use rocket::Outcome;
+use rocket::State;
+use rocket_contrib::json::Json;
+use rocket_contrib::json::JsonError;
+
+use rainguage_messages::TelemetryPacket;
+
+use crate::auth::IngestKey;
+use crate::events::Broadcaster;
+use crate::metrics;
+use crate::registry::Registry;
+
+// How long a client should wait before trying again when the queue is full.
+const RETRY_AFTER_SECS:u32 = 5;
+
+// The DHT22 is rated for -40 to 80 degrees.
+const MIN_TEMPERATURE:f32 = -40.0;
+const MAX_TEMPERATURE:f32 = 80.0;
+
+// vbat is a raw 12-bit ADC reading.
+const MAX_VBAT:u32 = 4095;
+
+// How far ahead of our clock the gateway's can be before its receive times are not believed.
+const MAX_CLOCK_SKEW_SECS:i64 = 300;
+
+/// A packet on its way to the persister, with the time the gateway heard it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Received {
+    #[serde(flatten)]
+    pub packet: TelemetryPacket,
+    pub received_at: DateTime<Utc>,
+    /// See Posted.
+    #[serde(default)]
+    pub history: bool
+}
+
+/// A telemetry packet as it is posted.  `history` is set for a reading the gauge sent again in a HistoryPacket, which
+/// does not keep the usb, lora or hardware counters, so the zeros in the packet are stored as NULL.
+#[derive(Debug, Deserialize)]
+pub struct Posted {
+    #[serde(flatten)]
+    pub packet: TelemetryPacket,
+    #[serde(default)]
+    pub history: bool
+}
+
+/// The `X-Received-At` header the downlink-processor sets to the time it read the packet from the radio.
+pub struct ReceivedAtHeader(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ReceivedAtHeader {
+    type Error = ();
+
+    fn from_request(request:&'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(ReceivedAtHeader(request.headers().get_one("X-Received-At").map(|value| value.to_string())))
+    }
+}
+
+/// When the packet was received, falling back to now when the client did not say.
+pub fn received_at(header:ReceivedAtHeader) -> Result<DateTime<Utc>, String> {
+    let now = Utc::now();
+    let received_at = match header.0 {
+        Some(value) => DateTime::parse_from_rfc3339(&value)
+            .map_err(|err| format!("X-Received-At {} is not an rfc3339 timestamp: {}", value, err))?
+            .with_timezone(&Utc),
+        None => return Ok(now)
+    };
+
+    if received_at - now > Duration::seconds(MAX_CLOCK_SKEW_SECS) {
+        return Err(format!("X-Received-At {} is in the future", received_at.to_rfc3339()));
+    }
+
+    Ok(received_at.min(now))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IngestResponse {
+    /// The packet was queued for the persister.
+    Queued,
+    /// The packet was written to the database.
+    Stored,
+    /// The persister is behind and the queue is full.
+    QueueFull,
+    /// The persister has stopped and nothing can be stored.
+    Unavailable,
+    /// The payload could not be parsed or does not make sense.
+    Invalid(String)
+}
+
+impl<'r> Responder<'r> for IngestResponse {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        match self {
+            IngestResponse::Queued => {
+                Response::build()
+                    .status(Status::Accepted)
+                    .ok()
+            },
+            IngestResponse::Stored => {
+                Response::build()
+                    .status(Status::Created)
+                    .ok()
+            },
+            IngestResponse::QueueFull | IngestResponse::Unavailable => {
+                Response::build()
+                    .status(Status::ServiceUnavailable)
+                    .raw_header("Retry-After", RETRY_AFTER_SECS.to_string())
+                    .ok()
+            },
+            IngestResponse::Invalid(reason) => {
+                Response::build()
+                    .status(Status::BadRequest)
+                    .header(ContentType::Plain)
+                    .sized_body(Cursor::new(reason))
+                    .ok()
+            }
+        }
+    }
+}
+
+#[post("/telemetry", format = "json", data = "<packet>")]
+pub fn post(posted:Result<Json<Posted>, JsonError>, header:ReceivedAtHeader, tx:State<SyncSender<Received>>,
+        registry:State<Arc<Registry>>, broadcaster:State<Arc<Broadcaster>>, _key:IngestKey) -> IngestResponse {
+    let Posted { packet, history } = match posted {
+        Ok(posted) => posted.into_inner(),
+        Err(err) => {
+            metrics::increment_invalid_cnt();
+            return IngestResponse::Invalid(format!("malformed telemetry: {:?}", err));
+        }
+    };
+
+    if let Err(reason) = validate(&packet) {
+        warn!("Rejecting telemetry {:?}: {}", packet, reason);
+        metrics::increment_invalid_cnt();
+        return IngestResponse::Invalid(reason);
+    }
+
+    let received_at = match received_at(header) {
+        Ok(received_at) => received_at,
+        Err(reason) => {
+            metrics::increment_invalid_cnt();
+            return IngestResponse::Invalid(reason);
+        }
+    };
+    let latest = packet.clone();
+
+    // Count the packet before handing it over, the persister may pick it up before try_send returns.
+    metrics::increment_queue_depth();
+    match tx.try_send(Received { packet, received_at, history }) {
+        Ok(_) => {
+            metrics::increment_accepted_cnt();
+            // Without its counters a reading sent again would look like the gauge had restarted.
+            if !history {
+                registry.update(&latest, received_at);
+            }
+            broadcaster.publish(&latest, received_at);
+            IngestResponse::Queued
+        },
+        Err(TrySendError::Full(_)) => {
+            metrics::decrement_queue_depth();
+            metrics::increment_queue_full_cnt();
+            warn!("Telemetry queue is full, asking the client to retry");
+            IngestResponse::QueueFull
+        },
+        Err(TrySendError::Disconnected(_)) => {
+            metrics::decrement_queue_depth();
+            metrics::increment_unavailable_cnt();
+            error!("The persister has stopped, telemetry cannot be stored");
+            IngestResponse::Unavailable
+        }
+    }
+}
+
+/// Check that a packet is something the rainguage could have sent.
+pub fn validate(packet:&TelemetryPacket) -> Result<(), String> {
+    if packet.device_id == [0; 16] {
+        return Err("device_id is missing".to_string());
+    }
+
+    if !packet.temperature.is_finite() || packet.temperature < MIN_TEMPERATURE || packet.temperature > MAX_TEMPERATURE {
+        return Err(format!("temperature {} is out of range", packet.temperature));
+    }
+
+    if !packet.relative_humidity.is_finite() || packet.relative_humidity < 0.0 || packet.relative_humidity > 100.0 {
+        return Err(format!("relative_humidity {} is out of range", packet.relative_humidity));
+    }
+
+    if packet.vbat > MAX_VBAT {
+        return Err(format!("vbat {} is larger than the adc can read", packet.vbat));
+    }
+
+    Ok(())
+}
@@ -3,33 +3,489 @@
 #[macro_use] extern crate rocket;
 
 #[macro_use] extern crate log;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
 use std::sync::mpsc::sync_channel;
 use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
 
+use chrono::DateTime;
+use chrono::Utc;
 use dotenv::dotenv;
+use dotenv::var;
+use structopt::StructOpt;
 
-use rainguage_messages::TelemetryPacket;
-use rocket_contrib::json::Json;
-use rocket::State;
+use auth::Authenticator;
+use auth::Scope;
+use bulk::FileFormat;
+use bulk::Filter;
+use database::Pool;
+use device::DeviceId;
+use events::Broadcaster;
+use ingest::Received;
+use registry::Registry;
+mod alerts;
+mod auth;
+mod battery;
+mod bulk;
+mod config;
+mod counters;
+mod dashboard;
+mod database;
+mod device;
+mod events;
+mod ingest;
+mod metrics;
 mod persister;
+mod power;
+mod query;
+mod rainfall;
+mod reconstruct;
+mod registry;
+mod rollup;
+mod sinks;
+mod tips;
 
-#[post("/telemetry", format = "json", data = "<packet>")]
-fn post(packet:Json<TelemetryPacket>, tx:State<SyncSender<TelemetryPacket>>) {
-    tx.send(packet.into_inner()).unwrap();
+// How many packets can be waiting for the persister before we start turning clients away.
+const QUEUE_SIZE:usize = 32;
+
+#[derive(StructOpt)]
+#[structopt(about = "Stores rainguage telemetry and serves it back")]
+struct Options {
+    /// A toml config file, by default telemetry-http-service.toml when there is one
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Change a setting, as name=value with the name from the config file or environment (postgres.port=5433 or
+    /// POSTGRES_PORT=5433).  Can be given more than once and wins over both
+    #[structopt(long = "set", number_of_values = 1)]
+    overrides: Vec<String>,
+    /// Report every problem with the settings and exit
+    #[structopt(long)]
+    check_config: bool,
+    #[structopt(subcommand)]
+    command: Option<Command>
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Run the service, this is the default
+    Serve,
+    /// Manage api keys
+    Keys(KeysCommand),
+    /// Write telemetry to a csv, ndjson or parquet file, ordered by device and time
+    Export {
+        /// Only export this device, can be given more than once.  By default every device is exported
+        #[structopt(long = "device")]
+        devices: Vec<DeviceId>,
+        /// rfc3339, inclusive
+        #[structopt(long)]
+        from: Option<DateTime<Utc>>,
+        /// rfc3339, exclusive
+        #[structopt(long)]
+        to: Option<DateTime<Utc>>,
+        /// csv, ndjson or parquet.  By default it is taken from the output file name, or csv
+        #[structopt(long)]
+        format: Option<FileFormat>,
+        /// Where to write, by default stdout
+        #[structopt(long, short, parse(from_os_str))]
+        output: Option<PathBuf>
+    },
+    /// Load files written by export, or the dead letter file, skipping rows that are already there
+    Import {
+        /// csv, ndjson or parquet.  By default it is taken from each file name
+        #[structopt(long)]
+        format: Option<FileFormat>,
+        #[structopt(parse(from_os_str), required = true)]
+        files: Vec<PathBuf>
+    }
+}
+
+#[derive(StructOpt)]
+enum KeysCommand {
+    /// Create a key and print it, it cannot be shown again
+    Create {
+        name: String,
+        /// ingest, read or admin
+        #[structopt(long, default_value = "read")]
+        scope: Scope
+    },
+    /// List the keys
+    List,
+    /// Delete a key
+    Revoke {
+        name: String
+    }
+}
+
+fn rocket(tx:SyncSender<Received>, pool:Pool, registry:Arc<Registry>, broadcaster:Arc<Broadcaster>,
+        authenticator:Authenticator) -> rocket::Rocket {
+    rocket::ignite()
+        .manage(tx)
+        .manage(pool)
+        .manage(registry)
+        .manage(broadcaster)
+        .manage(authenticator)
+        .register(catchers![auth::unauthorized])
+        .mount("/", routes![ingest::post, tips::post, power::post, metrics::metrics, events::events])
+        .mount("/", routes![query::devices, query::telemetry, query::latest, counters::counters,
+            reconstruct::clock])
+        .mount("/", routes![rainfall::rainfall, rainfall::rainfall_rolling, rainfall::rainfall_intensity,
+            rainfall::rainfall_minutes, rainfall::rainfall_storms, rainfall::get_calibration,
+            rainfall::put_calibration])
+        .mount("/", routes![battery::battery, battery::battery_runtime, battery::get_battery_calibration,
+            battery::put_battery_calibration, battery::get_hardware, battery::put_hardware, power::power])
+        .mount("/", routes![dashboard::index, dashboard::script, dashboard::style])
+}
+
+fn keys(pool:&Pool, command:KeysCommand) {
+    match command {
+        KeysCommand::Create { name, scope } => {
+            let key = auth::create_key(pool, &name, scope).expect("Failed to create the key.");
+            println!("{}", key);
+        },
+        KeysCommand::List => {
+            for (name, scope, created_at) in auth::list_keys(pool).expect("Failed to list the keys.") {
+                println!("{}\t{}\t{}", name, scope, created_at.to_rfc3339());
+            }
+        },
+        KeysCommand::Revoke { name } => {
+            if !auth::revoke_key(pool, &name).expect("Failed to revoke the key.") {
+                eprintln!("There is no key called {}", name);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn export(pool:&Pool, filter:Filter, format:Option<FileFormat>, output:Option<PathBuf>) {
+    let format = format
+        .or_else(|| output.as_ref().and_then(|output| FileFormat::from_path(output)))
+        .unwrap_or(FileFormat::Csv);
+
+    let exported = match output {
+        Some(output) => {
+            let file = File::create(&output).expect("Failed to create the output file.");
+            bulk::export(pool, &filter, format, BufWriter::new(file))
+        },
+        None => bulk::export(pool, &filter, format, BufWriter::new(std::io::stdout()))
+    };
+
+    match exported {
+        Ok(count) => eprintln!("Exported {} rows", count),
+        Err(err) => {
+            eprintln!("Export failed: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn import(pool:&Pool, format:Option<FileFormat>, files:Vec<PathBuf>) {
+    for file in files {
+        let format = match format.or_else(|| FileFormat::from_path(&file)) {
+            Some(format) => format,
+            None => {
+                eprintln!("Cannot tell the format of {}, use --format", file.display());
+                std::process::exit(1);
+            }
+        };
+
+        match bulk::import(pool, &file, format) {
+            Ok((read, inserted)) => eprintln!("{}: {} rows, {} new", file.display(), read, inserted),
+            Err(err) => {
+                eprintln!("{}: import failed: {:?}", file.display(), err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn serve(pool:Pool) {
+    let (tx, rx) = sync_channel::<Received>(QUEUE_SIZE);
+
+    persister::start(rx, pool.clone());
+    rollup::start(pool.clone());
+
+    let registry = Arc::new(Registry::default());
+    if let Err(err) = registry.load(&pool) {
+        error!("Could not load the latest telemetry, devices will appear as they report: {:?}", err);
+    }
+
+    alerts::start(pool.clone(), registry.clone(), sinks::from_env());
+
+    let authenticator = if var("AUTH_DISABLED").map(|disabled| disabled == "true").unwrap_or(false) {
+        warn!("AUTH_DISABLED is set, anyone can send and read telemetry");
+        Authenticator::disabled()
+    } else {
+        Authenticator::new(pool.clone())
+    };
+
+    info!("Starting ...");
+
+    rocket(tx, pool, registry, Arc::new(Broadcaster::new()), authenticator).launch();
 }
 
 fn main() {
-    simplelog::SimpleLogger::init(simplelog::LevelFilter::Info, simplelog::Config::default()).unwrap();
+    let options = Options::from_args();
+
+    // An export can go to stdout, so the log has to stay out of it.
+    match options.command {
+        Some(Command::Export { .. }) => {
+            simplelog::WriteLogger::init(simplelog::LevelFilter::Info, simplelog::Config::default(), std::io::stderr()).unwrap();
+        },
+        _ => {
+            simplelog::SimpleLogger::init(simplelog::LevelFilter::Info, simplelog::Config::default()).unwrap();
+        }
+    }
     dotenv().ok();
 
-    let (tx, rx) = sync_channel::<TelemetryPacket>(32);
+    let problems = config::load(options.config.as_deref(), &options.overrides);
+    rainguage_config::exit_on_problems(&problems, options.check_config);
 
-    persister::start(rx);
+    let pool = database::pool();
+    database::init_database(&pool).expect("Failed to initialize database.");
 
-    info!("Starting ...");
+    match options.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(pool),
+        Command::Keys(command) => keys(&pool, command),
+        Command::Export { devices, from, to, format, output } => export(&pool, Filter { devices, from, to }, format, output),
+        Command::Import { format, files } => import(&pool, format, files)
+    }
+}
 
-    rocket::ignite()
-        .manage(tx)
-        .mount("/", routes![post])
-        .launch();
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::sync_channel;
+    use std::sync::mpsc::SyncSender;
+    use std::sync::Arc;
+
+    use postgres::NoTls;
+    use r2d2_postgres::PostgresConnectionManager;
+    use rocket::http::ContentType;
+    use rocket::http::Header;
+    use rocket::http::Status;
+    use rocket::local::Client;
+
+    use rainguage_messages::TelemetryPacket;
+
+    use crate::auth::Authenticator;
+    use crate::auth::Scope;
+    use crate::database::Pool;
+    use crate::events::Broadcaster;
+    use crate::ingest::Received;
+    use crate::registry::Registry;
+
+    // The ingest routes never touch the database, so the pool does not have to point anywhere real.
+    fn pool() -> Pool {
+        let manager = PostgresConnectionManager::new(postgres::Config::new(), NoTls);
+        r2d2::Pool::builder().build_unchecked(manager)
+    }
+
+    fn client(tx:SyncSender<Received>, authenticator:Authenticator) -> Client {
+        Client::new(super::rocket(tx, pool(), Arc::new(Registry::default()), Arc::new(Broadcaster::new()), authenticator)).unwrap()
+    }
+
+    fn packet() -> TelemetryPacket {
+        let mut packet = TelemetryPacket::new();
+        packet.device_id = [10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 120, 130, 140, 150, 160, 170];
+        packet.loop_cnt = 180;
+        packet.vbat = 2500;
+        packet.temperature = 21.5;
+        packet.relative_humidity = 45.0;
+        packet
+    }
+
+    fn body(packet:&TelemetryPacket) -> String {
+        serde_json::to_string(packet).unwrap()
+    }
+
+    #[test]
+    fn accepted_when_queued() {
+        let (tx, rx) = sync_channel(1);
+        let client = client(tx, Authenticator::disabled());
+
+        let response = client.post("/telemetry")
+            .header(ContentType::JSON)
+            .body(body(&packet()))
+            .dispatch();
+
+        assert_eq!(Status::Accepted, response.status());
+        assert_eq!(packet(), rx.try_recv().unwrap().packet);
+    }
+
+    #[test]
+    fn history_is_marked() {
+        let (tx, rx) = sync_channel(1);
+        let client = client(tx, Authenticator::disabled());
+
+        let mut body = serde_json::to_value(&packet()).unwrap();
+        body["history"] = true.into();
+        let response = client.post("/telemetry")
+            .header(ContentType::JSON)
+            .body(body.to_string())
+            .dispatch();
+
+        assert_eq!(Status::Accepted, response.status());
+        let received = rx.try_recv().unwrap();
+        assert_eq!(packet(), received.packet);
+        assert!(received.history);
+    }
+
+    #[test]
+    fn retry_after_when_queue_full() {
+        let (tx, _rx) = sync_channel(1);
+        let client = client(tx, Authenticator::disabled());
+
+        let first = client.post("/telemetry")
+            .header(ContentType::JSON)
+            .body(body(&packet()))
+            .dispatch();
+        assert_eq!(Status::Accepted, first.status());
+
+        let second = client.post("/telemetry")
+            .header(ContentType::JSON)
+            .body(body(&packet()))
+            .dispatch();
+        assert_eq!(Status::ServiceUnavailable, second.status());
+        assert_eq!(Some("5"), second.headers().get_one("Retry-After"));
+    }
+
+    #[test]
+    fn unavailable_when_persister_stopped() {
+        let (tx, rx) = sync_channel(1);
+        drop(rx);
+        let client = client(tx, Authenticator::disabled());
+
+        let response = client.post("/telemetry")
+            .header(ContentType::JSON)
+            .body(body(&packet()))
+            .dispatch();
+
+        assert_eq!(Status::ServiceUnavailable, response.status());
+        assert!(response.headers().get_one("Retry-After").is_some());
+    }
+
+    #[test]
+    fn bad_request_for_malformed_json() {
+        let (tx, rx) = sync_channel(1);
+        let client = client(tx, Authenticator::disabled());
+
+        let response = client.post("/telemetry")
+            .header(ContentType::JSON)
+            .body("{\"device_id\": \"not a device\"}")
+            .dispatch();
+
+        assert_eq!(Status::BadRequest, response.status());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn bad_request_for_nonsense_values() {
+        let (tx, rx) = sync_channel(4);
+        let client = client(tx, Authenticator::disabled());
+
+        let mut humid = packet();
+        humid.relative_humidity = 250.0;
+
+        let mut hot = packet();
+        hot.temperature = 1000.0;
+
+        let mut anonymous = packet();
+        anonymous.device_id = [0; 16];
+
+        let mut overvolt = packet();
+        overvolt.vbat = 70000;
+
+        for bad in [humid, hot, anonymous, overvolt].iter() {
+            let response = client.post("/telemetry")
+                .header(ContentType::JSON)
+                .body(body(bad))
+                .dispatch();
+
+            assert_eq!(Status::BadRequest, response.status());
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn dashboard_is_served() {
+        let (tx, _rx) = sync_channel(1);
+        let client = client(tx, Authenticator::disabled());
+
+        let mut index = client.get("/").dispatch();
+        assert_eq!(Status::Ok, index.status());
+        assert_eq!(Some(ContentType::HTML), index.content_type());
+        assert!(index.body_string().unwrap().contains("/static/dashboard.js"));
+
+        let script = client.get("/static/dashboard.js").dispatch();
+        assert_eq!(Some(ContentType::JavaScript), script.content_type());
+    }
+
+    #[test]
+    fn unauthorized_without_a_key() {
+        let (tx, rx) = sync_channel(1);
+        let client = client(tx, Authenticator::new(pool()));
+
+        let response = client.post("/telemetry")
+            .header(ContentType::JSON)
+            .body(body(&packet()))
+            .dispatch();
+
+        assert_eq!(Status::Unauthorized, response.status());
+        assert!(response.headers().get_one("WWW-Authenticate").is_some());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn keys_are_limited_to_their_scope() {
+        let (tx, rx) = sync_channel(1);
+        let authenticator = Authenticator::new(pool());
+        authenticator.remember("ingest-key", Scope::Ingest);
+        authenticator.remember("read-key", Scope::Read);
+        let client = client(tx, authenticator);
+
+        let forbidden = client.post("/telemetry")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer read-key"))
+            .body(body(&packet()))
+            .dispatch();
+        assert_eq!(Status::Forbidden, forbidden.status());
+
+        let accepted = client.post("/telemetry")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer ingest-key"))
+            .body(body(&packet()))
+            .dispatch();
+        assert_eq!(Status::Accepted, accepted.status());
+        assert_eq!(packet(), rx.try_recv().unwrap().packet);
+
+        let metrics = client.get("/metrics")
+            .header(Header::new("X-Api-Key", "ingest-key"))
+            .dispatch();
+        assert_eq!(Status::Forbidden, metrics.status());
+
+        let metrics = client.get("/metrics?access_token=read-key").dispatch();
+        assert_eq!(Status::Ok, metrics.status());
+    }
+
+    #[test]
+    fn gateway_receive_time_is_kept() {
+        let (tx, rx) = sync_channel(2);
+        let client = client(tx, Authenticator::disabled());
+
+        let response = client.post("/telemetry")
+            .header(ContentType::JSON)
+            .header(Header::new("X-Received-At", "2020-09-01T12:00:00Z"))
+            .body(body(&packet()))
+            .dispatch();
+        assert_eq!(Status::Accepted, response.status());
+        assert_eq!("2020-09-01T12:00:00+00:00", rx.try_recv().unwrap().received_at.to_rfc3339());
+
+        let response = client.post("/telemetry")
+            .header(ContentType::JSON)
+            .header(Header::new("X-Received-At", "2999-01-01T00:00:00Z"))
+            .body(body(&packet()))
+            .dispatch();
+        assert_eq!(Status::BadRequest, response.status());
+    }
+}
@@ -0,0 +1,207 @@
+use std::fmt::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+use rocket::State;
+
+use crate::auth::ReadKey;
+use crate::registry::Registry;
+
+// Packets accepted by POST /telemetry and handed to the persister.
+static ACCEPTED_CNT:AtomicU64 = AtomicU64::new(0);
+
+// Packets turned away by POST /telemetry, by reason.
+static INVALID_CNT:AtomicU64 = AtomicU64::new(0);
+static QUEUE_FULL_CNT:AtomicU64 = AtomicU64::new(0);
+static UNAVAILABLE_CNT:AtomicU64 = AtomicU64::new(0);
+
+// The number of packets accepted by the http handler that the persister has not picked up yet.
+static QUEUE_DEPTH:AtomicU64 = AtomicU64::new(0);
+
+static WRITE_CNT:AtomicU64 = AtomicU64::new(0);
+
+// Total time spent in successful writes, divide by WRITE_CNT for the mean.
+static WRITE_LATENCY_MICROS:AtomicU64 = AtomicU64::new(0);
+
+static WRITE_LATENCY_MAX_MICROS:AtomicU64 = AtomicU64::new(0);
+
+// This is incremented every time a write attempt fails, including ones that later succeed on retry.
+static WRITE_ERROR_CNT:AtomicU64 = AtomicU64::new(0);
+
+// Packets that could not be stored after all retries and were written to the dead letter file.
+static DEAD_LETTER_CNT:AtomicU64 = AtomicU64::new(0);
+
+pub fn increment_accepted_cnt() {
+    ACCEPTED_CNT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn increment_invalid_cnt() {
+    INVALID_CNT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn increment_queue_full_cnt() {
+    QUEUE_FULL_CNT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn increment_unavailable_cnt() {
+    UNAVAILABLE_CNT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn increment_queue_depth() {
+    QUEUE_DEPTH.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn decrement_queue_depth() {
+    QUEUE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub fn get_queue_depth() -> u64 {
+    QUEUE_DEPTH.load(Ordering::Relaxed)
+}
+
+pub fn record_write_latency(latency:Duration) {
+    let micros = latency.as_micros() as u64;
+    WRITE_CNT.fetch_add(1, Ordering::Relaxed);
+    WRITE_LATENCY_MICROS.fetch_add(micros, Ordering::Relaxed);
+    WRITE_LATENCY_MAX_MICROS.fetch_max(micros, Ordering::Relaxed);
+}
+
+pub fn get_write_cnt() -> u64 {
+    WRITE_CNT.load(Ordering::Relaxed)
+}
+
+pub fn get_write_latency_micros() -> u64 {
+    WRITE_LATENCY_MICROS.load(Ordering::Relaxed)
+}
+
+pub fn get_write_latency_max_micros() -> u64 {
+    WRITE_LATENCY_MAX_MICROS.load(Ordering::Relaxed)
+}
+
+pub fn increment_write_error_cnt() {
+    WRITE_ERROR_CNT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn get_write_error_cnt() -> u64 {
+    WRITE_ERROR_CNT.load(Ordering::Relaxed)
+}
+
+pub fn increment_dead_letter_cnt() {
+    DEAD_LETTER_CNT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn get_dead_letter_cnt() -> u64 {
+    DEAD_LETTER_CNT.load(Ordering::Relaxed)
+}
+
+fn header(out:&mut String, name:&str, kind:&str, help:&str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Write the service and per device metrics in the prometheus text format.
+pub fn render(registry:&Registry) -> String {
+    let mut out = String::new();
+
+    header(&mut out, "telemetry_ingest_total", "counter", "Telemetry packets received by POST /telemetry.");
+    writeln!(out, "telemetry_ingest_total{{result=\"accepted\"}} {}", ACCEPTED_CNT.load(Ordering::Relaxed)).unwrap();
+    writeln!(out, "telemetry_ingest_total{{result=\"invalid\"}} {}", INVALID_CNT.load(Ordering::Relaxed)).unwrap();
+    writeln!(out, "telemetry_ingest_total{{result=\"queue_full\"}} {}", QUEUE_FULL_CNT.load(Ordering::Relaxed)).unwrap();
+    writeln!(out, "telemetry_ingest_total{{result=\"unavailable\"}} {}", UNAVAILABLE_CNT.load(Ordering::Relaxed)).unwrap();
+
+    header(&mut out, "telemetry_queue_depth", "gauge", "Packets waiting to be written to the database.");
+    writeln!(out, "telemetry_queue_depth {}", get_queue_depth()).unwrap();
+
+    header(&mut out, "telemetry_db_write_latency_seconds", "summary", "Time taken by successful database writes.");
+    writeln!(out, "telemetry_db_write_latency_seconds_sum {}", get_write_latency_micros() as f64 / 1_000_000.0).unwrap();
+    writeln!(out, "telemetry_db_write_latency_seconds_count {}", get_write_cnt()).unwrap();
+
+    header(&mut out, "telemetry_db_write_latency_max_seconds", "gauge", "The slowest successful database write.");
+    writeln!(out, "telemetry_db_write_latency_max_seconds {}", get_write_latency_max_micros() as f64 / 1_000_000.0).unwrap();
+
+    header(&mut out, "telemetry_db_errors_total", "counter", "Failed database write attempts, including ones that succeeded on retry.");
+    writeln!(out, "telemetry_db_errors_total {}", get_write_error_cnt()).unwrap();
+
+    header(&mut out, "telemetry_dead_letters_total", "counter", "Packets written to the dead letter file after every retry failed.");
+    writeln!(out, "telemetry_dead_letters_total {}", get_dead_letter_cnt()).unwrap();
+
+    let devices = registry.snapshot();
+    let now = Utc::now();
+
+    let gauges:[(&str, &str, &str, fn(&rainguage_messages::TelemetryPacket) -> f64); 9] = [
+        ("rainguage_vbat", "gauge", "Raw battery adc reading.", |p| p.vbat as f64),
+        ("rainguage_temperature_celsius", "gauge", "Temperature from the DHT22.", |p| p.temperature as f64),
+        ("rainguage_relative_humidity_percent", "gauge", "Relative humidity from the DHT22.", |p| p.relative_humidity as f64),
+        ("rainguage_tips_total", "counter", "Bucket tips since the gauge started.", |p| p.tip_cnt as f64),
+        ("rainguage_loops_total", "counter", "Main loop iterations since the gauge started.", |p| p.loop_cnt as f64),
+        ("rainguage_usb_errors_total", "counter", "Usb errors since the gauge started.", |p| p.usb_error_cnt as f64),
+        ("rainguage_lora_errors_total", "counter", "Lora transmit errors since the gauge started.", |p| p.lora_error_cnt as f64),
+        ("rainguage_lora_tx_bytes_total", "counter", "Bytes sent over lora since the gauge started.", |p| p.lora_tx_bytes as f64),
+        ("rainguage_hardware_other_errors_total", "counter", "Other hardware errors since the gauge started.", |p| p.hardware_err_other_cnt as f64),
+    ];
+
+    for (name, kind, help, value) in gauges.iter() {
+        header(&mut out, name, kind, help);
+        for (device_id, latest) in devices.iter() {
+            writeln!(out, "{}{{device_id=\"{}\"}} {}", name, device_id, value(&latest.packet)).unwrap();
+        }
+    }
+
+    // Older gauges do not send it.
+    header(&mut out, "rainguage_uptime_seconds_total", "counter", "Seconds since the gauge started, from its RTC.");
+    for (device_id, latest) in devices.iter() {
+        if let Some(uptime_secs) = latest.packet.uptime_secs {
+            writeln!(out, "rainguage_uptime_seconds_total{{device_id=\"{}\"}} {}", device_id, uptime_secs).unwrap();
+        }
+    }
+
+    header(&mut out, "rainguage_last_seen_seconds", "gauge", "Seconds since the last packet from the gauge.");
+    for (device_id, latest) in devices.iter() {
+        let age = (now - latest.received_at).num_milliseconds() as f64 / 1000.0;
+        writeln!(out, "rainguage_last_seen_seconds{{device_id=\"{}\"}} {}", device_id, age).unwrap();
+    }
+
+    out
+}
+
+#[get("/metrics")]
+pub fn metrics(registry:State<Arc<Registry>>, _key:ReadKey) -> Content<String> {
+    Content(ContentType::with_params("text", "plain", ("version", "0.0.4")), render(&registry))
+}
+
+#[cfg(test)]
+mod tests {
+    use rainguage_messages::TelemetryPacket;
+
+    use super::*;
+
+    #[test]
+    fn counters_are_totals() {
+        let registry = Registry::default();
+        let mut packet = TelemetryPacket::new();
+        packet.device_id = [1; 16];
+        packet.tip_cnt = 7;
+        packet.uptime_secs = Some(3600);
+        registry.update(&packet, Utc::now());
+
+        let out = render(&registry);
+        let device = "device_id=\"01010101010101010101010101010101\"";
+        assert!(out.contains("# TYPE rainguage_tips_total counter\n"), "{}", out);
+        assert!(out.contains(&format!("rainguage_tips_total{{{}}} 7\n", device)), "{}", out);
+        assert!(out.contains(&format!("rainguage_uptime_seconds_total{{{}}} 3600\n", device)), "{}", out);
+
+        let counters = out.lines()
+            .filter(|line| line.starts_with("# TYPE ") && line.ends_with(" counter"))
+            .map(|line| line.split(' ').nth(2).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(10, counters.len());
+        for name in counters {
+            assert!(name.ends_with("_total"), "{} should end in _total", name);
+        }
+    }
+}
@@ -1,119 +1,242 @@
+use std::fs::OpenOptions;
+use std::io::Write;
 use std::sync::mpsc::Receiver;
 use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
+use chrono::DateTime;
+use chrono::Utc;
 use dotenv::var;
+use rand::Rng;
 
-use postgres::Config;
-use postgres::NoTls;
+use crate::database::DatabaseError;
+use crate::database::Pool;
+use crate::device::DeviceId;
+use crate::ingest::Received;
+use crate::metrics;
+use crate::reconstruct::Observation;
+use crate::reconstruct::Reconstructor;
 
-use rainguage_messages::TelemetryPacket;
+// How many times a packet is attempted before it is sent to the dead letter file.
+const MAX_ATTEMPTS:u32 = 8;
+
+// The first retry waits up to this long, doubling with each attempt up to MAX_BACKOFF.
+const BASE_BACKOFF:Duration = Duration::from_millis(250);
+
+const MAX_BACKOFF:Duration = Duration::from_secs(30);
+
+// Write a summary of the persister metrics to the log every so many writes.
+const SUMMARY_INTERVAL:u64 = 100;
 
 #[derive(Debug)]
 enum WriterError {
-    PostgresError(postgres::Error)
+    DatabaseError(DatabaseError)
 }
 
 impl From<postgres::Error> for WriterError {
     fn from(err: postgres::Error) -> Self {
-        WriterError::PostgresError(err)
+        WriterError::DatabaseError(DatabaseError::PostgresError(err))
     }
 }
 
-pub fn start(rx:Receiver<TelemetryPacket>) {
-    let config = config();
-    init_database(&config).expect("Failed to initialize database.");
+impl From<r2d2::Error> for WriterError {
+    fn from(err: r2d2::Error) -> Self {
+        WriterError::DatabaseError(DatabaseError::PoolError(err))
+    }
+}
+
+pub fn start(rx:Receiver<Received>, pool:Pool) {
+    let dead_letter_file = var("DEAD_LETTER_FILE").unwrap_or_else(|_| "dead-letter.ndjson".to_string());
+    let mut reconstructor = Reconstructor::new();
 
     thread::spawn(move|| {
         loop {
             match rx.recv() {
-                Ok(packet) => {
-                    write_to_database_reliably(&packet, &config);
+                Ok(received) => {
+                    metrics::decrement_queue_depth();
+
+                    let observation = Observation { loop_cnt: received.packet.loop_cnt, received_at: received.received_at };
+                    let (ts, reconstructed) = reconstructor.timestamp(DeviceId(received.packet.device_id), observation);
+                    if reconstructed {
+                        info!("Back dating a packet received at {} to {}", received.received_at.to_rfc3339(), ts.to_rfc3339());
+                    }
+
+                    write_to_database_reliably(&received, ts, reconstructed, &pool, &dead_letter_file);
                 },
                 Err(err) => {
+                    // Every sender has hung up, there is nothing left to do.
                     error!("Error receiving messages:{:?}", err);
+                    return;
                 }
             }
         }
     });
 }
 
-fn config() -> Config {
-    let mut pg_config = Config::new();
-    pg_config.host(&var("POSTGRES_HOST").unwrap());
-    pg_config.user(&var("POSTGRES_USER").unwrap());
-    pg_config.password(&var("POSTGRES_PASSWORD").unwrap());
-    pg_config.port(var("POSTGRES_PORT").unwrap().parse::<u16>().unwrap());
-
-    pg_config
-}
-
-fn init_database(config:&Config) -> Result<(), WriterError> {
-    let mut client = config.connect(NoTls)?;
-
-    client.execute("
-        CREATE TABLE IF NOT EXISTS telemetry (
-            id SERIAL NOT NULL,
-            PRIMARY KEY (id),
-            ts TIMESTAMP WITH TIME ZONE NOT NULL,
-            loop_cnt INTEGER,
-            vbat INTEGER,
-            usb_int_cnt INTEGER,
-            usb_ser_read INTEGER,
-            usb_err_cnt INTEGER,
-            lora_xmit_cnt INTEGER,
-            device_id BYTEA
-        );
-    ", &[])?;
-
-    client.execute("ALTER TABLE telemetry DROP COLUMN IF EXISTS lora_xmit_cnt", &[])?;
-    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS lora_rx_bytes INTEGER", &[])?;
-    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS lora_tx_bytes INTEGER", &[])?;
-    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS corrupt BOOL DEFAULT false", &[])?;
-    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS tip_cnt INTEGER", &[])?;
-    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS temperature REAL", &[])?;
-    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS relative_humidity REAL", &[])?;
-    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS usb_bytes_read INTEGER", &[])?;
-    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS usb_bytes_written INTEGER", &[])?;
-    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS lora_error_cnt INTEGER", &[])?;
-    client.execute("ALTER TABLE telemetry ADD COLUMN IF NOT EXISTS hardware_error_other_cnt INTEGER", &[])?;
-
-    Ok(())
-}
-
-fn write_to_database_reliably(packet:&TelemetryPacket, config:&Config) {
-    loop {
-        match write_to_database(packet, config) {
+fn write_to_database_reliably(received:&Received, ts:DateTime<Utc>, reconstructed:bool, pool:&Pool, dead_letter_file:&str) {
+    for attempt in 0..MAX_ATTEMPTS {
+        let start = Instant::now();
+        match write_to_database(received, ts, reconstructed, pool) {
             Ok(_) => {
+                metrics::record_write_latency(start.elapsed());
+                log_summary();
                 return;
             },
             Err(err) => {
-                error!("Could not write, will retry: {:?}", err);
+                metrics::increment_write_error_cnt();
+                error!("Could not write (attempt {} of {}): {:?}", attempt + 1, MAX_ATTEMPTS, err);
+                if attempt + 1 < MAX_ATTEMPTS {
+                    thread::sleep(backoff(attempt));
+                }
             }
         }
     }
+
+    error!("Giving up on packet after {} attempts, writing to {}", MAX_ATTEMPTS, dead_letter_file);
+    metrics::increment_dead_letter_cnt();
+    if let Err(err) = write_dead_letter(received, dead_letter_file) {
+        error!("Could not write to dead letter file {}, packet lost: {:?} {:?}", dead_letter_file, err, received);
+    }
+}
+
+/// Exponential backoff with full jitter.  The delay is random between zero and BASE_BACKOFF * 2^attempt so that
+/// a burst of failures does not retry in lock step.
+fn backoff(attempt:u32) -> Duration {
+    let ceiling = BASE_BACKOFF.checked_mul(1 << attempt.min(16))
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF);
+
+    let millis = rand::thread_rng().gen_range(0, ceiling.as_millis() as u64 + 1);
+    Duration::from_millis(millis)
 }
 
+// The receive time is kept with the packet so that it is not lost when the file is replayed.
+fn write_dead_letter(received:&Received, dead_letter_file:&str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dead_letter_file)?;
+
+    let line = serde_json::to_string(received)?;
+    writeln!(file, "{}", line)?;
+    file.flush()
+}
+
+fn log_summary() {
+    let write_cnt = metrics::get_write_cnt();
+    if write_cnt % SUMMARY_INTERVAL == 0 {
+        info!("persister: {} writes, mean latency {}us, max latency {}us, {} errors, {} dead letters, queue depth {}",
+            write_cnt,
+            metrics::get_write_latency_micros() / write_cnt,
+            metrics::get_write_latency_max_micros(),
+            metrics::get_write_error_cnt(),
+            metrics::get_dead_letter_cnt(),
+            metrics::get_queue_depth());
+    }
+}
 
-fn write_to_database(packet:&TelemetryPacket, config:&Config) -> Result<(),WriterError> {
-    let mut client = config.connect(NoTls).unwrap();
-    let now = chrono::Utc::now();
-    
-    client.execute("INSERT INTO telemetry (ts, vbat, loop_cnt, lora_rx_bytes, lora_tx_bytes, lora_error_cnt, tip_cnt, temperature, relative_humidity, usb_bytes_read, usb_bytes_written, usb_err_cnt, hardware_error_other_cnt)
-                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)", 
-            &[  &now, 
-                &(packet.vbat as i32),
-                &(packet.loop_cnt as i32),
-                &(packet.lora_rx_bytes as i32),
-                &(packet.lora_tx_bytes as i32),
-                &(packet.lora_error_cnt as i32),
-                &(packet.tip_cnt as i32),
+fn write_to_database(received:&Received, ts:DateTime<Utc>, reconstructed:bool, pool:&Pool) -> Result<(),WriterError> {
+    let mut client = pool.get()?;
+    let packet = &received.packet;
+    // A reading sent again in a history packet does not have these, see Posted.
+    let counter = |value:u32| if received.history { None } else { Some(value as i64) };
+
+    client.execute("INSERT INTO telemetry (ts, vbat, loop_cnt, lora_rx_bytes, lora_tx_bytes, lora_error_cnt, tip_cnt, temperature, relative_humidity, usb_bytes_read, usb_bytes_written, usb_err_cnt, hardware_error_other_cnt, device_id, received_at, ts_reconstructed, uptime_secs, epoch, sequence)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19)
+                ON CONFLICT (device_id, epoch, sequence) DO NOTHING",
+            &[  &ts,
+                &(packet.vbat as i64),
+                &(packet.loop_cnt as i64),
+                &counter(packet.lora_rx_bytes),
+                &counter(packet.lora_tx_bytes),
+                &counter(packet.lora_error_cnt),
+                &(packet.tip_cnt as i64),
                 &(packet.temperature as f32),
                 &(packet.relative_humidity as f32),
-                &(packet.usb_bytes_read as i32),
-                &(packet.usb_bytes_written as i32),
-                &(packet.usb_error_cnt as i32),
-                &(packet.hardware_err_other_cnt as i32),
+                &counter(packet.usb_bytes_read),
+                &counter(packet.usb_bytes_written),
+                &counter(packet.usb_error_cnt),
+                &counter(packet.hardware_err_other_cnt),
+                &(&packet.device_id[..]),
+                &received.received_at,
+                &reconstructed,
+                &packet.uptime_secs.map(|secs| secs as i64),
+                &packet.epoch.map(|epoch| epoch as i64),
+                &packet.sequence.map(|sequence| sequence as i64),
                 ])?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use rainguage_messages::TelemetryPacket;
+
+    use super::*;
+
+    #[test]
+    fn stores_a_reading_once_however_often_it_is_posted() {
+        let pool = match crate::database::test_pool() {
+            Some(pool) => pool,
+            None => return
+        };
+        let mut packet = TelemetryPacket::new();
+        packet.device_id = [43; 16];
+        packet.loop_cnt = 180;
+        let mut client = pool.get().unwrap();
+        client.execute("DELETE FROM telemetry WHERE device_id = $1", &[&(&packet.device_id[..])]).unwrap();
+
+        // Live and then again in a history packet, or from a downlink-processor that restarted before the ack.  Then
+        // the same sequence after the gauge's flash was erased, in a new epoch.
+        let received_at = Utc::now();
+        let posts = [(Some(7), Some(12), 0), (Some(7), Some(12), 60), (Some(8), Some(12), 120), (None, None, 0),
+            (None, None, 60)];
+        for (epoch, sequence, seconds) in posts.iter() {
+            packet.epoch = *epoch;
+            packet.sequence = *sequence;
+            let received = Received { packet: packet.clone(), received_at: received_at + Duration::seconds(*seconds),
+                history: false };
+            write_to_database(&received, received.received_at, false, &pool).unwrap();
+        }
+
+        let count = |epoch:Option<i64>, sequence:Option<i64>| -> i64 {
+            pool.get().unwrap()
+                .query_one("SELECT COUNT(*) FROM telemetry
+                        WHERE device_id = $1 AND epoch IS NOT DISTINCT FROM $2 AND sequence IS NOT DISTINCT FROM $3",
+                    &[&(&packet.device_id[..]), &epoch, &sequence])
+                .unwrap()
+                .get(0)
+        };
+        assert_eq!(1, count(Some(7), Some(12)));
+        assert_eq!(1, count(Some(8), Some(12)));
+        // Gauges without sequences are stored as they come.
+        assert_eq!(2, count(None, None));
+    }
+    #[test]
+    fn history_is_stored_without_counters() {
+        let pool = match crate::database::test_pool() {
+            Some(pool) => pool,
+            None => return
+        };
+        let mut packet = TelemetryPacket::new();
+        packet.device_id = [44; 16];
+        packet.loop_cnt = 180;
+        packet.tip_cnt = 3;
+        let mut client = pool.get().unwrap();
+        client.execute("DELETE FROM telemetry WHERE device_id = $1", &[&(&packet.device_id[..])]).unwrap();
+
+        let received = Received { packet: packet.clone(), received_at: Utc::now(), history: true };
+        write_to_database(&received, received.received_at, false, &pool).unwrap();
+
+        let row = client.query_one("SELECT loop_cnt, tip_cnt, lora_tx_bytes, usb_err_cnt, hardware_error_other_cnt
+                FROM telemetry WHERE device_id = $1", &[&(&packet.device_id[..])]).unwrap();
+        assert_eq!(Some(180), row.get::<_, Option<i64>>(0));
+        assert_eq!(Some(3), row.get::<_, Option<i64>>(1));
+        assert_eq!(None, row.get::<_, Option<i64>>(2));
+        assert_eq!(None, row.get::<_, Option<i64>>(3));
+        assert_eq!(None, row.get::<_, Option<i64>>(4));
+    }
+}
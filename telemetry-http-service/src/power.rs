@@ -0,0 +1,318 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use dotenv::var;
+use rocket::State;
+use rocket_contrib::json::Json;
+use rocket_contrib::json::JsonError;
+use serde_json::json;
+
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+
+use rainguage_messages::PowerPacket;
+
+use crate::auth::IngestKey;
+use crate::auth::ReadKey;
+use crate::database::DatabaseError;
+use crate::database::Pool;
+use crate::device::DeviceId;
+use crate::ingest;
+use crate::ingest::IngestResponse;
+use crate::ingest::ReceivedAtHeader;
+use crate::metrics;
+use crate::query;
+use crate::query::Format;
+use crate::query::Output;
+use crate::query::QueryError;
+
+// Rough figures for a feather m0 with an rfm95 and a 2000mAh cell, see Budget.
+const DEFAULT_AWAKE_MA:f64 = 7.0;
+const DEFAULT_STANDBY_MA:f64 = 0.1;
+const DEFAULT_TX_MA:f64 = 120.0;
+const DEFAULT_BATTERY_MAH:f64 = 2000.0;
+
+/// How much current the gauge draws doing each thing, to turn its power reports into an average current.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Budget {
+    /// The processor running, with the radio asleep.
+    pub awake_ma: f64,
+    /// The processor in standby, with the radio asleep.
+    pub standby_ma: f64,
+    /// What the radio adds while it transmits.
+    pub tx_ma: f64,
+    pub battery_mah: f64
+}
+
+impl Budget {
+    pub fn from_env() -> Budget {
+        let setting = |name:&str, default:f64| var(name).ok()
+            .and_then(|value| value.parse::<f64>().ok())
+            .unwrap_or(default);
+
+        Budget {
+            awake_ma: setting("POWER_AWAKE_MA", DEFAULT_AWAKE_MA),
+            standby_ma: setting("POWER_STANDBY_MA", DEFAULT_STANDBY_MA),
+            tx_ma: setting("POWER_TX_MA", DEFAULT_TX_MA),
+            battery_mah: setting("POWER_BATTERY_MAH", DEFAULT_BATTERY_MAH)
+        }
+    }
+}
+
+/// A power report as stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Report {
+    pub ts: DateTime<Utc>,
+    pub loop_cnt: u32,
+    pub awake_ms: u32,
+    pub standby_ms: u32,
+    pub tx_ms: u32,
+    pub tip_wake_cnt: u32
+}
+
+/// Where the power went between one report and the one before it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Interval {
+    pub ts: DateTime<Utc>,
+    /// The time the gauge accounted for, which should be close to the time between the reports.
+    pub secs: f64,
+    pub awake: f64,
+    pub standby: f64,
+    pub tx: f64,
+    pub tip_wakes: u32,
+    pub average_ma: f64,
+    /// How long a full battery lasts at `average_ma`.
+    pub battery_days: f64
+}
+
+/// The share of the time each report says the gauge spent awake, in standby and transmitting since the report before
+/// it, and what that costs.  The first report after power on covers the time since power on.  The counts wrap, so
+/// only a `loop_cnt` going backwards is taken as a restart.
+pub fn intervals(reports:&[Report], budget:&Budget) -> Vec<Interval> {
+    let mut intervals = vec![];
+    let mut previous:Option<&Report> = None;
+
+    for report in reports {
+        let since = |count:fn(&Report) -> u32| match previous {
+            Some(previous) if report.loop_cnt >= previous.loop_cnt => count(report).wrapping_sub(count(previous)),
+            _ => count(report)
+        };
+
+        let awake_ms = since(|report| report.awake_ms) as f64;
+        let standby_ms = since(|report| report.standby_ms) as f64;
+        let tx_ms = since(|report| report.tx_ms) as f64;
+        let tip_wakes = since(|report| report.tip_wake_cnt);
+        let total_ms = awake_ms + standby_ms;
+        previous = Some(report);
+
+        if total_ms == 0.0 {
+            continue;
+        }
+
+        let awake = awake_ms / total_ms;
+        let standby = standby_ms / total_ms;
+        let tx = tx_ms / total_ms;
+        let average_ma = awake * budget.awake_ma + standby * budget.standby_ma + tx * budget.tx_ma;
+
+        intervals.push(Interval {
+            ts: report.ts,
+            secs: total_ms / 1000.0,
+            awake,
+            standby,
+            tx,
+            tip_wakes,
+            average_ma,
+            battery_days: budget.battery_mah / average_ma / 24.0
+        });
+    }
+
+    intervals
+}
+
+/// Check that a power packet is something the rainguage could have sent.
+pub fn validate(power:&PowerPacket) -> Result<(), String> {
+    if power.device_id == [0; 16] {
+        return Err("device_id is missing".to_string());
+    }
+
+    Ok(())
+}
+
+fn store(pool:&Pool, power:&PowerPacket, received_at:DateTime<Utc>) -> Result<(), DatabaseError> {
+    let mut client = pool.get()?;
+
+    // The same packet sent twice has the same receive time, so it is only stored once.
+    client.execute("
+        INSERT INTO power (device_id, ts, loop_cnt, awake_ms, standby_ms, tx_ms, tip_wake_cnt)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT DO NOTHING",
+        &[&(&power.device_id[..]), &received_at, &(power.loop_cnt as i64), &(power.awake_ms as i64),
+            &(power.standby_ms as i64), &(power.tx_ms as i64), &(power.tip_wake_cnt as i64)])?;
+
+    Ok(())
+}
+
+/// Store a power report.  Like tips these are written straight away, a 503 means they could not be.
+#[post("/power", format = "json", data = "<power>")]
+pub fn post(power:Result<Json<PowerPacket>, JsonError>, header:ReceivedAtHeader, pool:State<Pool>,
+        _key:IngestKey) -> IngestResponse {
+    let power = match power {
+        Ok(power) => power.into_inner(),
+        Err(err) => {
+            metrics::increment_invalid_cnt();
+            return IngestResponse::Invalid(format!("malformed power report: {:?}", err));
+        }
+    };
+
+    if let Err(reason) = validate(&power) {
+        warn!("Rejecting power report {:?}: {}", power, reason);
+        metrics::increment_invalid_cnt();
+        return IngestResponse::Invalid(reason);
+    }
+
+    let received_at = match ingest::received_at(header) {
+        Ok(received_at) => received_at,
+        Err(reason) => {
+            metrics::increment_invalid_cnt();
+            return IngestResponse::Invalid(reason);
+        }
+    };
+
+    match store(&pool, &power, received_at) {
+        Ok(_) => IngestResponse::Stored,
+        Err(err) => {
+            error!("Could not store power report {:?}: {:?}", power, err);
+            IngestResponse::Unavailable
+        }
+    }
+}
+
+fn load_reports(pool:&Pool, device_id:&DeviceId, from:DateTime<Utc>, to:DateTime<Utc>) -> Result<Vec<Report>, QueryError> {
+    let mut client = pool.get()?;
+
+    // The report before `from` is needed for the first interval.
+    let rows = client.query("
+        (SELECT ts, loop_cnt, awake_ms, standby_ms, tx_ms, tip_wake_cnt FROM power
+        WHERE device_id = $1 AND ts < $2 ORDER BY ts DESC LIMIT 1)
+        UNION ALL
+        (SELECT ts, loop_cnt, awake_ms, standby_ms, tx_ms, tip_wake_cnt FROM power
+        WHERE device_id = $1 AND ts >= $2 AND ts < $3)
+        ORDER BY ts", &[&device_id.as_bytes(), &from, &to])?;
+
+    Ok(rows.iter()
+        .map(|row| Report {
+            ts: row.get(0),
+            loop_cnt: row.get::<_, i64>(1) as u32,
+            awake_ms: row.get::<_, i64>(2) as u32,
+            standby_ms: row.get::<_, i64>(3) as u32,
+            tx_ms: row.get::<_, i64>(4) as u32,
+            tip_wake_cnt: row.get::<_, i64>(5) as u32
+        })
+        .collect())
+}
+
+/// Where the power went between each of the gauge's power reports, and how long the battery would last at that rate.
+/// Defaults to the last week.
+#[get("/devices/<device_id>/power?<from>&<to>&<format>")]
+pub fn power(device_id:DeviceId, from:Option<String>, to:Option<String>, format:Option<String>,
+        pool:State<Pool>, _key:ReadKey) -> Result<Output, QueryError> {
+    let format = Format::parse(format)?;
+    let to = query::parse_time("to", to)?.unwrap_or_else(Utc::now);
+    let from = query::parse_time("from", from)?.unwrap_or_else(|| to - Duration::days(7));
+
+    let reports = load_reports(&pool, &device_id, from, to)?;
+    let intervals = intervals(&reports, &Budget::from_env()).into_iter()
+        .filter(|interval| interval.ts >= from)
+        .collect::<Vec<Interval>>();
+
+    match format {
+        Format::Json => {
+            let intervals = intervals.iter()
+                .map(|interval| json!({
+                    "ts": interval.ts.to_rfc3339(),
+                    "secs": interval.secs,
+                    "awake": interval.awake,
+                    "standby": interval.standby,
+                    "tx": interval.tx,
+                    "tip_wakes": interval.tip_wakes,
+                    "average_ma": interval.average_ma,
+                    "battery_days": interval.battery_days
+                }))
+                .collect();
+            Ok(Output::Json(Json(serde_json::Value::Array(intervals))))
+        },
+        Format::Csv => {
+            let records = intervals.iter()
+                .map(|interval| vec![
+                    interval.ts.to_rfc3339(),
+                    interval.secs.to_string(),
+                    interval.awake.to_string(),
+                    interval.standby.to_string(),
+                    interval.tx.to_string(),
+                    interval.tip_wakes.to_string(),
+                    interval.average_ma.to_string(),
+                    interval.battery_days.to_string()
+                ])
+                .collect();
+            let header = ["ts", "secs", "awake", "standby", "tx", "tip_wakes", "average_ma", "battery_days"];
+            Ok(Output::Csv(Content(ContentType::CSV, query::csv(&header, records))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn report(hour:i64, loop_cnt:u32, awake_ms:u32, standby_ms:u32, tx_ms:u32) -> Report {
+        Report {
+            ts: Utc.ymd(2020, 9, 1).and_hms(0, 0, 0) + Duration::hours(hour),
+            loop_cnt,
+            awake_ms,
+            standby_ms,
+            tx_ms,
+            tip_wake_cnt: hour as u32
+        }
+    }
+
+    fn budget() -> Budget {
+        Budget { awake_ma: 10.0, standby_ma: 0.0, tx_ma: 100.0, battery_mah: 240.0 }
+    }
+
+    #[test]
+    fn shares_since_the_last_report() {
+        let intervals = intervals(&[
+            report(0, 100, 1_000, 9_000, 100),
+            report(1, 200, 2_000, 18_000, 150)
+        ], &budget());
+
+        assert_eq!(2, intervals.len());
+        assert_eq!(10.0, intervals[0].secs);
+        assert!((intervals[0].awake - 0.1).abs() < 1e-9);
+        assert!((intervals[0].tx - 0.01).abs() < 1e-9);
+        assert!((intervals[0].average_ma - 2.0).abs() < 1e-9);
+        assert!((intervals[0].battery_days - 5.0).abs() < 1e-9);
+
+        assert!((intervals[1].standby - 0.9).abs() < 1e-9);
+        assert!((intervals[1].tx - 0.005).abs() < 1e-9);
+        assert_eq!(1, intervals[1].tip_wakes);
+    }
+
+    #[test]
+    fn restarts_and_wraps() {
+        let intervals = intervals(&[
+            report(0, 100, u32::MAX - 499, 0, 0),
+            report(1, 200, 500, 9_000, 0),
+            report(2, 10, 100, 900, 0),
+            report(3, 10, 100, 900, 0)
+        ], &budget());
+
+        // Nothing happened between the last two.
+        assert_eq!(3, intervals.len());
+        assert_eq!(10.0, intervals[1].secs);
+        assert!((intervals[1].awake - 0.1).abs() < 1e-9);
+        assert_eq!(1.0, intervals[2].secs);
+    }
+}
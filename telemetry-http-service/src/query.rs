@@ -0,0 +1,471 @@
+use std::io::Cursor;
+
+use chrono::DateTime;
+use chrono::TimeZone;
+use chrono::Utc;
+use postgres::types::ToSql;
+use postgres::Row;
+use rocket::http::ContentType;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response;
+use rocket::response::content::Content;
+use rocket::response::Responder;
+use rocket::response::Response;
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde_json::json;
+use serde_json::Map;
+
+use crate::auth::ReadKey;
+use crate::database::DatabaseError;
+use crate::database::Pool;
+use crate::device::DeviceId;
+use crate::rollup;
+use crate::rollup::Resolution;
+use crate::rollup::Retention;
+
+const DEFAULT_LIMIT:i64 = 1000;
+const MAX_LIMIT:i64 = 10000;
+
+#[derive(Debug)]
+pub enum QueryError {
+    BadRequest(String),
+    NotFound,
+    DatabaseError(DatabaseError)
+}
+
+impl From<postgres::Error> for QueryError {
+    fn from(err: postgres::Error) -> Self {
+        QueryError::DatabaseError(DatabaseError::PostgresError(err))
+    }
+}
+
+impl From<r2d2::Error> for QueryError {
+    fn from(err: r2d2::Error) -> Self {
+        QueryError::DatabaseError(DatabaseError::PoolError(err))
+    }
+}
+
+impl From<DatabaseError> for QueryError {
+    fn from(err: DatabaseError) -> Self {
+        QueryError::DatabaseError(err)
+    }
+}
+
+impl<'r> Responder<'r> for QueryError {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        match self {
+            QueryError::BadRequest(reason) => {
+                Response::build()
+                    .status(Status::BadRequest)
+                    .header(ContentType::Plain)
+                    .sized_body(Cursor::new(reason))
+                    .ok()
+            },
+            QueryError::NotFound => Err(Status::NotFound),
+            QueryError::DatabaseError(err) => {
+                error!("Query failed: {:?}", err);
+                Err(Status::InternalServerError)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv
+}
+
+impl Format {
+    pub fn parse(format:Option<String>) -> Result<Format, QueryError> {
+        match format.as_ref().map(|f| f.as_str()) {
+            None | Some("json") => Ok(Format::Json),
+            Some("csv") => Ok(Format::Csv),
+            Some(other) => Err(QueryError::BadRequest(format!("unknown format {}, expected json or csv", other)))
+        }
+    }
+}
+
+#[derive(Responder)]
+pub enum Output {
+    Json(Json<serde_json::Value>),
+    Csv(Content<String>)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Timestamp,
+    Integer,
+    Real
+}
+
+/// A column that can be requested through `fields`.  Only these names ever make it into sql.
+struct Field {
+    name: &'static str,
+    kind: Kind
+}
+
+const FIELDS:[Field; 14] = [
+    Field { name: "ts", kind: Kind::Timestamp },
+    Field { name: "loop_cnt", kind: Kind::Integer },
+    Field { name: "tip_cnt", kind: Kind::Integer },
+    Field { name: "vbat", kind: Kind::Integer },
+    Field { name: "temperature", kind: Kind::Real },
+    Field { name: "relative_humidity", kind: Kind::Real },
+    Field { name: "usb_bytes_read", kind: Kind::Integer },
+    Field { name: "usb_bytes_written", kind: Kind::Integer },
+    Field { name: "usb_err_cnt", kind: Kind::Integer },
+    Field { name: "lora_rx_bytes", kind: Kind::Integer },
+    Field { name: "lora_tx_bytes", kind: Kind::Integer },
+    Field { name: "lora_error_cnt", kind: Kind::Integer },
+    Field { name: "hardware_error_other_cnt", kind: Kind::Integer },
+    Field { name: "uptime_secs", kind: Kind::Integer },
+];
+
+// The columns of the rollup tables, see rollup::run.  temperature, relative_humidity and vbat are the means over
+// each bucket.
+const ROLLUP_FIELDS:[Field; 11] = [
+    Field { name: "ts", kind: Kind::Timestamp },
+    Field { name: "packets", kind: Kind::Integer },
+    Field { name: "temperature", kind: Kind::Real },
+    Field { name: "temperature_min", kind: Kind::Real },
+    Field { name: "temperature_max", kind: Kind::Real },
+    Field { name: "relative_humidity", kind: Kind::Real },
+    Field { name: "relative_humidity_min", kind: Kind::Real },
+    Field { name: "relative_humidity_max", kind: Kind::Real },
+    Field { name: "vbat", kind: Kind::Real },
+    Field { name: "vbat_min", kind: Kind::Real },
+    Field { name: "vbat_max", kind: Kind::Real },
+];
+
+fn field(name:&str) -> Option<&'static Field> {
+    FIELDS.iter().find(|field| field.name == name)
+}
+
+/// Parse the `fields` parameter.  `ts` is always returned first whether it was asked for or not.
+fn parse_fields(fields:Option<String>) -> Result<Vec<&'static Field>, QueryError> {
+    let fields = match fields {
+        Some(fields) => fields,
+        None => {
+            return Ok(FIELDS.iter().collect());
+        }
+    };
+
+    let mut result = vec![&FIELDS[0]];
+    for name in fields.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+        match field(name) {
+            Some(field) => {
+                if !result.iter().any(|f| f.name == field.name) {
+                    result.push(field);
+                }
+            },
+            None => {
+                return Err(QueryError::BadRequest(format!("unknown field {}", name)));
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// The rollup columns for the fields that were asked for.  None unless every field is a measurement that is rolled
+/// up, the counters and packet times are only kept raw.
+fn rollup_fields(fields:&[&'static Field]) -> Option<Vec<&'static Field>> {
+    let mut result = vec![&ROLLUP_FIELDS[0], &ROLLUP_FIELDS[1]];
+
+    for field in fields.iter().filter(|field| field.name != "ts") {
+        let names = [field.name.to_string(), format!("{}_min", field.name), format!("{}_max", field.name)];
+        for name in names.iter() {
+            result.push(ROLLUP_FIELDS.iter().find(|rollup| rollup.name == name)?);
+        }
+    }
+
+    if result.len() > 2 {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+pub fn parse_time(name:&str, value:Option<String>) -> Result<Option<DateTime<Utc>>, QueryError> {
+    match value {
+        Some(value) => {
+            DateTime::parse_from_rfc3339(&value)
+                .map(|ts| Some(ts.with_timezone(&Utc)))
+                .map_err(|err| QueryError::BadRequest(format!("{} is not an rfc3339 timestamp: {}", name, err)))
+        },
+        None => Ok(None)
+    }
+}
+
+/// The `limit` and `offset` to page through a query with.
+fn page(limit:Option<i64>, offset:Option<i64>) -> Result<(i64, i64), QueryError> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT);
+    let offset = offset.unwrap_or(0);
+
+    if limit < 1 || limit > MAX_LIMIT {
+        return Err(QueryError::BadRequest(format!("limit must be between 1 and {}", MAX_LIMIT)));
+    }
+
+    if offset < 0 {
+        return Err(QueryError::BadRequest("offset must not be negative".to_string()));
+    }
+
+    Ok((limit, offset))
+}
+
+fn select_list(fields:&[&'static Field]) -> String {
+    fields.iter()
+        .map(|field| field.name)
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+fn to_json(field:&Field, row:&Row, i:usize) -> serde_json::Value {
+    match field.kind {
+        Kind::Timestamp => json!(row.get::<_, DateTime<Utc>>(i).to_rfc3339()),
+        Kind::Integer => json!(row.get::<_, Option<i64>>(i)),
+        Kind::Real => json!(row.get::<_, Option<f32>>(i))
+    }
+}
+
+fn to_csv(field:&Field, row:&Row, i:usize) -> String {
+    match field.kind {
+        Kind::Timestamp => row.get::<_, DateTime<Utc>>(i).to_rfc3339(),
+        Kind::Integer => row.get::<_, Option<i64>>(i).map(|v| v.to_string()).unwrap_or_default(),
+        Kind::Real => row.get::<_, Option<f32>>(i).map(|v| v.to_string()).unwrap_or_default()
+    }
+}
+
+pub fn csv(header:&[&str], records:Vec<Vec<String>>) -> String {
+    let mut writer = csv::Writer::from_writer(vec![]);
+
+    // Writing to memory can only fail if the records are not the same length as the header.
+    writer.write_record(header).unwrap();
+    for record in records {
+        writer.write_record(&record).unwrap();
+    }
+
+    String::from_utf8(writer.into_inner().unwrap()).unwrap()
+}
+
+fn render(fields:&[&'static Field], rows:&[Row], format:Format) -> Result<Output, QueryError> {
+    match format {
+        Format::Json => {
+            let objects = rows.iter()
+                .map(|row| {
+                    let mut object = Map::new();
+                    for (i, field) in fields.iter().enumerate() {
+                        object.insert(field.name.to_string(), to_json(field, row, i));
+                    }
+                    serde_json::Value::Object(object)
+                })
+                .collect::<Vec<serde_json::Value>>();
+
+            Ok(Output::Json(Json(serde_json::Value::Array(objects))))
+        },
+        Format::Csv => {
+            let header = fields.iter().map(|field| field.name).collect::<Vec<&str>>();
+            let records = rows.iter()
+                .map(|row| fields.iter().enumerate().map(|(i, field)| to_csv(field, row, i)).collect())
+                .collect();
+
+            Ok(Output::Csv(Content(ContentType::CSV, csv(&header, records))))
+        }
+    }
+}
+
+#[get("/devices?<format>")]
+pub fn devices(format:Option<String>, pool:State<Pool>, _key:ReadKey) -> Result<Output, QueryError> {
+    let format = Format::parse(format)?;
+    let mut client = pool.get()?;
+
+    let rows = client.query("
+        SELECT device_id, MIN(ts), MAX(ts), COUNT(*)
+        FROM telemetry
+        WHERE device_id IS NOT NULL
+        GROUP BY device_id
+        ORDER BY device_id", &[])?;
+
+    let devices = rows.iter()
+        .filter_map(|row| {
+            DeviceId::from_slice(row.get::<_, &[u8]>(0)).map(|device_id| {
+                (device_id, row.get::<_, DateTime<Utc>>(1), row.get::<_, DateTime<Utc>>(2), row.get::<_, i64>(3))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    match format {
+        Format::Json => {
+            let devices = devices.iter()
+                .map(|(device_id, first_seen, last_seen, packet_cnt)| json!({
+                    "device_id": device_id,
+                    "first_seen": first_seen.to_rfc3339(),
+                    "last_seen": last_seen.to_rfc3339(),
+                    "packet_cnt": packet_cnt
+                }))
+                .collect();
+
+            Ok(Output::Json(Json(serde_json::Value::Array(devices))))
+        },
+        Format::Csv => {
+            let records = devices.iter()
+                .map(|(device_id, first_seen, last_seen, packet_cnt)| vec![
+                    device_id.to_string(),
+                    first_seen.to_rfc3339(),
+                    last_seen.to_rfc3339(),
+                    packet_cnt.to_string()
+                ])
+                .collect();
+
+            Ok(Output::Csv(Content(ContentType::CSV, csv(&["device_id", "first_seen", "last_seen", "packet_cnt"], records))))
+        }
+    }
+}
+
+/// Telemetry for a device, oldest first.  `from` is inclusive and `to` is exclusive.  Page through large ranges
+/// with `limit` and `offset`.  When only measurements are asked for, long or old ranges come from the rollups.
+#[get("/devices/<device_id>/telemetry?<from>&<to>&<fields>&<limit>&<offset>&<format>&<resolution>")]
+pub fn telemetry(device_id:DeviceId,
+        from:Option<String>,
+        to:Option<String>,
+        fields:Option<String>,
+        limit:Option<i64>,
+        offset:Option<i64>,
+        format:Option<String>,
+        resolution:Option<String>,
+        pool:State<Pool>, _key:ReadKey) -> Result<Output, QueryError> {
+    let format = Format::parse(format)?;
+    let fields = parse_fields(fields)?;
+    let resolution = Resolution::parse(resolution)?;
+    let from = parse_time("from", from)?.unwrap_or_else(|| Utc.timestamp(0, 0));
+    let to = parse_time("to", to)?.unwrap_or_else(|| Utc.ymd(9999, 1, 1).and_hms(0, 0, 0));
+    let (limit, offset) = page(limit, offset)?;
+
+    let rollup_fields = rollup_fields(&fields);
+    let resolution = match (resolution, &rollup_fields) {
+        (Some(Resolution::Raw), _) | (None, None) => Resolution::Raw,
+        (Some(resolution), Some(_)) => resolution,
+        (Some(_), None) => {
+            return Err(QueryError::BadRequest("only temperature, relative_humidity and vbat are rolled up".to_string()));
+        },
+        (None, Some(_)) => rollup::choose(from, to, Utc::now(), &Retention::from_env())
+    };
+    let fields = match resolution {
+        Resolution::Raw => fields,
+        _ => rollup_fields.unwrap_or(fields)
+    };
+
+    let sql = format!("
+        SELECT {}
+        FROM {}
+        WHERE device_id = $1 AND ts >= $2 AND ts < $3
+        ORDER BY ts
+        LIMIT $4 OFFSET $5", select_list(&fields), resolution.table());
+
+    let device_id = device_id.as_bytes();
+    let params:[&(dyn ToSql + Sync); 5] = [&device_id, &from, &to, &limit, &offset];
+
+    let mut client = pool.get()?;
+    let rows = client.query(sql.as_str(), &params)?;
+
+    render(&fields, &rows, format)
+}
+
+#[get("/devices/<device_id>/latest?<fields>&<format>")]
+pub fn latest(device_id:DeviceId, fields:Option<String>, format:Option<String>, pool:State<Pool>,
+        _key:ReadKey) -> Result<Output, QueryError> {
+    let format = Format::parse(format)?;
+    let fields = parse_fields(fields)?;
+
+    let sql = format!("
+        SELECT {}
+        FROM telemetry
+        WHERE device_id = $1
+        ORDER BY ts DESC
+        LIMIT 1", select_list(&fields));
+
+    let mut client = pool.get()?;
+    let rows = client.query(sql.as_str(), &[&device_id.as_bytes()])?;
+
+    if rows.is_empty() {
+        return Err(QueryError::NotFound);
+    }
+
+    match render(&fields, &rows, format)? {
+        Output::Json(Json(serde_json::Value::Array(mut objects))) => Ok(Output::Json(Json(objects.remove(0)))),
+        output => Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(fields:&[&'static Field]) -> Vec<&'static str> {
+        fields.iter().map(|field| field.name).collect()
+    }
+
+    fn bad_request<T>(result:Result<T, QueryError>) -> String {
+        match result {
+            Err(QueryError::BadRequest(reason)) => reason,
+            Err(err) => panic!("expected a bad request, not {:?}", err),
+            Ok(_) => panic!("expected a bad request")
+        }
+    }
+
+    #[test]
+    fn fields_start_with_ts() {
+        assert_eq!(FIELDS.len(), parse_fields(None).unwrap().len());
+        assert_eq!(vec!["ts", "vbat", "tip_cnt"], names(&parse_fields(Some("vbat, tip_cnt".to_string())).unwrap()));
+        // Asking for ts, or a field twice, does not repeat it, and empty names are skipped.
+        assert_eq!(vec!["ts", "vbat"], names(&parse_fields(Some("vbat,ts,,vbat,".to_string())).unwrap()));
+        assert_eq!(vec!["ts"], names(&parse_fields(Some("".to_string())).unwrap()));
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert_eq!("unknown field device_id", bad_request(parse_fields(Some("vbat,device_id".to_string()))));
+        // Nothing that is not a column makes it into the sql.
+        assert_eq!("unknown field vbat; DROP TABLE telemetry",
+            bad_request(parse_fields(Some("vbat; DROP TABLE telemetry".to_string()))));
+    }
+
+    #[test]
+    fn only_measurements_are_rolled_up() {
+        let fields = parse_fields(Some("temperature".to_string())).unwrap();
+        assert_eq!(Some(vec!["ts", "packets", "temperature", "temperature_min", "temperature_max"]),
+            rollup_fields(&fields).map(|fields| names(&fields)));
+
+        assert!(rollup_fields(&parse_fields(Some("temperature,tip_cnt".to_string())).unwrap()).is_none());
+        assert!(rollup_fields(&parse_fields(Some("ts".to_string())).unwrap()).is_none());
+    }
+
+    #[test]
+    fn times_are_rfc3339() {
+        assert_eq!(None, parse_time("from", None).unwrap());
+        assert_eq!(Some(Utc.ymd(2020, 10, 1).and_hms(12, 0, 0)),
+            parse_time("from", Some("2020-10-01T14:00:00+02:00".to_string())).unwrap());
+        assert!(bad_request(parse_time("to", Some("2020-10-01".to_string()))).starts_with("to is not an rfc3339"));
+    }
+
+    #[test]
+    fn pages_are_bounded() {
+        assert_eq!((DEFAULT_LIMIT, 0), page(None, None).unwrap());
+        assert_eq!((1, 20), page(Some(1), Some(20)).unwrap());
+        assert_eq!((MAX_LIMIT, 0), page(Some(MAX_LIMIT), None).unwrap());
+
+        assert_eq!("limit must be between 1 and 10000", bad_request(page(Some(0), None)));
+        assert_eq!("limit must be between 1 and 10000", bad_request(page(Some(MAX_LIMIT + 1), None)));
+        assert_eq!("offset must not be negative", bad_request(page(None, Some(-1))));
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(Format::Json, Format::parse(None).unwrap());
+        assert_eq!(Format::Csv, Format::parse(Some("csv".to_string())).unwrap());
+        assert_eq!("unknown format xml, expected json or csv", bad_request(Format::parse(Some("xml".to_string()))));
+    }
+}
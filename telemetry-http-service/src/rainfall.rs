@@ -0,0 +1,488 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Timelike;
+use chrono::Utc;
+use dotenv::var;
+use postgres::GenericClient;
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+
+use crate::auth::AdminKey;
+use crate::auth::ReadKey;
+use crate::counters;
+use crate::counters::Sample;
+use crate::database::DatabaseError;
+use crate::database::Pool;
+use crate::device::DeviceId;
+use crate::query;
+use crate::query::Format;
+use crate::query::Output;
+use crate::query::QueryError;
+use crate::rollup::Resolution;
+use crate::rollup::Retention;
+
+// Used when a device has not been calibrated.  Calibrate a gauge by slowly pouring a known volume through it, with
+// the 100mm funnel from parts/rainmeter.scad 100ml of water is 12.7mm of rain.
+const DEFAULT_MM_PER_TIP:f64 = 0.2;
+
+// Storms are separated by at least this long without a tip.
+const DEFAULT_STORM_GAP_MINUTES:i64 = 6 * 60;
+
+const DEFAULT_WINDOW_MINUTES:i64 = 60;
+
+/// The rain that fell between two consecutive readings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Increment {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub depth_mm: f64
+}
+
+impl Increment {
+    /// The average intensity over the increment in mm/h.
+    pub fn intensity(&self) -> f64 {
+        let seconds = (self.end - self.start).num_milliseconds() as f64 / 1000.0;
+        if seconds <= 0.0 {
+            0.0
+        } else {
+            self.depth_mm * 3600.0 / seconds
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interval {
+    Hour,
+    Day
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Total {
+    pub start: DateTime<Utc>,
+    pub depth_mm: f64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Storm {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub depth_mm: f64,
+    pub peak_intensity: f64
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Calibration {
+    pub mm_per_tip: f64
+}
+
+/// Turn tip counts, oldest first, into the depth that fell between each pair of readings.
+pub fn increments(samples:&[Sample], mm_per_tip:f64) -> Vec<Increment> {
+    counters::deltas(samples).iter()
+        .map(|delta| Increment {
+            start: delta.start,
+            end: delta.end,
+            depth_mm: delta.values[0].unwrap_or(0) as f64 * mm_per_tip
+        })
+        .collect()
+}
+
+fn bucket(ts:DateTime<Utc>, interval:Interval) -> DateTime<Utc> {
+    match interval {
+        Interval::Hour => ts.date().and_hms(ts.hour(), 0, 0),
+        Interval::Day => ts.date().and_hms(0, 0, 0)
+    }
+}
+
+/// Total rain per hour or day.  An increment is counted in the bucket of the reading that reported it.
+pub fn totals(increments:&[Increment], interval:Interval) -> Vec<Total> {
+    let mut totals:Vec<Total> = vec![];
+
+    for increment in increments {
+        let start = bucket(increment.end, interval);
+        match totals.last_mut() {
+            Some(total) if total.start == start => {
+                total.depth_mm += increment.depth_mm;
+            },
+            _ => {
+                totals.push(Total { start, depth_mm: increment.depth_mm });
+            }
+        }
+    }
+
+    totals
+}
+
+/// The rain in the `window` ending at each reading.
+pub fn rolling(increments:&[Increment], window:Duration) -> Vec<Total> {
+    let mut result = Vec::with_capacity(increments.len());
+    let mut first = 0;
+    let mut depth_mm = 0.0;
+
+    for increment in increments {
+        depth_mm += increment.depth_mm;
+        while increments[first].end <= increment.end - window {
+            depth_mm -= increments[first].depth_mm;
+            first += 1;
+        }
+
+        result.push(Total { start: increment.end, depth_mm });
+    }
+
+    result
+}
+
+/// The rain in each minute from the tip times, oldest first.  Only the minutes with a tip are included.
+pub fn minutes(tip_times:&[DateTime<Utc>], mm_per_tip:f64) -> Vec<Increment> {
+    let mut minutes:Vec<Increment> = vec![];
+
+    for ts in tip_times {
+        let start = ts.date().and_hms(ts.hour(), ts.minute(), 0);
+        match minutes.last_mut() {
+            Some(minute) if minute.start == start => {
+                minute.depth_mm += mm_per_tip;
+            },
+            _ => {
+                minutes.push(Increment { start, end: start + Duration::minutes(1), depth_mm: mm_per_tip });
+            }
+        }
+    }
+
+    minutes
+}
+
+/// Group wet increments into storms.  A storm ends once `gap` passes without any rain.
+pub fn storms(increments:&[Increment], gap:Duration) -> Vec<Storm> {
+    let mut storms:Vec<Storm> = vec![];
+
+    for increment in increments.iter().filter(|increment| increment.depth_mm > 0.0) {
+        match storms.last_mut() {
+            Some(storm) if increment.end - storm.end <= gap => {
+                storm.end = increment.end;
+                storm.depth_mm += increment.depth_mm;
+                storm.peak_intensity = storm.peak_intensity.max(increment.intensity());
+            },
+            _ => {
+                storms.push(Storm {
+                    start: increment.start,
+                    end: increment.end,
+                    depth_mm: increment.depth_mm,
+                    peak_intensity: increment.intensity()
+                });
+            }
+        }
+    }
+
+    storms
+}
+
+pub fn mm_per_tip(pool:&Pool, device_id:&DeviceId) -> Result<f64, DatabaseError> {
+    mm_per_tip_in(&mut *pool.get()?, device_id)
+}
+
+/// The same as `mm_per_tip`, read through a connection or transaction that is already open.
+pub fn mm_per_tip_in(client:&mut impl GenericClient, device_id:&DeviceId) -> Result<f64, DatabaseError> {
+    let rows = client.query("SELECT mm_per_tip FROM calibration WHERE device_id = $1", &[&device_id.as_bytes()])?;
+
+    match rows.first().and_then(|row| row.get::<_, Option<f64>>(0)) {
+        Some(mm_per_tip) => Ok(mm_per_tip),
+        None => Ok(var("RAINFALL_MM_PER_TIP").ok()
+            .and_then(|mm| mm.parse::<f64>().ok())
+            .unwrap_or(DEFAULT_MM_PER_TIP))
+    }
+}
+
+/// Rainfall queries default to the last week.
+fn time_range(from:Option<String>, to:Option<String>) -> Result<(DateTime<Utc>, DateTime<Utc>), QueryError> {
+    let to = query::parse_time("to", to)?.unwrap_or_else(Utc::now);
+    let from = query::parse_time("from", from)?.unwrap_or_else(|| to - Duration::days(7));
+    Ok((from, to))
+}
+
+fn load_increments(pool:&Pool, device_id:&DeviceId, from:DateTime<Utc>, to:DateTime<Utc>) -> Result<Vec<Increment>, QueryError> {
+    let samples = counters::load_samples(pool, device_id, &["tip_cnt"], from, to)?;
+    Ok(increments(&samples, mm_per_tip(pool, device_id)?))
+}
+
+fn load_tip_times(pool:&Pool, device_id:&DeviceId, from:DateTime<Utc>,
+        to:DateTime<Utc>) -> Result<Vec<DateTime<Utc>>, QueryError> {
+    let mut client = pool.get()?;
+    let rows = client.query("
+        SELECT ts FROM tip
+        WHERE device_id = $1 AND ts >= $2 AND ts < $3
+        ORDER BY ts", &[&device_id.as_bytes(), &from, &to])?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Hourly or daily totals from the rollups, worked out with the calibration at the time they were rolled up.
+fn rollup_totals(pool:&Pool, device_id:&DeviceId, from:DateTime<Utc>, to:DateTime<Utc>,
+        interval:Interval) -> Result<Vec<Total>, QueryError> {
+    let resolution = match interval {
+        Interval::Hour => Resolution::Hour,
+        Interval::Day => Resolution::Day
+    };
+
+    let mut client = pool.get()?;
+    let rows = client.query(format!("
+        SELECT ts, rainfall_mm
+        FROM {}
+        WHERE device_id = $1 AND ts >= $2 AND ts < $3
+        ORDER BY ts", resolution.table()).as_str(), &[&device_id.as_bytes(), &resolution.bucket(from), &to])?;
+
+    Ok(rows.iter().map(|row| Total { start: row.get(0), depth_mm: row.get(1) }).collect())
+}
+
+fn render_totals(totals:&[Total], format:Format) -> Output {
+    match format {
+        Format::Json => {
+            let totals = totals.iter()
+                .map(|total| json!({ "ts": total.start.to_rfc3339(), "depth_mm": total.depth_mm }))
+                .collect();
+            Output::Json(Json(serde_json::Value::Array(totals)))
+        },
+        Format::Csv => {
+            let records = totals.iter()
+                .map(|total| vec![total.start.to_rfc3339(), total.depth_mm.to_string()])
+                .collect();
+            Output::Csv(Content(ContentType::CSV, query::csv(&["ts", "depth_mm"], records)))
+        }
+    }
+}
+
+/// Hourly or daily rainfall totals.  Defaults to the last week.
+#[get("/devices/<device_id>/rainfall?<from>&<to>&<interval>&<format>")]
+pub fn rainfall(device_id:DeviceId, from:Option<String>, to:Option<String>, interval:Option<String>, format:Option<String>,
+        pool:State<Pool>, _key:ReadKey) -> Result<Output, QueryError> {
+    let format = Format::parse(format)?;
+    let interval = match interval.as_ref().map(|i| i.as_str()) {
+        None | Some("hour") => Interval::Hour,
+        Some("day") => Interval::Day,
+        Some(other) => {
+            return Err(QueryError::BadRequest(format!("unknown interval {}, expected hour or day", other)));
+        }
+    };
+
+    let (from, to) = time_range(from, to)?;
+
+    // Once the raw telemetry for the range has been deleted the totals come from the rollups instead.
+    let raw_cutoff = Retention::from_env().cutoff(Resolution::Raw, Utc::now());
+    if raw_cutoff.map(|cutoff| from < cutoff).unwrap_or(false) {
+        return Ok(render_totals(&rollup_totals(&pool, &device_id, from, to, interval)?, format));
+    }
+
+    let increments = load_increments(&pool, &device_id, from, to)?;
+    Ok(render_totals(&totals(&increments, interval), format))
+}
+
+/// Rainfall in the `window` minutes ending at each reading.
+#[get("/devices/<device_id>/rainfall/rolling?<from>&<to>&<window>&<format>")]
+pub fn rainfall_rolling(device_id:DeviceId, from:Option<String>, to:Option<String>, window:Option<i64>, format:Option<String>,
+        pool:State<Pool>, _key:ReadKey) -> Result<Output, QueryError> {
+    let format = Format::parse(format)?;
+    let window = window.unwrap_or(DEFAULT_WINDOW_MINUTES);
+    if window < 1 {
+        return Err(QueryError::BadRequest("window must be at least one minute".to_string()));
+    }
+
+    let (from, to) = time_range(from, to)?;
+    let increments = load_increments(&pool, &device_id, from, to)?;
+    Ok(render_totals(&rolling(&increments, Duration::minutes(window)), format))
+}
+
+fn render_increments(increments:&[Increment], format:Format) -> Output {
+    match format {
+        Format::Json => {
+            let series = increments.iter()
+                .map(|increment| json!({
+                    "start": increment.start.to_rfc3339(),
+                    "end": increment.end.to_rfc3339(),
+                    "depth_mm": increment.depth_mm,
+                    "intensity_mm_h": increment.intensity()
+                }))
+                .collect();
+            Output::Json(Json(serde_json::Value::Array(series)))
+        },
+        Format::Csv => {
+            let records = increments.iter()
+                .map(|increment| vec![
+                    increment.start.to_rfc3339(),
+                    increment.end.to_rfc3339(),
+                    increment.depth_mm.to_string(),
+                    increment.intensity().to_string()
+                ])
+                .collect();
+            Output::Csv(Content(ContentType::CSV, query::csv(&["start", "end", "depth_mm", "intensity_mm_h"], records)))
+        }
+    }
+}
+
+/// Rain intensity in mm/h between each pair of readings.
+#[get("/devices/<device_id>/rainfall/intensity?<from>&<to>&<format>")]
+pub fn rainfall_intensity(device_id:DeviceId, from:Option<String>, to:Option<String>, format:Option<String>,
+        pool:State<Pool>, _key:ReadKey) -> Result<Output, QueryError> {
+    let format = Format::parse(format)?;
+    let (from, to) = time_range(from, to)?;
+    let increments = load_increments(&pool, &device_id, from, to)?;
+    Ok(render_increments(&increments, format))
+}
+
+/// Rain and its intensity in mm/h for each minute, from the time of each tip.
+#[get("/devices/<device_id>/rainfall/minutes?<from>&<to>&<format>")]
+pub fn rainfall_minutes(device_id:DeviceId, from:Option<String>, to:Option<String>, format:Option<String>,
+        pool:State<Pool>, _key:ReadKey) -> Result<Output, QueryError> {
+    let format = Format::parse(format)?;
+    let (from, to) = time_range(from, to)?;
+    let tip_times = load_tip_times(&pool, &device_id, from, to)?;
+    Ok(render_increments(&minutes(&tip_times, mm_per_tip(&pool, &device_id)?), format))
+}
+
+/// Storm events, separated by `gap` minutes without rain (6 hours by default).
+#[get("/devices/<device_id>/rainfall/storms?<from>&<to>&<gap>&<format>")]
+pub fn rainfall_storms(device_id:DeviceId, from:Option<String>, to:Option<String>, gap:Option<i64>, format:Option<String>,
+        pool:State<Pool>, _key:ReadKey) -> Result<Output, QueryError> {
+    let format = Format::parse(format)?;
+    let gap = gap.unwrap_or(DEFAULT_STORM_GAP_MINUTES);
+    if gap < 1 {
+        return Err(QueryError::BadRequest("gap must be at least one minute".to_string()));
+    }
+
+    let (from, to) = time_range(from, to)?;
+    let increments = load_increments(&pool, &device_id, from, to)?;
+    let storms = storms(&increments, Duration::minutes(gap));
+
+    match format {
+        Format::Json => {
+            let storms = storms.iter()
+                .map(|storm| json!({
+                    "start": storm.start.to_rfc3339(),
+                    "end": storm.end.to_rfc3339(),
+                    "depth_mm": storm.depth_mm,
+                    "peak_intensity_mm_h": storm.peak_intensity
+                }))
+                .collect();
+            Ok(Output::Json(Json(serde_json::Value::Array(storms))))
+        },
+        Format::Csv => {
+            let records = storms.iter()
+                .map(|storm| vec![
+                    storm.start.to_rfc3339(),
+                    storm.end.to_rfc3339(),
+                    storm.depth_mm.to_string(),
+                    storm.peak_intensity.to_string()
+                ])
+                .collect();
+            Ok(Output::Csv(Content(ContentType::CSV, query::csv(&["start", "end", "depth_mm", "peak_intensity_mm_h"], records))))
+        }
+    }
+}
+
+#[get("/devices/<device_id>/calibration")]
+pub fn get_calibration(device_id:DeviceId, pool:State<Pool>, _key:ReadKey) -> Result<Json<Calibration>, QueryError> {
+    Ok(Json(Calibration { mm_per_tip: mm_per_tip(&pool, &device_id)? }))
+}
+
+#[put("/devices/<device_id>/calibration", format = "json", data = "<calibration>")]
+pub fn put_calibration(device_id:DeviceId, calibration:Json<Calibration>, pool:State<Pool>,
+        _key:AdminKey) -> Result<Json<Calibration>, QueryError> {
+    if !calibration.mm_per_tip.is_finite() || calibration.mm_per_tip <= 0.0 {
+        return Err(QueryError::BadRequest("mm_per_tip must be positive".to_string()));
+    }
+
+    let mut client = pool.get()?;
+    client.execute("
+        INSERT INTO calibration (device_id, mm_per_tip) VALUES ($1, $2)
+        ON CONFLICT (device_id) DO UPDATE SET mm_per_tip = EXCLUDED.mm_per_tip",
+        &[&device_id.as_bytes(), &calibration.mm_per_tip])?;
+
+    Ok(calibration)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn sample(minute:i64, loop_cnt:u32, tip_cnt:u32) -> Sample {
+        Sample {
+            ts: Utc.ymd(2020, 9, 1).and_hms(0, 0, 0) + Duration::minutes(minute),
+            loop_cnt,
+            values: vec![Some(tip_cnt)]
+        }
+    }
+
+    fn depths(samples:&[Sample]) -> Vec<f64> {
+        increments(samples, 0.5).iter().map(|increment| increment.depth_mm).collect()
+    }
+
+    #[test]
+    fn counts_tips_between_readings() {
+        let samples = [sample(0, 0, 0), sample(1, 200, 2), sample(2, 400, 2), sample(3, 600, 7)];
+        assert_eq!(vec![1.0, 0.0, 2.5], depths(&samples));
+    }
+
+    #[test]
+    fn reboot_restarts_count() {
+        // The loop counter going backwards means the gauge restarted, the 3 tips happened since then.
+        let samples = [sample(0, 1000, 50), sample(1, 10, 3)];
+        assert_eq!(vec![1.5], depths(&samples));
+    }
+
+    #[test]
+    fn wraparound() {
+        let samples = [sample(0, 1000, u32::MAX - 1), sample(1, 1200, 2)];
+        assert_eq!(vec![2.0], depths(&samples));
+    }
+
+    #[test]
+    fn hourly_and_daily_totals() {
+        let samples = [sample(0, 0, 0), sample(30, 1, 2), sample(90, 2, 4), sample(24 * 60 + 5, 3, 5)];
+        let increments = increments(&samples, 1.0);
+
+        let hourly = totals(&increments, Interval::Hour);
+        assert_eq!(3, hourly.len());
+        assert_eq!(2.0, hourly[0].depth_mm);
+        assert_eq!(Utc.ymd(2020, 9, 1).and_hms(1, 0, 0), hourly[1].start);
+
+        let daily = totals(&increments, Interval::Day);
+        assert_eq!(vec![4.0, 1.0], daily.iter().map(|total| total.depth_mm).collect::<Vec<f64>>());
+    }
+
+    #[test]
+    fn rain_per_minute_from_tips() {
+        let start = Utc.ymd(2020, 9, 1).and_hms(0, 0, 0);
+        let tip_times = [start + Duration::seconds(5), start + Duration::seconds(50), start + Duration::seconds(250)];
+
+        let minutes = minutes(&tip_times, 0.2);
+        assert_eq!(2, minutes.len());
+        assert_eq!(start, minutes[0].start);
+        assert_eq!(0.4, minutes[0].depth_mm);
+        assert_eq!(24.0, minutes[0].intensity());
+        assert_eq!(start + Duration::minutes(4), minutes[1].start);
+    }
+
+    #[test]
+    fn storms_split_on_dry_gap() {
+        let samples = [sample(0, 0, 0), sample(10, 1, 4), sample(20, 2, 6), sample(500, 3, 6), sample(510, 4, 7)];
+        let storms = storms(&increments(&samples, 1.0), Duration::hours(6));
+
+        assert_eq!(2, storms.len());
+        assert_eq!(6.0, storms[0].depth_mm);
+        assert_eq!(24.0, storms[0].peak_intensity);
+        assert_eq!(1.0, storms[1].depth_mm);
+    }
+
+    #[test]
+    fn rolling_window() {
+        let samples = [sample(0, 0, 0), sample(30, 1, 1), sample(60, 2, 3), sample(90, 3, 3), sample(120, 4, 4)];
+        let rolling = rolling(&increments(&samples, 1.0), Duration::minutes(60));
+
+        assert_eq!(vec![1.0, 3.0, 2.0, 1.0], rolling.iter().map(|total| total.depth_mm).collect::<Vec<f64>>());
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use dotenv::var;
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde_json::json;
+
+use crate::auth::ReadKey;
+use crate::counters;
+use crate::database::Pool;
+use crate::device::DeviceId;
+use crate::query;
+use crate::query::QueryError;
+
+// The main loop waits 15 * 1024 * 1024 cycles at 48MHz, plus however long the work in the loop takes.
+const DEFAULT_NOMINAL_PERIOD_MS:f64 = 327.68;
+
+// A packet that arrives this much later than the fit expects was held up somewhere and is back dated.
+const DEFAULT_TOLERANCE_SECS:i64 = 60;
+
+// The fit needs a few packets from the current boot before it is trusted.
+const MIN_OBSERVATIONS:usize = 5;
+
+// How many on-time packets are kept per device for the fit.
+const MAX_OBSERVATIONS:usize = 1000;
+
+/// A packet's loop count and when the gateway heard it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Observation {
+    pub loop_cnt: u32,
+    pub received_at: DateTime<Utc>
+}
+
+/// A straight line through the receive times of one boot, loop count against time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fit {
+    /// When the loop count was zero, the time the gauge started.
+    pub boot: DateTime<Utc>,
+    /// Seconds per loop.
+    pub period: f64,
+    /// The root mean square difference between the fit and the receive times used, in seconds.
+    pub rms_error: f64,
+    pub observations: usize
+}
+
+impl Fit {
+    pub fn time_of(&self, loop_cnt:u32) -> DateTime<Utc> {
+        self.boot + Duration::microseconds((loop_cnt as f64 * self.period * 1_000_000.0) as i64)
+    }
+
+    /// How far the loop period is from what it should be, in parts per million.
+    pub fn drift_ppm(&self, nominal_period:f64) -> f64 {
+        (self.period - nominal_period) / nominal_period * 1_000_000.0
+    }
+}
+
+fn least_squares(observations:&[Observation]) -> Option<Fit> {
+    let origin = observations.first()?.received_at;
+    let seconds = |observation:&Observation| (observation.received_at - origin).num_microseconds().unwrap_or(0) as f64 / 1_000_000.0;
+
+    let n = observations.len() as f64;
+    let mean_loop = observations.iter().map(|observation| observation.loop_cnt as f64).sum::<f64>() / n;
+    let mean_secs = observations.iter().map(seconds).sum::<f64>() / n;
+
+    let covariance = observations.iter().map(|observation| (observation.loop_cnt as f64 - mean_loop) * (seconds(observation) - mean_secs)).sum::<f64>();
+    let variance = observations.iter().map(|observation| (observation.loop_cnt as f64 - mean_loop).powi(2)).sum::<f64>();
+    if variance == 0.0 {
+        return None;
+    }
+
+    let period = covariance / variance;
+    if period <= 0.0 {
+        return None;
+    }
+
+    let boot_secs = mean_secs - period * mean_loop;
+    let residuals = observations.iter().map(|observation| seconds(observation) - (boot_secs + period * observation.loop_cnt as f64));
+    let rms_error = (residuals.map(|residual| residual * residual).sum::<f64>() / n).sqrt();
+
+    Some(Fit {
+        boot: origin + Duration::microseconds((boot_secs * 1_000_000.0) as i64),
+        period,
+        rms_error,
+        observations: observations.len()
+    })
+}
+
+/// Fit the loop period of one boot.  Delays only ever make packets late, so anything well behind the first fit is
+/// left out of the second.
+pub fn fit(observations:&[Observation]) -> Option<Fit> {
+    if observations.len() < 2 {
+        return None;
+    }
+
+    let first = least_squares(observations)?;
+    let slack = first.rms_error.max(1.0);
+    let on_time = observations.iter()
+        .filter(|observation| {
+            let lag = (observation.received_at - first.time_of(observation.loop_cnt)).num_milliseconds() as f64 / 1000.0;
+            lag <= slack
+        })
+        .cloned()
+        .collect::<Vec<Observation>>();
+
+    if on_time.len() < 2 || on_time.len() == observations.len() {
+        return Some(first);
+    }
+    least_squares(&on_time)
+}
+
+/// Split observations, in the order they were received, into boots.
+pub fn boots(observations:&[Observation]) -> Vec<&[Observation]> {
+    let mut boots = vec![];
+    let mut start = 0;
+
+    for i in 1..observations.len() {
+        if counters::rebooted(observations[i - 1].loop_cnt, observations[i].loop_cnt) {
+            boots.push(&observations[start..i]);
+            start = i;
+        }
+    }
+    if start < observations.len() {
+        boots.push(&observations[start..]);
+    }
+
+    boots
+}
+
+/// Remembers the recent on-time packets from each device so that late ones can be back dated.
+pub struct Reconstructor {
+    tolerance: Duration,
+    devices: HashMap<DeviceId, Vec<Observation>>
+}
+
+impl Reconstructor {
+    pub fn new() -> Reconstructor {
+        let tolerance = var("RECONSTRUCT_TOLERANCE_SECS").ok()
+            .and_then(|secs| secs.parse::<i64>().ok())
+            .unwrap_or(DEFAULT_TOLERANCE_SECS);
+
+        Reconstructor {
+            tolerance: Duration::seconds(tolerance),
+            devices: HashMap::new()
+        }
+    }
+
+    /// When the packet was most likely sent, and whether that was worked out from the fit rather than taken from
+    /// the receive time.
+    pub fn timestamp(&mut self, device_id:DeviceId, observation:Observation) -> (DateTime<Utc>, bool) {
+        let history = self.devices.entry(device_id).or_insert_with(Vec::new);
+
+        // Only back date a packet whose loop count falls within this boot, otherwise it is the start of a new one.
+        let within = history.first().map(|first| first.loop_cnt <= observation.loop_cnt).unwrap_or(false)
+            && observation.loop_cnt != 0;
+
+        if within && history.len() >= MIN_OBSERVATIONS {
+            if let Some(fit) = fit(history) {
+                let expected = fit.time_of(observation.loop_cnt);
+                if observation.received_at - expected > self.tolerance {
+                    return (expected, true);
+                }
+            }
+        }
+
+        if !within {
+            history.clear();
+        }
+        if history.len() == MAX_OBSERVATIONS {
+            history.remove(0);
+        }
+        history.push(observation);
+
+        (observation.received_at, false)
+    }
+}
+
+pub fn nominal_period() -> f64 {
+    var("CLOCK_NOMINAL_PERIOD_MS").ok()
+        .and_then(|ms| ms.parse::<f64>().ok())
+        .unwrap_or(DEFAULT_NOMINAL_PERIOD_MS) / 1000.0
+}
+
+/// The loop period and clock drift of each boot, from the packets that arrived on time.  Defaults to the last week.
+#[get("/devices/<device_id>/clock?<from>&<to>")]
+pub fn clock(device_id:DeviceId, from:Option<String>, to:Option<String>, pool:State<Pool>,
+        _key:ReadKey) -> Result<Json<serde_json::Value>, QueryError> {
+    let to = query::parse_time("to", to)?.unwrap_or_else(Utc::now);
+    let from = query::parse_time("from", from)?.unwrap_or_else(|| to - Duration::days(7));
+
+    let mut client = pool.get()?;
+    let rows = client.query("
+        SELECT loop_cnt, COALESCE(received_at, ts)
+        FROM telemetry
+        WHERE device_id = $1 AND ts >= $2 AND ts < $3 AND NOT ts_reconstructed AND loop_cnt IS NOT NULL
+        ORDER BY COALESCE(received_at, ts)", &[&device_id.as_bytes(), &from, &to])?;
+
+    let observations = rows.iter()
+        .map(|row| Observation { loop_cnt: row.get::<_, i64>(0) as u32, received_at: row.get(1) })
+        .collect::<Vec<Observation>>();
+
+    let nominal = nominal_period();
+    let boots = boots(&observations).iter()
+        .filter_map(|boot| fit(boot).map(|fit| json!({
+            "boot": fit.boot.to_rfc3339(),
+            "first_seen": boot[0].received_at.to_rfc3339(),
+            "last_seen": boot[boot.len() - 1].received_at.to_rfc3339(),
+            "packets": boot.len(),
+            "period_ms": fit.period * 1000.0,
+            "drift_ppm": fit.drift_ppm(nominal),
+            "rms_error_secs": fit.rms_error
+        })))
+        .collect();
+
+    Ok(Json(json!({
+        "nominal_period_ms": nominal * 1000.0,
+        "boots": serde_json::Value::Array(boots)
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn start() -> DateTime<Utc> {
+        Utc.ymd(2020, 9, 1).and_hms(0, 0, 0)
+    }
+
+    // A gauge with a 0.3s loop reporting every 200 loops.
+    fn observation(loop_cnt:u32, delay_secs:i64) -> Observation {
+        Observation {
+            loop_cnt,
+            received_at: start() + Duration::milliseconds(loop_cnt as i64 * 300) + Duration::seconds(delay_secs)
+        }
+    }
+
+    #[test]
+    fn fits_period_and_ignores_late_packets() {
+        let mut observations = (0..20).map(|i| observation(i * 200, 0)).collect::<Vec<Observation>>();
+        observations[10] = observation(2000, 600);
+
+        let fit = fit(&observations).unwrap();
+        assert!((fit.period - 0.3).abs() < 1e-6, "{:?}", fit);
+        assert!((fit.boot - start()).num_milliseconds().abs() < 5);
+        assert_eq!(19, fit.observations);
+        assert!((fit.drift_ppm(0.25) - 200_000.0).abs() < 10.0);
+    }
+
+    #[test]
+    fn splits_boots() {
+        let observations = [observation(200, 0), observation(400, 0), observation(0, 0), observation(200, 0)];
+        let boots = boots(&observations);
+        assert_eq!(2, boots.len());
+        assert_eq!(2, boots[1].len());
+    }
+
+    #[test]
+    fn back_dates_buffered_packets() {
+        let mut reconstructor = Reconstructor::new();
+        let device_id = DeviceId([1; 16]);
+
+        for i in 0..10 {
+            assert_eq!((observation(i * 200, 0).received_at, false), reconstructor.timestamp(device_id, observation(i * 200, 0)));
+        }
+
+        // Held in a spool for ten minutes.
+        let (ts, reconstructed) = reconstructor.timestamp(device_id, observation(2000, 600));
+        assert!(reconstructed);
+        assert!((ts - observation(2000, 0).received_at).num_milliseconds().abs() < 5);
+
+        // A restart is not a late packet.
+        assert_eq!((observation(0, 3600).received_at, false), reconstructor.timestamp(device_id, observation(0, 3600)));
+    }
+}
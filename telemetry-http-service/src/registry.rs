@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use chrono::DateTime;
+use chrono::Utc;
+
+use rainguage_messages::TelemetryPacket;
+
+use crate::database::DatabaseError;
+use crate::database::Pool;
+use crate::device::DeviceId;
+
+/// The most recent packet from a device and when it arrived.
+#[derive(Clone, Debug)]
+pub struct Latest {
+    pub packet: TelemetryPacket,
+    pub received_at: DateTime<Utc>
+}
+
+/// Keeps the latest packet from every device in memory so that metrics and alerts do not have to ask the
+/// database.
+#[derive(Default)]
+pub struct Registry {
+    devices: Mutex<BTreeMap<DeviceId, Latest>>
+}
+
+impl Registry {
+    pub fn update(&self, packet:&TelemetryPacket, received_at:DateTime<Utc>) {
+        let mut devices = self.devices.lock().unwrap();
+        let device_id = DeviceId(packet.device_id);
+
+        // Packets can arrive out of order when they are replayed, only keep the newest.
+        let newer = devices.get(&device_id).map(|latest| latest.received_at <= received_at).unwrap_or(true);
+        if newer {
+            devices.insert(device_id, Latest { packet: packet.clone(), received_at });
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<(DeviceId, Latest)> {
+        self.devices.lock().unwrap()
+            .iter()
+            .map(|(device_id, latest)| (*device_id, latest.clone()))
+            .collect()
+    }
+
+    /// Seed the registry with the last row stored for each device so that a restart does not forget them.
+    pub fn load(&self, pool:&Pool) -> Result<(), DatabaseError> {
+        let mut client = pool.get()?;
+        let rows = client.query("
+            SELECT DISTINCT ON (device_id)
+                device_id, ts, loop_cnt, tip_cnt, vbat, temperature, relative_humidity, usb_bytes_read,
+                usb_bytes_written, usb_err_cnt, lora_rx_bytes, lora_tx_bytes, lora_error_cnt, hardware_error_other_cnt,
+                uptime_secs
+            FROM telemetry
+            WHERE device_id IS NOT NULL
+            ORDER BY device_id, ts DESC", &[])?;
+
+        let counter = |row:&postgres::Row, i:usize| row.get::<_, Option<i64>>(i).unwrap_or(0) as u32;
+
+        for row in rows.iter() {
+            let device_id = match DeviceId::from_slice(row.get::<_, &[u8]>(0)) {
+                Some(device_id) => device_id,
+                None => continue
+            };
+
+            let mut packet = TelemetryPacket::new();
+            packet.device_id = device_id.0;
+            packet.loop_cnt = counter(row, 2);
+            packet.tip_cnt = counter(row, 3);
+            packet.vbat = counter(row, 4);
+            packet.temperature = row.get::<_, Option<f32>>(5).unwrap_or(0.0);
+            packet.relative_humidity = row.get::<_, Option<f32>>(6).unwrap_or(0.0);
+            packet.usb_bytes_read = counter(row, 7);
+            packet.usb_bytes_written = counter(row, 8);
+            packet.usb_error_cnt = counter(row, 9);
+            packet.lora_rx_bytes = counter(row, 10);
+            packet.lora_tx_bytes = counter(row, 11);
+            packet.lora_error_cnt = counter(row, 12);
+            packet.hardware_err_other_cnt = counter(row, 13);
+            packet.uptime_secs = row.get::<_, Option<i64>>(14).map(|secs| secs as u32);
+
+            self.update(&packet, row.get(1));
+        }
+
+        info!("Loaded the latest telemetry for {} devices", rows.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,662 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::thread;
+
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::TimeZone;
+use chrono::Utc;
+use dotenv::var;
+
+use crate::counters;
+use crate::database::DatabaseError;
+use crate::database::Pool;
+use crate::device::DeviceId;
+use crate::query::QueryError;
+use crate::rainfall;
+
+const DEFAULT_INTERVAL_SECS:u64 = 300;
+
+// How many days of each resolution are kept, zero keeps it forever.
+const DEFAULT_RAW_DAYS:i64 = 90;
+const DEFAULT_FIVE_MINUTE_DAYS:i64 = 730;
+const DEFAULT_HOUR_DAYS:i64 = 0;
+const DEFAULT_DAY_DAYS:i64 = 0;
+
+// Dirty buckets closer together than this are recomputed in one go rather than loading the telemetry for each.
+const MERGE_GAP_HOURS:i64 = 24;
+
+// The measurements that are summarised with a min, max and mean.
+const MEASUREMENTS:[&str; 3] = ["temperature", "relative_humidity", "vbat"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolution {
+    Raw,
+    FiveMinutes,
+    Hour,
+    Day
+}
+
+const RESOLUTIONS:[Resolution; 4] = [Resolution::Raw, Resolution::FiveMinutes, Resolution::Hour, Resolution::Day];
+
+impl Resolution {
+    /// `None` when the resolution should be picked automatically.
+    pub fn parse(resolution:Option<String>) -> Result<Option<Resolution>, QueryError> {
+        match resolution.as_ref().map(|r| r.as_str()) {
+            None | Some("auto") => Ok(None),
+            Some("raw") => Ok(Some(Resolution::Raw)),
+            Some("5m") => Ok(Some(Resolution::FiveMinutes)),
+            Some("1h") => Ok(Some(Resolution::Hour)),
+            Some("1d") => Ok(Some(Resolution::Day)),
+            Some(other) => {
+                Err(QueryError::BadRequest(format!("unknown resolution {}, expected auto, raw, 5m, 1h or 1d", other)))
+            }
+        }
+    }
+
+    pub fn table(&self) -> &'static str {
+        match self {
+            Resolution::Raw => "telemetry",
+            Resolution::FiveMinutes => "telemetry_5m",
+            Resolution::Hour => "telemetry_1h",
+            Resolution::Day => "telemetry_1d"
+        }
+    }
+
+    fn width(&self) -> Duration {
+        match self {
+            Resolution::Raw => Duration::zero(),
+            Resolution::FiveMinutes => Duration::minutes(5),
+            Resolution::Hour => Duration::hours(1),
+            Resolution::Day => Duration::days(1)
+        }
+    }
+
+    // The longest range that is returned at this resolution when it is picked automatically.  A gauge reports about
+    // once a minute, so each one is at most a few thousand rows.
+    fn max_span(&self) -> Option<Duration> {
+        match self {
+            Resolution::Raw => Some(Duration::days(3)),
+            Resolution::FiveMinutes => Some(Duration::days(14)),
+            Resolution::Hour => Some(Duration::days(180)),
+            Resolution::Day => None
+        }
+    }
+
+    // What each rollup is built from.
+    fn finer(&self) -> Option<Resolution> {
+        match self {
+            Resolution::Raw | Resolution::FiveMinutes => None,
+            Resolution::Hour => Some(Resolution::FiveMinutes),
+            Resolution::Day => Some(Resolution::Hour)
+        }
+    }
+
+    /// The start of the bucket `ts` falls in.  Buckets are aligned to UTC.
+    pub fn bucket(&self, ts:DateTime<Utc>) -> DateTime<Utc> {
+        let width = self.width().num_seconds();
+        if width == 0 {
+            return ts;
+        }
+        Utc.timestamp(ts.timestamp() - ts.timestamp().rem_euclid(width), 0)
+    }
+}
+
+/// How long each resolution is kept for.  `None` keeps it forever.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Retention {
+    pub raw: Option<Duration>,
+    pub five_minutes: Option<Duration>,
+    pub hour: Option<Duration>,
+    pub day: Option<Duration>
+}
+
+fn days(name:&str, default:i64) -> Option<Duration> {
+    let days = var(name).ok()
+        .and_then(|days| days.parse::<i64>().ok())
+        .unwrap_or(default);
+
+    if days > 0 {
+        Some(Duration::days(days))
+    } else {
+        None
+    }
+}
+
+impl Retention {
+    pub fn from_env() -> Retention {
+        Retention {
+            raw: days("RETENTION_RAW_DAYS", DEFAULT_RAW_DAYS),
+            five_minutes: days("RETENTION_5M_DAYS", DEFAULT_FIVE_MINUTE_DAYS),
+            hour: days("RETENTION_1H_DAYS", DEFAULT_HOUR_DAYS),
+            day: days("RETENTION_1D_DAYS", DEFAULT_DAY_DAYS)
+        }
+    }
+
+    /// Anything at this resolution from before the cutoff has been deleted.
+    pub fn cutoff(&self, resolution:Resolution, now:DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let kept = match resolution {
+            Resolution::Raw => self.raw,
+            Resolution::FiveMinutes => self.five_minutes,
+            Resolution::Hour => self.hour,
+            Resolution::Day => self.day
+        };
+        kept.map(|kept| now - kept)
+    }
+}
+
+/// The finest resolution that still goes back to `from` and keeps the number of rows for the range reasonable.
+pub fn choose(from:DateTime<Utc>, to:DateTime<Utc>, now:DateTime<Utc>, retention:&Retention) -> Resolution {
+    let span = to.min(now) - from;
+
+    RESOLUTIONS.iter()
+        .cloned()
+        .find(|resolution| {
+            let kept = retention.cutoff(*resolution, now).map(|cutoff| from >= cutoff).unwrap_or(true);
+            let small = resolution.max_span().map(|max_span| span <= max_span).unwrap_or(true);
+            kept && small
+        })
+        .unwrap_or(Resolution::Day)
+}
+
+/// The values from one telemetry row that go into the rollups.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reading {
+    pub ts: DateTime<Utc>,
+    pub loop_cnt: u32,
+    pub tip_cnt: Option<u32>,
+    pub temperature: Option<f32>,
+    pub relative_humidity: Option<f32>,
+    pub vbat: Option<f32>
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    sum: f64,
+    count: i64
+}
+
+impl Stats {
+    fn add(&mut self, value:Option<f32>) {
+        if let Some(value) = value {
+            self.min = Some(self.min.map(|min| min.min(value)).unwrap_or(value));
+            self.max = Some(self.max.map(|max| max.max(value)).unwrap_or(value));
+            self.sum += value as f64;
+            self.count += 1;
+        }
+    }
+
+    pub fn mean(&self) -> Option<f32> {
+        if self.count > 0 {
+            Some((self.sum / self.count as f64) as f32)
+        } else {
+            None
+        }
+    }
+}
+
+/// Five minutes of telemetry from one device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bucket {
+    pub ts: DateTime<Utc>,
+    pub packets: i64,
+    pub temperature: Stats,
+    pub relative_humidity: Stats,
+    pub vbat: Stats,
+    pub tips: i64,
+    pub rainfall_mm: f64
+}
+
+impl Bucket {
+    fn new(ts:DateTime<Utc>) -> Bucket {
+        Bucket {
+            ts,
+            packets: 0,
+            temperature: Stats::default(),
+            relative_humidity: Stats::default(),
+            vbat: Stats::default(),
+            tips: 0,
+            rainfall_mm: 0.0
+        }
+    }
+}
+
+/// Summarise readings, oldest first, into five minute buckets.  `previous` is the reading before the first one so
+/// that its tips can be counted.  Tips are counted in the bucket of the reading that reported them, the same as
+/// rainfall::totals.
+pub fn summarise(previous:Option<&Reading>, readings:&[Reading], mm_per_tip:f64) -> Vec<Bucket> {
+    let mut buckets:Vec<Bucket> = vec![];
+    let mut previous = previous;
+
+    for reading in readings {
+        let ts = Resolution::FiveMinutes.bucket(reading.ts);
+        if buckets.last().map(|bucket| bucket.ts != ts).unwrap_or(true) {
+            buckets.push(Bucket::new(ts));
+        }
+        let bucket = buckets.last_mut().unwrap();
+
+        bucket.packets += 1;
+        bucket.temperature.add(reading.temperature);
+        bucket.relative_humidity.add(reading.relative_humidity);
+        bucket.vbat.add(reading.vbat);
+
+        if let Some(previous) = previous {
+            let rebooted = counters::rebooted(previous.loop_cnt, reading.loop_cnt);
+            let tips = match (previous.tip_cnt, reading.tip_cnt) {
+                (Some(prev), Some(cur)) => counters::delta(prev, cur, rebooted),
+                (None, Some(cur)) if rebooted => cur,
+                _ => 0
+            };
+            bucket.tips += tips as i64;
+            bucket.rainfall_mm += tips as f64 * mm_per_tip;
+        }
+
+        previous = Some(reading);
+    }
+
+    buckets
+}
+
+fn reading(row:&postgres::Row) -> Reading {
+    Reading {
+        ts: row.get(0),
+        loop_cnt: row.get::<_, Option<i64>>(1).unwrap_or(0) as u32,
+        tip_cnt: row.get::<_, Option<i64>>(2).map(|tip_cnt| tip_cnt as u32),
+        temperature: row.get(3),
+        relative_humidity: row.get(4),
+        vbat: row.get::<_, Option<i64>>(5).map(|vbat| vbat as f32)
+    }
+}
+
+/// Group the five minute buckets that need recomputing into ranges that are loaded together.
+fn ranges(buckets:&BTreeSet<DateTime<Utc>>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut ranges:Vec<(DateTime<Utc>, DateTime<Utc>)> = vec![];
+
+    for bucket in buckets {
+        let end = *bucket + Resolution::FiveMinutes.width();
+        match ranges.last_mut() {
+            Some(range) if *bucket - range.1 <= Duration::hours(MERGE_GAP_HOURS) => range.1 = end,
+            _ => ranges.push((*bucket, end))
+        }
+    }
+
+    ranges
+}
+
+/// Recompute the five minute buckets of one device between `from` and `to`, returning the buckets written.  Buckets
+/// before `raw_cutoff` may have lost some of their raw rows to the retention policy already, so one that was rolled up
+/// before is kept rather than replaced with part of it.
+fn summarise_range(transaction:&mut postgres::Transaction, device_id:&DeviceId, from:DateTime<Utc>, to:DateTime<Utc>,
+        raw_cutoff:Option<DateTime<Utc>>, mm_per_tip:f64) -> Result<Vec<DateTime<Utc>>, DatabaseError> {
+    let device_id_bytes = device_id.as_bytes();
+
+    // A packet that arrives late changes the tips counted by the reading after it, so the bucket that reading is in
+    // is recomputed too.
+    let next = transaction.query("
+        SELECT ts FROM telemetry WHERE device_id = $1 AND ts >= $2 ORDER BY ts LIMIT 1", &[&device_id_bytes, &to])?;
+    let to = match next.first() {
+        Some(row) => Resolution::FiveMinutes.bucket(row.get(0)) + Resolution::FiveMinutes.width(),
+        None => to
+    };
+
+    let previous = transaction.query("
+        SELECT ts, loop_cnt, tip_cnt, temperature, relative_humidity, vbat
+        FROM telemetry
+        WHERE device_id = $1 AND ts < $2
+        ORDER BY ts DESC
+        LIMIT 1", &[&device_id_bytes, &from])?;
+
+    let rows = transaction.query("
+        SELECT ts, loop_cnt, tip_cnt, temperature, relative_humidity, vbat
+        FROM telemetry
+        WHERE device_id = $1 AND ts >= $2 AND ts < $3
+        ORDER BY ts", &[&device_id_bytes, &from, &to])?;
+
+    let previous = previous.first().map(reading);
+    let readings = rows.iter().map(reading).collect::<Vec<Reading>>();
+    let buckets = summarise(previous.as_ref(), &readings, mm_per_tip);
+
+    for bucket in buckets.iter() {
+        let conflict = if raw_cutoff.map(|cutoff| bucket.ts < cutoff).unwrap_or(false) {
+            "DO NOTHING"
+        } else {
+            "DO UPDATE SET
+                packets = EXCLUDED.packets,
+                temperature = EXCLUDED.temperature,
+                temperature_min = EXCLUDED.temperature_min,
+                temperature_max = EXCLUDED.temperature_max,
+                relative_humidity = EXCLUDED.relative_humidity,
+                relative_humidity_min = EXCLUDED.relative_humidity_min,
+                relative_humidity_max = EXCLUDED.relative_humidity_max,
+                vbat = EXCLUDED.vbat,
+                vbat_min = EXCLUDED.vbat_min,
+                vbat_max = EXCLUDED.vbat_max,
+                tips = EXCLUDED.tips,
+                rainfall_mm = EXCLUDED.rainfall_mm"
+        };
+        let sql = format!("
+            INSERT INTO telemetry_5m (device_id, ts, packets, temperature, temperature_min, temperature_max,
+                relative_humidity, relative_humidity_min, relative_humidity_max, vbat, vbat_min, vbat_max, tips, rainfall_mm)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
+            ON CONFLICT (device_id, ts) {}", conflict);
+        transaction.execute(sql.as_str(),
+            &[  &device_id_bytes,
+                &bucket.ts,
+                &bucket.packets,
+                &bucket.temperature.mean(),
+                &bucket.temperature.min,
+                &bucket.temperature.max,
+                &bucket.relative_humidity.mean(),
+                &bucket.relative_humidity.min,
+                &bucket.relative_humidity.max,
+                &bucket.vbat.mean(),
+                &bucket.vbat.min,
+                &bucket.vbat.max,
+                &bucket.tips,
+                &bucket.rainfall_mm,
+                ])?;
+    }
+
+    Ok(buckets.iter().map(|bucket| bucket.ts).collect())
+}
+
+/// Recompute one hourly or daily bucket from the rollup below it.  The means are weighted by the number of packets.
+/// With `keep_existing` the bucket is only written when there is not one already, for when the rollup below has
+/// been cut back by the retention policy.
+fn roll_up(transaction:&mut postgres::Transaction, resolution:Resolution, device_id:&DeviceId,
+        ts:DateTime<Utc>, keep_existing:bool) -> Result<(), DatabaseError> {
+    let finer = match resolution.finer() {
+        Some(finer) => finer,
+        None => return Ok(())
+    };
+
+    let columns = MEASUREMENTS.iter()
+        .map(|m| format!("{m}, {m}_min, {m}_max", m = m))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let aggregates = MEASUREMENTS.iter()
+        .map(|m| format!("
+            SUM({m} * packets) / NULLIF(SUM(CASE WHEN {m} IS NULL THEN 0 ELSE packets END), 0),
+            MIN({m}_min),
+            MAX({m}_max)", m = m))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let updates = MEASUREMENTS.iter()
+        .map(|m| format!("{m} = EXCLUDED.{m}, {m}_min = EXCLUDED.{m}_min, {m}_max = EXCLUDED.{m}_max", m = m))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let conflict = if keep_existing {
+        "DO NOTHING".to_string()
+    } else {
+        format!("DO UPDATE SET
+            packets = EXCLUDED.packets, {}, tips = EXCLUDED.tips, rainfall_mm = EXCLUDED.rainfall_mm", updates)
+    };
+
+    let sql = format!("
+        INSERT INTO {table} (device_id, ts, packets, {columns}, tips, rainfall_mm)
+        SELECT device_id, $2, SUM(packets), {aggregates}, SUM(tips), SUM(rainfall_mm)
+        FROM {finer}
+        WHERE device_id = $1 AND ts >= $2 AND ts < $3
+        GROUP BY device_id
+        ON CONFLICT (device_id, ts) {conflict}",
+        table = resolution.table(),
+        finer = finer.table(),
+        columns = columns,
+        aggregates = aggregates,
+        conflict = conflict);
+
+    transaction.execute(sql.as_str(), &[&device_id.as_bytes(), &ts, &(ts + resolution.width())])?;
+    Ok(())
+}
+
+/// Bring the rollups up to date with the telemetry that has arrived since the last run, then apply the retention
+/// policy.  Returns how many packets were rolled up.
+pub fn run(pool:&Pool, retention:&Retention, now:DateTime<Utc>) -> Result<usize, DatabaseError> {
+    let mut client = pool.get()?;
+    let mut transaction = client.transaction()?;
+
+    // Claim the new rows in the same transaction that rolls them up, anything that arrives while this runs is left
+    // for the next run.
+    let rows = transaction.query("
+        UPDATE telemetry SET rolled_up = true
+        WHERE NOT rolled_up AND device_id IS NOT NULL
+        RETURNING device_id, ts", &[])?;
+
+    // Rows older than the raw retention, from an import or a gauge that was out of touch, are rolled up too so the
+    // coarser rollups keep them once they are deleted below.
+    let raw_cutoff = retention.cutoff(Resolution::Raw, now);
+    let mut dirty:BTreeMap<DeviceId, BTreeSet<DateTime<Utc>>> = BTreeMap::new();
+    for row in rows.iter() {
+        let ts:DateTime<Utc> = row.get(1);
+        if let Some(device_id) = DeviceId::from_slice(row.get::<_, &[u8]>(0)) {
+            dirty.entry(device_id).or_insert_with(BTreeSet::new).insert(Resolution::FiveMinutes.bucket(ts));
+        }
+    }
+
+    for (device_id, buckets) in dirty.iter() {
+        let mm_per_tip = rainfall::mm_per_tip_in(&mut transaction, device_id)?;
+
+        let mut written = BTreeSet::new();
+        for (from, to) in ranges(buckets) {
+            written.extend(summarise_range(&mut transaction, device_id, from, to, raw_cutoff, mm_per_tip)?);
+        }
+
+        for resolution in [Resolution::Hour, Resolution::Day].iter() {
+            let finer_cutoff = resolution.finer().and_then(|finer| retention.cutoff(finer, now));
+            let touched = written.iter()
+                .map(|ts| resolution.bucket(*ts))
+                .collect::<BTreeSet<DateTime<Utc>>>();
+
+            for ts in touched {
+                let keep_existing = finer_cutoff.map(|cutoff| ts < cutoff).unwrap_or(false);
+                roll_up(&mut transaction, *resolution, device_id, ts, keep_existing)?;
+            }
+        }
+    }
+
+    transaction.commit()?;
+
+    for resolution in RESOLUTIONS.iter() {
+        if let Some(cutoff) = retention.cutoff(*resolution, now) {
+            // Raw rows are only deleted once they have been rolled up.
+            let rolled_up = if *resolution == Resolution::Raw { " AND rolled_up" } else { "" };
+            let sql = format!("DELETE FROM {} WHERE ts < $1{}", resolution.table(), rolled_up);
+            let deleted = client.execute(sql.as_str(), &[&cutoff])?;
+            if deleted > 0 {
+                info!("Deleted {} rows from {} older than {}", deleted, resolution.table(), cutoff.to_rfc3339());
+            }
+        }
+    }
+
+    // The tip times and power reports are kept as long as the raw telemetry.
+    if let Some(cutoff) = retention.cutoff(Resolution::Raw, now) {
+        client.execute("DELETE FROM tip WHERE ts < $1", &[&cutoff])?;
+        client.execute("DELETE FROM power WHERE ts < $1", &[&cutoff])?;
+    }
+
+    Ok(rows.len())
+}
+
+pub fn start(pool:Pool) {
+    let interval = var("ROLLUP_INTERVAL_SECS").ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_INTERVAL_SECS);
+    let retention = Retention::from_env();
+
+    info!("Rolling up telemetry every {}s, keeping {:?}", interval, retention);
+
+    thread::spawn(move|| {
+        loop {
+            match run(&pool, &retention, Utc::now()) {
+                Ok(0) => {},
+                Ok(packets) => info!("Rolled up {} packets", packets),
+                Err(err) => error!("Could not roll up telemetry: {:?}", err)
+            }
+
+            thread::sleep(std::time::Duration::from_secs(interval));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(minute:i64) -> DateTime<Utc> {
+        Utc.ymd(2020, 9, 1).and_hms(0, 0, 0) + Duration::minutes(minute)
+    }
+
+    fn reading(minute:i64, tip_cnt:u32, temperature:f32) -> Reading {
+        Reading {
+            ts: time(minute),
+            loop_cnt: (1000 + minute * 200) as u32,
+            tip_cnt: Some(tip_cnt),
+            temperature: Some(temperature),
+            relative_humidity: None,
+            vbat: Some(2500.0)
+        }
+    }
+
+    #[test]
+    fn buckets_are_aligned_to_utc() {
+        let ts = Utc.ymd(2020, 9, 1).and_hms(13, 47, 12);
+        assert_eq!(Utc.ymd(2020, 9, 1).and_hms(13, 45, 0), Resolution::FiveMinutes.bucket(ts));
+        assert_eq!(Utc.ymd(2020, 9, 1).and_hms(13, 0, 0), Resolution::Hour.bucket(ts));
+        assert_eq!(Utc.ymd(2020, 9, 1).and_hms(0, 0, 0), Resolution::Day.bucket(ts));
+        assert_eq!(ts, Resolution::Raw.bucket(ts));
+    }
+
+    #[test]
+    fn summarises_five_minutes() {
+        let previous = reading(-1, 10, 0.0);
+        let readings = [reading(0, 12, 10.0), reading(1, 12, 20.0), reading(4, 13, 30.0), reading(5, 13, 15.0)];
+
+        let buckets = summarise(Some(&previous), &readings, 0.2);
+        assert_eq!(2, buckets.len());
+
+        assert_eq!(time(0), buckets[0].ts);
+        assert_eq!(3, buckets[0].packets);
+        assert_eq!(Some(10.0), buckets[0].temperature.min);
+        assert_eq!(Some(30.0), buckets[0].temperature.max);
+        assert_eq!(Some(20.0), buckets[0].temperature.mean());
+        assert_eq!(None, buckets[0].relative_humidity.mean());
+        assert_eq!(3, buckets[0].tips);
+        assert!((buckets[0].rainfall_mm - 0.6).abs() < 1e-9);
+
+        assert_eq!(time(5), buckets[1].ts);
+        assert_eq!(0, buckets[1].tips);
+    }
+
+    #[test]
+    fn counts_tips_after_a_reboot() {
+        let previous = reading(0, 50, 0.0);
+        let mut rebooted = reading(1, 2, 0.0);
+        rebooted.loop_cnt = 0;
+
+        assert_eq!(2, summarise(Some(&previous), &[rebooted], 0.2)[0].tips);
+        assert_eq!(0, summarise(None, &[rebooted], 0.2)[0].tips);
+    }
+
+    #[test]
+    fn chooses_the_finest_resolution_that_fits() {
+        let retention = Retention {
+            raw: Some(Duration::days(90)),
+            five_minutes: Some(Duration::days(730)),
+            hour: None,
+            day: None
+        };
+        let now = time(0);
+
+        assert_eq!(Resolution::Raw, choose(now - Duration::days(1), now, now, &retention));
+        assert_eq!(Resolution::FiveMinutes, choose(now - Duration::days(7), now, now, &retention));
+        assert_eq!(Resolution::Hour, choose(now - Duration::days(60), now, now, &retention));
+        assert_eq!(Resolution::Day, choose(now - Duration::days(400), now, now, &retention));
+
+        // The raw telemetry from a year ago has gone, even for a short range.
+        assert_eq!(Resolution::FiveMinutes, choose(now - Duration::days(365), now - Duration::days(364), now, &retention));
+        assert_eq!(Resolution::Hour, choose(now - Duration::days(1000), now - Duration::days(999), now, &retention));
+
+        // A range that runs into the future is only as long as the part that has happened.
+        assert_eq!(Resolution::Raw, choose(now - Duration::days(1), Utc.ymd(9999, 1, 1).and_hms(0, 0, 0), now, &retention));
+    }
+
+    #[test]
+    fn nearby_buckets_are_loaded_together() {
+        let buckets = [time(0), time(5), time(60), time(3 * 24 * 60)].iter().cloned().collect::<BTreeSet<_>>();
+        assert_eq!(vec![(time(0), time(65)), (time(3 * 24 * 60), time(3 * 24 * 60 + 5))], ranges(&buckets));
+    }
+
+    #[test]
+    fn keeps_imported_history_older_than_the_raw_telemetry() {
+        let pool = match crate::database::test_pool() {
+            Some(pool) => pool,
+            None => return
+        };
+        let device_id = DeviceId([39; 16]);
+        let mut client = pool.get().unwrap();
+        for table in ["telemetry", "telemetry_5m", "telemetry_1h", "telemetry_1d"].iter() {
+            client.execute(format!("DELETE FROM {} WHERE device_id = $1", table).as_str(), &[&device_id.as_bytes()]).unwrap();
+        }
+
+        // The hour at minute 60 was rolled up from a whole hour of telemetry long ago.
+        client.execute("
+            INSERT INTO telemetry_1h (device_id, ts, packets, temperature, tips, rainfall_mm)
+            VALUES ($1, $2, 60, 15.0, 10, 2.0)", &[&device_id.as_bytes(), &time(60)]).unwrap();
+
+        let record = |minute:i64| crate::bulk::Record {
+            device_id: device_id.to_string(),
+            ts: time(minute),
+            received_at: None,
+            ts_reconstructed: false,
+            loop_cnt: Some(minute * 300),
+            tip_cnt: Some(0),
+            vbat: Some(2500),
+            temperature: Some(20.0),
+            relative_humidity: Some(50.0),
+            usb_bytes_read: None,
+            usb_bytes_written: None,
+            usb_err_cnt: None,
+            lora_rx_bytes: None,
+            lora_tx_bytes: None,
+            lora_error_cnt: None,
+            hardware_error_other_cnt: None,
+            uptime_secs: None,
+            epoch: None,
+            sequence: None
+        };
+        let path = std::env::temp_dir().join("keeps_imported_history_older_than_the_raw_telemetry.ndjson");
+        let lines = [record(1), record(2), record(61)].iter()
+            .map(|record| serde_json::to_string(record).unwrap() + "\n")
+            .collect::<String>();
+        std::fs::write(&path, lines).unwrap();
+        assert_eq!(3, crate::bulk::import(&pool, &path, crate::bulk::FileFormat::Ndjson).unwrap().1);
+
+        // Past the raw and five minute retention.
+        let retention = Retention {
+            raw: Some(Duration::days(90)),
+            five_minutes: Some(Duration::days(95)),
+            hour: None,
+            day: None
+        };
+        run(&pool, &retention, time(0) + Duration::days(100)).unwrap();
+
+        let mut count = |table:&str| -> i64 {
+            client.query_one(format!("SELECT COUNT(*) FROM {} WHERE device_id = $1", table).as_str(),
+                &[&device_id.as_bytes()]).unwrap().get(0)
+        };
+        assert_eq!(0, count("telemetry"));
+        assert_eq!(0, count("telemetry_5m"));
+
+        let hours = client.query("
+            SELECT ts, packets FROM telemetry_1h WHERE device_id = $1 ORDER BY ts", &[&device_id.as_bytes()]).unwrap()
+            .iter()
+            .map(|row| (row.get::<_, DateTime<Utc>>(0), row.get::<_, i64>(1)))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(time(0), 2), (time(60), 60)], hours);
+        let days:i64 = client.query_one("
+            SELECT packets FROM telemetry_1d WHERE device_id = $1", &[&device_id.as_bytes()]).unwrap().get(0);
+        assert_eq!(62, days);
+    }
+}
@@ -0,0 +1,184 @@
+use dotenv::var;
+
+use lettre::smtp::authentication::Credentials;
+use lettre::SmtpClient;
+use lettre::Transport;
+use lettre_email::EmailBuilder;
+use serde::Serialize;
+
+use crate::device::DeviceId;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Firing,
+    Resolved
+}
+
+/// Sent to every sink when an alert starts firing, is still firing after the repeat interval, or resolves.
+#[derive(Clone, Debug, Serialize)]
+pub struct Notification {
+    pub device_id: DeviceId,
+    pub rule: &'static str,
+    pub state: State,
+    pub message: String
+}
+
+impl Notification {
+    pub fn subject(&self) -> String {
+        match self.state {
+            State::Firing => format!("[rainguage] {} firing for {}", self.rule, self.device_id),
+            State::Resolved => format!("[rainguage] {} resolved for {}", self.rule, self.device_id)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SinkError {
+    HttpError(reqwest::Error),
+    EmailError(lettre_email::error::Error),
+    SmtpError(lettre::smtp::error::Error)
+}
+
+impl From<reqwest::Error> for SinkError {
+    fn from(err: reqwest::Error) -> Self {
+        SinkError::HttpError(err)
+    }
+}
+
+impl From<lettre_email::error::Error> for SinkError {
+    fn from(err: lettre_email::error::Error) -> Self {
+        SinkError::EmailError(err)
+    }
+}
+
+impl From<lettre::smtp::error::Error> for SinkError {
+    fn from(err: lettre::smtp::error::Error) -> Self {
+        SinkError::SmtpError(err)
+    }
+}
+
+/// Somewhere alert notifications are delivered.
+pub trait Sink: Send {
+    fn name(&self) -> &str;
+
+    fn notify(&mut self, notification:&Notification) -> Result<(), SinkError>;
+}
+
+pub struct LogSink;
+
+impl Sink for LogSink {
+    fn name(&self) -> &str {
+        "log"
+    }
+
+    fn notify(&mut self, notification:&Notification) -> Result<(), SinkError> {
+        match notification.state {
+            State::Firing => warn!("{}: {}", notification.subject(), notification.message),
+            State::Resolved => info!("{}: {}", notification.subject(), notification.message)
+        }
+
+        Ok(())
+    }
+}
+
+/// POSTs the notification as json.
+pub struct WebhookSink {
+    url: String,
+    client: reqwest::blocking::Client
+}
+
+impl WebhookSink {
+    pub fn new(url:String) -> WebhookSink {
+        WebhookSink {
+            url,
+            client: reqwest::blocking::Client::new()
+        }
+    }
+}
+
+impl Sink for WebhookSink {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    fn notify(&mut self, notification:&Notification) -> Result<(), SinkError> {
+        self.client.post(&self.url)
+            .json(notification)
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+pub struct EmailSink {
+    host: String,
+    credentials: Option<Credentials>,
+    from: String,
+    to: String
+}
+
+impl EmailSink {
+    pub fn new(host:String, credentials:Option<Credentials>, from:String, to:String) -> EmailSink {
+        EmailSink {
+            host,
+            credentials,
+            from,
+            to
+        }
+    }
+}
+
+impl Sink for EmailSink {
+    fn name(&self) -> &str {
+        "email"
+    }
+
+    fn notify(&mut self, notification:&Notification) -> Result<(), SinkError> {
+        let email = EmailBuilder::new()
+            .from(self.from.clone())
+            .to(self.to.clone())
+            .subject(notification.subject())
+            .text(notification.message.clone())
+            .build()?;
+
+        let mut client = SmtpClient::new_simple(&self.host)?;
+        if let Some(credentials) = &self.credentials {
+            client = client.credentials(credentials.clone());
+        }
+
+        client.transport().send(email.into())?;
+        Ok(())
+    }
+}
+
+/// Build the sinks named in ALERT_SINKS (a comma separated list of log, webhook and email).  Only the log is used
+/// when it is not set.
+pub fn from_env() -> Vec<Box<dyn Sink>> {
+    let names = var("ALERT_SINKS").unwrap_or_else(|_| "log".to_string());
+    let mut sinks:Vec<Box<dyn Sink>> = vec![];
+
+    for name in names.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+        match name {
+            "log" => sinks.push(Box::new(LogSink)),
+            "webhook" => match var("ALERT_WEBHOOK_URL") {
+                Ok(url) => sinks.push(Box::new(WebhookSink::new(url))),
+                Err(_) => error!("The webhook alert sink needs ALERT_WEBHOOK_URL")
+            },
+            "email" => match (var("SMTP_HOST"), var("ALERT_EMAIL_FROM"), var("ALERT_EMAIL_TO")) {
+                (Ok(host), Ok(from), Ok(to)) => {
+                    let credentials = match (var("SMTP_USER"), var("SMTP_PASSWORD")) {
+                        (Ok(user), Ok(password)) => Some(Credentials::new(user, password)),
+                        _ => None
+                    };
+                    sinks.push(Box::new(EmailSink::new(host, credentials, from, to)));
+                },
+                _ => error!("The email alert sink needs SMTP_HOST, ALERT_EMAIL_FROM and ALERT_EMAIL_TO")
+            },
+            other => error!("Unknown alert sink {}", other)
+        }
+    }
+
+    sinks
+}
@@ -0,0 +1,174 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use rocket::State;
+use rocket_contrib::json::Json;
+use rocket_contrib::json::JsonError;
+
+use rainguage_messages::TipsPacket;
+use rainguage_messages::MAX_TIPS;
+
+use crate::auth::IngestKey;
+use crate::database::DatabaseError;
+use crate::database::Pool;
+use crate::ingest;
+use crate::ingest::IngestResponse;
+use crate::ingest::ReceivedAtHeader;
+use crate::metrics;
+use crate::reconstruct;
+
+/// When each tip in a packet happened, oldest first, along with the tip_cnt it took the count to and the loop_cnt it
+/// happened on.  The gauge says how many loops before the packet each tip was, which is turned into time with the
+/// nominal loop period in seconds.
+pub fn tip_times(tips:&TipsPacket, received_at:DateTime<Utc>, period:f64) -> Vec<(u32, u32, DateTime<Utc>)> {
+    let loops_before = tips.loops_before();
+
+    loops_before.iter()
+        .enumerate()
+        .map(|(index, loops)| {
+            let tip_cnt = tips.tip_cnt.wrapping_sub((loops_before.len() - 1 - index) as u32);
+            let loop_cnt = tips.loop_cnt.wrapping_sub(*loops as u32);
+            let ts = received_at - Duration::milliseconds((*loops as f64 * period * 1000.0).round() as i64);
+            (tip_cnt, loop_cnt, ts)
+        })
+        .collect()
+}
+
+/// Check that a tips packet is something the rainguage could have sent.
+pub fn validate(tips:&TipsPacket) -> Result<(), String> {
+    if tips.device_id == [0; 16] {
+        return Err("device_id is missing".to_string());
+    }
+
+    if tips.len as usize > MAX_TIPS {
+        return Err(format!("len {} is more than the {} tips a packet can hold", tips.len, MAX_TIPS));
+    }
+
+    if tips.loops_before().windows(2).any(|pair| pair[0] < pair[1]) {
+        return Err("loops_before is not oldest first".to_string());
+    }
+
+    Ok(())
+}
+
+fn store(pool:&Pool, tips:&TipsPacket, received_at:DateTime<Utc>) -> Result<(), DatabaseError> {
+    let mut client = pool.get()?;
+    let mut transaction = client.transaction()?;
+
+    // A tip is known by the counts the gauge gave it, which are the same however many times and whenever the packet is
+    // heard, so it is only stored once.
+    for (tip_cnt, loop_cnt, ts) in tip_times(tips, received_at, reconstruct::nominal_period()) {
+        transaction.execute("
+            INSERT INTO tip (device_id, tip_cnt, loop_cnt, ts, received_at) VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT DO NOTHING",
+            &[&(&tips.device_id[..]), &(tip_cnt as i64), &(loop_cnt as i64), &ts, &received_at])?;
+    }
+
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Store when each tip since the last telemetry packet happened.  Unlike telemetry the tips are written straight
+/// away, a 503 means they could not be.
+#[post("/tips", format = "json", data = "<tips>")]
+pub fn post(tips:Result<Json<TipsPacket>, JsonError>, header:ReceivedAtHeader, pool:State<Pool>,
+        _key:IngestKey) -> IngestResponse {
+    let tips = match tips {
+        Ok(tips) => tips.into_inner(),
+        Err(err) => {
+            metrics::increment_invalid_cnt();
+            return IngestResponse::Invalid(format!("malformed tips: {:?}", err));
+        }
+    };
+
+    if let Err(reason) = validate(&tips) {
+        warn!("Rejecting tips {:?}: {}", tips, reason);
+        metrics::increment_invalid_cnt();
+        return IngestResponse::Invalid(reason);
+    }
+
+    let received_at = match ingest::received_at(header) {
+        Ok(received_at) => received_at,
+        Err(reason) => {
+            metrics::increment_invalid_cnt();
+            return IngestResponse::Invalid(reason);
+        }
+    };
+
+    match store(&pool, &tips, received_at) {
+        Ok(_) => IngestResponse::Stored,
+        Err(err) => {
+            error!("Could not store tips {:?}: {:?}", tips, err);
+            IngestResponse::Unavailable
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn tips(tip_cnt:u32, loops_before:&[u16]) -> TipsPacket {
+        let mut tips = TipsPacket::new();
+        tips.device_id = [10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 120, 130, 140, 150, 160, 170];
+        tips.loop_cnt = 1000;
+        tips.tip_cnt = tip_cnt;
+        tips.len = loops_before.len() as u8;
+        tips.loops_before[..loops_before.len()].copy_from_slice(loops_before);
+        tips
+    }
+
+    #[test]
+    fn dates_tips_from_the_loop_period() {
+        let received_at = Utc.ymd(2020, 9, 1).and_hms(12, 0, 0);
+        let times = tip_times(&tips(12, &[100, 10, 0]), received_at, 0.5);
+
+        assert_eq!(vec![
+            (10, 900, Utc.ymd(2020, 9, 1).and_hms(11, 59, 10)),
+            (11, 990, Utc.ymd(2020, 9, 1).and_hms(11, 59, 55)),
+            (12, 1000, received_at)
+        ], times);
+    }
+
+    #[test]
+    fn tip_cnt_wraps() {
+        let received_at = Utc.ymd(2020, 9, 1).and_hms(12, 0, 0);
+        let times = tip_times(&tips(0, &[2, 1]), received_at, 1.0);
+        assert_eq!(vec![u32::MAX, 0], times.iter().map(|(tip_cnt, _, _)| *tip_cnt).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn stores_a_tip_once_however_often_it_is_heard() {
+        let pool = match crate::database::test_pool() {
+            Some(pool) => pool,
+            None => return
+        };
+        let mut tips = tips(12, &[100, 10, 0]);
+        tips.device_id = [42; 16];
+        let mut client = pool.get().unwrap();
+        client.execute("DELETE FROM tip WHERE device_id = $1", &[&(&tips.device_id[..])]).unwrap();
+
+        // Heard by another gateway, or replayed, a little later.
+        let received_at = Utc::now();
+        store(&pool, &tips, received_at).unwrap();
+        store(&pool, &tips, received_at + Duration::seconds(3)).unwrap();
+
+        let count:i64 = client.query_one("SELECT COUNT(*) FROM tip WHERE device_id = $1", &[&(&tips.device_id[..])])
+            .unwrap()
+            .get(0);
+        assert_eq!(3, count);
+    }
+
+    #[test]
+    fn rejects_nonsense() {
+        assert!(validate(&tips(3, &[30, 20, 10])).is_ok());
+        assert!(validate(&tips(3, &[10, 20, 30])).is_err());
+        assert!(validate(&TipsPacket::new()).is_err());
+
+        let mut long = tips(3, &[]);
+        long.len = MAX_TIPS as u8 + 1;
+        assert!(validate(&long).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+'use strict';
+
+// A device is shown as stale after this long without a packet, the same as the silent alert.
+const STALE_MINUTES = 30;
+
+// The link quality view covers this many hours.
+const LINK_HOURS = 48;
+
+const HOUR = 60 * 60 * 1000;
+
+const main = document.getElementById('main');
+const live = document.getElementById('live');
+
+// A read key for the API, asked for the first time one is needed and kept in the browser.
+function apiKey(forget) {
+    if (forget) {
+        localStorage.removeItem('apiKey');
+    }
+    let key = localStorage.getItem('apiKey');
+    if (!key) {
+        key = prompt('API key') || '';
+        localStorage.setItem('apiKey', key);
+    }
+    return key;
+}
+
+async function get(path) {
+    let response = await fetch(path, { headers: { 'Authorization': 'Bearer ' + apiKey(false) } });
+    if (response.status === 401) {
+        response = await fetch(path, { headers: { 'Authorization': 'Bearer ' + apiKey(true) } });
+    }
+    if (!response.ok) {
+        throw new Error(path + ' returned ' + response.status);
+    }
+    return response.json();
+}
+
+// Like get, but a device without data for a view is not an error.
+async function getOrNull(path) {
+    try {
+        return await get(path);
+    } catch (err) {
+        return null;
+    }
+}
+
+function template(id) {
+    return document.getElementById(id).content.cloneNode(true);
+}
+
+function cell(row, text, className) {
+    const td = document.createElement('td');
+    td.textContent = text;
+    if (className) {
+        td.className = className;
+    }
+    row.appendChild(td);
+    return td;
+}
+
+function age(ts) {
+    const minutes = Math.round((Date.now() - Date.parse(ts)) / 60000);
+    if (minutes < 60) {
+        return minutes + ' min ago';
+    }
+    if (minutes < 48 * 60) {
+        return Math.round(minutes / 60) + ' h ago';
+    }
+    return Math.round(minutes / (24 * 60)) + ' days ago';
+}
+
+function fixed(value, digits, unit) {
+    return value === null || value === undefined ? '' : value.toFixed(digits) + unit;
+}
+
+function battery(runtime) {
+    if (!runtime) {
+        return '';
+    }
+
+    let text = fixed(runtime.volts, 2, 'V') + ' (' + fixed(runtime.state_of_charge, 0, '%') + ')';
+    if (runtime.days_remaining !== null) {
+        text += ', ' + Math.round(runtime.days_remaining) + ' days left';
+    }
+    return text;
+}
+
+function hourOf(ts) {
+    return Math.floor(Date.parse(ts) / HOUR) * HOUR;
+}
+
+// A bar chart of [label, value] pairs as an svg.
+function barChart(container, bars, unit) {
+    container.textContent = '';
+    if (bars.length === 0) {
+        container.innerHTML = '<p class="empty">No data.</p>';
+        return;
+    }
+
+    const width = 900, height = 200, bottom = 20, left = 40;
+    const max = Math.max(...bars.map(bar => bar[1]), 0.001);
+    const step = (width - left) / bars.length;
+
+    const ns = 'http://www.w3.org/2000/svg';
+    const svg = document.createElementNS(ns, 'svg');
+    svg.setAttribute('width', width);
+    svg.setAttribute('height', height);
+
+    const axis = document.createElementNS(ns, 'text');
+    axis.setAttribute('x', 0);
+    axis.setAttribute('y', 10);
+    axis.textContent = max.toFixed(1) + unit;
+    svg.appendChild(axis);
+
+    bars.forEach((bar, i) => {
+        const barHeight = (height - bottom - 15) * bar[1] / max;
+        const rect = document.createElementNS(ns, 'rect');
+        rect.setAttribute('x', left + i * step);
+        rect.setAttribute('y', height - bottom - barHeight);
+        rect.setAttribute('width', Math.max(step - 1, 1));
+        rect.setAttribute('height', barHeight);
+
+        const title = document.createElementNS(ns, 'title');
+        title.textContent = bar[0] + ': ' + bar[1].toFixed(1) + unit;
+        rect.appendChild(title);
+        svg.appendChild(rect);
+
+        // Label roughly every 80 pixels.
+        if (i % Math.max(Math.round(80 / step), 1) === 0) {
+            const label = document.createElementNS(ns, 'text');
+            label.setAttribute('x', left + i * step);
+            label.setAttribute('y', height - 5);
+            label.textContent = bar[0];
+            svg.appendChild(label);
+        }
+    });
+
+    container.appendChild(svg);
+}
+
+async function deviceRow(device) {
+    const [latest, runtime] = await Promise.all([
+        getOrNull('/devices/' + device.device_id + '/latest?fields=temperature,relative_humidity'),
+        getOrNull('/devices/' + device.device_id + '/battery/runtime')
+    ]);
+
+    const row = document.createElement('tr');
+    row.id = 'device-' + device.device_id;
+    row.dataset.lastSeen = device.last_seen;
+
+    const link = document.createElement('a');
+    link.href = '#/devices/' + device.device_id;
+    link.textContent = device.device_id;
+    cell(row, '').appendChild(link);
+
+    cell(row, age(device.last_seen), 'last-seen');
+    cell(row, battery(runtime), 'battery');
+    cell(row, latest ? fixed(latest.temperature, 1, '°C') : '', 'temperature number');
+    cell(row, latest ? fixed(latest.relative_humidity, 0, '%') : '', 'humidity number');
+
+    markStale(row);
+    return row;
+}
+
+function markStale(row) {
+    const stale = Date.now() - Date.parse(row.dataset.lastSeen) > STALE_MINUTES * 60000;
+    row.classList.toggle('stale', stale);
+    row.querySelector('.last-seen').textContent = age(row.dataset.lastSeen);
+}
+
+async function showDevices() {
+    const page = template('devices-template');
+    const body = page.querySelector('tbody');
+
+    const devices = await get('/devices');
+    const rows = await Promise.all(devices.map(deviceRow));
+    rows.forEach(row => body.appendChild(row));
+
+    main.textContent = '';
+    main.appendChild(page);
+}
+
+async function showRainfall(page, deviceId, interval, days) {
+    page.querySelectorAll('nav.interval a').forEach(a => {
+        a.classList.toggle('selected', a.dataset.interval === interval);
+    });
+
+    const from = new Date(Date.now() - days * 24 * HOUR).toISOString();
+    const totals = await get('/devices/' + deviceId + '/rainfall?interval=' + interval + '&from=' + encodeURIComponent(from));
+
+    const label = ts => interval === 'hour' ? ts.substring(11, 16) : ts.substring(5, 10);
+    barChart(page.querySelector('.chart.rainfall'), totals.map(total => [label(total.ts), total.depth_mm]), 'mm');
+}
+
+async function showLink(page, deviceId) {
+    const from = encodeURIComponent(new Date(Date.now() - LINK_HOURS * HOUR).toISOString());
+    const [packets, deltas] = await Promise.all([
+        get('/devices/' + deviceId + '/telemetry?fields=ts&limit=10000&from=' + from),
+        get('/devices/' + deviceId + '/counters?fields=lora_error_cnt,lora_tx_bytes&from=' + from)
+    ]);
+
+    const start = Math.floor((Date.now() - LINK_HOURS * HOUR) / HOUR) * HOUR;
+    const hours = new Map();
+    for (let hour = start; hour <= Date.now(); hour += HOUR) {
+        hours.set(hour, { packets: 0, gap: 0, errors: 0, bytes: 0, restarts: 0 });
+    }
+
+    const gaps = [];
+    packets.forEach((packet, i) => {
+        const hour = hours.get(hourOf(packet.ts));
+        if (!hour) {
+            return;
+        }
+        hour.packets += 1;
+        if (i > 0) {
+            const gap = Date.parse(packet.ts) - Date.parse(packets[i - 1].ts);
+            hour.gap = Math.max(hour.gap, gap);
+            gaps.push(gap);
+        }
+    });
+
+    deltas.forEach(delta => {
+        const hour = hours.get(hourOf(delta.end));
+        if (hour) {
+            hour.errors += delta.lora_error_cnt || 0;
+            hour.bytes += delta.lora_tx_bytes || 0;
+            hour.restarts += delta.rebooted ? 1 : 0;
+        }
+    });
+
+    // The gauge sends on a fixed interval, so the usual gap says how many packets there should have been.
+    const summary = page.querySelector('.link-summary');
+    if (gaps.length > 0) {
+        const sorted = gaps.slice().sort((a, b) => a - b);
+        const interval = sorted[Math.floor(sorted.length / 2)];
+        const span = Date.parse(packets[packets.length - 1].ts) - Date.parse(packets[0].ts);
+        const expected = Math.round(span / interval) + 1;
+        summary.textContent = packets.length + ' of about ' + expected + ' packets received in the last ' + LINK_HOURS
+            + ' hours (' + Math.min(100, 100 * packets.length / expected).toFixed(0) + '%), one every '
+            + (interval / 60000).toFixed(1) + ' minutes.';
+    } else {
+        summary.textContent = 'Not enough packets in the last ' + LINK_HOURS + ' hours.';
+    }
+
+    const entries = Array.from(hours.entries());
+    barChart(page.querySelector('.chart.packets'),
+        entries.map(([hour, stats]) => [new Date(hour).toISOString().substring(11, 16), stats.packets]), '');
+
+    const body = page.querySelector('table.link tbody');
+    entries.reverse().forEach(([hour, stats]) => {
+        const row = document.createElement('tr');
+        cell(row, new Date(hour).toISOString().substring(0, 16).replace('T', ' '));
+        cell(row, stats.packets, 'number');
+        cell(row, stats.gap ? (stats.gap / 60000).toFixed(1) + ' min' : '', 'number');
+        cell(row, stats.errors, 'number');
+        cell(row, stats.bytes, 'number');
+        cell(row, stats.restarts || '', 'number');
+        body.appendChild(row);
+    });
+}
+
+async function showDevice(deviceId) {
+    const fragment = template('device-template');
+    main.textContent = '';
+    main.appendChild(fragment);
+    const page = main;
+
+    page.querySelector('.device-id').textContent = deviceId;
+
+    const [latest, runtime] = await Promise.all([
+        getOrNull('/devices/' + deviceId + '/latest?fields=temperature,relative_humidity'),
+        getOrNull('/devices/' + deviceId + '/battery/runtime')
+    ]);
+    if (latest) {
+        page.querySelector('.summary').textContent = 'Last seen ' + age(latest.ts) + '.  '
+            + fixed(latest.temperature, 1, '°C') + ', ' + fixed(latest.relative_humidity, 0, '%') + ' humidity.  '
+            + 'Battery ' + battery(runtime) + '.';
+    }
+
+    page.querySelectorAll('nav.interval a').forEach(a => {
+        a.onclick = () => showRainfall(page, deviceId, a.dataset.interval, Number(a.dataset.days));
+    });
+
+    await Promise.all([
+        showRainfall(page, deviceId, 'hour', 2),
+        showLink(page, deviceId)
+    ]);
+}
+
+// Keep the device list up to date from the event stream rather than polling.
+function listen() {
+    // EventSource cannot send headers.
+    const events = new EventSource('/events?access_token=' + encodeURIComponent(apiKey(false)));
+    events.onopen = () => {
+        live.textContent = 'live';
+        live.classList.add('connected');
+    };
+    events.onerror = () => {
+        live.textContent = 'reconnecting';
+        live.classList.remove('connected');
+    };
+    events.addEventListener('telemetry', event => {
+        const packet = JSON.parse(event.data);
+        const row = document.getElementById('device-' + packet.device_id);
+        if (row) {
+            row.dataset.lastSeen = packet.received_at;
+            row.querySelector('.temperature').textContent = fixed(packet.temperature, 1, '°C');
+            row.querySelector('.humidity').textContent = fixed(packet.relative_humidity, 0, '%');
+            markStale(row);
+        } else if (location.hash === '' || location.hash === '#/') {
+            route();
+        }
+    });
+}
+
+async function route() {
+    const match = location.hash.match(/^#\/devices\/([0-9a-f]{32})$/);
+    try {
+        if (match) {
+            await showDevice(match[1]);
+        } else {
+            await showDevices();
+        }
+    } catch (err) {
+        main.innerHTML = '<p class="empty"></p>';
+        main.querySelector('p').textContent = err.message;
+    }
+}
+
+window.addEventListener('hashchange', route);
+setInterval(() => document.querySelectorAll('tr[data-last-seen]').forEach(markStale), 60000);
+
+route().then(listen);